@@ -0,0 +1,6 @@
+MEMORY
+{
+    /* STM32F103C8, the last two 1K pages hold the startup settings */
+    FLASH : ORIGIN = 0x08000000, LENGTH = 64K - 2K
+    RAM : ORIGIN = 0x20000000, LENGTH = 20K
+}
@@ -1,6 +1,16 @@
+use doggie_core::StartupStore;
+use embassy_stm32::flash::{Blocking, Flash, FLASH_SIZE, MAX_ERASE_SIZE};
+use embassy_stm32::peripherals::FLASH;
 use embassy_stm32::rcc::*;
 use embassy_stm32::{time::Hertz, Config as StmConfig};
 
+// Last two pages of the flash, for the startup settings. memory.x keeps the
+// program below them
+const STARTUP_SIZE: usize = 2 * MAX_ERASE_SIZE;
+const STARTUP_OFFSET: u32 = (FLASH_SIZE - STARTUP_SIZE) as u32;
+
+pub type FlashType = Flash<'static, Blocking>;
+
 pub fn init() -> embassy_stm32::Peripherals {
     let mut config = StmConfig::default();
 
@@ -24,3 +34,11 @@ pub fn init() -> embassy_stm32::Peripherals {
 
     embassy_stm32::init(config)
 }
+
+pub fn flash(flash: FLASH) -> FlashType {
+    Flash::new_blocking(flash)
+}
+
+pub fn startup_store(flash: FLASH) -> StartupStore<FlashType> {
+    StartupStore::new(self::flash(flash), STARTUP_OFFSET, STARTUP_SIZE)
+}
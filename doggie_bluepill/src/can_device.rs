@@ -1,26 +1,161 @@
+use core::num::{NonZeroU16, NonZeroU8};
 use defmt::{error, info};
-use doggie_core::{CanBitrates, CanDevice};
+use doggie_core::{
+    AsyncCanDevice, BitTiming, BitrateError, CanBitrate, CanDevice, CanMode, CanStatus,
+    FilterEntry, FilterFit,
+};
 use embassy_futures::block_on;
+use embassy_stm32::can::util::{calc_can_timings, NominalBitTiming};
 use embassy_stm32::can::Can as StmCan;
-use embassy_stm32::can::{filter, Fifo, Id};
+use embassy_stm32::can::{filter, Fifo, Id, Mailbox};
+use embassy_stm32::interrupt;
+use embassy_stm32::time::Hertz;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
 use embedded_can::{blocking::Can, ErrorKind, ExtendedId, StandardId};
 
+// bxCAN runs from APB1, configured at 36MHz in bluepill.rs
+const CAN_CLOCK_HZ: u32 = 36_000_000;
+
+// MCR / MSR bits
+const MCR_INRQ: u32 = 1 << 0;
+const MSR_INAK: u32 = 1 << 0;
+const MSR_ERRI: u32 = 1 << 2;
+
+// IER bits: TX mailbox empty, FIFO 0 pending and the error interrupts
+const IER_TMEIE: u32 = 1 << 0;
+const IER_FMPIE0: u32 = 1 << 1;
+const IER_EWGIE: u32 = 1 << 8;
+const IER_EPVIE: u32 = 1 << 9;
+const IER_BOFIE: u32 = 1 << 10;
+const IER_LECIE: u32 = 1 << 11;
+const IER_ERRIE: u32 = 1 << 15;
+const IER_EVENTS: u32 =
+    IER_TMEIE | IER_FMPIE0 | IER_EWGIE | IER_EPVIE | IER_BOFIE | IER_LECIE | IER_ERRIE;
+
+// ESR bits
+const ESR_EWGF: u32 = 1 << 0;
+const ESR_EPVF: u32 = 1 << 1;
+const ESR_BOFF: u32 = 1 << 2;
+const ESR_LEC_MASK: u32 = 0b111 << 4;
+
+// RF0R bits
+const RFR_FULL: u32 = 1 << 3;
+const RFR_FOVR: u32 = 1 << 4;
+
+// TSR bits
+const TSR_ALST0: u32 = 1 << 2;
+const TSR_ALST1: u32 = 1 << 10;
+const TSR_ALST2: u32 = 1 << 18;
+const TSR_TME0: u32 = 1 << 26;
+const TSR_TME_MASK: u32 = 0b111 << 26;
+
+// Raised by the bxCAN interrupts, wait_for_event sleeps on it
+static CAN_EVENT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Bound after the embassy handlers of the same interrupts, see
+// doggie_bluepill_uart_int.rs
+pub struct EventInterruptHandler;
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::USB_LP_CAN1_RX0>
+    for EventInterruptHandler
+{
+    unsafe fn on_interrupt() {
+        CAN_EVENT.signal(());
+    }
+}
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::USB_HP_CAN1_TX> for EventInterruptHandler {
+    unsafe fn on_interrupt() {
+        CAN_EVENT.signal(());
+    }
+}
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::CAN1_SCE> for EventInterruptHandler {
+    unsafe fn on_interrupt() {
+        // Acknowledge the error so it does not fire again once re-armed, the
+        // flags stay in ESR for status
+        embassy_stm32::pac::CAN.msr().write(|w| w.0 = MSR_ERRI);
+        CAN_EVENT.signal(());
+    }
+}
+
+fn status_from_registers(esr: u32, rf0r: u32, tsr: u32) -> CanStatus {
+    CanStatus {
+        rx_fifo_full: rf0r & RFR_FULL != 0,
+        // No empty mailbox left
+        tx_fifo_full: tsr & TSR_TME_MASK == 0,
+        error_warning: esr & ESR_EWGF != 0,
+        data_overrun: rf0r & RFR_FOVR != 0,
+        error_passive: esr & ESR_EPVF != 0,
+        bus_off: esr & ESR_BOFF != 0,
+        arbitration_lost: tsr & (TSR_ALST0 | TSR_ALST1 | TSR_ALST2) != 0,
+        bus_error: esr & ESR_LEC_MASK != 0,
+        tx_error_count: (esr >> 16) as u8,
+        rx_error_count: (esr >> 24) as u8,
+    }
+}
+
+fn nominal_from_bit_timing(timing: &BitTiming) -> Option<NominalBitTiming> {
+    let timing = timing.with_clock(CAN_CLOCK_HZ)?;
+
+    // BTR limits: BRP 10 bits, TS1 4 bits, TS2 3 bits, SJW 2 bits
+    if timing.prescaler > 1024 || timing.tseg1 > 16 || timing.tseg2 > 8 || timing.sjw > 4 {
+        return None;
+    }
+
+    Some(NominalBitTiming {
+        prescaler: NonZeroU16::new(timing.prescaler)?,
+        seg1: NonZeroU8::new(timing.tseg1)?,
+        seg2: NonZeroU8::new(timing.tseg2)?,
+        sync_jump_width: NonZeroU8::new(timing.sjw)?,
+    })
+}
+
 pub struct CanWrapper<'d> {
     can: StmCan<'d>,
+    mode: CanMode,
+    // Frame in each TX mailbox, until it is sent
+    tx_pending: [Option<embassy_stm32::can::frame::Frame>; 3],
 }
 
 impl<'d> CanWrapper<'d> {
     pub fn new(can: StmCan<'d>) -> Self {
-        CanWrapper { can }
+        CanWrapper {
+            can,
+            mode: CanMode::Normal,
+            tx_pending: [None, None, None],
+        }
+    }
+
+    // A frame dequeued for a higher priority one is replaced, it was never
+    // sent
+    fn track_transmit(&mut self, mailbox: Mailbox, frame: &embassy_stm32::can::frame::Frame) {
+        self.tx_pending[mailbox as usize] = Some(frame.clone());
+    }
+
+    // Any config change leaves initialization mode, so put the controller
+    // back on the bus, or keep it in initialization mode while configuring
+    fn restart(&mut self) {
+        if self.mode == CanMode::Configuration {
+            let regs = embassy_stm32::pac::CAN;
+
+            regs.mcr().modify(|w| w.0 |= MCR_INRQ);
+            while regs.msr().read().0 & MSR_INAK == 0 {}
+        } else {
+            // Re enable can
+            block_on(self.can.enable());
+        }
     }
 }
 
 #[derive(Debug)]
-pub struct CanError {}
+pub struct CanError(ErrorKind);
 
 impl<'d> embedded_can::Error for CanError {
     fn kind(&self) -> ErrorKind {
-        ErrorKind::Other
+        self.0
     }
 }
 
@@ -30,49 +165,172 @@ impl<'d> Can for CanWrapper<'d> {
 
     fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
         match self.can.try_write(frame) {
-            Ok(_) => Ok(()),
+            Ok(status) => {
+                self.track_transmit(status.mailbox(), frame);
+                Ok(())
+            }
             Err(err) => {
                 error!("CAN controller Error: {:?}", err);
-                Err(CanError {})
+                Err(CanError(ErrorKind::Other))
             }
         }
     }
     fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
         match self.can.try_read() {
             Ok(envelope) => Ok(envelope.frame),
-            Err(_) => Err(CanError {}),
+            Err(_) => Err(CanError(ErrorKind::Other)),
         }
     }
 }
 
 impl<'d> CanDevice for CanWrapper<'d> {
-    fn set_bitrate(&mut self, bitrate: CanBitrates) {
-        info!("Setting bitrate to: {:X}", bitrate as u32);
-        self.can.set_bitrate((bitrate as u32) * 1_000);
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        // Checked here, embassy panics on bitrates it can't reach
+        let Some(nominal) = calc_can_timings(Hertz(CAN_CLOCK_HZ), bitrate.bps()) else {
+            error!("Bitrate not supported by bxCAN");
+            return Err(BitrateError::Unsupported);
+        };
 
-        // Re enable can
-        block_on(self.can.enable());
+        info!("Setting bitrate to: {}", bitrate.bps());
+        self.can.modify_config().set_bit_timing(nominal);
+
+        self.restart();
+
+        Ok(())
     }
 
-    fn set_filter(&mut self, id: Id) {
-        self.can.modify_filters().enable_bank(
-            0,
-            Fifo::Fifo0,
-            [
-                filter::ListEntry32::data_frames_with_id(id),
-                filter::ListEntry32::data_frames_with_id(id),
-            ],
-        );
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        let Some(nominal) = nominal_from_bit_timing(&timing) else {
+            error!("Bit timing can't be represented on bxCAN");
+            return Err(BitrateError::Unsupported);
+        };
+
+        info!("Setting bit timing to {} bps", timing.bitrate());
+        self.can.modify_config().set_bit_timing(nominal);
+
+        self.restart();
+
+        Ok(())
+    }
+
+    fn status(&mut self) -> CanStatus {
+        let regs = embassy_stm32::pac::CAN;
+
+        let esr = regs.esr().read().0;
+        let rf0r = regs.rfr(0).read().0;
+        let tsr = regs.tsr().read().0;
+
+        // Overrun and last error code are latched, clear them once read
+        regs.rfr(0).write(|w| w.0 = RFR_FOVR);
+        regs.esr().modify(|w| w.0 &= !ESR_LEC_MASK);
+
+        status_from_registers(esr, rf0r, tsr)
     }
 
-    fn set_mask(&mut self, id: Id) {
-        let new_filter = match id {
-            Id::Standard(id) => filter::Mask32::frames_with_std_id(StandardId::MAX, id),
-            Id::Extended(id) => filter::Mask32::frames_with_ext_id(ExtendedId::MAX, id),
+    fn set_mode(&mut self, mode: CanMode) {
+        let (silent, loopback) = match mode {
+            CanMode::Normal | CanMode::Configuration => (false, false),
+            CanMode::ListenOnly => (true, false),
+            CanMode::Loopback => (false, true),
         };
 
+        self.mode = mode;
+        if mode == CanMode::Configuration {
+            self.tx_pending = [None, None, None];
+        }
+
         self.can
-            .modify_filters()
-            .enable_bank(0, Fifo::Fifo0, new_filter);
+            .modify_config()
+            .set_silent(silent)
+            .set_loopback(loopback);
+
+        self.restart();
+    }
+
+    // One bank in 32 bit mask mode per filter. When there are more filters
+    // than banks, the last bank accepts everything
+    fn set_filters(&mut self, entries: &[FilterEntry]) -> FilterFit {
+        let entries = if entries.is_empty() {
+            &FilterEntry::ACCEPT_ALL[..]
+        } else {
+            entries
+        };
+
+        let mut filters = self.can.modify_filters();
+        filters.clear();
+
+        let banks = filters.num_banks() as usize;
+        let exact = entries.len() <= banks;
+
+        for (bank, entry) in entries.iter().take(banks).enumerate() {
+            let config = match (exact || bank < banks - 1, entry.id) {
+                (false, _) => filter::Mask32::accept_all(),
+                (true, Id::Standard(id)) => filter::Mask32::frames_with_std_id(
+                    id,
+                    StandardId::new(entry.mask as u16 & StandardId::MAX.as_raw()).unwrap(),
+                ),
+                (true, Id::Extended(id)) => filter::Mask32::frames_with_ext_id(
+                    id,
+                    ExtendedId::new(entry.mask & ExtendedId::MAX.as_raw()).unwrap(),
+                ),
+            };
+
+            filters.enable_bank(bank as u8, Fifo::Fifo0, config);
+        }
+
+        info!("Filters changed");
+
+        if exact {
+            FilterFit::Exact
+        } else {
+            FilterFit::Superset
+        }
+    }
+
+    // Frames are retransmitted until sent, so a mailbox only empties before
+    // that when its frame is dequeued, which replaces it
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        let tsr = embassy_stm32::pac::CAN.tsr().read().0;
+
+        self.tx_pending
+            .iter_mut()
+            .enumerate()
+            .find(|(mailbox, pending)| pending.is_some() && tsr & (TSR_TME0 << mailbox) != 0)
+            .and_then(|(_, pending)| pending.take())
+            .map(|frame| (frame, Instant::now()))
+    }
+}
+
+// Uses the bxCAN RX, TX and SCE interrupts, they must be bound with the Can
+// and EventInterruptHandler
+impl<'d> AsyncCanDevice for CanWrapper<'d> {
+    async fn wait_for_event(&mut self) {
+        // The embassy handlers mask RX and error interrupts once they fire,
+        // until their own futures re-arm them
+        embassy_stm32::pac::CAN.ier().modify(|w| w.0 |= IER_EVENTS);
+        CAN_EVENT.wait().await;
+    }
+
+    // The RX interrupt timestamps the frame as it leaves the FIFO
+    async fn receive_async(&mut self) -> Result<(Self::Frame, Instant), Self::Error> {
+        match self.can.read().await {
+            Ok(envelope) => Ok((envelope.frame, envelope.ts)),
+            Err(err) => {
+                error!("CAN bus error: {:?}", err);
+                Err(CanError(ErrorKind::Other))
+            }
+        }
+    }
+
+    // Fails with Overrun while every mailbox is pending, the core retries
+    // until its TX timeout
+    async fn transmit_async(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        match self.can.try_write(frame) {
+            Ok(status) => {
+                self.track_transmit(status.mailbox(), frame);
+                Ok(())
+            }
+            Err(_) => Err(CanError(ErrorKind::Overrun)),
+        }
     }
 }
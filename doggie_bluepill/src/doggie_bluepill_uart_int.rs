@@ -5,11 +5,13 @@ mod can_device;
 mod uart;
 mod uart_device;
 
+use bluepill::FlashType;
 use can_device::CanWrapper;
 use uart_device::UartWrapper;
 
 use doggie_core::{
     core_create_tasks, core_run, Bsp, CanChannel, CanChannelReceiver, CanChannelSender, Core,
+    DeviceId,
 };
 
 use defmt::info;
@@ -28,10 +30,10 @@ use embassy_stm32::{
 use embassy_time::Timer;
 
 bind_interrupts!(struct CanIrqs {
-    USB_LP_CAN1_RX0 => Rx0InterruptHandler<CAN>;
+    USB_LP_CAN1_RX0 => Rx0InterruptHandler<CAN>, can_device::EventInterruptHandler;
     CAN1_RX1 => Rx1InterruptHandler<CAN>;
-    CAN1_SCE => SceInterruptHandler<CAN>;
-    USB_HP_CAN1_TX => TxInterruptHandler<CAN>;
+    CAN1_SCE => SceInterruptHandler<CAN>, can_device::EventInterruptHandler;
+    USB_HP_CAN1_TX => TxInterruptHandler<CAN>, can_device::EventInterruptHandler;
 });
 
 #[embassy_executor::task]
@@ -53,7 +55,15 @@ async fn main(spawner: Spawner) {
 
     spawner.spawn(blink_task(led)).unwrap();
 
-    let serial = create_default_uart!(p);
+    // 96 bit unique id of the STM32
+    let device_id = DeviceId::new(embassy_stm32::uid::uid());
+
+    // Startup settings saved in flash
+    let mut startup_store = bluepill::startup_store(p.FLASH);
+    let startup = startup_store.load_or_default();
+
+    let baudrate = startup.uart_baud.unwrap_or(uart::DEFAULT_BAUDRATE);
+    let serial = create_default_uart!(p, baudrate);
 
     // Set alternate pin mapping to B8/B9
     embassy_stm32::pac::AFIO
@@ -74,7 +84,9 @@ async fn main(spawner: Spawner) {
 
     let can_wrapper = CanWrapper::new(can);
 
-    let bsp = Bsp::new(can_wrapper, serial);
+    let bsp = Bsp::new(can_wrapper, serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
 
     // Create and run the Doggie core
     let core = Core::new(spawner, bsp);
@@ -86,4 +98,4 @@ async fn main(spawner: Spawner) {
 type SerialType = UartWrapper<'static>;
 type CanType = CanWrapper<'static>;
 
-core_create_tasks!(SerialType, CanType);
+core_create_tasks!(SerialType, CanType, FlashType);
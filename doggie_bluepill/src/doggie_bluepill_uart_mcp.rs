@@ -8,20 +8,22 @@ mod spi_device;
 mod uart;
 mod uart_device;
 
+use bluepill::FlashType;
 use soft_timer::SoftTimer;
 use spi_device::CustomSpiDevice;
 use uart_device::UartWrapper;
 
 use doggie_core::{
     core_create_tasks, core_run, Bsp, CanChannel, CanChannelReceiver, CanChannelSender, Core,
+    DeviceId, Mcp2515Irq,
 };
 
 use defmt::info;
-use mcp2515::MCP2515;
 use {defmt_rtt as _, panic_probe as _};
 
 use embassy_executor::Spawner;
-use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Level, Output, Pull, Speed};
 use embassy_stm32::mode;
 use embassy_time::Timer;
 
@@ -44,7 +46,15 @@ async fn main(spawner: Spawner) {
 
     spawner.spawn(blink_task(led)).unwrap();
 
-    let serial = create_default_uart!(p);
+    // 96 bit unique id of the STM32
+    let device_id = DeviceId::new(embassy_stm32::uid::uid());
+
+    // Startup settings saved in flash
+    let mut startup_store = bluepill::startup_store(p.FLASH);
+    let startup = startup_store.load_or_default();
+
+    let baudrate = startup.uart_baud.unwrap_or(uart::DEFAULT_BAUDRATE);
+    let serial = create_default_uart!(p, baudrate);
 
     // Delay for the MCP2515
     let delay = SoftTimer {};
@@ -52,7 +62,15 @@ async fn main(spawner: Spawner) {
     // Setup SPI
     let spi = create_default_spi!(p);
 
-    let bsp = Bsp::new_with_mcp2515(spi, delay, serial);
+    // MCP2515 INT pin
+    let int = ExtiInput::new(p.PB0, p.EXTI0, Pull::Up);
+
+    // Crystal, CLKOUT and initial bitrate, set through the mcp2515-* features.
+    // A crystal saved with cWx takes over
+    let config = startup.mcp2515_config();
+    let bsp = Bsp::new_with_mcp2515_irq(spi, delay, int, config, serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
 
     // Create and run the Doggie core
     let core = Core::new(spawner, bsp);
@@ -62,6 +80,6 @@ async fn main(spawner: Spawner) {
 }
 
 type SerialType = UartWrapper<'static>;
-type CanType = MCP2515<CustomSpiDevice<'static, mode::Blocking>>;
+type CanType = Mcp2515Irq<CustomSpiDevice<'static, mode::Blocking>, ExtiInput<'static>>;
 
-core_create_tasks!(SerialType, CanType);
+core_create_tasks!(SerialType, CanType, FlashType);
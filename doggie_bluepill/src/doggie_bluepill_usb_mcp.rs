@@ -7,22 +7,24 @@ mod spi;
 mod spi_device;
 mod usb_device;
 
+use bluepill::FlashType;
 use soft_timer::SoftTimer;
 use spi_device::CustomSpiDevice;
 use usb_device::UsbWrapper;
 
 use doggie_core::{
     core_create_tasks, core_run, Bsp, CanChannel, CanChannelReceiver, CanChannelSender, Core,
+    DeviceId, Mcp2515Irq, UsbSerialBuffer,
 };
 
 use defmt::info;
-use mcp2515::MCP2515;
 use {defmt_rtt as _, panic_probe as _};
 
 use embassy_executor::Spawner;
 use embassy_stm32::{
     bind_interrupts,
-    gpio::{Level, Output, Speed},
+    exti::ExtiInput,
+    gpio::{Level, Output, Pull, Speed},
     mode, peripherals,
     peripherals::USB,
     usb,
@@ -63,6 +65,16 @@ async fn main(spawner: Spawner) {
 
     spawner.spawn(blink_task(led)).unwrap();
 
+    // 96 bit unique id of the STM32
+    let device_id = DeviceId::new(embassy_stm32::uid::uid());
+
+    // Startup settings saved in flash
+    let mut startup_store = bluepill::startup_store(p.FLASH);
+    let startup = startup_store.load_or_default();
+
+    static USB_SERIAL: StaticCell<UsbSerialBuffer> = StaticCell::new();
+    let usb_serial = device_id.usb_serial(USB_SERIAL.init([0; 32]));
+
     let serial = {
         {
             // BluePill board has a pull-up resistor on the D+ line.
@@ -81,7 +93,7 @@ async fn main(spawner: Spawner) {
             let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
             config.manufacturer = Some("Aznarez/Gianatiempo");
             config.product = Some("DoggieBluepill");
-            config.serial_number = Some("1337");
+            config.serial_number = Some(usb_serial);
             config.max_power = 100;
             config.max_packet_size_0 = 64;
             config.device_class = 0xEF;
@@ -137,7 +149,15 @@ async fn main(spawner: Spawner) {
     // Setup SPI
     let spi = create_default_spi!(p);
 
-    let bsp = Bsp::new_with_mcp2515(spi, delay, serial);
+    // MCP2515 INT pin
+    let int = ExtiInput::new(p.PB0, p.EXTI0, Pull::Up);
+
+    // Crystal, CLKOUT and initial bitrate, set through the mcp2515-* features.
+    // A crystal saved with cWx takes over
+    let config = startup.mcp2515_config();
+    let bsp = Bsp::new_with_mcp2515_irq(spi, delay, int, config, serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
 
     // Create and run the Doggie core
     let core = Core::new(spawner, bsp);
@@ -147,6 +167,6 @@ async fn main(spawner: Spawner) {
 }
 
 type SerialType = UsbWrapper<'static>;
-type CanType = MCP2515<CustomSpiDevice<'static, mode::Blocking>>;
+type CanType = Mcp2515Irq<CustomSpiDevice<'static, mode::Blocking>, ExtiInput<'static>>;
 
-core_create_tasks!(SerialType, CanType);
+core_create_tasks!(SerialType, CanType, FlashType);
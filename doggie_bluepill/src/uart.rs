@@ -8,15 +8,19 @@ bind_interrupts!(struct UartIrqs {
     USART2 => usart::InterruptHandler<peripherals::USART2>;
 });
 
+// Unless another one was saved with cWu
+pub const DEFAULT_BAUDRATE: u32 = 921_600;
+
 pub fn create_uart<'d>(
     uart: peripherals::USART2,
     tx: peripherals::PA2,
     rx: peripherals::PA3,
     dma1: peripherals::DMA1_CH7,
     dma2: peripherals::DMA1_CH6,
+    baudrate: u32,
 ) -> UartWrapper<'d> {
     let mut uart_config = usart::Config::default();
-    uart_config.baudrate = 921_600;
+    uart_config.baudrate = baudrate;
 
     // Initialize UART
     UartWrapper::new(Uart::new(uart, rx, tx, UartIrqs, dma1, dma2, uart_config).unwrap())
@@ -24,7 +28,14 @@ pub fn create_uart<'d>(
 
 #[macro_export]
 macro_rules! create_default_uart {
-    ($p:expr) => {{
-        uart::create_uart($p.USART2, $p.PA2, $p.PA3, $p.DMA1_CH7, $p.DMA1_CH6)
+    ($p:expr, $baudrate:expr) => {{
+        uart::create_uart(
+            $p.USART2,
+            $p.PA2,
+            $p.PA3,
+            $p.DMA1_CH7,
+            $p.DMA1_CH6,
+            $baudrate,
+        )
     }};
 }
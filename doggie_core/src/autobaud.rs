@@ -0,0 +1,219 @@
+use crate::can::{CanBitrate, CanDevice, CanMode};
+use embedded_can::{Error, ErrorKind};
+
+// Rates tried while detecting the bitrate, the most common first
+pub const AUTOBAUD_BITRATES: [CanBitrate; 11] = [
+    CanBitrate::from_kbps(500),
+    CanBitrate::from_kbps(250),
+    CanBitrate::from_kbps(125),
+    CanBitrate::from_kbps(1000),
+    CanBitrate::from_kbps(100),
+    CanBitrate::from_bps(83_333),
+    CanBitrate::from_kbps(50),
+    CanBitrate::from_bps(33_333),
+    CanBitrate::from_kbps(800),
+    CanBitrate::from_kbps(20),
+    CanBitrate::from_kbps(10),
+];
+
+// Frames without any error needed to stop at a rate without trying the rest
+const AUTOBAUD_CONFIDENT_FRAMES: u32 = 4;
+
+// What was heard while listening at a rate
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct AutobaudScore {
+    pub frames: u32,
+    pub errors: u32,
+}
+
+impl AutobaudScore {
+    // At a wrong rate the controller mostly sees errors, frames only get
+    // through by chance
+    pub fn value(&self) -> u32 {
+        self.frames.saturating_sub(self.errors)
+    }
+
+    pub fn is_confident(&self) -> bool {
+        self.errors == 0 && self.frames >= AUTOBAUD_CONFIDENT_FRAMES
+    }
+}
+
+// Bitrate detection, listening to the bus at each rate in turn. The caller
+// decides how long to listen at each one, calling `poll` meanwhile.
+pub struct Autobaud {
+    next: usize,
+    current: Option<CanBitrate>,
+    score: AutobaudScore,
+    rx_error_count: u8,
+    best: Option<(CanBitrate, AutobaudScore)>,
+}
+
+impl Default for Autobaud {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Autobaud {
+    pub fn new() -> Self {
+        Autobaud {
+            next: 0,
+            current: None,
+            score: AutobaudScore::default(),
+            rx_error_count: 0,
+            best: None,
+        }
+    }
+
+    // Score the current rate and listen at the next one the controller
+    // supports. Returns None once done, all rates tried or one is certain.
+    pub fn next_rate<CAN: CanDevice>(&mut self, can: &mut CAN) -> Option<CanBitrate> {
+        if let Some(bitrate) = self.current.take() {
+            if self.score.value() > self.best.map_or(0, |(_, best)| best.value()) {
+                self.best = Some((bitrate, self.score));
+            }
+
+            if self.score.is_confident() {
+                return None;
+            }
+        }
+
+        while let Some(&bitrate) = AUTOBAUD_BITRATES.get(self.next) {
+            self.next += 1;
+
+            // Off the bus while changing the bitrate
+            can.set_mode(CanMode::Configuration);
+            if can.set_bitrate(bitrate).is_err() {
+                continue;
+            }
+
+            // Clear the flags latched at the previous rate
+            self.rx_error_count = can.status().rx_error_count;
+            self.score = AutobaudScore::default();
+            self.current = Some(bitrate);
+
+            // Listen only, a wrong bitrate must not disturb the bus
+            can.set_mode(CanMode::ListenOnly);
+
+            return Some(bitrate);
+        }
+
+        None
+    }
+
+    // Read what the controller got at the current rate
+    pub fn poll<CAN: CanDevice>(&mut self, can: &mut CAN) {
+        if self.current.is_none() {
+            return;
+        }
+
+        loop {
+            match can.receive() {
+                Ok(_) => self.score.frames += 1,
+                Err(e) => {
+                    match e.kind() {
+                        // Frames were lost, but they were received fine
+                        ErrorKind::Overrun => self.score.frames += 1,
+                        // Nothing received
+                        ErrorKind::Other => {}
+                        _ => self.score.errors += 1,
+                    }
+                    break;
+                }
+            }
+        }
+
+        let status = can.status();
+        if status.bus_error || status.rx_error_count > self.rx_error_count {
+            self.score.errors += 1;
+        }
+        self.rx_error_count = status.rx_error_count;
+    }
+
+    // The current rate is certain, no need to keep listening
+    pub fn is_confident(&self) -> bool {
+        self.score.is_confident()
+    }
+
+    // Rate with the best score once done, None if no frame was received
+    pub fn detected(&self) -> Option<CanBitrate> {
+        self.best.map(|(bitrate, _)| bitrate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCan, MockEvent};
+
+    // Listen a few times at each rate, as can_task does for a while
+    fn detect(can: &mut MockCan) -> Option<CanBitrate> {
+        let mut autobaud = Autobaud::new();
+        while autobaud.next_rate(can).is_some() {
+            assert!(can.mode == CanMode::ListenOnly);
+            for _ in 0..4 {
+                autobaud.poll(can);
+            }
+        }
+        autobaud.detected()
+    }
+
+    #[test]
+    fn test_autobaud_stops_when_confident() {
+        let mut can = MockCan::new(|bitrate| match bitrate.bps() {
+            500_000 => &[MockEvent::ErrorFrame, MockEvent::Error(ErrorKind::Crc)],
+            250_000 => &[MockEvent::Frame(0x100); 5],
+            _ => &[MockEvent::Frame(0x200); 8],
+        });
+
+        assert_eq!(detect(&mut can), Some(CanBitrate::from_kbps(250)));
+        // 125 kbit/s and slower were never tried
+        assert_eq!(can.bitrates_set, 2);
+    }
+
+    #[test]
+    fn test_autobaud_best_score() {
+        let mut can = MockCan::new(|bitrate| match bitrate.bps() {
+            500_000 => &[MockEvent::Frame(0x100), MockEvent::ErrorFrame],
+            125_000 => &[
+                MockEvent::Frame(0x100),
+                MockEvent::Frame(0x101),
+                MockEvent::Error(ErrorKind::Stuff),
+            ],
+            83_333 => &[MockEvent::Frame(0x100), MockEvent::Frame(0x101)],
+            _ => &[],
+        });
+
+        assert_eq!(detect(&mut can), Some(CanBitrate::from_bps(83_333)));
+        assert_eq!(can.bitrates_set, AUTOBAUD_BITRATES.len());
+    }
+
+    #[test]
+    fn test_autobaud_errors_only() {
+        let mut can = MockCan::new(|_| &[MockEvent::ErrorFrame, MockEvent::Error(ErrorKind::Form)]);
+
+        assert_eq!(detect(&mut can), None);
+    }
+
+    #[test]
+    fn test_autobaud_skips_unsupported() {
+        let mut can = MockCan::new(|bitrate| match bitrate.bps() {
+            1_000_000 | 10_000 => &[MockEvent::Frame(0x100); 5],
+            _ => &[],
+        })
+        .with_unsupported(&[1_000_000]);
+
+        assert_eq!(detect(&mut can), Some(CanBitrate::from_kbps(10)));
+        assert_eq!(can.bitrates_set, AUTOBAUD_BITRATES.len() - 1);
+    }
+
+    #[test]
+    fn test_autobaud_overrun_counts_as_traffic() {
+        let mut can = MockCan::new(|bitrate| match bitrate.bps() {
+            50_000 => &[MockEvent::Error(ErrorKind::Overrun)],
+            _ => &[],
+        });
+
+        assert_eq!(detect(&mut can), Some(CanBitrate::from_kbps(50)));
+    }
+}
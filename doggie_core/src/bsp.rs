@@ -1,16 +1,26 @@
 use embedded_io_async::{Read, Write};
+use embedded_storage::nor_flash::NorFlash;
 
 use crate::can::CanDevice;
+use crate::device_id::DeviceId;
+use crate::recovery::BusConfig;
+use crate::startup::{NoFlash, StartupConfig, StartupStore};
 
 use core::cell::RefCell;
 
-pub struct Bsp<CAN, SERIAL>
+pub struct Bsp<CAN, SERIAL, FLASH = NoFlash>
 where
     CAN: CanDevice,
     SERIAL: Read + Write,
+    FLASH: NorFlash,
 {
     pub can: RefCell<Option<CAN>>,
     pub serial: RefCell<Option<SERIAL>>,
+    pub device_id: DeviceId,
+    pub bus_config: BusConfig,
+    // Settings loaded at boot, and where they are saved
+    pub startup: StartupConfig,
+    pub startup_store: RefCell<Option<StartupStore<FLASH>>>,
 }
 
 impl<CAN, SERIAL> Bsp<CAN, SERIAL>
@@ -22,6 +32,46 @@ where
         Bsp {
             can: RefCell::new(Some(can)),
             serial: RefCell::new(Some(serial)),
+            device_id: DeviceId::default(),
+            bus_config: BusConfig::default(),
+            startup: StartupConfig::default(),
+            startup_store: RefCell::new(Some(StartupStore::new(NoFlash, 0, 0))),
         }
     }
+
+    // Settings loaded from `store` at boot, before the peripherals were set
+    // up with them
+    pub fn with_startup<F: NorFlash>(
+        self,
+        store: StartupStore<F>,
+        startup: StartupConfig,
+    ) -> Bsp<CAN, SERIAL, F> {
+        Bsp {
+            can: self.can,
+            serial: self.serial,
+            device_id: self.device_id,
+            bus_config: self.bus_config,
+            startup,
+            startup_store: RefCell::new(Some(store)),
+        }
+    }
+}
+
+impl<CAN, SERIAL, FLASH> Bsp<CAN, SERIAL, FLASH>
+where
+    CAN: CanDevice,
+    SERIAL: Read + Write,
+    FLASH: NorFlash,
+{
+    // Identity reported to the host, read from the chip unique id
+    pub fn with_device_id(mut self, device_id: DeviceId) -> Self {
+        self.device_id = device_id;
+        self
+    }
+
+    // Bus off recovery policy and TX timeout
+    pub fn with_bus_config(mut self, bus_config: BusConfig) -> Self {
+        self.bus_config = bus_config;
+        self
+    }
 }
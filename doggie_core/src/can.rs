@@ -1,50 +1,346 @@
-use embedded_can::{blocking::Can, Id};
-
-#[repr(u16)]
-#[derive(Clone, Copy)]
-pub enum CanBitrates {
-    Kbps5 = 5,
-    Kbps10 = 10,
-    Kbps20 = 20,
-    Kbps31_25 = 31,
-    Kbps33_3 = 33,
-    Kbps40 = 40,
-    Kbps50 = 50,
-    Kbps80 = 80,
-    Kbps100 = 100,
-    Kbps125 = 125,
-    Kbps200 = 200,
-    Kbps250 = 250,
-    Kbps500 = 500,
-    Kbps1000 = 1000,
-}
-
-impl From<u16> for CanBitrates {
-    fn from(value: u16) -> CanBitrates {
-        match value {
-            5 => CanBitrates::Kbps5,
-            10 => CanBitrates::Kbps10,
-            20 => CanBitrates::Kbps20,
-            31 => CanBitrates::Kbps31_25,
-            33 => CanBitrates::Kbps33_3,
-            40 => CanBitrates::Kbps40,
-            50 => CanBitrates::Kbps50,
-            80 => CanBitrates::Kbps80,
-            100 => CanBitrates::Kbps100,
-            125 => CanBitrates::Kbps125,
-            200 => CanBitrates::Kbps200,
-            250 => CanBitrates::Kbps250,
-            500 => CanBitrates::Kbps500,
-            1000 => CanBitrates::Kbps1000,
-            _ => CanBitrates::Kbps250,
+use crate::filter::{FilterEntry, FilterFit};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_can::{blocking::Can, Error, ErrorKind, Frame};
+use slcan::{
+    CanFrame, SlcanAcceptanceFilter, SlcanBitTiming, SlcanBitrates, SlcanBusState,
+    SlcanStatusFlags, SLCAN_BTR_CLOCK_HZ,
+};
+
+// How often controllers that can't tell when a frame was sent are asked
+// for it, while an echo is due
+const TX_CONFIRM_POLL: Duration = Duration::from_millis(1);
+
+// Nominal bitrate in bits per second. Any rate can be asked for, each
+// controller checks if it can run at it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CanBitrate(u32);
+
+impl CanBitrate {
+    pub const fn from_bps(bps: u32) -> Self {
+        CanBitrate(bps)
+    }
+
+    pub const fn from_kbps(kbps: u32) -> Self {
+        CanBitrate(kbps * 1000)
+    }
+
+    pub const fn bps(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<SlcanBitrates> for CanBitrate {
+    fn from(value: SlcanBitrates) -> Self {
+        CanBitrate::from_bps(value.bps())
+    }
+}
+
+// Why the controller isn't running at the requested bitrate
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BitrateError {
+    // It can't run at it, nothing was changed
+    Unsupported,
+    // Writing it to the controller failed
+    NotApplied,
+}
+
+// Raw bit timing, expressed in time quanta of `clock_hz / prescaler`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BitTiming {
+    pub clock_hz: u32,
+    pub prescaler: u16,
+    pub sjw: u8,
+    pub tseg1: u8,
+    pub tseg2: u8,
+    pub triple_sample: bool,
+}
+
+impl BitTiming {
+    pub fn quanta(&self) -> u32 {
+        1 + self.tseg1 as u32 + self.tseg2 as u32
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        self.clock_hz / (self.prescaler as u32 * self.quanta())
+    }
+
+    // Translate the timing to a controller running at `clock_hz`, keeping the
+    // same time quantum. Returns None if the prescaler can't be matched exactly.
+    pub fn with_clock(&self, clock_hz: u32) -> Option<BitTiming> {
+        let scaled = self.prescaler as u64 * clock_hz as u64;
+        let prescaler = scaled / self.clock_hz as u64;
+
+        if prescaler * self.clock_hz as u64 != scaled {
+            return None;
         }
+
+        let prescaler = u16::try_from(prescaler).ok()?;
+
+        Some(BitTiming {
+            clock_hz,
+            prescaler,
+            ..*self
+        })
+    }
+}
+
+impl From<SlcanBitTiming> for BitTiming {
+    fn from(value: SlcanBitTiming) -> Self {
+        BitTiming {
+            clock_hz: SLCAN_BTR_CLOCK_HZ,
+            prescaler: value.prescaler(),
+            sjw: value.sjw(),
+            tseg1: value.tseg1(),
+            tseg2: value.tseg2(),
+            triple_sample: value.triple_sample(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CanMode {
+    Normal,
+    // Receive only, the controller doesn't ACK nor transmit
+    ListenOnly,
+    Loopback,
+    // Off the bus, as while the channel is closed
+    Configuration,
+}
+
+// Controller state as reported by the hardware
+#[derive(Clone, Copy, Default)]
+pub struct CanStatus {
+    pub rx_fifo_full: bool,
+    pub tx_fifo_full: bool,
+    pub error_warning: bool,
+    pub data_overrun: bool,
+    pub error_passive: bool,
+    pub bus_off: bool,
+    pub arbitration_lost: bool,
+    pub bus_error: bool,
+    pub tx_error_count: u8,
+    pub rx_error_count: u8,
+}
+
+// Error counters at which a node becomes error warning and error passive
+const ERROR_WARNING_LIMIT: u8 = 96;
+const ERROR_PASSIVE_LIMIT: u8 = 127;
+
+// Fault confinement state of the node
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BusState {
+    ErrorActive,
+    ErrorWarning,
+    ErrorPassive,
+    BusOff,
+}
+
+impl CanStatus {
+    // The counters are checked too, not every controller raises the flags.
+    // Bus off can't be told from 8 bit counters, it needs the flag
+    pub fn bus_state(&self) -> BusState {
+        let max_count = self.tx_error_count.max(self.rx_error_count);
+
+        if self.bus_off {
+            BusState::BusOff
+        } else if self.error_passive || max_count > ERROR_PASSIVE_LIMIT {
+            BusState::ErrorPassive
+        } else if self.error_warning || max_count >= ERROR_WARNING_LIMIT {
+            BusState::ErrorWarning
+        } else {
+            BusState::ErrorActive
+        }
+    }
+}
+
+impl From<BusState> for SlcanBusState {
+    fn from(state: BusState) -> Self {
+        match state {
+            BusState::ErrorActive => SlcanBusState::Active,
+            BusState::ErrorWarning => SlcanBusState::Warning,
+            BusState::ErrorPassive => SlcanBusState::Passive,
+            BusState::BusOff => SlcanBusState::BusOff,
+        }
+    }
+}
+
+impl From<CanStatus> for SlcanStatusFlags {
+    fn from(status: CanStatus) -> Self {
+        let mut flags = SlcanStatusFlags::default();
+
+        flags.set(SlcanStatusFlags::RX_FIFO_FULL, status.rx_fifo_full);
+        flags.set(SlcanStatusFlags::TX_FIFO_FULL, status.tx_fifo_full);
+        flags.set(SlcanStatusFlags::DATA_OVERRUN, status.data_overrun);
+        // From the state, so controllers without the flags report them from
+        // their counters. Lawicel has no bus off flag, it is reported as
+        // error passive
+        let state = status.bus_state();
+        flags.set(
+            SlcanStatusFlags::ERROR_WARNING,
+            state != BusState::ErrorActive,
+        );
+        flags.set(
+            SlcanStatusFlags::ERROR_PASSIVE,
+            matches!(state, BusState::ErrorPassive | BusState::BusOff),
+        );
+        flags.set(SlcanStatusFlags::ARBITRATION_LOST, status.arbitration_lost);
+        flags.set(SlcanStatusFlags::BUS_ERROR, status.bus_error);
+
+        flags
     }
 }
 
 pub trait CanDevice: Can {
-    fn set_bitrate(&mut self, bitrate: CanBitrates);
+    // Fails without touching the controller when the bitrate can't be
+    // reached closely enough
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError>;
+
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError>;
+
+    fn status(&mut self) -> CanStatus;
+
+    fn set_mode(&mut self, mode: CanMode);
+
+    // Only let through frames matching any of the filters, all of them when
+    // the list is empty. Returns whether the hardware filters are exact or let
+    // more frames through
+    fn set_filters(&mut self, filters: &[FilterEntry]) -> FilterFit;
+
+    // Oldest frame the controller confirmed as sent on the bus, with the
+    // instant it went out at. Only the last few are kept until read
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)>;
+
+    // Lawicel acceptance filter, controllers with SJA1000 style filters can
+    // take it as is
+    fn set_acceptance_filter(&mut self, filter: &SlcanAcceptanceFilter) -> FilterFit {
+        self.set_filters(&FilterEntry::from_acceptance(filter))
+    }
+
+    // Controller frame for a frame from the host, None when the controller
+    // can't send it, as CAN FD frames on classic controllers
+    fn frame_from_slcan(frame: &CanFrame) -> Option<Self::Frame> {
+        if frame.is_fd() {
+            None
+        } else if frame.is_remote() {
+            Self::Frame::new_remote(frame.id, frame.dlc)
+        } else {
+            Self::Frame::new(frame.id, frame.data())
+        }
+    }
+
+    // Remote frames carry no data, only the dlc asked for
+    fn frame_to_slcan(frame: &Self::Frame) -> Option<CanFrame> {
+        if frame.is_remote_frame() {
+            CanFrame::new(frame.id(), true, [0; 8].get(..frame.dlc())?)
+        } else {
+            CanFrame::new(frame.id(), false, frame.data().get(..frame.dlc())?)
+        }
+    }
+}
 
-    fn set_filter(&mut self, id: Id);
+// Async access to the controller, so the core can wait for it instead of
+// polling in a loop
+#[allow(async_fn_in_trait)]
+pub trait AsyncCanDevice: CanDevice {
+    // Wait until the controller has something new: a frame received, a TX
+    // buffer freed or an error. Spurious wake ups are allowed
+    async fn wait_for_event(&mut self);
 
-    fn set_mask(&mut self, id: Id);
+    // Returns the frame with the instant it was received at, taken from the
+    // controller when it timestamps frames
+    async fn receive_async(&mut self) -> Result<(Self::Frame, Instant), Self::Error> {
+        loop {
+            match self.receive() {
+                Ok(frame) => return Ok((frame, Instant::now())),
+                // Nothing received yet
+                Err(e) if e.kind() != ErrorKind::Overrun => self.wait_for_event().await,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn transmit_async(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        self.transmit(frame)
+    }
+
+    // Like receive_async, but returns None once a sent frame may have been
+    // confirmed, for transmitted() to pick it up
+    async fn receive_or_transmitted(
+        &mut self,
+    ) -> Option<Result<(Self::Frame, Instant), Self::Error>> {
+        match select(self.receive_async(), Timer::after(TX_CONFIRM_POLL)).await {
+            Either::First(res) => Some(res),
+            Either::Second(()) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockCan;
+    use embedded_can::StandardId;
+
+    #[test]
+    fn test_frame_from_slcan_fd() {
+        // FD frames from the host are refused by classic controllers, never
+        // cut down to a classic frame
+        let id = StandardId::new(0x123).unwrap();
+        let frame = CanFrame::new_fd(id, true, false, &[0xAA; 12]).unwrap();
+        assert!(MockCan::frame_from_slcan(&frame).is_none());
+
+        let frame = CanFrame::new_fd(id, false, false, &[0xAA; 3]).unwrap();
+        assert!(MockCan::frame_from_slcan(&frame).is_none());
+    }
+
+    #[test]
+    fn test_frame_from_slcan_classic() {
+        let id = StandardId::new(0x123).unwrap();
+        let frame = CanFrame::new(id, false, &[0x11, 0x22]).unwrap();
+        let converted = MockCan::frame_from_slcan(&frame).unwrap();
+        assert_eq!(MockCan::frame_to_slcan(&converted), Some(frame));
+
+        let frame = CanFrame::new(id, true, &[0; 4]).unwrap();
+        let converted = MockCan::frame_from_slcan(&frame).unwrap();
+        assert!(converted.is_remote_frame());
+        assert_eq!(converted.dlc(), 4);
+        assert_eq!(MockCan::frame_to_slcan(&converted), Some(frame));
+    }
+
+    #[test]
+    fn test_status_flags_from_counters() {
+        // Controllers without the flags only give the counters
+        let status = CanStatus {
+            tx_error_count: 100,
+            ..CanStatus::default()
+        };
+        assert_eq!(
+            SlcanStatusFlags::from(status),
+            SlcanStatusFlags(SlcanStatusFlags::ERROR_WARNING)
+        );
+
+        let status = CanStatus {
+            rx_error_count: 128,
+            ..CanStatus::default()
+        };
+        assert_eq!(
+            SlcanStatusFlags::from(status),
+            SlcanStatusFlags(SlcanStatusFlags::ERROR_WARNING | SlcanStatusFlags::ERROR_PASSIVE)
+        );
+
+        let status = CanStatus {
+            bus_off: true,
+            ..CanStatus::default()
+        };
+        assert_eq!(
+            SlcanStatusFlags::from(status),
+            SlcanStatusFlags(SlcanStatusFlags::ERROR_WARNING | SlcanStatusFlags::ERROR_PASSIVE)
+        );
+
+        assert_eq!(
+            SlcanStatusFlags::from(CanStatus::default()),
+            SlcanStatusFlags::default()
+        );
+    }
 }
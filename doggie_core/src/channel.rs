@@ -0,0 +1,64 @@
+use crate::can::CanMode;
+use slcan::{SlcanCommand, SlcanStartupMode};
+
+// Lawicel channel state, the channel must be closed to configure it and
+// open to receive or transmit frames
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    Closed,
+    Open,
+    ListenOnly,
+}
+
+// State the channel comes up in at power on
+impl From<SlcanStartupMode> for ChannelState {
+    fn from(mode: SlcanStartupMode) -> Self {
+        match mode {
+            SlcanStartupMode::Off => ChannelState::Closed,
+            SlcanStartupMode::Normal => ChannelState::Open,
+            SlcanStartupMode::ListenOnly => ChannelState::ListenOnly,
+        }
+    }
+}
+
+impl ChannelState {
+    pub fn is_open(&self) -> bool {
+        *self != ChannelState::Closed
+    }
+
+    // Check if the command can be executed in the current state
+    pub fn accepts(&self, cmd: &SlcanCommand) -> bool {
+        match cmd {
+            SlcanCommand::OpenChannel | SlcanCommand::Listen => !self.is_open(),
+            SlcanCommand::CloseChannel => self.is_open(),
+            SlcanCommand::SetBitrate(_)
+            | SlcanCommand::SetBitTimeRegister(_)
+            | SlcanCommand::Autobaud
+            | SlcanCommand::AcceptanceCode(_)
+            | SlcanCommand::AcceptanceMask(_)
+            | SlcanCommand::FilterMode(_)
+            | SlcanCommand::AddFilterRule(_)
+            | SlcanCommand::ClearFilterRules
+            | SlcanCommand::Timestamp(_)
+            | SlcanCommand::EchoMode(_)
+            | SlcanCommand::AutoPoll(_)
+            | SlcanCommand::WriteConfig(_)
+            | SlcanCommand::FactoryReset => !self.is_open(),
+            SlcanCommand::Frame(_) => *self == ChannelState::Open,
+            SlcanCommand::ReadStatusFlags
+            | SlcanCommand::PollOne
+            | SlcanCommand::PollAll
+            | SlcanCommand::AutoStartup(_) => self.is_open(),
+            _ => true,
+        }
+    }
+
+    // Controller mode for the state
+    pub fn can_mode(&self) -> CanMode {
+        match self {
+            ChannelState::Closed => CanMode::Configuration,
+            ChannelState::Open => CanMode::Normal,
+            ChannelState::ListenOnly => CanMode::ListenOnly,
+        }
+    }
+}
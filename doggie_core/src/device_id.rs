@@ -0,0 +1,116 @@
+// Longest unique id of the supported chips, the STM32 one is 96 bits
+pub const DEVICE_ID_MAX_LEN: usize = 16;
+
+// Buffer for the id in hex, as used in the USB serial number descriptor
+pub type UsbSerialBuffer = [u8; DEVICE_ID_MAX_LEN * 2];
+
+fn nibble_to_hex_char(value: u8) -> u8 {
+    match value {
+        0..=9 => b'0' + value,
+        _ => b'A' + (value - 10),
+    }
+}
+
+// CRC-16/CCITT-FALSE, also guards the startup records
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+// Unique identifier of the board, read from the chip by each BSP
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct DeviceId {
+    uid: [u8; DEVICE_ID_MAX_LEN],
+    len: usize,
+}
+
+impl DeviceId {
+    // Ids longer than DEVICE_ID_MAX_LEN are truncated
+    pub fn new(uid: &[u8]) -> Self {
+        let len = uid.len().min(DEVICE_ID_MAX_LEN);
+        let mut id = DeviceId {
+            uid: [0; DEVICE_ID_MAX_LEN],
+            len,
+        };
+        id.uid[0..len].copy_from_slice(&uid[0..len]);
+
+        id
+    }
+
+    pub fn uid(&self) -> &[u8] {
+        &self.uid[0..self.len]
+    }
+
+    // Full id in hex, for the USB serial number descriptor
+    pub fn usb_serial<'a>(&self, buffer: &'a mut UsbSerialBuffer) -> &'a str {
+        for (i, byte) in self.uid().iter().enumerate() {
+            buffer[2 * i] = nibble_to_hex_char(byte >> 4);
+            buffer[2 * i + 1] = nibble_to_hex_char(byte & 0x0f);
+        }
+
+        core::str::from_utf8(&buffer[0..self.len * 2]).unwrap_or("")
+    }
+
+    // Lawicel serial numbers are 4 characters long, so the id is folded
+    // into 16 bits
+    pub fn lawicel_serial(&self) -> [u8; 4] {
+        let crc = crc16(self.uid());
+
+        [
+            nibble_to_hex_char((crc >> 12) as u8 & 0x0f),
+            nibble_to_hex_char((crc >> 8) as u8 & 0x0f),
+            nibble_to_hex_char((crc >> 4) as u8 & 0x0f),
+            nibble_to_hex_char(crc as u8 & 0x0f),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn test_usb_serial() {
+        let id = DeviceId::new(&[0x01, 0x23, 0xAB, 0xCD]);
+        let mut buffer = [0; 32];
+        assert_eq!(id.usb_serial(&mut buffer), "0123ABCD");
+    }
+
+    #[test]
+    fn test_usb_serial_max_len() {
+        let id = DeviceId::new(&[0xFF; 20]);
+        let mut buffer = [0; 32];
+        assert_eq!(id.usb_serial(&mut buffer).len(), 32);
+    }
+
+    #[test]
+    fn test_lawicel_serial() {
+        let id = DeviceId::new(b"123456789");
+        assert_eq!(&id.lawicel_serial(), b"29B1");
+    }
+
+    #[test]
+    fn test_lawicel_serial_unique() {
+        // STM32 ids of two chips of the same wafer only differ in a few bits
+        let a = DeviceId::new(&[0x30, 0x00, 0x41, 0x00, 0x0A, 0x51, 0x33, 0x34]);
+        let b = DeviceId::new(&[0x31, 0x00, 0x41, 0x00, 0x0A, 0x51, 0x33, 0x34]);
+        assert_ne!(a.lawicel_serial(), b.lawicel_serial());
+    }
+}
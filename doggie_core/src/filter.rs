@@ -0,0 +1,586 @@
+use embedded_can::{ExtendedId, Id, StandardId};
+use slcan::{CanFrame, SlcanAcceptanceFilter, SlcanFilterRule, SlcanIdFormat};
+
+// Rules of the software filter, set with fA
+pub const SOFTWARE_FILTER_MAX_RULES: usize = 16;
+
+// Largest number of filters sharing a mask, the MCP2515 RXB1 has 4
+pub const MASK_GROUP_MAX_FILTERS: usize = 4;
+
+// Filter on the id of the frames with the same format as `id`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FilterEntry {
+    pub id: Id,
+    // Bits of the id that must match, the others are don't care
+    pub mask: u32,
+}
+
+// How the hardware filters represent the requested ones
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FilterFit {
+    Exact,
+    // Frames that don't match any filter get through too
+    Superset,
+}
+
+impl FilterEntry {
+    // Standard and extended entries letting every frame through
+    pub const ACCEPT_ALL: [FilterEntry; 2] = [
+        FilterEntry {
+            id: Id::Standard(StandardId::ZERO),
+            mask: 0,
+        },
+        FilterEntry {
+            id: Id::Extended(ExtendedId::ZERO),
+            mask: 0,
+        },
+    ];
+
+    pub fn matches(&self, id: Id) -> bool {
+        match (self.id, id) {
+            (Id::Standard(filter), Id::Standard(id)) => {
+                (filter.as_raw() ^ id.as_raw()) as u32 & self.mask == 0
+            }
+            (Id::Extended(filter), Id::Extended(id)) => {
+                (filter.as_raw() ^ id.as_raw()) & self.mask == 0
+            }
+            _ => false,
+        }
+    }
+
+    // Entries for the ids an acceptance filter may accept, a standard and an
+    // extended one for each SJA1000 filter
+    pub fn from_acceptance(filter: &SlcanAcceptanceFilter) -> [FilterEntry; 4] {
+        let [(id0, mask0), (id1, mask1)] = filter.id_prefilters();
+
+        // Prefilters are 11 bits wide, they always fit
+        let standard = |id: u16, mask: u16| FilterEntry {
+            id: Id::Standard(StandardId::new(id).unwrap()),
+            mask: mask as u32,
+        };
+        let extended = |id: u16, mask: u16| FilterEntry {
+            id: Id::Extended(ExtendedId::new((id as u32) << 18).unwrap()),
+            mask: (mask as u32) << 18,
+        };
+
+        [
+            standard(id0, mask0),
+            extended(id0, mask0),
+            standard(id1, mask1),
+            extended(id1, mask1),
+        ]
+    }
+
+    // Standard ids go in the 11 upper bits of the 29 bit layout, as both
+    // share the SID field in the controllers
+    fn raw(&self) -> (RawFilter, u32) {
+        match self.id {
+            Id::Standard(id) => (
+                RawFilter {
+                    extended: false,
+                    id: (id.as_raw() as u32) << 18,
+                },
+                (self.mask & 0x7FF) << 18,
+            ),
+            Id::Extended(id) => (
+                RawFilter {
+                    extended: true,
+                    id: id.as_raw(),
+                },
+                self.mask & 0x1FFF_FFFF,
+            ),
+        }
+    }
+}
+
+// Filter id in the 29 bit layout
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RawFilter {
+    pub extended: bool,
+    pub id: u32,
+}
+
+// Filters sharing one mask in the 29 bit layout, like the MCP2515 receive
+// buffers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MaskGroup {
+    pub mask: u32,
+    // One more than the capacity, so a push can overflow before merging
+    filters: [RawFilter; MASK_GROUP_MAX_FILTERS + 1],
+    len: usize,
+    capacity: usize,
+}
+
+impl MaskGroup {
+    fn new(capacity: usize) -> Self {
+        MaskGroup {
+            mask: 0,
+            filters: [RawFilter::default(); MASK_GROUP_MAX_FILTERS + 1],
+            len: 0,
+            capacity: capacity.clamp(2, MASK_GROUP_MAX_FILTERS),
+        }
+    }
+
+    pub fn filters(&self) -> &[RawFilter] {
+        &self.filters[0..self.len]
+    }
+
+    // Filter for a hardware slot, unused slots repeat the last filter so they
+    // don't let anything else through
+    pub fn filter(&self, slot: usize) -> RawFilter {
+        self.filters[slot.min(self.len.saturating_sub(1))]
+    }
+
+    fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    // Returns false when the group lets more frames through than before
+    fn push(&mut self, filter: RawFilter, mask: u32) -> bool {
+        let mut exact = true;
+
+        if self.len == 0 {
+            self.mask = mask;
+        } else if self.mask != mask {
+            self.mask &= mask;
+            exact = false;
+        }
+
+        // Already covered by a filter of the group
+        if self
+            .filters()
+            .iter()
+            .any(|f| f.extended == filter.extended && (f.id ^ filter.id) & self.mask == 0)
+        {
+            return exact;
+        }
+
+        self.filters[self.len] = filter;
+        self.len += 1;
+
+        if self.len > self.capacity {
+            self.merge();
+            exact = false;
+        }
+
+        exact
+    }
+
+    // Merge the two filters of the same format that cost the fewest mask bits.
+    // There are more filters than a capacity of at least 2, so two of them
+    // share the format
+    fn merge(&mut self) {
+        let mut best: Option<(u32, usize, usize)> = None;
+
+        for a in 0..self.len {
+            for b in (a + 1)..self.len {
+                let (fa, fb) = (self.filters[a], self.filters[b]);
+                if fa.extended != fb.extended {
+                    continue;
+                }
+
+                let lost = ((fa.id ^ fb.id) & self.mask).count_ones();
+                if best.is_none_or(|(cost, _, _)| lost < cost) {
+                    best = Some((lost, a, b));
+                }
+            }
+        }
+
+        let Some((_, a, b)) = best else {
+            return;
+        };
+
+        self.mask &= !(self.filters[a].id ^ self.filters[b].id);
+        self.filters.copy_within(b + 1..self.len, b);
+        self.len -= 1;
+    }
+}
+
+// Spread the filters over groups of filters sharing a mask. Masks used by
+// the most filters are placed first, so they keep a group of their own. An
+// empty list accepts every frame
+pub fn allocate_mask_groups<const N: usize>(
+    filters: &[FilterEntry],
+    capacities: [usize; N],
+) -> ([MaskGroup; N], FilterFit) {
+    let filters = if filters.is_empty() {
+        &FilterEntry::ACCEPT_ALL[..]
+    } else {
+        filters
+    };
+
+    let mut groups = capacities.map(MaskGroup::new);
+    let mut fit = FilterFit::Exact;
+
+    // Bigger groups are tried first
+    let mut order: [usize; N] = core::array::from_fn(|i| i);
+    order.sort_unstable_by_key(|&i| core::cmp::Reverse(groups[i].capacity));
+
+    let count = |mask: u32| filters.iter().filter(|f| f.raw().1 == mask).count();
+
+    // Masks sorted by use count, and by value for the same count
+    let mut previous: Option<(usize, u32)> = None;
+    while let Some(key) = filters
+        .iter()
+        .map(|f| (count(f.raw().1), !f.raw().1))
+        .filter(|key| previous.is_none_or(|p| *key < p))
+        .max()
+    {
+        previous = Some(key);
+        let mask = !key.1;
+
+        for (filter, _) in filters.iter().map(|f| f.raw()).filter(|f| f.1 == mask) {
+            let index = order
+                .iter()
+                .find(|&&i| !groups[i].is_full() && (groups[i].len == 0 || groups[i].mask == mask))
+                .or_else(|| order.iter().find(|&&i| !groups[i].is_full()))
+                .or_else(|| order.iter().find(|&&i| groups[i].mask == mask))
+                .copied()
+                .unwrap_or(order[0]);
+
+            if !groups[index].push(filter, mask) {
+                fit = FilterFit::Superset;
+            }
+        }
+    }
+
+    // Empty groups take filters from another one, a subset of its filters
+    // doesn't let anything new through
+    if let Some(source) = groups.iter().find(|g| g.len > 0).copied() {
+        for group in groups.iter_mut().filter(|g| g.len == 0) {
+            group.mask = source.mask;
+            group.len = source.len.min(group.capacity);
+            group.filters[0..group.len].copy_from_slice(&source.filters[0..group.len]);
+        }
+    }
+
+    (groups, fit)
+}
+
+// Id and mask covering the whole range, from the bits the range bounds share
+fn range_filter(low: u32, high: u32) -> (u32, u32) {
+    let span = match low ^ high {
+        0 => 0,
+        diff => u32::MAX >> diff.leading_zeros(),
+    };
+
+    (low & !span, !span)
+}
+
+// Filter stage run by can_task, for what the hardware filters can't express.
+// A frame passes when any rule matches it, or when there are no rules
+#[derive(Clone, Debug)]
+pub struct SoftwareFilter {
+    rules: [SlcanFilterRule; SOFTWARE_FILTER_MAX_RULES],
+    len: usize,
+}
+
+impl Default for SoftwareFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Rules past `len` are left overs
+impl PartialEq for SoftwareFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules() == other.rules()
+    }
+}
+
+impl Eq for SoftwareFilter {}
+
+impl SoftwareFilter {
+    pub fn new() -> Self {
+        SoftwareFilter {
+            rules: [SlcanFilterRule::default(); SOFTWARE_FILTER_MAX_RULES],
+            len: 0,
+        }
+    }
+
+    pub fn rules(&self) -> &[SlcanFilterRule] {
+        &self.rules[0..self.len]
+    }
+
+    // Returns false when there is no room left for the rule or its id
+    // range is wrong
+    pub fn add(&mut self, rule: SlcanFilterRule) -> bool {
+        if self.len == SOFTWARE_FILTER_MAX_RULES || !rule.is_valid() {
+            return false;
+        }
+
+        self.rules[self.len] = rule;
+        self.len += 1;
+
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn accepts(&self, frame: &CanFrame) -> bool {
+        self.len == 0 || self.rules().iter().any(|rule| rule.matches(frame))
+    }
+
+    // Hardware filters letting through at least the frames matching the
+    // rules, two entries per rule at most
+    pub fn hardware_filters<'a>(
+        &self,
+        buffer: &'a mut [FilterEntry; 2 * SOFTWARE_FILTER_MAX_RULES],
+    ) -> &'a [FilterEntry] {
+        let mut len = 0;
+
+        for rule in self.rules() {
+            let standard = rule.format != SlcanIdFormat::Extended && rule.id_low <= 0x7FF;
+            let extended = rule.format != SlcanIdFormat::Standard;
+
+            // Rules are checked when added, the ids always fit
+            if standard {
+                let (id, mask) = range_filter(rule.id_low, rule.id_high.min(0x7FF));
+                if let Some(id) = StandardId::new(id as u16) {
+                    buffer[len] = FilterEntry {
+                        id: Id::Standard(id),
+                        mask: mask & 0x7FF,
+                    };
+                    len += 1;
+                }
+            }
+
+            if extended {
+                let (id, mask) = range_filter(rule.id_low, rule.id_high);
+                if let Some(id) = ExtendedId::new(id) {
+                    buffer[len] = FilterEntry {
+                        id: Id::Extended(id),
+                        mask: mask & 0x1FFF_FFFF,
+                    };
+                    len += 1;
+                }
+            }
+        }
+
+        &buffer[0..len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slcan::{SlcanFilterMode, SlcanFrameType};
+
+    fn standard(id: u16, mask: u32) -> FilterEntry {
+        FilterEntry {
+            id: Id::Standard(StandardId::new(id).unwrap()),
+            mask,
+        }
+    }
+
+    fn extended(id: u32, mask: u32) -> FilterEntry {
+        FilterEntry {
+            id: Id::Extended(ExtendedId::new(id).unwrap()),
+            mask,
+        }
+    }
+
+    fn raw_standard(id: u16) -> RawFilter {
+        RawFilter {
+            extended: false,
+            id: (id as u32) << 18,
+        }
+    }
+
+    // Check that every id accepted by the filters gets through the groups
+    fn assert_superset(filters: &[FilterEntry], groups: &[MaskGroup]) {
+        for filter in filters {
+            let (raw, _) = filter.raw();
+            assert!(groups.iter().any(|g| g
+                .filters()
+                .iter()
+                .any(|f| f.extended == raw.extended && (f.id ^ raw.id) & g.mask == 0)));
+        }
+    }
+
+    #[test]
+    fn test_filter_entry_matches() {
+        let filter = standard(0x120, 0x7F0);
+        assert!(filter.matches(Id::Standard(StandardId::new(0x12F).unwrap())));
+        assert!(!filter.matches(Id::Standard(StandardId::new(0x130).unwrap())));
+        assert!(!filter.matches(Id::Extended(ExtendedId::new(0x120).unwrap())));
+    }
+
+    #[test]
+    fn test_from_acceptance() {
+        let acceptance = SlcanAcceptanceFilter {
+            code: 0x2460_0000,
+            mask: 0x001F_FFFF,
+            mode: SlcanFilterMode::Single,
+        };
+        let entries = FilterEntry::from_acceptance(&acceptance);
+        assert_eq!(entries[0], standard(0x123, 0x7FF));
+        assert_eq!(entries[1], extended(0x123 << 18, 0x7FF << 18));
+    }
+
+    #[test]
+    fn test_empty_accepts_all() {
+        let ([rxb0, rxb1], fit) = allocate_mask_groups(&[], [2, 4]);
+        assert_eq!(fit, FilterFit::Exact);
+        assert_eq!(rxb0.mask, 0);
+        assert_eq!(rxb1.mask, 0);
+        assert_eq!(rxb1.filters().len(), 2);
+    }
+
+    #[test]
+    fn test_six_ids_exact() {
+        let filters = [0x100, 0x101, 0x102, 0x103, 0x104, 0x105].map(|id| standard(id, 0x7FF));
+        let (groups, fit) = allocate_mask_groups(&filters, [2, 4]);
+        assert_eq!(fit, FilterFit::Exact);
+        assert_eq!(
+            groups[0].filters(),
+            &[raw_standard(0x104), raw_standard(0x105)]
+        );
+        assert_eq!(groups[1].filters().len(), 4);
+        assert_superset(&filters, &groups);
+    }
+
+    #[test]
+    fn test_two_masks_exact() {
+        let filters = [
+            standard(0x100, 0x7FF),
+            standard(0x200, 0x7F0),
+            standard(0x101, 0x7FF),
+            standard(0x300, 0x7F0),
+            standard(0x102, 0x7FF),
+        ];
+        let ([rxb0, rxb1], fit) = allocate_mask_groups(&filters, [2, 4]);
+        assert_eq!(fit, FilterFit::Exact);
+        assert_eq!(rxb0.mask, 0x7F0 << 18);
+        assert_eq!(rxb1.mask, 0x7FF << 18);
+        assert_superset(&filters, &[rxb0, rxb1]);
+    }
+
+    #[test]
+    fn test_duplicates_exact() {
+        let filters = [standard(0x100, 0x7FF); 8];
+        let ([rxb0, rxb1], fit) = allocate_mask_groups(&filters, [2, 4]);
+        assert_eq!(fit, FilterFit::Exact);
+        assert_eq!(rxb1.filters(), &[raw_standard(0x100)]);
+        assert_eq!(rxb0.filters(), &[raw_standard(0x100)]);
+    }
+
+    #[test]
+    fn test_too_many_ids_superset() {
+        let filters =
+            [0x100, 0x101, 0x102, 0x103, 0x104, 0x105, 0x106].map(|id| standard(id, 0x7FF));
+        let (groups, fit) = allocate_mask_groups(&filters, [2, 4]);
+        assert_eq!(fit, FilterFit::Superset);
+        assert_superset(&filters, &groups);
+    }
+
+    #[test]
+    fn test_three_masks_superset() {
+        let filters = [
+            standard(0x100, 0x7FF),
+            standard(0x200, 0x7F0),
+            extended(0x1234567, 0x1FFF_FFFF),
+        ];
+        let (groups, fit) = allocate_mask_groups(&filters, [2, 4]);
+        assert_eq!(fit, FilterFit::Superset);
+        assert_superset(&filters, &groups);
+    }
+
+    #[test]
+    fn test_mixed_formats() {
+        let filters = [standard(0x123, 0x7FF), extended(0x123 << 18, 0x7FF << 18)];
+        let ([rxb0, rxb1], fit) = allocate_mask_groups(&filters, [2, 4]);
+        assert_eq!(fit, FilterFit::Exact);
+        assert_eq!(rxb1.filters().len(), 2);
+        assert_eq!(
+            rxb0,
+            MaskGroup {
+                capacity: 2,
+                ..rxb1
+            }
+        );
+    }
+
+    #[test]
+    fn test_unused_slots() {
+        let filters = [standard(0x100, 0x7FF), standard(0x101, 0x7FF)];
+        let ([_, rxb1], _) = allocate_mask_groups(&filters, [2, 4]);
+        assert_eq!(rxb1.filter(3), raw_standard(0x101));
+    }
+
+    fn rule(format: SlcanIdFormat, id_low: u32, id_high: u32) -> SlcanFilterRule {
+        SlcanFilterRule::new(format, SlcanFrameType::Any, id_low, id_high).unwrap()
+    }
+
+    fn test_frame(id: u16) -> CanFrame {
+        CanFrame::new(StandardId::new(id).unwrap(), false, &[]).unwrap()
+    }
+
+    #[test]
+    fn test_range_filter() {
+        assert_eq!(range_filter(0x100, 0x100), (0x100, u32::MAX));
+        assert_eq!(range_filter(0x100, 0x1FF), (0x100, !0xFF));
+        assert_eq!(range_filter(0x0FF, 0x100), (0, !0x1FF));
+    }
+
+    #[test]
+    fn test_software_filter_empty() {
+        let filter = SoftwareFilter::new();
+        assert!(filter.accepts(&test_frame(0x123)));
+    }
+
+    #[test]
+    fn test_software_filter_id_list() {
+        let mut filter = SoftwareFilter::new();
+        filter.add(rule(SlcanIdFormat::Standard, 0x123, 0x123));
+        filter.add(rule(SlcanIdFormat::Standard, 0x456, 0x456));
+        assert!(filter.accepts(&test_frame(0x123)));
+        assert!(filter.accepts(&test_frame(0x456)));
+        assert!(!filter.accepts(&test_frame(0x124)));
+
+        filter.clear();
+        assert!(filter.accepts(&test_frame(0x124)));
+    }
+
+    #[test]
+    fn test_software_filter_full() {
+        let mut filter = SoftwareFilter::new();
+        for id in 0..SOFTWARE_FILTER_MAX_RULES as u32 {
+            assert!(filter.add(rule(SlcanIdFormat::Any, id, id)));
+        }
+        assert!(!filter.add(rule(SlcanIdFormat::Any, 0x7FF, 0x7FF)));
+    }
+
+    #[test]
+    fn test_software_filter_invalid_rule() {
+        let mut filter = SoftwareFilter::new();
+        let mut invalid = rule(SlcanIdFormat::Any, 0, 0);
+        invalid.id_low = 0x2000_0000;
+        invalid.id_high = 0x2000_0000;
+        assert!(!filter.add(invalid));
+
+        let mut buffer = [FilterEntry::ACCEPT_ALL[0]; 2 * SOFTWARE_FILTER_MAX_RULES];
+        assert!(filter.hardware_filters(&mut buffer).is_empty());
+    }
+
+    #[test]
+    fn test_software_filter_hardware_filters() {
+        let mut filter = SoftwareFilter::new();
+        filter.add(rule(SlcanIdFormat::Standard, 0x100, 0x1FF));
+        filter.add(rule(SlcanIdFormat::Any, 0x7E8, 0x7E8));
+        filter.add(rule(SlcanIdFormat::Any, 0x18DA_F100, 0x18DA_F1FF));
+
+        let mut buffer = [FilterEntry::ACCEPT_ALL[0]; 2 * SOFTWARE_FILTER_MAX_RULES];
+        assert_eq!(
+            filter.hardware_filters(&mut buffer),
+            &[
+                standard(0x100, 0x700),
+                standard(0x7E8, 0x7FF),
+                extended(0x7E8, 0x1FFF_FFFF),
+                extended(0x18DA_F100, 0x1FFF_FF00),
+            ]
+        );
+    }
+}
@@ -1,75 +1,185 @@
 #![no_std]
 
+mod autobaud;
 mod bsp;
 mod can;
+mod channel;
+mod device_id;
+mod filter;
 mod macros;
 mod mcp2515;
+mod mcp2518fd;
+#[cfg(test)]
+mod mock;
+mod poll;
+mod recovery;
+mod session;
+mod soft_can;
+mod startup;
+mod twai;
 mod types;
 
+pub use self::mcp2515::{
+    cnf_from_bit_timing, mcp2515_bit_timing, Mcp2515, Mcp2515Config, Mcp2515Irq,
+};
+pub use self::mcp2518fd::{
+    mcp2518fd_bit_timing, mcp2518fd_dbtcfg, mcp2518fd_nbtcfg, mcp2518fd_rx_object,
+    mcp2518fd_status_from_registers, mcp2518fd_tdc, mcp2518fd_tef_object, mcp2518fd_tx_object,
+    Mcp2518fd, Mcp2518fdError, Mcp2518fdFrame, MCP2518FD_RX_OBJECT_SIZE, MCP2518FD_TX_OBJECT_SIZE,
+};
+pub use autobaud::{Autobaud, AutobaudScore, AUTOBAUD_BITRATES};
 pub use bsp::Bsp;
-pub use can::{CanBitrates, CanDevice};
+pub use can::{
+    AsyncCanDevice, BitTiming, BitrateError, BusState, CanBitrate, CanDevice, CanMode, CanStatus,
+};
+pub use channel::ChannelState;
 use defmt::warn;
+pub use device_id::{DeviceId, UsbSerialBuffer};
 use embedded_can::Error;
 use embedded_can::ErrorKind;
+pub use filter::{FilterEntry, FilterFit, SoftwareFilter, SOFTWARE_FILTER_MAX_RULES};
+pub use poll::{PendingFrames, PENDING_FRAMES_MAX};
+pub use recovery::{BusConfig, BusMonitor, RecoveryPolicy};
+pub use session::{SessionAction, SessionOutput, SlcanSession, SLCAN_BELL, SLCAN_OK};
+pub use soft_can::{
+    soft_can_clock_divider, SoftCanBits, SoftCanDecoder, SoftCanError, SoftCanEvent,
+    SOFT_CAN_CYCLES_PER_BIT,
+};
+pub use startup::{
+    NoFlash, StartupBitrate, StartupConfig, StartupStore, StartupStoreError, STARTUP_RECORD_SIZE,
+};
+pub use twai::{
+    twai_acceptance_from_filters, twai_bit_timing, twai_btr, twai_status_from_registers,
+    TWAI_CLOCK_HZ,
+};
 pub use types::*;
 
-use slcan::{SlcanCommand, SlcanError};
+use slcan::{
+    SlcanAcceptanceFilter, SlcanCommand, SlcanError, SlcanStatusFlags, SlcanTimestamp,
+    SlcanTimestampMode,
+};
 
 use defmt::{debug, error, info};
 
 use embassy_executor::Spawner;
 use embassy_futures::select::select;
+use embassy_futures::select::select3;
 use embassy_futures::select::Either;
-use embassy_futures::yield_now;
+use embassy_futures::select::Either3;
 
-use embassy_time::Instant;
-use embedded_can::Frame;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
+use embedded_storage::nor_flash::NorFlash;
 
-// Struct to hold timestamp functionality
+// Time spent listening at each rate while detecting the bitrate
+const AUTOBAUD_LISTEN_TIME: Duration = Duration::from_millis(250);
+
+// How often the controller error state is checked while the channel is open
+const BUS_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Status flags cleared on the controller once read, kept by the core when
+// it reads the status on its own
+const LATCHED_FLAGS: u8 = SlcanStatusFlags::DATA_OVERRUN
+    | SlcanStatusFlags::ARBITRATION_LOST
+    | SlcanStatusFlags::BUS_ERROR;
+
+// Reception timestamps, relative to the moment they were enabled
 pub struct Timestamp {
-    start: Option<Instant>,
+    start: Instant,
+    mode: SlcanTimestampMode,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Timestamp {
-    // Initialize the timestamp with the current time as the start
     pub fn new() -> Self {
-        Self { start: None }
+        Self {
+            start: Instant::now(),
+            mode: SlcanTimestampMode::Off,
+        }
     }
 
-    pub fn start(&mut self) {
-        self.start = Some(Instant::now());
+    pub fn mode(&self) -> SlcanTimestampMode {
+        self.mode
     }
 
-    // Get the elapsed time in milliseconds since initialization
-    pub fn get_current(&self) -> Option<u16> {
-        match self.start {
-            Some(instant) => Some(instant.elapsed().as_micros() as u16),
-            None => None,
+    // Restart the counter every time timestamps get enabled
+    pub fn set_mode(&mut self, mode: SlcanTimestampMode) {
+        if mode != SlcanTimestampMode::Off {
+            self.start = Instant::now();
         }
+        self.mode = mode;
     }
+
+    // Timestamp of a frame received at `instant`
+    pub fn get(&self, instant: Instant) -> Option<SlcanTimestamp> {
+        let elapsed = instant.saturating_duration_since(self.start);
+        self.mode.timestamp(elapsed.as_micros())
+    }
+}
+
+// Errors seen by the core since the last status flags read
+#[derive(Default)]
+pub struct ErrorCounters {
+    pub rx_overruns: u32,
+    pub tx_overruns: u32,
+    pub serial_queue_full: u32,
+    pub tx_timeouts: u32,
+    // Frames lost while waiting to be polled
+    pub pending_overruns: u32,
+    // Flags seen while checking the bus
+    pub latched: SlcanStatusFlags,
 }
 
-pub struct Core<CAN, SERIAL>
+impl ErrorCounters {
+    pub fn apply(&self, flags: &mut SlcanStatusFlags) {
+        if self.rx_overruns > 0 {
+            flags.set(SlcanStatusFlags::DATA_OVERRUN, true);
+        }
+        if self.tx_overruns > 0 {
+            flags.set(SlcanStatusFlags::TX_FIFO_FULL, true);
+        }
+        if self.serial_queue_full > 0 {
+            flags.set(SlcanStatusFlags::RX_FIFO_FULL, true);
+        }
+        if self.tx_timeouts > 0 {
+            flags.set(SlcanStatusFlags::BUS_ERROR, true);
+        }
+        if self.pending_overruns > 0 {
+            flags.set(SlcanStatusFlags::DATA_OVERRUN, true);
+        }
+        flags.0 |= self.latched.0;
+    }
+}
+
+pub struct Core<CAN, SERIAL, FLASH = NoFlash>
 where
-    CAN: CanDevice,
+    CAN: AsyncCanDevice,
     SERIAL: Read + Write,
+    FLASH: NorFlash,
 {
-    pub bsp: Bsp<CAN, SERIAL>,
+    pub bsp: Bsp<CAN, SERIAL, FLASH>,
     pub spawner: Spawner,
 }
 
-impl<CAN, SERIAL> Core<CAN, SERIAL>
+impl<CAN, SERIAL, FLASH> Core<CAN, SERIAL, FLASH>
 where
-    CAN: CanDevice,
+    CAN: AsyncCanDevice,
     SERIAL: Read + Write,
+    FLASH: NorFlash,
 {
-    pub fn new(spawner: Spawner, bsp: Bsp<CAN, SERIAL>) -> Self {
+    pub fn new(spawner: Spawner, bsp: Bsp<CAN, SERIAL, FLASH>) -> Self {
         Core { bsp, spawner }
     }
 
     pub async fn slcan_task(
         mut serial: SERIAL,
+        device_id: DeviceId,
+        startup: StartupConfig,
         in_channel: CanChannelReceiver,
         out_channel: CanChannelSender,
     ) -> ! {
@@ -78,9 +188,8 @@ where
         // need it now, but i hope in the future
         let mut slcan_serializer = slcan::SlcanSerializer::new();
 
-        let mut listen_only = false;
-        let mut timestamp_enabled = false;
-        let mut timestamp = Timestamp::new();
+        let mut session = SlcanSession::with_device_id(&device_id);
+        session.apply_startup(&startup);
 
         loop {
             let serial_future = serial.read(&mut serial_in_buf);
@@ -101,40 +210,8 @@ where
                     };
 
                     for byte in &serial_in_buf[0..size] {
-                        let res: Option<&[u8]> = match slcan_serializer.from_byte(*byte) {
-                            Ok(SlcanCommand::IncompleteMessage) => {
-                                // Do nothing
-                                // warn!("IncompleteMessage");
-                                None
-                            }
-                            Ok(SlcanCommand::OpenChannel) => Some(b"\r"),
-                            Ok(SlcanCommand::CloseChannel) => Some(b"\r"),
-                            Ok(SlcanCommand::ReadStatusFlags) => Some(b"F00\r"),
-                            Ok(SlcanCommand::Listen) => {
-                                listen_only = true;
-                                Some(b"\r")
-                            }
-                            Ok(SlcanCommand::Version) => Some(b"V1337\r"),
-                            Ok(SlcanCommand::SerialNo) => Some(b"N1337\r"),
-                            Ok(SlcanCommand::Timestamp(enabled)) => {
-                                if !timestamp_enabled && enabled {
-                                    info!("Timestamp started");
-                                    timestamp.start();
-                                }
-
-                                timestamp_enabled = enabled;
-
-                                Some(b"\r")
-                            }
-                            Ok(cmd) => {
-                                if !listen_only {
-                                    out_channel.send(cmd).await;
-                                } else {
-                                    error!("Cannot send frame in listen only mode")
-                                }
-
-                                None
-                            }
+                        let output = match slcan_serializer.from_byte(*byte) {
+                            Ok(cmd) => session.handle_command(cmd),
                             Err(e) => {
                                 match e {
                                     SlcanError::InvalidCommand => error!("Invalid slcan command"),
@@ -143,91 +220,339 @@ where
                                     }
                                     SlcanError::MessageTooLong => error!("Command to long"),
                                 };
-                                None
+                                // The host is told the command was rejected
+                                SessionOutput::reply(SLCAN_BELL)
                             }
                         };
 
-                        match res {
-                            Some(res_str) => match serial.write(res_str).await {
-                                Ok(_) => {}
-                                Err(_) => error!("Error writing response"),
-                            },
+                        if let Some(SessionAction::Forward(cmd)) = output.action {
+                            out_channel.send(cmd).await;
+                        }
+
+                        match output.reply {
+                            Some(SLCAN_BELL) => {
+                                error!("Command rejected");
+                                Self::write_reply(&mut serial, SLCAN_BELL).await;
+                            }
+                            Some(reply) => Self::write_reply(&mut serial, reply).await,
                             None => {}
                         };
                     }
                 }
 
                 Either::Second(can_cmd) => {
-                    match can_cmd {
-                        SlcanCommand::Frame(mut frame) => {
-                            // Serialize and send the frame
-                            if timestamp_enabled {
-                                frame.timestamp = timestamp.get_current();
-                            }
-                            if let Some((buffer, size)) =
-                                slcan_serializer.to_bytes(SlcanCommand::Frame(frame))
-                            {
-                                let mut start = 0;
-                                while start != size {
-                                    start += match serial.write(&buffer[start..size]).await {
-                                        Ok(size) => size,
-                                        Err(_) => {
-                                            error!("Error writing to serial, up to retry");
-                                            0
-                                        }
-                                    }
+                    let cmd = session.handle_can(can_cmd);
+
+                    // Serialize and send the response
+                    if let Some((buffer, size)) = cmd.and_then(|c| slcan_serializer.to_bytes(c)) {
+                        let mut start = 0;
+                        while start != size {
+                            start += match serial.write(&buffer[start..size]).await {
+                                Ok(size) => size,
+                                Err(_) => {
+                                    error!("Error writing to serial, up to retry");
+                                    0
                                 }
                             }
                         }
-                        _ => {
-                            // We are not expecting other message
-                        }
                     }
                 }
             };
         }
     }
 
+    async fn write_reply(serial: &mut SERIAL, reply: &[u8]) {
+        match serial.write(reply).await {
+            Ok(_) => {}
+            Err(_) => error!("Error writing response"),
+        }
+    }
+
+    // Save `config`, kept as `saved` once written
+    fn save_startup(
+        store: &mut StartupStore<FLASH>,
+        saved: &mut StartupConfig,
+        config: StartupConfig,
+    ) -> SlcanCommand {
+        match store.save(&config) {
+            Ok(()) => {
+                info!("Startup settings saved");
+                *saved = config;
+                SlcanCommand::Ack
+            }
+            Err(_) => {
+                error!("Failed to save the startup settings");
+                SlcanCommand::Bell
+            }
+        }
+    }
+
+    // The rules go to the hardware filters only when the acceptance filter
+    // lets everything through, frames the hardware can't filter out are
+    // dropped by can_task
+    fn update_filters(
+        can: &mut CAN,
+        acceptance: &SlcanAcceptanceFilter,
+        software_filter: &SoftwareFilter,
+    ) {
+        let fit = if acceptance.accepts_all() {
+            let mut buffer = [FilterEntry::ACCEPT_ALL[0]; 2 * SOFTWARE_FILTER_MAX_RULES];
+            can.set_filters(software_filter.hardware_filters(&mut buffer))
+        } else {
+            can.set_acceptance_filter(acceptance)
+        };
+
+        if fit == FilterFit::Superset {
+            debug!("Filters partially done in software");
+        }
+    }
+
+    // Listen at each rate for a while, the controller is left closed at the
+    // detected rate. Without one it stays at the last rate tried, so the host
+    // has to set the bitrate again
+    async fn autobaud(can: &mut CAN) -> Option<CanBitrate> {
+        let mut autobaud = Autobaud::new();
+
+        while let Some(bitrate) = autobaud.next_rate(can) {
+            debug!("Autobaud: listening at {} bps", bitrate.bps());
+
+            let deadline = Instant::now() + AUTOBAUD_LISTEN_TIME;
+            while !autobaud.is_confident() && Instant::now() < deadline {
+                select(can.wait_for_event(), Timer::at(deadline)).await;
+                autobaud.poll(can);
+            }
+        }
+
+        can.set_mode(ChannelState::Closed.can_mode());
+
+        let bitrate = autobaud.detected()?;
+        can.set_bitrate(bitrate).ok()?;
+
+        Some(bitrate)
+    }
+
+    // In echo mode, frames the controller confirmed as sent go back to the
+    // host, timestamped as they went out
+    async fn echo_transmitted(
+        can: &mut CAN,
+        unconfirmed: &mut usize,
+        timestamp: &Timestamp,
+        out_channel: &CanChannelSender,
+    ) {
+        while let Some((frame, instant)) = can.transmitted() {
+            // Sent without echo, or already reported dropped
+            if *unconfirmed == 0 {
+                continue;
+            }
+            *unconfirmed -= 1;
+
+            let Some(mut echo_frame) = CAN::frame_to_slcan(&frame) else {
+                error!("Invalid frame sent by CAN controller");
+                continue;
+            };
+            echo_frame.timestamp = timestamp.get(instant);
+
+            out_channel.send(SlcanCommand::TxEcho(echo_frame)).await;
+        }
+    }
+
+    // While echoes are due, also wake once the controller may have sent one
+    async fn receive_or_transmitted(
+        can: &mut CAN,
+        echo_due: bool,
+    ) -> Option<Result<(CAN::Frame, Instant), CAN::Error>> {
+        if echo_due {
+            can.receive_or_transmitted().await
+        } else {
+            Some(can.receive_async().await)
+        }
+    }
+
+    // Follow the controller error state, the host is told about every change.
+    // Once bus off the controller is held off the bus until the recovery
+    // policy lets it back
+    async fn check_bus(
+        can: &mut CAN,
+        channel: ChannelState,
+        monitor: &mut BusMonitor,
+        counters: &mut ErrorCounters,
+        out_channel: &CanChannelSender,
+    ) {
+        let now = Instant::now();
+        let status = can.status();
+        counters.latched.0 |= SlcanStatusFlags::from(status).0 & LATCHED_FLAGS;
+
+        if let Some(state) = monitor.update(&status, now) {
+            if monitor.is_bus_off() {
+                error!("CAN controller is bus off");
+                can.set_mode(CanMode::Configuration);
+            } else {
+                info!("CAN controller error state changed");
+            }
+            out_channel.send(SlcanCommand::State(state)).await;
+        }
+
+        if monitor.recovery_due(now) {
+            info!("Recovering from bus off");
+            can.set_mode(channel.can_mode());
+            if let Some(state) = monitor.reset(now) {
+                out_channel.send(SlcanCommand::State(state)).await;
+            }
+        }
+    }
+
     pub async fn can_task(
         mut can: CAN,
+        config: BusConfig,
+        mut store: StartupStore<FLASH>,
+        mut saved: StartupConfig,
         in_channel: CanChannelReceiver,
         out_channel: CanChannelSender,
     ) -> ! {
         info!("Init: can_task");
+        let mut counters = ErrorCounters::default();
+        let mut timestamp = Timestamp::new();
+        timestamp.set_mode(saved.timestamp);
+        let mut acceptance = saved.acceptance;
+        let mut software_filter = saved.software_filter.clone();
+        // Q decides if the channel is opened without waiting for the host
+        let mut channel = ChannelState::from(saved.mode);
+        let mut monitor = BusMonitor::new(config.recovery);
+        let mut next_check = Instant::now() + BUS_CHECK_INTERVAL;
+        let mut echo = false;
+        // Frames sent in echo mode the controller hasn't confirmed yet
+        let mut unconfirmed = 0;
+        // With auto-poll off, received frames wait for P or A
+        let mut auto_poll = saved.auto_poll;
+        let mut pending = PendingFrames::new();
+        // Unconfirmed frames are reported dropped after then
+        let mut echo_until = Instant::now();
+        // Bitrate as last set by the host, saved with Q
+        let mut host_bitrate = saved.bitrate;
+
+        // The channel starts closed, keep the controller off the bus
+        can.set_mode(ChannelState::Closed.can_mode());
+
+        // Come up with the saved configuration
+        let applied = match saved.bitrate {
+            Some(StartupBitrate::Bps(bps)) => can.set_bitrate(CanBitrate::from_bps(bps)),
+            Some(StartupBitrate::BitTiming(timing)) => can.set_bit_timing(BitTiming::from(timing)),
+            None => Ok(()),
+        };
+        if applied.is_err() {
+            error!("Saved bitrate not applied by the CAN controller");
+        }
+
+        Self::update_filters(&mut can, &acceptance, &software_filter);
+        if channel.is_open() {
+            can.set_mode(channel.can_mode());
+        }
+
         loop {
-            // Try to receive a message
-            match can.receive() {
-                Ok(frame) => {
+            // Checked on a deadline, so a busy bus can't delay it
+            if Instant::now() >= next_check {
+                next_check = Instant::now() + BUS_CHECK_INTERVAL;
+                if channel.is_open() {
+                    Self::check_bus(&mut can, channel, &mut monitor, &mut counters, &out_channel)
+                        .await;
+                }
+            }
+
+            Self::echo_transmitted(&mut can, &mut unconfirmed, &timestamp, &out_channel).await;
+
+            // The host is told about every frame it won't get an echo for
+            if unconfirmed > 0 && Instant::now() >= echo_until {
+                error!("Sent frames never confirmed, dropped");
+                for _ in 0..unconfirmed {
+                    counters.tx_timeouts += 1;
+                    out_channel.send(SlcanCommand::TxDropped).await;
+                    out_channel.send(SlcanCommand::Bell).await;
+                }
+                unconfirmed = 0;
+            }
+
+            let wake_at = if unconfirmed > 0 {
+                next_check.min(echo_until)
+            } else {
+                next_check
+            };
+
+            // Sleep until a frame is received or confirmed as sent, the host
+            // sends a command or the bus has to be checked
+            match select3(
+                Self::receive_or_transmitted(&mut can, unconfirmed > 0),
+                in_channel.receive(),
+                Timer::at(wake_at),
+            )
+            .await
+            {
+                // Picked up by echo_transmitted
+                Either3::First(None) => {}
+                Either3::First(Some(Ok((frame, instant)))) => {
                     debug!("New frame received");
-                    let new_frame = slcan::CanFrame::new(
-                        frame.id(),
-                        frame.is_remote_frame(),
-                        &frame.data()[0..frame.dlc()],
-                    )
-                    .unwrap();
+                    let Some(mut new_frame) = CAN::frame_to_slcan(&frame) else {
+                        error!("Invalid frame received from CAN controller");
+                        continue;
+                    };
+                    new_frame.timestamp = timestamp.get(instant);
+
+                    // Hardware filters are coarser than the acceptance and software ones
+                    if !acceptance.accepts(&new_frame) || !software_filter.accepts(&new_frame) {
+                        continue;
+                    }
+
+                    if !auto_poll {
+                        if !pending.push(new_frame) {
+                            error!("Pending frames full, frame dropped");
+                            counters.pending_overruns += 1;
+                        }
+                        continue;
+                    }
+
+                    if out_channel.is_full() {
+                        counters.serial_queue_full += 1;
+                    }
 
                     out_channel.send(SlcanCommand::Frame(new_frame)).await;
                 }
-                Err(e) => match e.kind() {
+                Either3::First(Some(Err(e))) => match e.kind() {
                     ErrorKind::Overrun => {
                         error!("Overrun error received from CAN controller");
+                        counters.rx_overruns += 1;
                     }
                     _ => {}
                 },
-            }
-
-            if !in_channel.is_empty() {
-                match in_channel.receive().await {
+                Either3::Second(cmd) => match cmd {
                     SlcanCommand::Frame(frame) => {
                         debug!("Sending new frame");
 
-                        let new_frame =
-                            CAN::Frame::new(frame.id, &frame.data[0..frame.dlc]).unwrap();
+                        let Some(new_frame) = CAN::frame_from_slcan(&frame) else {
+                            error!("Frame not supported by the CAN controller");
+                            out_channel.send(SlcanCommand::Bell).await;
+                            continue;
+                        };
+
+                        // The controller can't send while listening only
+                        if channel != ChannelState::Open {
+                            error!("CAN controller not open for sending, frame dropped");
+                            out_channel.send(SlcanCommand::Bell).await;
+                            continue;
+                        }
 
-                        while let Err(e) = can.transmit(&new_frame) {
+                        if monitor.is_bus_off() {
+                            error!("CAN controller is bus off, frame dropped");
+                            out_channel.send(SlcanCommand::TxDropped).await;
+                            out_channel.send(SlcanCommand::Bell).await;
+                            continue;
+                        }
+
+                        let deadline = Instant::now() + config.tx_timeout;
+                        let mut sent = true;
+                        while let Err(e) = can.transmit_async(&new_frame).await {
                             match e.kind() {
                                 ErrorKind::Overrun => {
                                     error!("Overrun error received from CAN controller");
+                                    counters.tx_overruns += 1;
                                 }
                                 ErrorKind::Other => {
                                     error!("Other error received from CAN controller");
@@ -237,26 +562,423 @@ where
                                 }
                             };
 
-                            yield_now().await;
+                            if Instant::now() >= deadline {
+                                error!("Transmission timed out, frame dropped");
+                                counters.tx_timeouts += 1;
+                                out_channel.send(SlcanCommand::TxDropped).await;
+                                out_channel.send(SlcanCommand::Bell).await;
+                                sent = false;
+                                break;
+                            }
+
+                            // Retry once the controller frees a buffer, the frame
+                            // it sent is echoed first
+                            select(can.wait_for_event(), Timer::at(deadline)).await;
+                            Self::echo_transmitted(
+                                &mut can,
+                                &mut unconfirmed,
+                                &timestamp,
+                                &out_channel,
+                            )
+                            .await;
+                        }
+
+                        if !sent {
+                            continue;
+                        }
+                        if echo {
+                            unconfirmed += 1;
+                            echo_until = Instant::now() + config.tx_timeout;
+                        } else {
+                            out_channel.send(SlcanCommand::TxAck(frame.id)).await;
+                        }
+                    }
+                    SlcanCommand::ReadStatusFlags => {
+                        let mut flags = SlcanStatusFlags::from(can.status());
+                        counters.apply(&mut flags);
+                        if in_channel.is_full() {
+                            flags.set(SlcanStatusFlags::TX_FIFO_FULL, true);
                         }
+                        if pending.is_full() {
+                            flags.set(SlcanStatusFlags::RX_FIFO_FULL, true);
+                        }
+
+                        // Flags are cleared once read, as Lawicel does
+                        counters = ErrorCounters::default();
+
+                        out_channel.send(SlcanCommand::StatusFlags(flags)).await;
+                    }
+                    // Opening the channel restarts the controller, which is
+                    // also how it recovers from bus off by hand
+                    SlcanCommand::OpenChannel
+                    | SlcanCommand::Listen
+                    | SlcanCommand::CloseChannel => {
+                        channel = match cmd {
+                            SlcanCommand::OpenChannel => ChannelState::Open,
+                            SlcanCommand::Listen => ChannelState::ListenOnly,
+                            _ => ChannelState::Closed,
+                        };
+                        can.set_mode(channel.can_mode());
+                        monitor.reset(Instant::now());
+                        pending.clear();
+                    }
+                    SlcanCommand::AcceptanceCode(code) => {
+                        acceptance.code = code;
+                        Self::update_filters(&mut can, &acceptance, &software_filter);
+                    }
+                    SlcanCommand::AcceptanceMask(mask) => {
+                        acceptance.mask = mask;
+                        Self::update_filters(&mut can, &acceptance, &software_filter);
+                    }
+                    SlcanCommand::FilterMode(mode) => {
+                        acceptance.mode = mode;
+                        Self::update_filters(&mut can, &acceptance, &software_filter);
+                    }
+                    SlcanCommand::AddFilterRule(rule) => {
+                        if !software_filter.add(rule) {
+                            error!("Software filter full");
+                        }
+                        Self::update_filters(&mut can, &acceptance, &software_filter);
+                    }
+                    SlcanCommand::ClearFilterRules => {
+                        software_filter.clear();
+                        Self::update_filters(&mut can, &acceptance, &software_filter);
                     }
-                    SlcanCommand::FilterId(id) => can.set_filter(id),
-                    SlcanCommand::FilterMask(mask) => can.set_filter(mask),
                     SlcanCommand::SetBitrate(bitrate) => {
-                        can.set_bitrate(can::CanBitrates::from(bitrate as u16))
+                        let reply = match can.set_bitrate(CanBitrate::from(bitrate)) {
+                            Ok(()) => {
+                                host_bitrate = Some(StartupBitrate::Bps(bitrate.bps()));
+                                SlcanCommand::Ack
+                            }
+                            Err(BitrateError::Unsupported) => {
+                                error!("Bitrate not supported by the CAN controller");
+                                SlcanCommand::Bell
+                            }
+                            Err(BitrateError::NotApplied) => {
+                                error!("CAN controller didn't take the bitrate");
+                                SlcanCommand::Bell
+                            }
+                        };
+                        out_channel.send(reply).await;
                     }
-                    SlcanCommand::SetBitTimeRegister(_) => {
-                        // TODO: Implement
+                    SlcanCommand::Autobaud => {
+                        let reply = match Self::autobaud(&mut can).await {
+                            Some(bitrate) => {
+                                info!("Bitrate detected: {} bps", bitrate.bps());
+                                host_bitrate = Some(StartupBitrate::Bps(bitrate.bps()));
+                                SlcanCommand::DetectedBitrate(bitrate.bps())
+                            }
+                            None => {
+                                error!("No bitrate detected");
+                                SlcanCommand::Bell
+                            }
+                        };
+                        out_channel.send(reply).await;
+                    }
+                    SlcanCommand::SetBitTimeRegister(timing) => {
+                        let reply = match can.set_bit_timing(BitTiming::from(timing)) {
+                            Ok(()) => {
+                                host_bitrate = Some(StartupBitrate::BitTiming(timing));
+                                SlcanCommand::Ack
+                            }
+                            Err(_) => {
+                                error!("Bit timing not applied by the CAN controller");
+                                SlcanCommand::Bell
+                            }
+                        };
+                        out_channel.send(reply).await;
+                    }
+                    SlcanCommand::Timestamp(mode) => {
+                        info!("Timestamp mode changed");
+                        timestamp.set_mode(mode)
+                    }
+                    SlcanCommand::EchoMode(enabled) => {
+                        info!("Echo mode changed");
+                        echo = enabled;
+                    }
+                    SlcanCommand::AutoPoll(enabled) => {
+                        info!("Auto-poll changed");
+                        auto_poll = enabled;
+                        pending.clear();
+                    }
+                    // An empty poll is answered with \r, as Lawicel does
+                    SlcanCommand::PollOne => {
+                        let reply = match pending.pop() {
+                            Some(frame) => SlcanCommand::Frame(frame),
+                            None => SlcanCommand::Ack,
+                        };
+                        out_channel.send(reply).await;
+                    }
+                    // Q saves the settings in use, they are applied at power on
+                    SlcanCommand::AutoStartup(mode) => {
+                        let config = StartupConfig {
+                            mode,
+                            bitrate: host_bitrate,
+                            acceptance,
+                            software_filter: software_filter.clone(),
+                            timestamp: timestamp.mode(),
+                            auto_poll,
+                            ..saved.clone()
+                        };
+                        let reply = Self::save_startup(&mut store, &mut saved, config);
+                        out_channel.send(reply).await;
+                    }
+                    SlcanCommand::ReadConfig(key) => {
+                        out_channel
+                            .send(SlcanCommand::ConfigValue(saved.value(key)))
+                            .await;
+                    }
+                    // Written values are used from the next power on
+                    SlcanCommand::WriteConfig(value) => {
+                        let mut config = saved.clone();
+                        config.set(value);
+                        let reply = Self::save_startup(&mut store, &mut saved, config);
+                        out_channel.send(reply).await;
+                    }
+                    SlcanCommand::FactoryReset => {
+                        let reply = match store.factory_reset() {
+                            Ok(()) => {
+                                info!("Configuration erased");
+                                saved = StartupConfig::default();
+                                SlcanCommand::Ack
+                            }
+                            Err(_) => {
+                                error!("Failed to erase the configuration");
+                                SlcanCommand::Bell
+                            }
+                        };
+                        out_channel.send(reply).await;
+                    }
+                    SlcanCommand::PollAll => {
+                        while let Some(frame) = pending.pop() {
+                            out_channel.send(SlcanCommand::Frame(frame)).await;
+                        }
+                        out_channel.send(SlcanCommand::PollEnd).await;
                     }
                     _ => {
                         // We don't expect other message type
                         warn!("SlcanCommand not supported");
                     }
-                }
+                },
+                // The bus and sent frames are checked at the top of the loop
+                Either3::Third(()) => {}
             }
-
-            // Avoid starvation
-            yield_now().await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCan, MockEvent, MockFlash, MockSerial, MOCK_FLASH_SIZE};
+    use core::future::Future;
+    use embassy_futures::block_on;
+    use embassy_time::with_timeout;
+    use embedded_can::{Id, StandardId};
+    use slcan::{CanFrame, SlcanBitrates, SlcanTimestamp};
+
+    type MockCore = Core<MockCan, MockSerial, MockFlash>;
+
+    fn quiet_bus(_bitrate: CanBitrate) -> &'static [MockEvent] {
+        &[]
+    }
+
+    fn one_frame(_bitrate: CanBitrate) -> &'static [MockEvent] {
+        &[MockEvent::Frame(0x123)]
+    }
+
+    fn test_frame() -> CanFrame {
+        CanFrame::new(StandardId::new(0x456).unwrap(), false, &[0x11, 0x22]).unwrap()
+    }
+
+    // can_task with `can`, while `host` sends it commands through `in_channel`
+    // and reads what it answers from `out_channel`
+    fn run_can_task(
+        can: MockCan,
+        in_channel: &'static CanChannel,
+        out_channel: &'static CanChannel,
+        host: impl Future<Output = ()>,
+    ) {
+        let store = StartupStore::new(MockFlash::new(), 0, MOCK_FLASH_SIZE);
+        let config = BusConfig {
+            tx_timeout: Duration::from_millis(10),
+            ..BusConfig::default()
+        };
+        let task = MockCore::can_task(
+            can,
+            config,
+            store,
+            StartupConfig::default(),
+            in_channel.receiver(),
+            out_channel.sender(),
+        );
+
+        block_on(select(task, host));
+    }
+
+    async fn reply(out_channel: &CanChannel) -> SlcanCommand {
+        with_timeout(Duration::from_secs(1), out_channel.receive())
+            .await
+            .expect("No answer from can_task")
+    }
+
+    #[test]
+    fn test_timestamp_milliseconds_wrap() {
+        let mut timestamp = Timestamp::new();
+        assert_eq!(timestamp.get(Instant::now()), None);
+
+        timestamp.set_mode(SlcanTimestampMode::Milliseconds);
+        let start = timestamp.start;
+        assert_eq!(
+            timestamp.get(start + Duration::from_millis(59_999)),
+            Some(SlcanTimestamp::Milliseconds(59_999))
+        );
+        assert_eq!(
+            timestamp.get(start + Duration::from_millis(60_000)),
+            Some(SlcanTimestamp::Milliseconds(0))
+        );
+        assert_eq!(
+            timestamp.get(start + Duration::from_millis(61_234)),
+            Some(SlcanTimestamp::Milliseconds(1234))
+        );
+    }
+
+    #[test]
+    fn test_timestamp_microseconds() {
+        let mut timestamp = Timestamp::new();
+        timestamp.set_mode(SlcanTimestampMode::Microseconds);
+        let start = timestamp.start;
+
+        assert_eq!(
+            timestamp.get(start + Duration::from_micros(61_000_123)),
+            Some(SlcanTimestamp::Microseconds(61_000_123))
+        );
+        // Frames received before the counter started are at 0
+        assert_eq!(
+            timestamp.get(start - Duration::from_millis(1)),
+            Some(SlcanTimestamp::Microseconds(0))
+        );
+    }
+
+    #[test]
+    fn test_can_task_frame_acked() {
+        static IN: CanChannel = CanChannel::new();
+        static OUT: CanChannel = CanChannel::new();
+
+        run_can_task(MockCan::new(quiet_bus), &IN, &OUT, async {
+            IN.send(SlcanCommand::OpenChannel).await;
+            IN.send(SlcanCommand::Frame(test_frame())).await;
+
+            let id = Id::Standard(StandardId::new(0x456).unwrap());
+            assert_eq!(reply(&OUT).await, SlcanCommand::TxAck(id));
+        });
+    }
+
+    #[test]
+    fn test_can_task_frame_closed() {
+        static IN: CanChannel = CanChannel::new();
+        static OUT: CanChannel = CanChannel::new();
+
+        run_can_task(MockCan::new(quiet_bus), &IN, &OUT, async {
+            IN.send(SlcanCommand::Frame(test_frame())).await;
+            assert_eq!(reply(&OUT).await, SlcanCommand::Bell);
+
+            // Listen only can't send either
+            IN.send(SlcanCommand::Listen).await;
+            IN.send(SlcanCommand::Frame(test_frame())).await;
+            assert_eq!(reply(&OUT).await, SlcanCommand::Bell);
+        });
+    }
+
+    #[test]
+    fn test_can_task_frame_echo() {
+        static IN: CanChannel = CanChannel::new();
+        static OUT: CanChannel = CanChannel::new();
+
+        run_can_task(MockCan::new(quiet_bus), &IN, &OUT, async {
+            IN.send(SlcanCommand::EchoMode(true)).await;
+            IN.send(SlcanCommand::OpenChannel).await;
+            IN.send(SlcanCommand::Frame(test_frame())).await;
+
+            assert_eq!(reply(&OUT).await, SlcanCommand::TxEcho(test_frame()));
+        });
+    }
+
+    #[test]
+    fn test_can_task_echo_never_confirmed() {
+        static IN: CanChannel = CanChannel::new();
+        static OUT: CanChannel = CanChannel::new();
+
+        let mut can = MockCan::new(quiet_bus);
+        can.confirms_sent = false;
+
+        run_can_task(can, &IN, &OUT, async {
+            IN.send(SlcanCommand::EchoMode(true)).await;
+            IN.send(SlcanCommand::OpenChannel).await;
+            IN.send(SlcanCommand::Frame(test_frame())).await;
+
+            assert_eq!(reply(&OUT).await, SlcanCommand::TxDropped);
+            assert_eq!(reply(&OUT).await, SlcanCommand::Bell);
+        });
+    }
+
+    #[test]
+    fn test_can_task_set_bitrate() {
+        static IN: CanChannel = CanChannel::new();
+        static OUT: CanChannel = CanChannel::new();
+
+        let can = MockCan::new(quiet_bus).with_unsupported(&[10_000]);
+
+        run_can_task(can, &IN, &OUT, async {
+            IN.send(SlcanCommand::SetBitrate(SlcanBitrates::CAN500KB))
+                .await;
+            assert_eq!(reply(&OUT).await, SlcanCommand::Ack);
+
+            IN.send(SlcanCommand::SetBitrate(SlcanBitrates::CAN10KB))
+                .await;
+            assert_eq!(reply(&OUT).await, SlcanCommand::Bell);
+        });
+    }
+
+    #[test]
+    fn test_can_task_poll() {
+        static IN: CanChannel = CanChannel::new();
+        static OUT: CanChannel = CanChannel::new();
+
+        run_can_task(MockCan::new(one_frame), &IN, &OUT, async {
+            IN.send(SlcanCommand::AutoPoll(false)).await;
+            IN.send(SlcanCommand::SetBitrate(SlcanBitrates::CAN500KB))
+                .await;
+            assert_eq!(reply(&OUT).await, SlcanCommand::Ack);
+            IN.send(SlcanCommand::OpenChannel).await;
+
+            // The frame received waits for the poll
+            IN.send(SlcanCommand::PollOne).await;
+            let frame = CanFrame::new(StandardId::new(0x123).unwrap(), false, &[]).unwrap();
+            assert_eq!(reply(&OUT).await, SlcanCommand::Frame(frame));
+
+            IN.send(SlcanCommand::PollOne).await;
+            assert_eq!(reply(&OUT).await, SlcanCommand::Ack);
+        });
+    }
+
+    #[test]
+    fn test_can_task_status_flags() {
+        static IN: CanChannel = CanChannel::new();
+        static OUT: CanChannel = CanChannel::new();
+
+        let mut can = MockCan::new(quiet_bus);
+        can.status.bus_error = true;
+
+        run_can_task(can, &IN, &OUT, async {
+            IN.send(SlcanCommand::ReadStatusFlags).await;
+            let flags = SlcanStatusFlags(SlcanStatusFlags::BUS_ERROR);
+            assert_eq!(reply(&OUT).await, SlcanCommand::StatusFlags(flags));
+
+            // Cleared once read
+            IN.send(SlcanCommand::ReadStatusFlags).await;
+            let flags = SlcanStatusFlags::default();
+            assert_eq!(reply(&OUT).await, SlcanCommand::StatusFlags(flags));
+        });
+    }
+}
@@ -4,6 +4,12 @@ macro_rules! core_run {
         // Unpack all the peripherals
         let serial = $core_instance.bsp.serial.replace(None).unwrap();
         let can = $core_instance.bsp.can.replace(None).unwrap();
+        let device_id = $core_instance.bsp.device_id;
+        let bus_config = $core_instance.bsp.bus_config;
+        let startup_store = $core_instance.bsp.startup_store.replace(None).unwrap();
+
+        // Settings loaded at boot, both tasks start with them
+        let startup = $core_instance.bsp.startup.clone();
 
         // Create Channels
         static SERIAL_CHANNEL: CanChannel = CanChannel::new();
@@ -14,6 +20,8 @@ macro_rules! core_run {
             .spawner
             .spawn(slcan_task(
                 serial,
+                device_id,
+                startup.clone(),
                 SERIAL_CHANNEL.receiver(),
                 CAN_CHANNEL.sender(),
             ))
@@ -22,6 +30,9 @@ macro_rules! core_run {
             .spawner
             .spawn(can_task(
                 can,
+                bus_config,
+                startup_store,
+                startup,
                 CAN_CHANNEL.receiver(),
                 SERIAL_CHANNEL.sender(),
             ))
@@ -31,23 +42,47 @@ macro_rules! core_run {
 
 #[macro_export]
 macro_rules! core_create_tasks {
+    // Without flash for the startup settings, Q and cW always fail
     ($SerialType:ty, $CanType:ty) => {
+        $crate::core_create_tasks!($SerialType, $CanType, $crate::NoFlash);
+    };
+    ($SerialType:ty, $CanType:ty, $FlashType:ty) => {
         #[embassy_executor::task]
         async fn slcan_task(
             serial: $SerialType,
+            device_id: $crate::DeviceId,
+            startup: $crate::StartupConfig,
             channel_in: CanChannelReceiver,
             channel_out: CanChannelSender,
         ) {
-            Core::<$CanType, $SerialType>::slcan_task(serial, channel_in, channel_out).await;
+            Core::<$CanType, $SerialType, $FlashType>::slcan_task(
+                serial,
+                device_id,
+                startup,
+                channel_in,
+                channel_out,
+            )
+            .await;
         }
 
         #[embassy_executor::task]
         async fn can_task(
             can: $CanType,
+            bus_config: $crate::BusConfig,
+            startup_store: $crate::StartupStore<$FlashType>,
+            startup: $crate::StartupConfig,
             channel_in: CanChannelReceiver,
             channel_out: CanChannelSender,
         ) {
-            Core::<$CanType, $SerialType>::can_task(can, channel_in, channel_out).await;
+            Core::<$CanType, $SerialType, $FlashType>::can_task(
+                can,
+                bus_config,
+                startup_store,
+                startup,
+                channel_in,
+                channel_out,
+            )
+            .await;
         }
     };
 }
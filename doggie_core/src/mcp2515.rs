@@ -1,93 +1,675 @@
-use crate::can::{CanBitrates, CanDevice};
-use embedded_can::Id;
+use crate::can::{
+    AsyncCanDevice, BitTiming, BitrateError, CanBitrate, CanDevice, CanMode, CanStatus,
+};
+use crate::filter::{allocate_mask_groups, FilterEntry, FilterFit, RawFilter};
+use defmt::{error, info};
+use embassy_time::Instant;
+use embedded_can::{blocking::Can, Error, ErrorKind, ExtendedId, Id, StandardId};
 use embedded_hal::{delay::DelayNs, spi::SpiDevice};
+use embedded_hal_async::digital::Wait;
 use embedded_io_async::{Read, Write};
-use defmt::{info, error};
 
 use crate::bsp::Bsp;
 
 use mcp2515::{
     filter::{RxFilter, RxMask},
-    regs::OpMode,
+    regs::{
+        OpMode, CANINTE, CANINTF, CANSTAT, CNF1, CNF2, CNF3, EFLG, REC, RXB0CTRL, TEC, TXB0CTRL,
+    },
     CanSpeed, McpSpeed, MCP2515,
 };
 
-// TODO: The next 3 options should be features
-const MCP_CLOCK: McpSpeed = McpSpeed::MHz8;
-const MCP_CLOCK_ENABLE: bool = false;
-const MCP_INITIAL_BAUDRATE: CanSpeed = CanSpeed::Kbps250;
-
-fn convert_bitrate(from: CanBitrates) -> CanSpeed {
-    can_speed_from_raw(from as u16)
-}
-
-pub fn can_speed_from_raw(speed: u16) -> CanSpeed {
-    match speed {
-        5 => CanSpeed::Kbps5,
-        10 => CanSpeed::Kbps10,
-        20 => CanSpeed::Kbps20,
-        3125 => CanSpeed::Kbps31_25,
-        333 => CanSpeed::Kbps33_3,
-        40 => CanSpeed::Kbps40,
-        50 => CanSpeed::Kbps50,
-        80 => CanSpeed::Kbps80,
-        100 => CanSpeed::Kbps100,
-        125 => CanSpeed::Kbps125,
-        200 => CanSpeed::Kbps200,
-        250 => CanSpeed::Kbps250,
-        500 => CanSpeed::Kbps500,
-        1000 => CanSpeed::Kbps1000,
-        // Default
-        _ => CanSpeed::Kbps250,
-    }
-}
-
-impl<SPI: SpiDevice> CanDevice for MCP2515<SPI> {
-    fn set_bitrate(&mut self, bitrate: CanBitrates) {
-        info!("Setting bitrate to {} Kbps", bitrate as u16);
-        match self.set_mode(OpMode::Configuration) {
+// Build time defaults, the crystal is 8 MHz and the bitrate 250 kbit/s
+// unless a feature says otherwise
+const MCP_CRYSTAL_HZ: u32 = if cfg!(feature = "mcp2515-20mhz") {
+    20_000_000
+} else if cfg!(feature = "mcp2515-16mhz") {
+    16_000_000
+} else {
+    8_000_000
+};
+const MCP_CLKOUT: bool = cfg!(feature = "mcp2515-clkout");
+const MCP_INITIAL_BITRATE: CanBitrate = if cfg!(feature = "mcp2515-1000kbps") {
+    CanBitrate::from_kbps(1000)
+} else if cfg!(feature = "mcp2515-500kbps") {
+    CanBitrate::from_kbps(500)
+} else if cfg!(feature = "mcp2515-125kbps") {
+    CanBitrate::from_kbps(125)
+} else {
+    CanBitrate::from_kbps(250)
+};
+
+// CiA recommended sample point
+const MCP_SAMPLE_POINT_PERMILLE: u16 = 875;
+
+#[derive(Clone, Copy)]
+pub struct Mcp2515Config {
+    pub crystal_hz: u32,
+    // Output the crystal clock on the CLKOUT pin
+    pub clkout: bool,
+    // Bitrate set on startup
+    pub bitrate: CanBitrate,
+    // Sample point for the bitrates set with S, in tenths of a percent
+    pub sample_point_permille: u16,
+}
+
+impl Default for Mcp2515Config {
+    fn default() -> Self {
+        Mcp2515Config {
+            crystal_hz: MCP_CRYSTAL_HZ,
+            clkout: MCP_CLKOUT,
+            bitrate: MCP_INITIAL_BITRATE,
+            sample_point_permille: MCP_SAMPLE_POINT_PERMILLE,
+        }
+    }
+}
+
+// Bit timing for `bitrate` on a MCP2515 running from `crystal_hz`, with the
+// sample point closest to the target. Bitrates within 0.5% are accepted,
+// preferring the most time quanta per bit
+pub fn mcp2515_bit_timing(
+    crystal_hz: u32,
+    bitrate: u32,
+    sample_point_permille: u16,
+) -> Option<BitTiming> {
+    let clock_hz = crystal_hz / 2;
+    let mut best: Option<(u32, u32, BitTiming)> = None;
+
+    for prescaler in 1..=64u32 {
+        for quanta in (5..=25u32).rev() {
+            let actual = clock_hz / (prescaler * quanta);
+            let bitrate_error = actual.abs_diff(bitrate);
+            if bitrate_error * 200 > bitrate {
+                continue;
+            }
+
+            // PHSEG2 is 2 to 8 quanta and can't be longer than TSEG1, which
+            // is 16 quanta at most
+            let tseg2 = (quanta * (1000 - sample_point_permille as u32) + 500) / 1000;
+            let tseg2 = tseg2.max(quanta.saturating_sub(17));
+            let tseg2 = tseg2.clamp(2, 8.min((quanta - 1) / 2));
+            let tseg1 = quanta - 1 - tseg2;
+            if tseg1 > 16 {
+                continue;
+            }
+
+            let sample_point = 1000 * (quanta - tseg2) / quanta;
+            let sample_point_error = sample_point.abs_diff(sample_point_permille as u32);
+
+            if best.is_some_and(|(best_bitrate, best_sample_point, _)| {
+                (best_bitrate, best_sample_point) <= (bitrate_error, sample_point_error)
+            }) {
+                continue;
+            }
+
+            let timing = BitTiming {
+                clock_hz,
+                prescaler: prescaler as u16,
+                sjw: tseg2.min(4) as u8,
+                tseg1: tseg1 as u8,
+                tseg2: tseg2 as u8,
+                triple_sample: false,
+            };
+            best = Some((bitrate_error, sample_point_error, timing));
+        }
+    }
+
+    best.map(|(_, _, timing)| timing)
+}
+
+// Encode a bit timing into the CNF1, CNF2 and CNF3 registers.
+// The MCP2515 time quantum is 2 * (BRP + 1) / Fosc.
+pub fn cnf_from_bit_timing(
+    timing: &BitTiming,
+    crystal_hz: u32,
+    clkout_en: bool,
+) -> Option<[u8; 3]> {
+    let timing = timing.with_clock(crystal_hz / 2)?;
+
+    if !(1..=64).contains(&timing.prescaler)
+        || !(1..=4).contains(&timing.sjw)
+        || !(2..=16).contains(&timing.tseg1)
+        || !(2..=8).contains(&timing.tseg2)
+        || timing.sjw > timing.tseg2
+        || timing.tseg1 < timing.tseg2
+    {
+        return None;
+    }
+
+    // Split TSEG1 between the propagation and phase 1 segments
+    let phseg1 = timing.tseg1 / 2;
+    let prseg = timing.tseg1 - phseg1;
+
+    let cnf1 = ((timing.sjw - 1) << 6) | (timing.prescaler - 1) as u8;
+    // BTLMODE set, so PHSEG2 is taken from CNF3
+    let cnf2 = 0x80 | ((timing.triple_sample as u8) << 6) | ((phseg1 - 1) << 3) | (prseg - 1);
+    // SOF only matters when CLKOUT is enabled
+    let cnf3 = ((!clkout_en as u8) << 7) | (timing.tseg2 - 1);
+
+    Some([cnf1, cnf2, cnf3])
+}
+
+// EFLG bits
+const EFLG_EWARN: u8 = 1 << 0;
+const EFLG_RXEP: u8 = 1 << 3;
+const EFLG_TXEP: u8 = 1 << 4;
+const EFLG_TXBO: u8 = 1 << 5;
+const EFLG_RX0OVR: u8 = 1 << 6;
+const EFLG_RX1OVR: u8 = 1 << 7;
+
+// CANINTF bits
+const CANINTF_RX0IF: u8 = 1 << 0;
+const CANINTF_RX1IF: u8 = 1 << 1;
+const CANINTF_TX0IF: u8 = 1 << 2;
+const CANINTF_TX1IF: u8 = 1 << 3;
+const CANINTF_TX2IF: u8 = 1 << 4;
+const CANINTF_ERRIF: u8 = 1 << 5;
+const CANINTF_MERRF: u8 = 1 << 7;
+
+// CANINTE bits, the same layout as CANINTF
+const CANINTE_EVENTS: u8 =
+    CANINTF_RX0IF | CANINTF_RX1IF | CANINTF_TX0IF | CANINTF_TX1IF | CANINTF_TX2IF | CANINTF_ERRIF;
+
+// RXB0CTRL bits, filters stay enabled with RXM cleared
+const RXB0CTRL_BUKT: u8 = 1 << 2;
+
+// TXBnCTRL bits
+const TXBCTRL_TXREQ: u8 = 1 << 3;
+const TXBCTRL_MLOA: u8 = 1 << 5;
+
+pub fn status_from_registers(eflg: u8, canintf: u8, txb0ctrl: u8, tec: u8, rec: u8) -> CanStatus {
+    CanStatus {
+        rx_fifo_full: canintf & (CANINTF_RX0IF | CANINTF_RX1IF) == (CANINTF_RX0IF | CANINTF_RX1IF),
+        tx_fifo_full: txb0ctrl & TXBCTRL_TXREQ != 0,
+        error_warning: eflg & EFLG_EWARN != 0,
+        data_overrun: eflg & (EFLG_RX0OVR | EFLG_RX1OVR) != 0,
+        error_passive: eflg & (EFLG_RXEP | EFLG_TXEP) != 0,
+        bus_off: eflg & EFLG_TXBO != 0,
+        arbitration_lost: txb0ctrl & TXBCTRL_MLOA != 0,
+        bus_error: canintf & CANINTF_MERRF != 0,
+        tx_error_count: tec,
+        rx_error_count: rec,
+    }
+}
+
+fn read_status<SPI: SpiDevice>(mcp: &mut MCP2515<SPI>) -> Option<CanStatus> {
+    let eflg: u8 = mcp.read_register::<EFLG>().ok()?.into();
+    let canintf: u8 = mcp.read_register::<CANINTF>().ok()?.into();
+    let txb0ctrl: u8 = mcp.read_register::<TXB0CTRL>().ok()?.into();
+    let tec: u8 = mcp.read_register::<TEC>().ok()?.into();
+    let rec: u8 = mcp.read_register::<REC>().ok()?.into();
+
+    // Overflow and message error flags are latched, clear them once read
+    mcp.modify_register(EFLG::from(0), EFLG_RX0OVR | EFLG_RX1OVR)
+        .ok()?;
+    mcp.modify_register(CANINTF::from(0), CANINTF_MERRF).ok()?;
+
+    Some(status_from_registers(eflg, canintf, txb0ctrl, tec, rec))
+}
+
+fn convert_mode(mode: CanMode) -> OpMode {
+    match mode {
+        CanMode::Normal => OpMode::Normal,
+        CanMode::ListenOnly => OpMode::ListenOnly,
+        CanMode::Loopback => OpMode::Loopback,
+        CanMode::Configuration => OpMode::Configuration,
+    }
+}
+
+// Operation mode the controller is running in, so it can be restored after
+// going through configuration mode
+fn current_mode<SPI: SpiDevice>(mcp: &mut MCP2515<SPI>) -> OpMode {
+    let canstat: u8 = match mcp.read_register::<CANSTAT>() {
+        Ok(canstat) => canstat.into(),
+        Err(_) => {
+            error!("Failed to read the MCP2515 mode");
+            return OpMode::Normal;
+        }
+    };
+
+    match canstat >> 5 {
+        0b001 => OpMode::Sleep,
+        0b010 => OpMode::Loopback,
+        0b011 => OpMode::ListenOnly,
+        0b100 => OpMode::Configuration,
+        _ => OpMode::Normal,
+    }
+}
+
+fn raw_filter_id(filter: RawFilter) -> Option<Id> {
+    if filter.extended {
+        ExtendedId::new(filter.id).map(Id::Extended)
+    } else {
+        StandardId::new((filter.id >> 18) as u16).map(Id::Standard)
+    }
+}
+
+// RXB0 has Mask0 with F0-F1, RXB1 has Mask1 with F2-F5. For standard frames
+// the EID mask bits compare the data bytes, the allocation leaves them clear
+// in any group with standard filters
+fn write_filters<SPI: SpiDevice>(
+    mcp: &mut MCP2515<SPI>,
+    filters: &[FilterEntry],
+) -> Option<FilterFit> {
+    let ([rxb0, rxb1], fit) = allocate_mask_groups(filters, [2, 4]);
+
+    let rxb0_filters = [RxFilter::F0, RxFilter::F1];
+    let rxb1_filters = [RxFilter::F2, RxFilter::F3, RxFilter::F4, RxFilter::F5];
+
+    for (mask, group, filters) in [
+        (RxMask::Mask0, rxb0, &rxb0_filters[..]),
+        (RxMask::Mask1, rxb1, &rxb1_filters[..]),
+    ] {
+        let mask_id = ExtendedId::new(group.mask).map(Id::Extended)?;
+        mcp.set_mask(mask, mask_id).ok()?;
+
+        for (slot, filter) in filters.iter().enumerate() {
+            mcp.set_filter(*filter, raw_filter_id(group.filter(slot))?)
+                .ok()?;
+        }
+    }
+
+    Some(fit)
+}
+
+// MCP2515 with the configuration it was set up with
+pub struct Mcp2515<SPI> {
+    mcp: MCP2515<SPI>,
+    config: Mcp2515Config,
+    // Frame in TXB0, the only buffer the driver sends through
+    tx_pending: Option<<MCP2515<SPI> as Can>::Frame>,
+}
+
+impl<SPI: SpiDevice> Mcp2515<SPI> {
+    // CNF registers can only be written in configuration mode, the previous
+    // mode is restored afterwards
+    fn write_cnf(&mut self, [cnf1, cnf2, cnf3]: [u8; 3]) -> bool {
+        let mode = current_mode(&mut self.mcp);
+        match self.mcp.set_mode(OpMode::Configuration) {
+            Ok(_) => info!("Switching to Configuration Mode"),
+            Err(_) => error!("Failed to switch to Configuration Mode"),
+        }
+
+        let res = self
+            .mcp
+            .write_register(CNF1::from(cnf1))
+            .and_then(|_| self.mcp.write_register(CNF2::from(cnf2)))
+            .and_then(|_| self.mcp.write_register(CNF3::from(cnf3)));
+
+        match self.mcp.set_mode(mode) {
+            Ok(_) => info!("Restoring operation mode"),
+            Err(_) => error!("Failed to restore operation mode"),
+        }
+
+        res.is_ok()
+    }
+
+    fn bitrate_cnf(&self, bitrate: CanBitrate) -> Option<[u8; 3]> {
+        let config = &self.config;
+        let timing = mcp2515_bit_timing(
+            config.crystal_hz,
+            bitrate.bps(),
+            config.sample_point_permille,
+        )?;
+
+        cnf_from_bit_timing(&timing, config.crystal_hz, config.clkout)
+    }
+}
+
+impl<SPI: SpiDevice> Can for Mcp2515<SPI> {
+    type Frame = <MCP2515<SPI> as Can>::Frame;
+    type Error = <MCP2515<SPI> as Can>::Error;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        self.mcp.transmit(frame)?;
+        self.tx_pending = Some(frame.clone());
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        self.mcp.receive()
+    }
+}
+
+impl<SPI: SpiDevice> CanDevice for Mcp2515<SPI> {
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        let Some(cnf) = self.bitrate_cnf(bitrate) else {
+            error!("Bitrate not supported with this MCP2515 crystal");
+            return Err(BitrateError::Unsupported);
+        };
+
+        info!("Setting bitrate to {} bps", bitrate.bps());
+        if !self.write_cnf(cnf) {
+            error!("Failed to set bitrate!!!");
+            return Err(BitrateError::NotApplied);
+        }
+
+        info!("Bitrate set!");
+        Ok(())
+    }
+
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        let Some(cnf) = cnf_from_bit_timing(&timing, self.config.crystal_hz, self.config.clkout)
+        else {
+            error!("Bit timing can't be represented on the MCP2515");
+            return Err(BitrateError::Unsupported);
+        };
+
+        info!("Setting bit timing to {} bps", timing.bitrate());
+        if !self.write_cnf(cnf) {
+            error!("Failed to set bit timing!!!");
+            return Err(BitrateError::NotApplied);
+        }
+
+        info!("Bit timing set!");
+        Ok(())
+    }
+
+    fn status(&mut self) -> CanStatus {
+        match read_status(&mut self.mcp) {
+            Some(status) => status,
+            None => {
+                error!("Failed to read the MCP2515 status");
+                CanStatus::default()
+            }
+        }
+    }
+
+    fn set_mode(&mut self, mode: CanMode) {
+        // Off the bus the frame in TXB0 is not waited for anymore
+        if mode == CanMode::Configuration {
+            self.tx_pending = None;
+        }
+
+        match self.mcp.set_mode(convert_mode(mode)) {
+            Ok(_) => info!("Operation mode changed"),
+            Err(_) => error!("Failed to change operation mode"),
+        }
+    }
+
+    fn set_filters(&mut self, filters: &[FilterEntry]) -> FilterFit {
+        // Filters can only be written in configuration mode
+        let mode = current_mode(&mut self.mcp);
+        match self.mcp.set_mode(OpMode::Configuration) {
             Ok(_) => info!("Switching to Configuration Mode"),
-            Err(_) => error!("Failed to switch to Configuration Mode")
+            Err(_) => error!("Failed to switch to Configuration Mode"),
+        }
+
+        let fit = match write_filters(&mut self.mcp, filters) {
+            Some(fit) => {
+                info!("Filters changed");
+                fit
+            }
+            None => {
+                // The filters are left half written, nothing can be told
+                error!("Failed to change filters");
+                FilterFit::Superset
+            }
+        };
+
+        match self.mcp.set_mode(mode) {
+            Ok(_) => info!("Restoring operation mode"),
+            Err(_) => error!("Failed to restore operation mode"),
+        }
+
+        fit
+    }
+
+    // TXREQ is cleared once the frame made it to the bus
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        self.tx_pending.as_ref()?;
+
+        let txb0ctrl: u8 = match self.mcp.read_register::<TXB0CTRL>() {
+            Ok(txb0ctrl) => txb0ctrl.into(),
+            Err(_) => {
+                error!("Failed to read the MCP2515 TX buffer");
+                return None;
+            }
+        };
+        if txb0ctrl & TXBCTRL_TXREQ != 0 {
+            return None;
+        }
+
+        self.tx_pending.take().map(|frame| (frame, Instant::now()))
+    }
+}
+
+// MCP2515 with the INT pin wired, so the core sleeps until the controller
+// has something for it
+pub struct Mcp2515Irq<SPI, INT> {
+    can: Mcp2515<SPI>,
+    int: INT,
+    // TX0IF seen by wait_for_event, TXB0 emptied since
+    tx_done: bool,
+}
+
+impl<SPI: SpiDevice, INT: Wait> Can for Mcp2515Irq<SPI, INT> {
+    type Frame = <Mcp2515<SPI> as Can>::Frame;
+    type Error = <Mcp2515<SPI> as Can>::Error;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        self.can.transmit(frame)
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        self.can.receive()
+    }
+}
+
+impl<SPI: SpiDevice, INT: Wait> CanDevice for Mcp2515Irq<SPI, INT> {
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        self.can.set_bitrate(bitrate)
+    }
+
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        self.can.set_bit_timing(timing)
+    }
+
+    fn status(&mut self) -> CanStatus {
+        self.can.status()
+    }
+
+    fn set_mode(&mut self, mode: CanMode) {
+        self.can.set_mode(mode)
+    }
+
+    fn set_filters(&mut self, filters: &[FilterEntry]) -> FilterFit {
+        self.can.set_filters(filters)
+    }
+
+    // Only asks the controller once TX0IF was raised, TXREQ tells if it was
+    // for the frame in TXB0 now
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        if !core::mem::take(&mut self.tx_done) {
+            return None;
         }
-        
-        match self.set_bitrate(convert_bitrate(bitrate), MCP_CLOCK, MCP_CLOCK_ENABLE) {
-            Ok(_) => info!("Bitrate set!"),
-            Err(_) => error!("Failed to set bitrate!!!")
+
+        self.can.transmitted()
+    }
+}
+
+impl<SPI: SpiDevice, INT: Wait> AsyncCanDevice for Mcp2515Irq<SPI, INT> {
+    async fn wait_for_event(&mut self) {
+        // INT is held low while any enabled flag is set, waiting for the
+        // level instead of the edge doesn't miss a flag raised before
+        if self.int.wait_for_low().await.is_err() {
+            error!("Failed to wait for the MCP2515 interrupt");
+            return;
+        }
+
+        let canintf: u8 = match self.can.mcp.read_register::<CANINTF>() {
+            Ok(canintf) => canintf.into(),
+            Err(_) => {
+                error!("Failed to read the MCP2515 interrupt flags");
+                return;
+            }
         };
-        match self.set_mode(OpMode::Normal) {
-            Ok(_) => info!("Switching to Normal Mode"),
-            Err(_) => error!("Failed to switch to Normal Mode")
+        if canintf & CANINTF_TX0IF != 0 {
+            self.tx_done = true;
+        }
+
+        // RX flags are cleared when the frame is read, the others once seen.
+        // Only the ones read, a flag raised meanwhile is kept for next time
+        let flags = canintf & (CANINTF_TX0IF | CANINTF_TX1IF | CANINTF_TX2IF | CANINTF_ERRIF);
+        if flags != 0
+            && self
+                .can
+                .mcp
+                .modify_register(CANINTF::from(0), flags)
+                .is_err()
+        {
+            error!("Failed to clear the MCP2515 interrupt flags");
         }
     }
 
-    fn set_filter(&mut self, id: Id) {
-        self.set_filter(RxFilter::F0, id).unwrap();
+    // TX0IF wakes it, so a sent frame is seen without polling
+    async fn receive_or_transmitted(
+        &mut self,
+    ) -> Option<Result<(Self::Frame, Instant), Self::Error>> {
+        loop {
+            if self.tx_done {
+                return None;
+            }
+
+            match self.receive() {
+                Ok(frame) => return Some(Ok((frame, Instant::now()))),
+                // Nothing received yet
+                Err(e) if e.kind() != ErrorKind::Overrun => self.wait_for_event().await,
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
+}
+
+fn init_mcp2515<SPI: SpiDevice, DELAY: DelayNs>(
+    spi: SPI,
+    mut delay: DELAY,
+    config: Mcp2515Config,
+) -> Mcp2515<SPI> {
+    let mut mcp = MCP2515::new(spi);
+
+    // The driver only knows the timings for some crystals, the CNF registers
+    // are written afterwards for the configured one
+    mcp.init(
+        &mut delay,
+        mcp2515::Settings {
+            mode: OpMode::Configuration,
+            can_speed: CanSpeed::Kbps250,
+            mcp_speed: McpSpeed::MHz8,
+            clkout_en: config.clkout,
+        },
+    )
+    .unwrap();
+
+    // Frames for RXB0 roll over to RXB1 when it is full
+    mcp.write_register(RXB0CTRL::from(RXB0CTRL_BUKT)).unwrap();
 
-    fn set_mask(&mut self, id: Id) {
-        self.set_mask(RxMask::Mask0, id).unwrap();
+    let mut can = Mcp2515 {
+        mcp,
+        config,
+        tx_pending: None,
+    };
+    match can.bitrate_cnf(config.bitrate) {
+        Some(cnf) => {
+            can.write_cnf(cnf);
+        }
+        None => error!("Initial bitrate not supported with this MCP2515 crystal"),
     }
+
+    can.mcp.set_mode(OpMode::Normal).unwrap();
+
+    can
 }
 
-impl<SPI, SERIAL> Bsp<MCP2515<SPI>, SERIAL>
+impl<SPI, INT, SERIAL> Bsp<Mcp2515Irq<SPI, INT>, SERIAL>
 where
     SPI: SpiDevice,
+    INT: Wait,
     SERIAL: Read + Write,
 {
-    pub fn new_with_mcp2515<DELAY: DelayNs>(spi: SPI, mut delay: DELAY, serial: SERIAL) -> Self {
-        let mut can = MCP2515::new(spi);
-
-        can.init(
-            &mut delay,
-            mcp2515::Settings {
-                mode: OpMode::Normal,
-                can_speed: MCP_INITIAL_BAUDRATE,
-                mcp_speed: MCP_CLOCK,
-                clkout_en: MCP_CLOCK_ENABLE,
+    pub fn new_with_mcp2515_irq<DELAY: DelayNs>(
+        spi: SPI,
+        delay: DELAY,
+        int: INT,
+        config: Mcp2515Config,
+        serial: SERIAL,
+    ) -> Self {
+        let mut can = init_mcp2515(spi, delay, config);
+
+        // Raise INT on RX, TX done and errors
+        can.mcp
+            .write_register(CANINTE::from(CANINTE_EVENTS))
+            .unwrap();
+
+        Bsp::new(
+            Mcp2515Irq {
+                can,
+                int,
+                tx_done: false,
             },
+            serial,
         )
-        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cnf(crystal_hz: u32, bitrate: u32, sample_point_permille: u16) -> Option<[u8; 3]> {
+        let timing = mcp2515_bit_timing(crystal_hz, bitrate, sample_point_permille)?;
+        cnf_from_bit_timing(&timing, crystal_hz, false)
+    }
+
+    #[test]
+    fn test_bit_timing_16mhz() {
+        let timing = mcp2515_bit_timing(16_000_000, 500_000, 875).unwrap();
+
+        assert_eq!(timing.prescaler, 1);
+        assert_eq!(timing.quanta(), 16);
+        assert_eq!(timing.tseg2, 2);
+        assert_eq!(timing.bitrate(), 500_000);
+        assert_eq!(cnf(16_000_000, 500_000, 875), Some([0x40, 0xAE, 0x81]));
+    }
+
+    #[test]
+    fn test_bit_timing_800k() {
+        // Only 5 quanta per bit with a 8 MHz crystal, the sample point ends up at 60%
+        let timing = mcp2515_bit_timing(8_000_000, 800_000, 875).unwrap();
+        assert_eq!((timing.quanta(), timing.tseg2), (5, 2));
+        assert_eq!(timing.bitrate(), 800_000);
+
+        let timing = mcp2515_bit_timing(16_000_000, 800_000, 875).unwrap();
+        assert_eq!((timing.quanta(), timing.tseg2), (10, 2));
+        assert_eq!(timing.bitrate(), 800_000);
+    }
+
+    #[test]
+    fn test_bit_timing_sample_point() {
+        // 250 kbit/s with 16 quanta, the sample point moves with PHSEG2
+        let timing = mcp2515_bit_timing(8_000_000, 250_000, 750).unwrap();
+        assert_eq!((timing.quanta(), timing.tseg2), (16, 4));
+
+        let timing = mcp2515_bit_timing(8_000_000, 250_000, 875).unwrap();
+        assert_eq!((timing.quanta(), timing.tseg2), (16, 2));
+    }
+
+    #[test]
+    fn test_bit_timing_every_crystal() {
+        for crystal_hz in [8_000_000, 16_000_000, 20_000_000] {
+            for bitrate in [
+                10_000, 20_000, 31_250, 33_333, 40_000, 50_000, 80_000, 100_000, 125_000, 200_000,
+                250_000, 500_000,
+            ] {
+                assert!(cnf(crystal_hz, bitrate, 875).is_some());
+            }
+        }
 
-        Bsp::new(can, serial)
+        // Out of the prescaler and quanta ranges
+        assert_eq!(cnf(8_000_000, 1_000_000, 875), None);
+        assert_eq!(cnf(20_000_000, 5_000, 875), None);
+        assert!(cnf(16_000_000, 1_000_000, 875).is_some());
+        assert!(cnf(16_000_000, 5_000, 875).is_some());
     }
 }
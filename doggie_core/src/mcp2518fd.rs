@@ -0,0 +1,1378 @@
+use crate::can::{
+    AsyncCanDevice, BitTiming, BitrateError, CanBitrate, CanDevice, CanMode, CanStatus,
+};
+use crate::filter::{FilterEntry, FilterFit};
+use defmt::{error, info};
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Instant};
+use embedded_can::{blocking::Can, ErrorKind, ExtendedId, Frame, Id, StandardId};
+use embedded_hal::{
+    delay::DelayNs,
+    spi::{Operation, SpiDevice},
+};
+use embedded_io_async::{Read, Write};
+use heapless::Deque;
+use slcan::{canfd_dlc_to_len, canfd_len_to_dlc, CanFrame, CANFD_MAX_DLEN, CAN_MAX_DLEN};
+
+use crate::bsp::Bsp;
+
+// Most MCP2518FD modules come with a 40 MHz crystal, used without the PLL
+const MCP2518FD_CLOCK_HZ: u32 = 40_000_000;
+const MCP2518FD_INITIAL_BITRATE: CanBitrate = CanBitrate::from_kbps(250);
+const MCP2518FD_INITIAL_DATA_BITRATE: u32 = 2_000_000;
+
+// SPI instructions, followed by a 12 bit address
+const INSTRUCTION_RESET: u8 = 0x0;
+const INSTRUCTION_WRITE: u8 = 0x2;
+const INSTRUCTION_READ: u8 = 0x3;
+
+// SFR addresses
+const C1CON: u16 = 0x000;
+const C1NBTCFG: u16 = 0x004;
+const C1DBTCFG: u16 = 0x008;
+const C1TDC: u16 = 0x00C;
+const C1TBC: u16 = 0x010;
+const C1TSCON: u16 = 0x014;
+const C1INT: u16 = 0x01C;
+const C1TREC: u16 = 0x034;
+const C1TEFCON: u16 = 0x040;
+const C1TEFSTA: u16 = 0x044;
+const C1TEFUA: u16 = 0x048;
+const C1TXQCON: u16 = 0x050;
+const C1TXQSTA: u16 = 0x054;
+const C1TXQUA: u16 = 0x058;
+// FIFO 1, the one receiving
+const C1FIFOCON1: u16 = 0x05C;
+const C1FIFOSTA1: u16 = 0x060;
+const C1FIFOUA1: u16 = 0x064;
+const C1FLTCON0: u16 = 0x1D0;
+const C1FLTOBJ0: u16 = 0x1F0;
+const C1MASK0: u16 = 0x1F4;
+const RAM_START: u16 = 0x400;
+const OSC: u16 = 0xE00;
+
+// OSC bits
+const OSC_OSCRDY: u32 = 1 << 10;
+
+// C1CON fields, REQOP is written alone through the top byte
+const CON_OPMOD_SHIFT: u32 = 21;
+const CON_REQOP_BYTE: u16 = C1CON + 3;
+
+// Operation modes
+const OPMOD_NORMAL_FD: u8 = 0;
+const OPMOD_INTERNAL_LOOPBACK: u8 = 2;
+const OPMOD_LISTEN_ONLY: u8 = 3;
+const OPMOD_CONFIGURATION: u8 = 4;
+
+// C1TSCON, the time base counts microseconds
+const TSCON_TBCEN: u32 = 1 << 16;
+
+// C1TDC
+const TDC_TDCMOD_AUTO: u32 = 0b10 << 16;
+const TDC_TDCO_MAX: u32 = 63;
+
+// C1INT bits, flags are cleared by writing them as zero
+const INT_SERRIF: u32 = 1 << 12;
+const INT_CERRIF: u32 = 1 << 13;
+
+// C1TREC bits
+const TREC_EWARN: u32 = 1 << 16;
+const TREC_RXBP: u32 = 1 << 19;
+const TREC_TXBP: u32 = 1 << 20;
+const TREC_TXBO: u32 = 1 << 21;
+
+// FIFOCON, TXQCON and TEFCON bits. UINC and TXREQ are set through the
+// second byte
+const FIFOCON_TSEN: u32 = 1 << 5;
+const FIFOCON_UINC: u8 = 1 << 0;
+const FIFOCON_TXREQ: u8 = 1 << 1;
+const FIFOCON_FSIZE_SHIFT: u32 = 24;
+const FIFOCON_PLSIZE_SHIFT: u32 = 29;
+const PLSIZE_64: u32 = 7;
+
+// FIFOSTA, TXQSTA and TEFSTA bits
+const FIFOSTA_NOT_EMPTY_OR_FULL: u32 = 1 << 0;
+const FIFOSTA_RX_FULL: u32 = 1 << 2;
+const FIFOSTA_RXOVIF: u32 = 1 << 3;
+const FIFOSTA_TXLARB: u32 = 1 << 6;
+
+// FLTCON, one byte per filter
+const FLTCON_FLTEN: u8 = 1 << 7;
+const FLTCON_FIFO1: u8 = 1;
+const FILTERS: usize = 32;
+
+// FLTOBJ and MASK bits, MIDE makes the filter match the IDE bit
+const FLTOBJ_EXIDE: u32 = 1 << 30;
+const MASK_MIDE: u32 = 1 << 30;
+
+// Message object flags
+const OBJ_IDE: u32 = 1 << 4;
+const OBJ_RTR: u32 = 1 << 5;
+const OBJ_BRS: u32 = 1 << 6;
+const OBJ_FDF: u32 = 1 << 7;
+const OBJ_ESI: u32 = 1 << 8;
+const OBJ_SEQ_SHIFT: u32 = 9;
+// The MCP2517FD only has 7 sequence bits
+const OBJ_SEQ_MASK: u8 = 0x7F;
+
+// RAM layout: TEF, TXQ and the RX FIFO, 1888 of the 2048 bytes
+const TEF_DEPTH: usize = 8;
+const TXQ_DEPTH: usize = 8;
+const RX_FIFO_DEPTH: usize = 16;
+
+// Id and flags, the RX objects and TEF entries add a timestamp
+const TX_HEADER_SIZE: usize = 8;
+const RX_HEADER_SIZE: usize = 12;
+const TEF_OBJECT_SIZE: usize = 12;
+pub const MCP2518FD_TX_OBJECT_SIZE: usize = TX_HEADER_SIZE + CANFD_MAX_DLEN;
+pub const MCP2518FD_RX_OBJECT_SIZE: usize = RX_HEADER_SIZE + CANFD_MAX_DLEN;
+
+// Tries waiting for the controller to change its operation mode, it only
+// does so once the bus is idle
+const MODE_POLLS: usize = 1000;
+
+#[derive(Debug)]
+pub struct Mcp2518fdError(ErrorKind);
+
+impl embedded_can::Error for Mcp2518fdError {
+    fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
+const SPI_ERROR: Mcp2518fdError = Mcp2518fdError(ErrorKind::Other);
+
+// Classic or CAN FD frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mcp2518fdFrame {
+    id: Id,
+    data: [u8; CANFD_MAX_DLEN],
+    len: usize,
+    remote: bool,
+    fd: bool,
+    brs: bool,
+    esi: bool,
+}
+
+impl Mcp2518fdFrame {
+    pub fn new_fd(id: impl Into<Id>, brs: bool, esi: bool, data: &[u8]) -> Option<Self> {
+        canfd_len_to_dlc(data.len())?;
+
+        let mut frame = Mcp2518fdFrame {
+            id: id.into(),
+            data: [0; CANFD_MAX_DLEN],
+            len: data.len(),
+            remote: false,
+            fd: true,
+            brs,
+            esi,
+        };
+        frame.data[0..data.len()].copy_from_slice(data);
+
+        Some(frame)
+    }
+
+    pub fn is_fd(&self) -> bool {
+        self.fd
+    }
+
+    pub fn brs(&self) -> bool {
+        self.brs
+    }
+
+    pub fn esi(&self) -> bool {
+        self.esi
+    }
+}
+
+impl Frame for Mcp2518fdFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > CAN_MAX_DLEN {
+            return None;
+        }
+
+        let mut frame = Mcp2518fdFrame {
+            id: id.into(),
+            data: [0; CANFD_MAX_DLEN],
+            len: data.len(),
+            remote: false,
+            fd: false,
+            brs: false,
+            esi: false,
+        };
+        frame.data[0..data.len()].copy_from_slice(data);
+
+        Some(frame)
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        let mut frame = Self::new(id, &[])?;
+        if dlc > CAN_MAX_DLEN {
+            return None;
+        }
+
+        frame.len = dlc;
+        frame.remote = true;
+
+        Some(frame)
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.remote
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    // Payload length, up to 64 on FD frames
+    fn dlc(&self) -> usize {
+        self.len
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data[0..self.len]
+    }
+}
+
+// Search the smallest prescaler, so the most time quanta per bit, with a
+// sample point at 80%. The data phase segments are shorter
+pub fn mcp2518fd_bit_timing(clock_hz: u32, bitrate: u32, data_phase: bool) -> Option<BitTiming> {
+    let (max_tseg1, max_tseg2) = if data_phase { (32, 16) } else { (256, 128) };
+
+    (1..=256u32).find_map(|prescaler| {
+        let tq_hz = clock_hz / prescaler;
+        let quanta = (tq_hz + bitrate / 2) / bitrate;
+
+        if !(8..=1 + max_tseg1 + max_tseg2).contains(&quanta) {
+            return None;
+        }
+
+        // Within 0.5% of the requested bitrate
+        let actual = clock_hz / (prescaler * quanta);
+        if actual.abs_diff(bitrate) * 200 > bitrate {
+            return None;
+        }
+
+        let tseg2 = (quanta / 5).clamp(1, max_tseg2);
+        let tseg1 = quanta - 1 - tseg2;
+        if tseg1 > max_tseg1 {
+            return None;
+        }
+
+        Some(BitTiming {
+            clock_hz,
+            prescaler: prescaler as u16,
+            sjw: tseg2 as u8,
+            tseg1: u8::try_from(tseg1).ok()?,
+            tseg2: tseg2 as u8,
+            triple_sample: false,
+        })
+    })
+}
+
+// Encode the nominal bit timing into C1NBTCFG, all fields are value - 1
+pub fn mcp2518fd_nbtcfg(timing: &BitTiming, clock_hz: u32) -> Option<u32> {
+    let timing = timing.with_clock(clock_hz)?;
+
+    if !(1..=256).contains(&timing.prescaler)
+        || !(1..=128).contains(&timing.sjw)
+        || timing.tseg1 == 0
+        || !(1..=128).contains(&timing.tseg2)
+        || timing.sjw > timing.tseg2
+    {
+        return None;
+    }
+
+    Some(
+        ((timing.prescaler as u32 - 1) << 24)
+            | ((timing.tseg1 as u32 - 1) << 16)
+            | ((timing.tseg2 as u32 - 1) << 8)
+            | (timing.sjw as u32 - 1),
+    )
+}
+
+// Encode the data phase bit timing into C1DBTCFG
+pub fn mcp2518fd_dbtcfg(timing: &BitTiming, clock_hz: u32) -> Option<u32> {
+    let timing = timing.with_clock(clock_hz)?;
+
+    if !(1..=256).contains(&timing.prescaler)
+        || !(1..=16).contains(&timing.sjw)
+        || !(1..=32).contains(&timing.tseg1)
+        || !(1..=16).contains(&timing.tseg2)
+        || timing.sjw > timing.tseg2
+    {
+        return None;
+    }
+
+    Some(
+        ((timing.prescaler as u32 - 1) << 24)
+            | ((timing.tseg1 as u32 - 1) << 16)
+            | ((timing.tseg2 as u32 - 1) << 8)
+            | (timing.sjw as u32 - 1),
+    )
+}
+
+// Transmitter delay compensation, needed at fast data bitrates. The offset
+// is the sample point in clock cycles, TDC is only usable with prescalers of
+// 1 or 2
+pub fn mcp2518fd_tdc(timing: &BitTiming) -> u32 {
+    if timing.prescaler > 2 {
+        return 0;
+    }
+
+    let tdco = (timing.prescaler as u32 * (1 + timing.tseg1 as u32)).min(TDC_TDCO_MAX);
+
+    TDC_TDCMOD_AUTO | (tdco << 8)
+}
+
+pub fn mcp2518fd_status_from_registers(
+    trec: u32,
+    int: u32,
+    rx_fifo_sta: u32,
+    txq_sta: u32,
+) -> CanStatus {
+    CanStatus {
+        rx_fifo_full: rx_fifo_sta & FIFOSTA_RX_FULL != 0,
+        tx_fifo_full: txq_sta & FIFOSTA_NOT_EMPTY_OR_FULL == 0,
+        error_warning: trec & TREC_EWARN != 0,
+        data_overrun: rx_fifo_sta & FIFOSTA_RXOVIF != 0,
+        error_passive: trec & (TREC_RXBP | TREC_TXBP) != 0,
+        bus_off: trec & TREC_TXBO != 0,
+        arbitration_lost: txq_sta & FIFOSTA_TXLARB != 0,
+        bus_error: int & (INT_SERRIF | INT_CERRIF) != 0,
+        tx_error_count: (trec >> 8) as u8,
+        rx_error_count: trec as u8,
+    }
+}
+
+// Extended ids keep the 11 base bits in SID and the other 18 in EID
+fn encode_extended(raw: u32) -> u32 {
+    ((raw >> 18) & 0x7FF) | ((raw & 0x3FFFF) << 11)
+}
+
+fn encode_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => encode_extended(id.as_raw()),
+    }
+}
+
+fn decode_id(word: u32, extended: bool) -> Option<Id> {
+    if extended {
+        let raw = ((word & 0x7FF) << 18) | ((word >> 11) & 0x3FFFF);
+        ExtendedId::new(raw).map(Id::Extended)
+    } else {
+        StandardId::new((word & 0x7FF) as u16).map(Id::Standard)
+    }
+}
+
+fn frame_flags(frame: &Mcp2518fdFrame) -> u32 {
+    let mut flags = canfd_len_to_dlc(frame.len).unwrap_or(0) as u32;
+
+    for (set, flag) in [
+        (frame.is_extended(), OBJ_IDE),
+        (frame.remote, OBJ_RTR),
+        (frame.brs, OBJ_BRS),
+        (frame.fd, OBJ_FDF),
+        (frame.esi, OBJ_ESI),
+    ] {
+        if set {
+            flags |= flag;
+        }
+    }
+
+    flags
+}
+
+// Write the TX message object for `frame` into `buffer`. Returns its size,
+// RAM is written in whole words
+pub fn mcp2518fd_tx_object(
+    frame: &Mcp2518fdFrame,
+    seq: u8,
+    buffer: &mut [u8; MCP2518FD_TX_OBJECT_SIZE],
+) -> usize {
+    let flags = frame_flags(frame) | ((seq & OBJ_SEQ_MASK) as u32) << OBJ_SEQ_SHIFT;
+    let len = if frame.remote { 0 } else { frame.len };
+
+    buffer[0..4].copy_from_slice(&encode_id(frame.id).to_le_bytes());
+    buffer[4..8].copy_from_slice(&flags.to_le_bytes());
+    buffer[TX_HEADER_SIZE..TX_HEADER_SIZE + len].copy_from_slice(&frame.data[0..len]);
+
+    TX_HEADER_SIZE + len.div_ceil(4) * 4
+}
+
+fn word(buffer: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes([
+        buffer[4 * index],
+        buffer[4 * index + 1],
+        buffer[4 * index + 2],
+        buffer[4 * index + 3],
+    ])
+}
+
+// Payload length of a received object, without reading its data
+fn rx_object_len(flags: u32) -> usize {
+    let dlc = (flags & 0xF) as u8;
+
+    if flags & OBJ_FDF != 0 {
+        canfd_dlc_to_len(dlc).unwrap_or(0)
+    } else {
+        (dlc as usize).min(CAN_MAX_DLEN)
+    }
+}
+
+// Decode a RX message object with its timestamp
+pub fn mcp2518fd_rx_object(buffer: &[u8]) -> Option<(Mcp2518fdFrame, u32)> {
+    let flags = word(buffer, 1);
+    let timestamp = word(buffer, 2);
+    let id = decode_id(word(buffer, 0), flags & OBJ_IDE != 0)?;
+    let len = rx_object_len(flags);
+
+    let frame = if flags & OBJ_FDF != 0 {
+        Mcp2518fdFrame::new_fd(
+            id,
+            flags & OBJ_BRS != 0,
+            flags & OBJ_ESI != 0,
+            buffer.get(RX_HEADER_SIZE..RX_HEADER_SIZE + len)?,
+        )
+    } else if flags & OBJ_RTR != 0 {
+        Mcp2518fdFrame::new_remote(id, len)
+    } else {
+        Mcp2518fdFrame::new(id, buffer.get(RX_HEADER_SIZE..RX_HEADER_SIZE + len)?)
+    }?;
+
+    Some((frame, timestamp))
+}
+
+// Decode a TEF entry into the sequence number and the timestamp
+pub fn mcp2518fd_tef_object(buffer: &[u8]) -> (u8, u32) {
+    let seq = (word(buffer, 1) >> OBJ_SEQ_SHIFT) as u8 & OBJ_SEQ_MASK;
+
+    (seq, word(buffer, 2))
+}
+
+// FLTOBJ and MASK values for a filter entry, matching only its id format
+fn filter_registers(entry: &FilterEntry) -> (u32, u32) {
+    match entry.id {
+        Id::Standard(_) => (encode_id(entry.id), (entry.mask & 0x7FF) | MASK_MIDE),
+        Id::Extended(_) => (
+            encode_id(entry.id) | FLTOBJ_EXIDE,
+            encode_extended(entry.mask) | MASK_MIDE,
+        ),
+    }
+}
+
+// MCP2518FD or MCP2517FD on SPI. Frames go out through the TXQ and come in
+// through FIFO 1, the TEF tells when each one made it to the bus
+pub struct Mcp2518fd<SPI> {
+    spi: SPI,
+    clock_hz: u32,
+    seq: u8,
+    // Frames in the TXQ, with their sequence number
+    tx_pending: Deque<(u8, Mcp2518fdFrame), TXQ_DEPTH>,
+    // Frames sent, with the timestamp they were sent at
+    tx_echo: Deque<(Mcp2518fdFrame, u32), TXQ_DEPTH>,
+    // RX FIFO overflow not yet reported by receive and by the status
+    rx_overrun: bool,
+    status_overrun: bool,
+}
+
+impl<SPI: SpiDevice> Mcp2518fd<SPI> {
+    pub fn new(spi: SPI, clock_hz: u32) -> Self {
+        Mcp2518fd {
+            spi,
+            clock_hz,
+            seq: 0,
+            tx_pending: Deque::new(),
+            tx_echo: Deque::new(),
+            rx_overrun: false,
+            status_overrun: false,
+        }
+    }
+
+    fn header(instruction: u8, address: u16) -> [u8; 2] {
+        [
+            (instruction << 4) | ((address >> 8) as u8 & 0xF),
+            address as u8,
+        ]
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), Mcp2518fdError> {
+        let header = Self::header(INSTRUCTION_READ, address);
+
+        self.spi
+            .transaction(&mut [Operation::Write(&header), Operation::Read(buffer)])
+            .map_err(|_| SPI_ERROR)
+    }
+
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), Mcp2518fdError> {
+        let header = Self::header(INSTRUCTION_WRITE, address);
+
+        self.spi
+            .transaction(&mut [Operation::Write(&header), Operation::Write(data)])
+            .map_err(|_| SPI_ERROR)
+    }
+
+    fn read_register(&mut self, address: u16) -> Result<u32, Mcp2518fdError> {
+        let mut buffer = [0; 4];
+        self.read(address, &mut buffer)?;
+
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    fn write_register(&mut self, address: u16, value: u32) -> Result<(), Mcp2518fdError> {
+        self.write(address, &value.to_le_bytes())
+    }
+
+    fn reset(&mut self) -> Result<(), Mcp2518fdError> {
+        self.spi
+            .write(&Self::header(INSTRUCTION_RESET, 0))
+            .map_err(|_| SPI_ERROR)
+    }
+
+    fn operation_mode(&mut self) -> Result<u8, Mcp2518fdError> {
+        Ok((self.read_register(C1CON)? >> CON_OPMOD_SHIFT) as u8 & 0x7)
+    }
+
+    fn request_mode(&mut self, mode: u8) -> Result<(), Mcp2518fdError> {
+        self.write(CON_REQOP_BYTE, &[mode])?;
+
+        // Configuration mode resets the TXQ and the TEF
+        if mode == OPMOD_CONFIGURATION {
+            self.tx_pending.clear();
+        }
+
+        for _ in 0..MODE_POLLS {
+            if self.operation_mode()? == mode {
+                return Ok(());
+            }
+        }
+
+        Err(Mcp2518fdError(ErrorKind::Other))
+    }
+
+    // Bit timing can only be written in configuration mode, the previous
+    // mode is restored afterwards
+    fn configure(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<(), Mcp2518fdError>,
+    ) -> Result<(), Mcp2518fdError> {
+        let mode = self.operation_mode()?;
+
+        self.request_mode(OPMOD_CONFIGURATION)?;
+        f(self)?;
+        self.request_mode(mode)
+    }
+
+    fn write_bit_timing(&mut self, nbtcfg: u32) -> Result<(), Mcp2518fdError> {
+        self.configure(|mcp| mcp.write_register(C1NBTCFG, nbtcfg))
+    }
+
+    pub fn set_data_bitrate(&mut self, bitrate: u32) -> bool {
+        match mcp2518fd_bit_timing(self.clock_hz, bitrate, true) {
+            Some(timing) => self.set_data_bit_timing(timing),
+            None => {
+                error!("Data bitrate not supported by the MCP2518FD");
+                false
+            }
+        }
+    }
+
+    pub fn set_data_bit_timing(&mut self, timing: BitTiming) -> bool {
+        let Some((timing, dbtcfg)) = timing
+            .with_clock(self.clock_hz)
+            .and_then(|timing| Some((timing, mcp2518fd_dbtcfg(&timing, self.clock_hz)?)))
+        else {
+            error!("Data bit timing can't be represented on the MCP2518FD");
+            return false;
+        };
+
+        let res = self.configure(|mcp| {
+            mcp.write_register(C1DBTCFG, dbtcfg)?;
+            mcp.write_register(C1TDC, mcp2518fd_tdc(&timing))
+        });
+
+        match res {
+            Ok(_) => info!("Data bit timing set to {} bps", timing.bitrate()),
+            Err(_) => error!("Failed to set the data bit timing"),
+        }
+
+        res.is_ok()
+    }
+
+    // Instant a time base counter value was taken at, the counter runs in
+    // microseconds and wraps every 71 minutes
+    fn timestamp_instant(&mut self, timestamp: u32) -> Instant {
+        let now = Instant::now();
+        let Ok(counter) = self.read_register(C1TBC) else {
+            return now;
+        };
+
+        let age = Duration::from_micros(counter.wrapping_sub(timestamp) as u64);
+        now.checked_sub(age).unwrap_or(now)
+    }
+
+    // Move the TEF entries to the echo queue. Entries lost to an overflow
+    // drop their frames from the pending queue
+    fn service_tef(&mut self) -> Result<(), Mcp2518fdError> {
+        while self.read_register(C1TEFSTA)? & FIFOSTA_NOT_EMPTY_OR_FULL != 0 {
+            let address = RAM_START + self.read_register(C1TEFUA)? as u16;
+            let mut buffer = [0; TEF_OBJECT_SIZE];
+            self.read(address, &mut buffer)?;
+            self.write(C1TEFCON + 1, &[FIFOCON_UINC])?;
+
+            let (seq, timestamp) = mcp2518fd_tef_object(&buffer);
+
+            while let Some((pending_seq, frame)) = self.tx_pending.pop_front() {
+                if pending_seq != seq {
+                    continue;
+                }
+
+                if self.tx_echo.is_full() {
+                    self.tx_echo.pop_front();
+                }
+                let _ = self.tx_echo.push_back((frame, timestamp));
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // RX FIFO status. The overflow flag is only cleared here, and latched
+    // until both receive and the status have reported it
+    fn rx_fifo_status(&mut self) -> Result<u32, Mcp2518fdError> {
+        let status = self.read_register(C1FIFOSTA1)?;
+        if status & FIFOSTA_RXOVIF != 0 {
+            // The other flags are read only
+            self.write(C1FIFOSTA1, &[0])?;
+            self.rx_overrun = true;
+            self.status_overrun = true;
+        }
+
+        Ok(status)
+    }
+
+    // Oldest received frame, with its timestamp
+    fn receive_frame(&mut self) -> Result<(Mcp2518fdFrame, u32), Mcp2518fdError> {
+        self.service_tef()?;
+
+        let status = self.rx_fifo_status()?;
+        if status & FIFOSTA_NOT_EMPTY_OR_FULL == 0 {
+            if core::mem::take(&mut self.rx_overrun) {
+                return Err(Mcp2518fdError(ErrorKind::Overrun));
+            }
+
+            // Nothing received
+            return Err(Mcp2518fdError(ErrorKind::Other));
+        }
+
+        let address = RAM_START + self.read_register(C1FIFOUA1)? as u16;
+
+        // Header first, then only the words holding data
+        let mut buffer = [0; MCP2518FD_RX_OBJECT_SIZE];
+        self.read(address, &mut buffer[0..RX_HEADER_SIZE])?;
+        let len = rx_object_len(word(&buffer, 1)).div_ceil(4) * 4;
+        if len > 0 {
+            self.read(
+                address + RX_HEADER_SIZE as u16,
+                &mut buffer[RX_HEADER_SIZE..RX_HEADER_SIZE + len],
+            )?;
+        }
+        self.write(C1FIFOCON1 + 1, &[FIFOCON_UINC])?;
+
+        let Some((frame, timestamp)) = mcp2518fd_rx_object(&buffer) else {
+            error!("Invalid MCP2518FD RX object");
+            return Err(Mcp2518fdError(ErrorKind::Other));
+        };
+
+        Ok((frame, timestamp))
+    }
+
+    fn transmit_frame(&mut self, frame: &Mcp2518fdFrame) -> Result<(), Mcp2518fdError> {
+        self.service_tef()?;
+
+        // The TXQ is reset while not transmitting
+        match self.operation_mode()? {
+            OPMOD_LISTEN_ONLY | OPMOD_CONFIGURATION => {
+                return Err(Mcp2518fdError(ErrorKind::Other))
+            }
+            _ => {}
+        }
+
+        if self.read_register(C1TXQSTA)? & FIFOSTA_NOT_EMPTY_OR_FULL == 0
+            || self.tx_pending.is_full()
+        {
+            return Err(Mcp2518fdError(ErrorKind::Overrun));
+        }
+
+        let address = RAM_START + self.read_register(C1TXQUA)? as u16;
+        let mut buffer = [0; MCP2518FD_TX_OBJECT_SIZE];
+        let size = mcp2518fd_tx_object(frame, self.seq, &mut buffer);
+
+        self.write(address, &buffer[0..size])?;
+        self.write(C1TXQCON + 1, &[FIFOCON_UINC | FIFOCON_TXREQ])?;
+
+        let _ = self.tx_pending.push_back((self.seq, *frame));
+        self.seq = self.seq.wrapping_add(1) & OBJ_SEQ_MASK;
+
+        Ok(())
+    }
+
+    fn read_status(&mut self) -> Result<CanStatus, Mcp2518fdError> {
+        let trec = self.read_register(C1TREC)?;
+        let int = self.read_register(C1INT)?;
+        let mut rx_fifo_sta = self.rx_fifo_status()? & !FIFOSTA_RXOVIF;
+        if core::mem::take(&mut self.status_overrun) {
+            rx_fifo_sta |= FIFOSTA_RXOVIF;
+        }
+        let txq_sta = self.read_register(C1TXQSTA)?;
+
+        // Bus error flags are latched, clear them once read
+        self.write(C1INT + 1, &[!((INT_SERRIF | INT_CERRIF) >> 8) as u8])?;
+
+        Ok(mcp2518fd_status_from_registers(
+            trec,
+            int,
+            rx_fifo_sta,
+            txq_sta,
+        ))
+    }
+
+    // Every entry gets its own filter and mask, sending to FIFO 1. Filters
+    // must be disabled while written
+    fn write_filters(&mut self, filters: &[FilterEntry]) -> Result<FilterFit, Mcp2518fdError> {
+        let mut fltcon = [0; FILTERS];
+        self.write(C1FLTCON0, &fltcon)?;
+
+        // With no entries, or more than the filters, a zero mask without MIDE
+        // lets everything through
+        let fit = if filters.is_empty() || filters.len() > FILTERS {
+            self.write_register(C1FLTOBJ0, 0)?;
+            self.write_register(C1MASK0, 0)?;
+            fltcon[0] = FLTCON_FLTEN | FLTCON_FIFO1;
+
+            match filters.len() {
+                0 => FilterFit::Exact,
+                _ => FilterFit::Superset,
+            }
+        } else {
+            for (index, entry) in filters.iter().enumerate() {
+                let (obj, mask) = filter_registers(entry);
+
+                let offset = 8 * index as u16;
+                self.write_register(C1FLTOBJ0 + offset, obj)?;
+                self.write_register(C1MASK0 + offset, mask)?;
+                fltcon[index] = FLTCON_FLTEN | FLTCON_FIFO1;
+            }
+
+            FilterFit::Exact
+        };
+
+        self.write(C1FLTCON0, &fltcon)?;
+
+        Ok(fit)
+    }
+
+    pub fn init<DELAY: DelayNs>(&mut self, delay: &mut DELAY) -> Result<(), Mcp2518fdError> {
+        self.reset()?;
+
+        // The controller is in configuration mode once the oscillator runs
+        let mut ready = false;
+        for _ in 0..10 {
+            delay.delay_ms(1);
+            if self.read_register(OSC)? & OSC_OSCRDY != 0 {
+                ready = true;
+                break;
+            }
+        }
+        if !ready || self.operation_mode()? != OPMOD_CONFIGURATION {
+            error!("MCP2518FD not ready after reset");
+            return Err(Mcp2518fdError(ErrorKind::Other));
+        }
+
+        // Timestamps in microseconds
+        let prescaler = self.clock_hz / 1_000_000 - 1;
+        self.write_register(C1TSCON, TSCON_TBCEN | prescaler)?;
+
+        // TEF and TXQ are enabled by C1CON after reset
+        self.write_register(
+            C1TEFCON,
+            ((TEF_DEPTH as u32 - 1) << FIFOCON_FSIZE_SHIFT) | FIFOCON_TSEN,
+        )?;
+        self.write_register(
+            C1TXQCON,
+            (PLSIZE_64 << FIFOCON_PLSIZE_SHIFT) | ((TXQ_DEPTH as u32 - 1) << FIFOCON_FSIZE_SHIFT),
+        )?;
+        self.write_register(
+            C1FIFOCON1,
+            (PLSIZE_64 << FIFOCON_PLSIZE_SHIFT)
+                | ((RX_FIFO_DEPTH as u32 - 1) << FIFOCON_FSIZE_SHIFT)
+                | FIFOCON_TSEN,
+        )?;
+
+        let nominal = mcp2518fd_bit_timing(self.clock_hz, MCP2518FD_INITIAL_BITRATE.bps(), false)
+            .and_then(|timing| mcp2518fd_nbtcfg(&timing, self.clock_hz));
+        let data = mcp2518fd_bit_timing(self.clock_hz, MCP2518FD_INITIAL_DATA_BITRATE, true)
+            .and_then(|timing| Some((timing, mcp2518fd_dbtcfg(&timing, self.clock_hz)?)));
+        let (Some(nbtcfg), Some((data, dbtcfg))) = (nominal, data) else {
+            error!("Initial bitrates not supported by the MCP2518FD");
+            return Err(Mcp2518fdError(ErrorKind::Other));
+        };
+
+        self.write_register(C1NBTCFG, nbtcfg)?;
+        self.write_register(C1DBTCFG, dbtcfg)?;
+        self.write_register(C1TDC, mcp2518fd_tdc(&data))?;
+
+        self.write_filters(&[])?;
+
+        self.request_mode(OPMOD_NORMAL_FD)
+    }
+}
+
+impl<SPI: SpiDevice> Can for Mcp2518fd<SPI> {
+    type Frame = Mcp2518fdFrame;
+    type Error = Mcp2518fdError;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        self.transmit_frame(frame)
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        self.receive_frame().map(|(frame, _)| frame)
+    }
+}
+
+impl<SPI: SpiDevice> CanDevice for Mcp2518fd<SPI> {
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        let Some(nbtcfg) = mcp2518fd_bit_timing(self.clock_hz, bitrate.bps(), false)
+            .and_then(|timing| mcp2518fd_nbtcfg(&timing, self.clock_hz))
+        else {
+            error!("Bitrate not supported by the MCP2518FD");
+            return Err(BitrateError::Unsupported);
+        };
+
+        info!("Setting bitrate to {} bps", bitrate.bps());
+        match self.write_bit_timing(nbtcfg) {
+            Ok(_) => {
+                info!("Bitrate set!");
+                Ok(())
+            }
+            Err(_) => {
+                error!("Failed to set bitrate!!!");
+                Err(BitrateError::NotApplied)
+            }
+        }
+    }
+
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        let Some(nbtcfg) = mcp2518fd_nbtcfg(&timing, self.clock_hz) else {
+            error!("Bit timing can't be represented on the MCP2518FD");
+            return Err(BitrateError::Unsupported);
+        };
+
+        info!("Setting bit timing to {} bps", timing.bitrate());
+        match self.write_bit_timing(nbtcfg) {
+            Ok(_) => {
+                info!("Bit timing set!");
+                Ok(())
+            }
+            Err(_) => {
+                error!("Failed to set bit timing!!!");
+                Err(BitrateError::NotApplied)
+            }
+        }
+    }
+
+    fn status(&mut self) -> CanStatus {
+        match self.read_status() {
+            Ok(status) => status,
+            Err(_) => {
+                error!("Failed to read the MCP2518FD status");
+                CanStatus::default()
+            }
+        }
+    }
+
+    fn set_mode(&mut self, mode: CanMode) {
+        let mode = match mode {
+            // Classic frames are still sent and received in FD mode
+            CanMode::Normal => OPMOD_NORMAL_FD,
+            CanMode::ListenOnly => OPMOD_LISTEN_ONLY,
+            CanMode::Loopback => OPMOD_INTERNAL_LOOPBACK,
+            CanMode::Configuration => OPMOD_CONFIGURATION,
+        };
+
+        // Modes can't be switched between without going through configuration
+        let res = self
+            .request_mode(OPMOD_CONFIGURATION)
+            .and_then(|_| self.request_mode(mode));
+
+        match res {
+            Ok(_) => info!("Operation mode changed"),
+            Err(_) => error!("Failed to change operation mode"),
+        }
+    }
+
+    fn set_filters(&mut self, filters: &[FilterEntry]) -> FilterFit {
+        match self.write_filters(filters) {
+            Ok(fit) => {
+                info!("Filters changed");
+                fit
+            }
+            Err(_) => {
+                error!("Failed to change filters");
+                FilterFit::Superset
+            }
+        }
+    }
+
+    // Confirmed by the TEF, with the time the frame was sent at
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        if self.service_tef().is_err() {
+            error!("Failed to read the MCP2518FD TEF");
+        }
+
+        let (frame, timestamp) = self.tx_echo.pop_front()?;
+
+        Some((frame, self.timestamp_instant(timestamp)))
+    }
+
+    fn frame_from_slcan(frame: &CanFrame) -> Option<Self::Frame> {
+        if frame.is_fd() {
+            Mcp2518fdFrame::new_fd(frame.id, frame.brs(), frame.esi(), frame.data())
+        } else if frame.is_remote() {
+            Mcp2518fdFrame::new_remote(frame.id, frame.dlc)
+        } else {
+            Mcp2518fdFrame::new(frame.id, frame.data())
+        }
+    }
+
+    fn frame_to_slcan(frame: &Self::Frame) -> Option<CanFrame> {
+        if frame.fd {
+            CanFrame::new_fd(frame.id, frame.brs, frame.esi, frame.data())
+        } else {
+            CanFrame::new(frame.id, frame.remote, frame.data())
+        }
+    }
+}
+
+// Without the INT pin there is nothing to wait for, the core polls
+impl<SPI: SpiDevice> AsyncCanDevice for Mcp2518fd<SPI> {
+    async fn wait_for_event(&mut self) {
+        yield_now().await
+    }
+
+    // Frames carry the controller timestamp
+    async fn receive_async(&mut self) -> Result<(Self::Frame, Instant), Self::Error> {
+        loop {
+            match self.receive_frame() {
+                Ok((frame, timestamp)) => return Ok((frame, self.timestamp_instant(timestamp))),
+                Err(e) if e.0 != ErrorKind::Overrun => self.wait_for_event().await,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<SPI, SERIAL> Bsp<Mcp2518fd<SPI>, SERIAL>
+where
+    SPI: SpiDevice,
+    SERIAL: Read + Write,
+{
+    pub fn new_with_mcp2518fd<DELAY: DelayNs>(spi: SPI, mut delay: DELAY, serial: SERIAL) -> Self {
+        let mut can = Mcp2518fd::new(spi, MCP2518FD_CLOCK_HZ);
+        can.init(&mut delay).unwrap();
+
+        Bsp::new(can, serial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use embedded_can::Error as _;
+    use embedded_hal::spi::ErrorType;
+
+    // Register and RAM space, the controller is emulated just enough for the
+    // driver: mode requests, and FIFOs holding a single object
+    struct MockSpi {
+        mem: [u8; 0x1000],
+    }
+
+    impl MockSpi {
+        // As after a reset, in configuration mode with the oscillator ready
+        fn new() -> Self {
+            let mut spi = MockSpi { mem: [0; 0x1000] };
+            spi.reset();
+            spi
+        }
+
+        fn reset(&mut self) {
+            self.mem = [0; 0x1000];
+            self.set(C1CON, 0x0498_0760);
+            self.set(OSC, OSC_OSCRDY);
+        }
+
+        fn get(&self, address: u16) -> u32 {
+            word(&self.mem[address as usize..], 0)
+        }
+
+        fn set(&mut self, address: u16, value: u32) {
+            let address = address as usize;
+            self.mem[address..address + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn byte(&self, address: u16) -> u8 {
+            self.mem[address as usize]
+        }
+
+        fn write(&mut self, address: u16, data: &[u8]) {
+            let start = address as usize;
+            self.mem[start..start + data.len()].copy_from_slice(data);
+
+            let written =
+                |register: u16| (address..address + data.len() as u16).contains(&register);
+
+            if written(CON_REQOP_BYTE) {
+                let mode = self.byte(CON_REQOP_BYTE) & 0x7;
+                self.mem[C1CON as usize + 2] = (self.byte(C1CON + 2) & !0xE0) | (mode << 5);
+            }
+
+            // Incrementing a FIFO empties it
+            if written(C1TEFCON + 1) && self.byte(C1TEFCON + 1) & FIFOCON_UINC != 0 {
+                self.mem[C1TEFSTA as usize] &= !(FIFOSTA_NOT_EMPTY_OR_FULL as u8);
+            }
+            if written(C1FIFOCON1 + 1) && self.byte(C1FIFOCON1 + 1) & FIFOCON_UINC != 0 {
+                self.mem[C1FIFOSTA1 as usize] &= !(FIFOSTA_NOT_EMPTY_OR_FULL as u8);
+            }
+        }
+    }
+
+    impl ErrorType for MockSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for MockSpi {
+        fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            let [Operation::Write(header), rest @ ..] = operations else {
+                panic!("SPI transaction without header");
+            };
+            let instruction = header[0] >> 4;
+            let address = ((header[0] as u16 & 0xF) << 8) | header[1] as u16;
+
+            match (instruction, rest) {
+                (INSTRUCTION_RESET, []) => self.reset(),
+                (INSTRUCTION_READ, [Operation::Read(buffer)]) => {
+                    let start = address as usize;
+                    buffer.copy_from_slice(&self.mem[start..start + buffer.len()]);
+                }
+                (INSTRUCTION_WRITE, [Operation::Write(data)]) => self.write(address, data),
+                _ => panic!("Unexpected SPI transaction"),
+            }
+
+            Ok(())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    fn running_mcp() -> Mcp2518fd<MockSpi> {
+        let mut mcp = Mcp2518fd::new(MockSpi::new(), MCP2518FD_CLOCK_HZ);
+        mcp.init(&mut NoDelay).unwrap();
+        mcp
+    }
+
+    fn fd_frame() -> Mcp2518fdFrame {
+        let data: [u8; 12] = core::array::from_fn(|i| i as u8);
+        Mcp2518fdFrame::new_fd(ExtendedId::new(0x18DA_F110).unwrap(), true, false, &data).unwrap()
+    }
+
+    #[test]
+    fn test_nominal_bit_timing() {
+        let timing = mcp2518fd_bit_timing(40_000_000, 500_000, false).unwrap();
+
+        assert_eq!(timing.prescaler, 1);
+        assert_eq!(timing.tseg1, 63);
+        assert_eq!(timing.tseg2, 16);
+        assert_eq!(timing.sjw, 16);
+        assert_eq!(timing.bitrate(), 500_000);
+        assert_eq!(mcp2518fd_nbtcfg(&timing, 40_000_000), Some(0x003E_0F0F));
+    }
+
+    #[test]
+    fn test_data_bit_timing() {
+        let timing = mcp2518fd_bit_timing(40_000_000, 2_000_000, true).unwrap();
+
+        assert_eq!(mcp2518fd_dbtcfg(&timing, 40_000_000), Some(0x000E_0303));
+        // Auto TDC, offset at the sample point
+        assert_eq!(mcp2518fd_tdc(&timing), 0x0002_1000);
+
+        // Fewer than 8 quanta per bit are not used
+        assert_eq!(mcp2518fd_bit_timing(40_000_000, 8_000_000, true), None);
+    }
+
+    #[test]
+    fn test_every_bitrate_has_a_timing() {
+        for bitrate in [
+            5_000, 10_000, 20_000, 31_250, 33_333, 40_000, 50_000, 80_000, 100_000, 125_000,
+            200_000, 250_000, 500_000, 800_000, 1_000_000,
+        ] {
+            let timing = mcp2518fd_bit_timing(40_000_000, bitrate, false).unwrap();
+            assert!(mcp2518fd_nbtcfg(&timing, 40_000_000).is_some());
+        }
+    }
+
+    #[test]
+    fn test_nbtcfg_from_btr_timing() {
+        // 500 kbit/s as given by the s command, for a 8 MHz quantum clock
+        let timing = BitTiming {
+            clock_hz: 8_000_000,
+            prescaler: 1,
+            sjw: 1,
+            tseg1: 13,
+            tseg2: 2,
+            triple_sample: false,
+        };
+
+        assert_eq!(mcp2518fd_nbtcfg(&timing, 40_000_000), Some(0x040C_0100));
+        // Too long for the data phase
+        let long = BitTiming {
+            tseg1: 40,
+            ..timing
+        };
+        assert_eq!(mcp2518fd_dbtcfg(&long, 40_000_000), None);
+    }
+
+    #[test]
+    fn test_tx_object() {
+        let mut buffer = [0; MCP2518FD_TX_OBJECT_SIZE];
+        let size = mcp2518fd_tx_object(&fd_frame(), 5, &mut buffer);
+
+        assert_eq!(size, 20);
+        assert_eq!(
+            buffer[0..size],
+            [
+                0x36, 0x86, 0x88, 0x17, 0xD9, 0x0A, 0x00, 0x00, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10,
+                11
+            ]
+        );
+
+        // Payloads are padded to whole words
+        let frame = Mcp2518fdFrame::new(StandardId::new(0x123).unwrap(), &[0x11, 0x22]).unwrap();
+        let size = mcp2518fd_tx_object(&frame, 0, &mut buffer);
+        assert_eq!(size, 12);
+        assert_eq!(buffer[0..10], [0x23, 0x01, 0, 0, 0x02, 0, 0, 0, 0x11, 0x22]);
+    }
+
+    #[test]
+    fn test_rx_object() {
+        let mut tx = [0; MCP2518FD_TX_OBJECT_SIZE];
+        mcp2518fd_tx_object(&fd_frame(), 0, &mut tx);
+
+        // RX objects have the timestamp between the flags and the data
+        let mut rx = [0; MCP2518FD_RX_OBJECT_SIZE];
+        rx[0..8].copy_from_slice(&tx[0..8]);
+        rx[8..12].copy_from_slice(&1234u32.to_le_bytes());
+        rx[12..].copy_from_slice(&tx[8..]);
+
+        assert_eq!(mcp2518fd_rx_object(&rx), Some((fd_frame(), 1234)));
+
+        // Remote frames keep their DLC without data
+        let mut rx = [0; MCP2518FD_RX_OBJECT_SIZE];
+        rx[0..4].copy_from_slice(&0x7FFu32.to_le_bytes());
+        rx[4..8].copy_from_slice(&(OBJ_RTR | 2).to_le_bytes());
+        let (frame, _) = mcp2518fd_rx_object(&rx).unwrap();
+        assert!(frame.is_remote_frame());
+        assert_eq!(frame.dlc(), 2);
+    }
+
+    #[test]
+    fn test_status_from_registers() {
+        let status = mcp2518fd_status_from_registers(
+            TREC_EWARN | TREC_TXBP | (130 << 8) | 5,
+            INT_CERRIF,
+            FIFOSTA_RX_FULL | FIFOSTA_RXOVIF | FIFOSTA_NOT_EMPTY_OR_FULL,
+            FIFOSTA_TXLARB,
+        );
+
+        assert!(status.rx_fifo_full);
+        assert!(status.tx_fifo_full);
+        assert!(status.error_warning);
+        assert!(status.data_overrun);
+        assert!(status.error_passive);
+        assert!(!status.bus_off);
+        assert!(status.arbitration_lost);
+        assert!(status.bus_error);
+        assert_eq!(status.tx_error_count, 130);
+        assert_eq!(status.rx_error_count, 5);
+    }
+
+    #[test]
+    fn test_init() {
+        let mcp = running_mcp();
+        let spi = &mcp.spi;
+
+        assert_eq!(spi.byte(C1CON + 2) >> 5, OPMOD_NORMAL_FD);
+        assert_eq!(spi.get(C1TSCON), TSCON_TBCEN | 39);
+        assert_eq!(spi.get(C1TEFCON), 0x0700_0020);
+        assert_eq!(spi.get(C1TXQCON), 0xE700_0000);
+        assert_eq!(spi.get(C1FIFOCON1), 0xEF00_0020);
+        // 250 kbit/s, 160 quanta
+        assert_eq!(spi.get(C1NBTCFG), 0x007E_1F1F);
+        assert_eq!(spi.get(C1DBTCFG), 0x000E_0303);
+
+        // A single filter, letting everything through to FIFO 1
+        assert_eq!(spi.byte(C1FLTCON0), FLTCON_FLTEN | FLTCON_FIFO1);
+        assert_eq!(spi.byte(C1FLTCON0 + 1), 0);
+        assert_eq!(spi.get(C1MASK0), 0);
+    }
+
+    #[test]
+    fn test_transmit_and_tef() {
+        let mut mcp = running_mcp();
+        mcp.spi.set(C1TXQSTA, FIFOSTA_NOT_EMPTY_OR_FULL);
+        mcp.spi.set(C1TXQUA, 0x60);
+
+        mcp.transmit(&fd_frame()).unwrap();
+
+        let mut expected = [0; MCP2518FD_TX_OBJECT_SIZE];
+        let size = mcp2518fd_tx_object(&fd_frame(), 0, &mut expected);
+        assert_eq!(mcp.spi.mem[0x460..0x460 + size], expected[0..size]);
+        assert_eq!(mcp.spi.byte(C1TXQCON + 1), FIFOCON_UINC | FIFOCON_TXREQ);
+
+        // The TEF entry for sequence 0 moves the frame to the echo queue
+        let mut entry = [0; TEF_OBJECT_SIZE];
+        entry[8..12].copy_from_slice(&1000u32.to_le_bytes());
+        mcp.spi.mem[0x400..0x400 + TEF_OBJECT_SIZE].copy_from_slice(&entry);
+        mcp.spi.set(C1TEFSTA, FIFOSTA_NOT_EMPTY_OR_FULL);
+
+        mcp.service_tef().unwrap();
+
+        assert_eq!(mcp.tx_pending.len(), 0);
+        assert_eq!(mcp.tx_echo.front(), Some(&(fd_frame(), 1000)));
+        assert_eq!(mcp.transmitted().map(|(frame, _)| frame), Some(fd_frame()));
+        assert_eq!(mcp.transmitted(), None);
+    }
+
+    #[test]
+    fn test_transmit_full_or_listen_only() {
+        let mut mcp = running_mcp();
+
+        // TXQ full
+        assert_eq!(
+            mcp.transmit(&fd_frame()).unwrap_err().kind(),
+            ErrorKind::Overrun
+        );
+
+        mcp.spi.set(C1TXQSTA, FIFOSTA_NOT_EMPTY_OR_FULL);
+        CanDevice::set_mode(&mut mcp, CanMode::ListenOnly);
+        assert_eq!(mcp.spi.byte(C1CON + 2) >> 5, OPMOD_LISTEN_ONLY);
+        assert_eq!(
+            mcp.transmit(&fd_frame()).unwrap_err().kind(),
+            ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut mcp = running_mcp();
+        assert_eq!(mcp.receive().unwrap_err().kind(), ErrorKind::Other);
+
+        let mut object = [0; MCP2518FD_RX_OBJECT_SIZE];
+        object[0..4].copy_from_slice(&0x123u32.to_le_bytes());
+        object[4..8].copy_from_slice(&2u32.to_le_bytes());
+        object[12..14].copy_from_slice(&[0x11, 0x22]);
+        mcp.spi.mem[0x500..0x500 + MCP2518FD_RX_OBJECT_SIZE].copy_from_slice(&object);
+        mcp.spi.set(C1FIFOUA1, 0x100);
+        mcp.spi.set(C1FIFOSTA1, FIFOSTA_NOT_EMPTY_OR_FULL);
+
+        let frame = mcp.receive().unwrap();
+        assert_eq!(frame.id(), Id::Standard(StandardId::new(0x123).unwrap()));
+        assert_eq!(frame.data(), &[0x11, 0x22]);
+        assert_eq!(mcp.spi.byte(C1FIFOCON1 + 1), FIFOCON_UINC);
+
+        // Overflows are reported once
+        mcp.spi.set(C1FIFOSTA1, FIFOSTA_RXOVIF);
+        assert_eq!(mcp.receive().unwrap_err().kind(), ErrorKind::Overrun);
+        assert_eq!(mcp.receive().unwrap_err().kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_overrun_reported_to_status_and_receive() {
+        let mut mcp = running_mcp();
+
+        // Reading the status first doesn't lose the overrun for receive
+        mcp.spi.set(C1FIFOSTA1, FIFOSTA_RXOVIF);
+        assert!(mcp.status().data_overrun);
+        assert!(!mcp.status().data_overrun);
+        assert_eq!(mcp.spi.byte(C1FIFOSTA1), 0);
+        assert_eq!(mcp.receive().unwrap_err().kind(), ErrorKind::Overrun);
+        assert_eq!(mcp.receive().unwrap_err().kind(), ErrorKind::Other);
+
+        // Nor the other way around
+        mcp.spi.set(C1FIFOSTA1, FIFOSTA_RXOVIF);
+        assert_eq!(mcp.receive().unwrap_err().kind(), ErrorKind::Overrun);
+        assert!(mcp.status().data_overrun);
+        assert!(!mcp.status().data_overrun);
+    }
+
+    #[test]
+    fn test_set_filters() {
+        let mut mcp = running_mcp();
+        let filters = [
+            FilterEntry {
+                id: Id::Standard(StandardId::new(0x123).unwrap()),
+                mask: 0x7F0,
+            },
+            FilterEntry {
+                id: Id::Extended(ExtendedId::new(0x18DA_F110).unwrap()),
+                mask: 0x1FFF_FF00,
+            },
+        ];
+
+        assert_eq!(CanDevice::set_filters(&mut mcp, &filters), FilterFit::Exact);
+
+        let spi = &mcp.spi;
+        assert_eq!(spi.get(C1FLTOBJ0), 0x123);
+        assert_eq!(spi.get(C1MASK0), 0x7F0 | MASK_MIDE);
+        assert_eq!(spi.get(C1FLTOBJ0 + 8), 0x1788_8636 | FLTOBJ_EXIDE);
+        assert_eq!(
+            spi.get(C1MASK0 + 8),
+            encode_extended(0x1FFF_FF00) | MASK_MIDE
+        );
+        assert_eq!(spi.byte(C1FLTCON0 + 1), FLTCON_FLTEN | FLTCON_FIFO1);
+        assert_eq!(spi.byte(C1FLTCON0 + 2), 0);
+
+        // More entries than filters let everything through
+        let many = [filters[0]; FILTERS + 1];
+        assert_eq!(CanDevice::set_filters(&mut mcp, &many), FilterFit::Superset);
+        assert_eq!(mcp.spi.get(C1MASK0), 0);
+        assert_eq!(mcp.spi.byte(C1FLTCON0 + 1), 0);
+    }
+
+    #[test]
+    fn test_slcan_frames() {
+        let frame =
+            CanFrame::new_fd(ExtendedId::new(0x18DA_F110).unwrap(), true, false, &[0; 12]).unwrap();
+        let converted = Mcp2518fd::<MockSpi>::frame_from_slcan(&frame).unwrap();
+
+        assert!(converted.is_fd());
+        assert!(converted.brs());
+        assert_eq!(converted.dlc(), 12);
+        assert_eq!(
+            Mcp2518fd::<MockSpi>::frame_to_slcan(&converted),
+            Some(frame)
+        );
+    }
+}
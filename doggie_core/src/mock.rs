@@ -0,0 +1,338 @@
+use crate::can::{
+    AsyncCanDevice, BitTiming, BitrateError, CanBitrate, CanDevice, CanMode, CanStatus,
+};
+use crate::filter::{FilterEntry, FilterFit};
+use core::convert::Infallible;
+use core::future::pending;
+use embassy_time::Instant;
+use embedded_can::{blocking::Can, ErrorKind, Frame, Id, StandardId};
+use embedded_io_async::{Read, Write};
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use heapless::Deque;
+
+// Host tests have no probe to log to, defmt output is dropped
+#[defmt::global_logger]
+struct MockLogger;
+
+unsafe impl defmt::Logger for MockLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+// Dependencies logging through defmt panic through it too
+#[defmt::panic_handler]
+fn mock_panic() -> ! {
+    panic!("defmt panic")
+}
+
+// Something happening on the bus, as seen by the controller
+#[derive(Clone, Copy, Debug)]
+pub enum MockEvent {
+    // A valid frame with this standard ID
+    Frame(u16),
+    // `receive` failing with this error
+    Error(ErrorKind),
+    // An error frame, it raises the bus error flag and the RX error counter
+    ErrorFrame,
+}
+
+// Events the controller sees at a bitrate
+pub type MockBus = fn(CanBitrate) -> &'static [MockEvent];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockFrame {
+    id: Id,
+    is_remote: bool,
+    dlc: usize,
+    data: [u8; 8],
+}
+
+impl Frame for MockFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+
+        let mut frame = MockFrame {
+            id: id.into(),
+            is_remote: false,
+            dlc: data.len(),
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+
+        Some(frame)
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+
+        Some(MockFrame {
+            id: id.into(),
+            is_remote: true,
+            dlc,
+            data: [0; 8],
+        })
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.is_remote
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn dlc(&self) -> usize {
+        self.dlc
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data[..self.dlc]
+    }
+}
+
+#[derive(Debug)]
+pub struct MockError(pub ErrorKind);
+
+impl embedded_can::Error for MockError {
+    fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
+// Controller playing a scripted bus, for the hardware independent logic.
+// Nothing is received while it is in configuration mode.
+pub struct MockCan {
+    bus: MockBus,
+    events: &'static [MockEvent],
+    // Bitrates `set_bitrate` refuses
+    unsupported: &'static [u32],
+    pub bitrate: Option<CanBitrate>,
+    pub bitrates_set: usize,
+    pub mode: CanMode,
+    pub status: CanStatus,
+    // Frames sent, until read through `transmitted`
+    pub sent: Deque<MockFrame, 4>,
+    // Cleared for a controller that never confirms a sent frame
+    pub confirms_sent: bool,
+}
+
+impl MockCan {
+    pub fn new(bus: MockBus) -> Self {
+        MockCan {
+            bus,
+            events: &[],
+            unsupported: &[],
+            bitrate: None,
+            bitrates_set: 0,
+            mode: CanMode::Configuration,
+            status: CanStatus::default(),
+            sent: Deque::new(),
+            confirms_sent: true,
+        }
+    }
+
+    pub fn with_unsupported(mut self, unsupported: &'static [u32]) -> Self {
+        self.unsupported = unsupported;
+        self
+    }
+}
+
+impl Can for MockCan {
+    type Frame = MockFrame;
+    type Error = MockError;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        if self.mode != CanMode::Normal && self.mode != CanMode::Loopback {
+            return Err(MockError(ErrorKind::Other));
+        }
+
+        self.sent
+            .push_back(frame.clone())
+            .map_err(|_| MockError(ErrorKind::Overrun))
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        if self.mode == CanMode::Configuration {
+            return Err(MockError(ErrorKind::Other));
+        }
+
+        while let Some((event, rest)) = self.events.split_first() {
+            self.events = rest;
+
+            match *event {
+                MockEvent::Frame(id) => {
+                    let id = StandardId::new(id).unwrap();
+                    return Ok(MockFrame::new(id, &[]).unwrap());
+                }
+                MockEvent::Error(kind) => return Err(MockError(kind)),
+                MockEvent::ErrorFrame => {
+                    self.status.bus_error = true;
+                    self.status.rx_error_count = self.status.rx_error_count.saturating_add(8);
+                }
+            }
+        }
+
+        // Nothing received
+        Err(MockError(ErrorKind::Other))
+    }
+}
+
+impl CanDevice for MockCan {
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        if self.unsupported.contains(&bitrate.bps()) {
+            return Err(BitrateError::Unsupported);
+        }
+
+        self.bitrate = Some(bitrate);
+        self.bitrates_set += 1;
+        self.events = (self.bus)(bitrate);
+
+        Ok(())
+    }
+
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        self.bitrate = Some(CanBitrate::from_bps(timing.bitrate()));
+        Ok(())
+    }
+
+    // The bus error flag is latched, cleared once read
+    fn status(&mut self) -> CanStatus {
+        let status = self.status;
+        self.status.bus_error = false;
+        status
+    }
+
+    fn set_mode(&mut self, mode: CanMode) {
+        self.mode = mode;
+    }
+
+    fn set_filters(&mut self, _filters: &[FilterEntry]) -> FilterFit {
+        FilterFit::Exact
+    }
+
+    // Frames go out as soon as they are transmitted
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        if !self.confirms_sent {
+            return None;
+        }
+
+        self.sent.pop_front().map(|frame| (frame, Instant::now()))
+    }
+}
+
+// The scripted events are all there from the start, nothing else comes
+impl AsyncCanDevice for MockCan {
+    async fn wait_for_event(&mut self) {
+        pending().await
+    }
+}
+
+// Serial port for a Core, the tests talk to can_task through its channels
+pub struct MockSerial;
+
+impl embedded_io_async::ErrorType for MockSerial {
+    type Error = Infallible;
+}
+
+impl Read for MockSerial {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        pending().await
+    }
+}
+
+impl Write for MockSerial {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(buf.len())
+    }
+}
+
+pub const MOCK_FLASH_SIZE: usize = 4096;
+
+// Flash in RAM, with the NOR rules: erasing sets every bit of a block,
+// writing can only clear bits
+pub struct MockFlash {
+    pub data: [u8; MOCK_FLASH_SIZE],
+    pub erases: usize,
+    // Bytes written before the power is lost, the rest of a write is missing
+    pub write_budget: Option<usize>,
+}
+
+impl MockFlash {
+    pub fn new() -> Self {
+        MockFlash {
+            data: [0xFF; MOCK_FLASH_SIZE],
+            erases: 0,
+            write_budget: None,
+        }
+    }
+}
+
+impl ErrorType for MockFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        MOCK_FLASH_SIZE
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 1024;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+
+        self.data[from as usize..to as usize].fill(0xFF);
+        self.erases += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+
+        let len = match self.write_budget {
+            Some(budget) => bytes.len().min(budget),
+            None => bytes.len(),
+        };
+        if let Some(budget) = &mut self.write_budget {
+            *budget -= len;
+        }
+
+        let offset = offset as usize;
+        for (cell, byte) in self.data[offset..offset + len].iter_mut().zip(bytes) {
+            *cell &= *byte;
+        }
+
+        if len < bytes.len() {
+            return Err(NorFlashErrorKind::Other);
+        }
+        Ok(())
+    }
+}
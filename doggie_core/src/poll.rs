@@ -0,0 +1,89 @@
+use heapless::Deque;
+use slcan::CanFrame;
+
+// Frames held while auto-poll is off, up to 64 data bytes each
+pub const PENDING_FRAMES_MAX: usize = 16;
+
+// Frames received with auto-poll off (X0), held until the host polls them
+// with P or A. Once full, new frames are lost as in a hardware FIFO
+pub struct PendingFrames {
+    frames: Deque<CanFrame, PENDING_FRAMES_MAX>,
+}
+
+impl Default for PendingFrames {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingFrames {
+    pub fn new() -> Self {
+        PendingFrames {
+            frames: Deque::new(),
+        }
+    }
+
+    // Returns false when the frame was lost, there is no room left
+    pub fn push(&mut self, frame: CanFrame) -> bool {
+        self.frames.push_back(frame).is_ok()
+    }
+
+    // Oldest frame first
+    pub fn pop(&mut self) -> Option<CanFrame> {
+        self.frames.pop_front()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.frames.is_full()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::StandardId;
+
+    fn test_frame(id: u16) -> CanFrame {
+        CanFrame::new(StandardId::new(id).unwrap(), false, &[]).unwrap()
+    }
+
+    #[test]
+    fn test_pending_frames_order() {
+        let mut pending = PendingFrames::new();
+        assert!(pending.push(test_frame(0x100)));
+        assert!(pending.push(test_frame(0x200)));
+
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.pop(), Some(test_frame(0x100)));
+        assert_eq!(pending.pop(), Some(test_frame(0x200)));
+        assert_eq!(pending.pop(), None);
+    }
+
+    #[test]
+    fn test_pending_frames_overflow() {
+        let mut pending = PendingFrames::new();
+        for id in 0..PENDING_FRAMES_MAX as u16 {
+            assert!(pending.push(test_frame(id)));
+        }
+        assert!(pending.is_full());
+
+        // The newest frame is the one lost
+        assert!(!pending.push(test_frame(0x7FF)));
+        assert_eq!(pending.pop(), Some(test_frame(0)));
+
+        pending.clear();
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::can::{BusState, CanStatus};
+use embassy_time::{Duration, Instant};
+use slcan::{SlcanBusState, SlcanState};
+
+// Bus off recovery takes 128 * 11 recessive bits, under 300 ms down to
+// 5 kbit/s. The controller may still report bus off meanwhile
+const RECOVERY_TIME: Duration = Duration::from_millis(300);
+
+// What to do once the controller goes bus off
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecoveryPolicy {
+    // Back on the bus right away
+    Auto,
+    // Off the bus until the host closes and opens the channel again
+    Manual,
+    // Back on the bus after a while off it
+    Delayed(Duration),
+}
+
+#[derive(Clone, Copy)]
+pub struct BusConfig {
+    pub recovery: RecoveryPolicy,
+    // A frame the controller couldn't send by then is dropped
+    pub tx_timeout: Duration,
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        BusConfig {
+            recovery: RecoveryPolicy::Auto,
+            tx_timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+// Follows the controller error state through its status. Once bus off the
+// controller is kept off the bus until the recovery policy lets it back.
+pub struct BusMonitor {
+    policy: RecoveryPolicy,
+    state: BusState,
+    bus_off_since: Option<Instant>,
+    restarted_at: Option<Instant>,
+}
+
+impl BusMonitor {
+    pub fn new(policy: RecoveryPolicy) -> Self {
+        BusMonitor {
+            policy,
+            state: BusState::ErrorActive,
+            bus_off_since: None,
+            restarted_at: None,
+        }
+    }
+
+    pub fn state(&self) -> BusState {
+        self.state
+    }
+
+    pub fn is_bus_off(&self) -> bool {
+        self.state == BusState::BusOff
+    }
+
+    // Track a status read, returns the state to report when it changed
+    pub fn update(&mut self, status: &CanStatus, now: Instant) -> Option<SlcanState> {
+        // Held off the bus, there is nothing to follow
+        if self.is_bus_off() {
+            return None;
+        }
+
+        let state = status.bus_state();
+        let recovering = self
+            .restarted_at
+            .is_some_and(|restarted_at| now < restarted_at + RECOVERY_TIME);
+        if state == self.state || (state == BusState::BusOff && recovering) {
+            return None;
+        }
+
+        self.state = state;
+        if state == BusState::BusOff {
+            self.bus_off_since = Some(now);
+        }
+
+        Some(SlcanState {
+            state: state.into(),
+            tx_error_count: status.tx_error_count,
+            rx_error_count: status.rx_error_count,
+        })
+    }
+
+    // Bus off, and the policy lets the controller back on the bus
+    pub fn recovery_due(&self, now: Instant) -> bool {
+        match (self.bus_off_since, self.policy) {
+            (Some(_), RecoveryPolicy::Auto) => true,
+            (Some(since), RecoveryPolicy::Delayed(delay)) => now >= since + delay,
+            _ => false,
+        }
+    }
+
+    // The controller was restarted, so its error counters start over.
+    // Returns the state to report when it changed
+    pub fn reset(&mut self, now: Instant) -> Option<SlcanState> {
+        self.bus_off_since = None;
+        self.restarted_at = Some(now);
+
+        if self.state == BusState::ErrorActive {
+            return None;
+        }
+
+        self.state = BusState::ErrorActive;
+        Some(SlcanState {
+            state: SlcanBusState::Active,
+            tx_error_count: 0,
+            rx_error_count: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn counters(tx_error_count: u8, rx_error_count: u8) -> CanStatus {
+        CanStatus {
+            tx_error_count,
+            rx_error_count,
+            ..CanStatus::default()
+        }
+    }
+
+    fn bus_off() -> CanStatus {
+        CanStatus {
+            bus_off: true,
+            tx_error_count: 255,
+            ..CanStatus::default()
+        }
+    }
+
+    #[test]
+    fn test_bus_state_from_counters() {
+        assert_eq!(counters(0, 95).bus_state(), BusState::ErrorActive);
+        assert_eq!(counters(96, 0).bus_state(), BusState::ErrorWarning);
+        assert_eq!(counters(0, 128).bus_state(), BusState::ErrorPassive);
+        assert_eq!(counters(255, 0).bus_state(), BusState::ErrorPassive);
+        assert_eq!(bus_off().bus_state(), BusState::BusOff);
+    }
+
+    #[test]
+    fn test_state_changes_reported_once() {
+        let mut monitor = BusMonitor::new(RecoveryPolicy::Auto);
+
+        assert_eq!(monitor.update(&counters(0, 0), at(0)), None);
+        assert_eq!(
+            monitor.update(&counters(136, 8), at(10)),
+            Some(SlcanState {
+                state: SlcanBusState::Passive,
+                tx_error_count: 136,
+                rx_error_count: 8,
+            })
+        );
+        assert_eq!(monitor.update(&counters(144, 8), at(20)), None);
+        assert_eq!(
+            monitor.update(&counters(0, 0), at(30)).map(|s| s.state),
+            Some(SlcanBusState::Active)
+        );
+    }
+
+    #[test]
+    fn test_auto_recovery() {
+        let mut monitor = BusMonitor::new(RecoveryPolicy::Auto);
+
+        assert!(!monitor.recovery_due(at(0)));
+        assert_eq!(
+            monitor.update(&bus_off(), at(0)).map(|s| s.state),
+            Some(SlcanBusState::BusOff)
+        );
+        assert!(monitor.recovery_due(at(0)));
+
+        assert_eq!(
+            monitor.reset(at(0)).map(|s| s.state),
+            Some(SlcanBusState::Active)
+        );
+        assert!(!monitor.is_bus_off());
+        assert!(!monitor.recovery_due(at(0)));
+    }
+
+    #[test]
+    fn test_bus_off_while_recovering() {
+        let mut monitor = BusMonitor::new(RecoveryPolicy::Auto);
+        monitor.update(&bus_off(), at(0));
+        monitor.reset(at(0));
+
+        // Still counting the recessive bits
+        assert_eq!(monitor.update(&bus_off(), at(100)), None);
+        assert!(!monitor.is_bus_off());
+
+        // Never recovered
+        assert!(monitor.update(&bus_off(), at(400)).is_some());
+        assert!(monitor.is_bus_off());
+    }
+
+    #[test]
+    fn test_manual_recovery() {
+        let mut monitor = BusMonitor::new(RecoveryPolicy::Manual);
+        monitor.update(&bus_off(), at(0));
+
+        assert!(!monitor.recovery_due(at(60_000)));
+        // Held off the bus, the status is ignored
+        assert_eq!(monitor.update(&counters(0, 0), at(60_000)), None);
+        assert!(monitor.is_bus_off());
+
+        // Channel closed and opened again
+        monitor.reset(at(60_000));
+        assert_eq!(monitor.state(), BusState::ErrorActive);
+    }
+
+    #[test]
+    fn test_delayed_recovery() {
+        let mut monitor = BusMonitor::new(RecoveryPolicy::Delayed(Duration::from_millis(500)));
+        monitor.update(&bus_off(), at(1000));
+
+        assert!(!monitor.recovery_due(at(1000)));
+        assert!(!monitor.recovery_due(at(1499)));
+        assert!(monitor.recovery_due(at(1500)));
+    }
+
+    #[test]
+    fn test_reset_when_active() {
+        let mut monitor = BusMonitor::new(RecoveryPolicy::Auto);
+        assert_eq!(monitor.reset(at(0)), None);
+    }
+}
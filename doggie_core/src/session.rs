@@ -0,0 +1,667 @@
+use crate::channel::ChannelState;
+use crate::device_id::DeviceId;
+use crate::filter::SOFTWARE_FILTER_MAX_RULES;
+use crate::startup::StartupConfig;
+use slcan::SlcanCommand;
+
+pub const SLCAN_OK: &[u8] = b"\r";
+pub const SLCAN_BELL: &[u8] = b"\x07";
+
+const VERSION_REPLY: &[u8] = b"V1337\r";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionAction {
+    // Command to be executed by the CAN task
+    Forward(SlcanCommand),
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SessionOutput<'a> {
+    pub reply: Option<&'a [u8]>,
+    pub action: Option<SessionAction>,
+}
+
+impl<'a> SessionOutput<'a> {
+    pub(crate) fn reply(reply: &'a [u8]) -> Self {
+        SessionOutput {
+            reply: Some(reply),
+            action: None,
+        }
+    }
+
+    pub(crate) fn forward(cmd: SlcanCommand, reply: Option<&'a [u8]>) -> Self {
+        SessionOutput {
+            reply,
+            action: Some(SessionAction::Forward(cmd)),
+        }
+    }
+}
+
+// Hardware independent slcan protocol state
+pub struct SlcanSession {
+    channel: ChannelState,
+    // N reply: N, 4 characters and \r
+    serial_no_reply: [u8; 6],
+    // Rules held by the CAN task software filter
+    filter_rules: usize,
+    // Frames are only polled with auto-poll off
+    auto_poll: bool,
+}
+
+impl Default for SlcanSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlcanSession {
+    pub fn new() -> Self {
+        Self::with_device_id(&DeviceId::default())
+    }
+
+    pub fn with_device_id(device_id: &DeviceId) -> Self {
+        let mut serial_no_reply = *b"N0000\r";
+        serial_no_reply[1..5].copy_from_slice(&device_id.lawicel_serial());
+
+        SlcanSession {
+            channel: ChannelState::Closed,
+            serial_no_reply,
+            filter_rules: 0,
+            auto_poll: true,
+        }
+    }
+
+    pub fn channel(&self) -> ChannelState {
+        self.channel
+    }
+
+    // Start as the CAN task does with the saved configuration
+    pub fn apply_startup(&mut self, config: &StartupConfig) {
+        self.channel = ChannelState::from(config.mode);
+        self.auto_poll = config.auto_poll;
+        self.filter_rules = config.software_filter.rules().len();
+    }
+
+    // Handle a command received from the host
+    pub fn handle_command(&mut self, cmd: SlcanCommand) -> SessionOutput<'_> {
+        if cmd == SlcanCommand::IncompleteMessage {
+            return SessionOutput::default();
+        }
+
+        if !self.channel.accepts(&cmd) {
+            return SessionOutput::reply(SLCAN_BELL);
+        }
+
+        match cmd {
+            SlcanCommand::OpenChannel => {
+                self.channel = ChannelState::Open;
+                SessionOutput::forward(cmd, Some(SLCAN_OK))
+            }
+            SlcanCommand::Listen => {
+                self.channel = ChannelState::ListenOnly;
+                SessionOutput::forward(cmd, Some(SLCAN_OK))
+            }
+            SlcanCommand::CloseChannel => {
+                self.channel = ChannelState::Closed;
+                SessionOutput::forward(cmd, Some(SLCAN_OK))
+            }
+            // The CAN task answers with the controller status
+            SlcanCommand::ReadStatusFlags => SessionOutput::forward(cmd, None),
+            // The CAN task answers once the controller took the bit timing
+            SlcanCommand::SetBitrate(_) | SlcanCommand::SetBitTimeRegister(_) => {
+                SessionOutput::forward(cmd, None)
+            }
+            // The CAN task answers with the detected bitrate
+            SlcanCommand::Autobaud => SessionOutput::forward(cmd, None),
+            SlcanCommand::Version => SessionOutput::reply(VERSION_REPLY),
+            SlcanCommand::SerialNo => SessionOutput::reply(&self.serial_no_reply),
+            // Frames are timestamped by the CAN task as they are received
+            SlcanCommand::Timestamp(_) => SessionOutput::forward(cmd, Some(SLCAN_OK)),
+            // The CAN task answers sent frames with z/Z or their echo
+            SlcanCommand::EchoMode(_) => SessionOutput::forward(cmd, Some(SLCAN_OK)),
+            SlcanCommand::AutoPoll(enabled) => {
+                self.auto_poll = enabled;
+                SessionOutput::forward(cmd, Some(SLCAN_OK))
+            }
+            SlcanCommand::PollOne | SlcanCommand::PollAll if self.auto_poll => {
+                SessionOutput::reply(SLCAN_BELL)
+            }
+            // The CAN task answers with the pending frames
+            SlcanCommand::PollOne | SlcanCommand::PollAll => SessionOutput::forward(cmd, None),
+            // The CAN task answers once the settings are saved
+            SlcanCommand::AutoStartup(_) => SessionOutput::forward(cmd, None),
+            // The CAN task holds the configuration and answers
+            SlcanCommand::ReadConfig(_)
+            | SlcanCommand::WriteConfig(_)
+            | SlcanCommand::FactoryReset => SessionOutput::forward(cmd, None),
+            SlcanCommand::AcceptanceCode(_)
+            | SlcanCommand::AcceptanceMask(_)
+            | SlcanCommand::FilterMode(_) => SessionOutput::forward(cmd, Some(SLCAN_OK)),
+            SlcanCommand::AddFilterRule(_) if self.filter_rules == SOFTWARE_FILTER_MAX_RULES => {
+                SessionOutput::reply(SLCAN_BELL)
+            }
+            SlcanCommand::AddFilterRule(rule) if !rule.is_valid() => {
+                SessionOutput::reply(SLCAN_BELL)
+            }
+            SlcanCommand::AddFilterRule(_) => {
+                self.filter_rules += 1;
+                SessionOutput::forward(cmd, Some(SLCAN_OK))
+            }
+            SlcanCommand::ClearFilterRules => {
+                self.filter_rules = 0;
+                SessionOutput::forward(cmd, Some(SLCAN_OK))
+            }
+            cmd => SessionOutput::forward(cmd, None),
+        }
+    }
+
+    // Handle a message coming from the CAN task, returns what should be sent
+    // to the host
+    pub fn handle_can(&mut self, cmd: SlcanCommand) -> Option<SlcanCommand> {
+        match cmd {
+            // Frames are not forwarded while the channel is closed
+            SlcanCommand::Frame(_) if !self.channel.is_open() => None,
+            SlcanCommand::Frame(frame) => Some(SlcanCommand::Frame(frame)),
+            SlcanCommand::StatusFlags(flags) => Some(SlcanCommand::StatusFlags(flags)),
+            SlcanCommand::DetectedBitrate(bps) => Some(SlcanCommand::DetectedBitrate(bps)),
+            SlcanCommand::ConfigValue(value) => Some(SlcanCommand::ConfigValue(value)),
+            // Bus errors only matter while the channel is open
+            SlcanCommand::State(_) | SlcanCommand::TxDropped if !self.channel.is_open() => None,
+            SlcanCommand::State(state) => Some(SlcanCommand::State(state)),
+            SlcanCommand::TxDropped => Some(SlcanCommand::TxDropped),
+            SlcanCommand::TxEcho(_) if !self.channel.is_open() => None,
+            SlcanCommand::TxEcho(frame) => Some(SlcanCommand::TxEcho(frame)),
+            // Answers a transmit command, even if the channel was closed since
+            SlcanCommand::TxAck(id) => Some(SlcanCommand::TxAck(id)),
+            SlcanCommand::Ack | SlcanCommand::Bell | SlcanCommand::PollEnd => Some(cmd),
+            // We are not expecting other message
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::StandardId;
+    use slcan::{
+        CanFrame, SlcanBitrates, SlcanBusState, SlcanConfigKey, SlcanConfigValue, SlcanFilterRule,
+        SlcanStartupMode, SlcanState, SlcanStatusFlags, SlcanTimestamp, SlcanTimestampMode,
+    };
+
+    fn test_frame() -> CanFrame {
+        CanFrame::new(StandardId::new(0x123).unwrap(), false, &[0x11, 0x22]).unwrap()
+    }
+
+    fn open_session() -> SlcanSession {
+        let mut session = SlcanSession::new();
+        session.handle_command(SlcanCommand::OpenChannel);
+        session
+    }
+
+    #[test]
+    fn test_incomplete_message() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::IncompleteMessage),
+            SessionOutput::default()
+        );
+    }
+
+    #[test]
+    fn test_open_channel() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::OpenChannel),
+            SessionOutput {
+                reply: Some(SLCAN_OK),
+                action: Some(SessionAction::Forward(SlcanCommand::OpenChannel)),
+            }
+        );
+        assert!(session.channel() == ChannelState::Open);
+    }
+
+    #[test]
+    fn test_open_channel_twice() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::OpenChannel),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_listen() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Listen),
+            SessionOutput {
+                reply: Some(SLCAN_OK),
+                action: Some(SessionAction::Forward(SlcanCommand::Listen)),
+            }
+        );
+        assert!(session.channel() == ChannelState::ListenOnly);
+    }
+
+    #[test]
+    fn test_close_channel() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::CloseChannel),
+            SessionOutput {
+                reply: Some(SLCAN_OK),
+                action: Some(SessionAction::Forward(SlcanCommand::CloseChannel)),
+            }
+        );
+        assert!(session.channel() == ChannelState::Closed);
+    }
+
+    #[test]
+    fn test_close_channel_closed() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::CloseChannel),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_set_bitrate_closed() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::SetBitrate(SlcanBitrates::CAN500KB)),
+            SessionOutput::forward(SlcanCommand::SetBitrate(SlcanBitrates::CAN500KB), None)
+        );
+    }
+
+    #[test]
+    fn test_set_bitrate_open() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::SetBitrate(SlcanBitrates::CAN500KB)),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_autobaud_closed() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Autobaud),
+            SessionOutput::forward(SlcanCommand::Autobaud, None)
+        );
+    }
+
+    #[test]
+    fn test_autobaud_open() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Autobaud),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_frame_open() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Frame(test_frame())),
+            SessionOutput::forward(SlcanCommand::Frame(test_frame()), None)
+        );
+    }
+
+    #[test]
+    fn test_frame_closed() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Frame(test_frame())),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_frame_listen_only() {
+        let mut session = SlcanSession::new();
+        session.handle_command(SlcanCommand::Listen);
+        assert_eq!(
+            session.handle_command(SlcanCommand::Frame(test_frame())),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_status_flags_open() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::ReadStatusFlags),
+            SessionOutput::forward(SlcanCommand::ReadStatusFlags, None)
+        );
+    }
+
+    #[test]
+    fn test_status_flags_closed() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::ReadStatusFlags),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Version),
+            SessionOutput::reply(b"V1337\r")
+        );
+    }
+
+    #[test]
+    fn test_serial_no() {
+        let mut session = SlcanSession::with_device_id(&DeviceId::new(b"123456789"));
+        assert_eq!(
+            session.handle_command(SlcanCommand::SerialNo),
+            SessionOutput::reply(b"N29B1\r")
+        );
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Timestamp(SlcanTimestampMode::Milliseconds)),
+            SessionOutput::forward(
+                SlcanCommand::Timestamp(SlcanTimestampMode::Milliseconds),
+                Some(SLCAN_OK)
+            )
+        );
+    }
+
+    #[test]
+    fn test_timestamp_open() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::Timestamp(SlcanTimestampMode::Off)),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_acceptance_code() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::AcceptanceCode(0x2460_0000)),
+            SessionOutput::forward(SlcanCommand::AcceptanceCode(0x2460_0000), Some(SLCAN_OK))
+        );
+    }
+
+    #[test]
+    fn test_acceptance_mask_open() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::AcceptanceMask(0xFFFF_FFFF)),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_filter_rules_full() {
+        let mut session = SlcanSession::new();
+        let rule = SlcanFilterRule::default();
+        for _ in 0..SOFTWARE_FILTER_MAX_RULES {
+            assert_eq!(
+                session.handle_command(SlcanCommand::AddFilterRule(rule)),
+                SessionOutput::forward(SlcanCommand::AddFilterRule(rule), Some(SLCAN_OK))
+            );
+        }
+        assert_eq!(
+            session.handle_command(SlcanCommand::AddFilterRule(rule)),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+
+        session.handle_command(SlcanCommand::ClearFilterRules);
+        assert_eq!(
+            session.handle_command(SlcanCommand::AddFilterRule(rule)),
+            SessionOutput::forward(SlcanCommand::AddFilterRule(rule), Some(SLCAN_OK))
+        );
+    }
+
+    #[test]
+    fn test_filter_rule_invalid() {
+        let mut session = SlcanSession::new();
+        let mut rule = SlcanFilterRule::default();
+        rule.id_low = 0x200;
+        rule.id_high = 0x100;
+        assert_eq!(
+            session.handle_command(SlcanCommand::AddFilterRule(rule)),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_can_frame_closed() {
+        let mut session = SlcanSession::new();
+        assert_eq!(session.handle_can(SlcanCommand::Frame(test_frame())), None);
+    }
+
+    #[test]
+    fn test_can_frame_open() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_can(SlcanCommand::Frame(test_frame())),
+            Some(SlcanCommand::Frame(test_frame()))
+        );
+    }
+
+    #[test]
+    fn test_can_frame_timestamp() {
+        let mut session = open_session();
+
+        let mut frame = test_frame();
+        frame.timestamp = Some(SlcanTimestamp::Milliseconds(10));
+
+        let mut expected = test_frame();
+        expected.timestamp = Some(SlcanTimestamp::Milliseconds(10));
+
+        assert_eq!(
+            session.handle_can(SlcanCommand::Frame(frame)),
+            Some(SlcanCommand::Frame(expected))
+        );
+    }
+
+    #[test]
+    fn test_can_state() {
+        let state = SlcanState {
+            state: SlcanBusState::BusOff,
+            tx_error_count: 255,
+            rx_error_count: 0,
+        };
+
+        let mut session = SlcanSession::new();
+        assert_eq!(session.handle_can(SlcanCommand::State(state)), None);
+
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_can(SlcanCommand::State(state)),
+            Some(SlcanCommand::State(state))
+        );
+    }
+
+    #[test]
+    fn test_echo_mode() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::EchoMode(true)),
+            SessionOutput::forward(SlcanCommand::EchoMode(true), Some(SLCAN_OK))
+        );
+
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::EchoMode(true)),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_auto_poll() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_command(SlcanCommand::AutoPoll(false)),
+            SessionOutput::forward(SlcanCommand::AutoPoll(false), Some(SLCAN_OK))
+        );
+
+        session.handle_command(SlcanCommand::OpenChannel);
+        assert_eq!(
+            session.handle_command(SlcanCommand::AutoPoll(true)),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_poll() {
+        let mut session = SlcanSession::new();
+        session.handle_command(SlcanCommand::AutoPoll(false));
+        assert_eq!(
+            session.handle_command(SlcanCommand::PollOne),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+
+        session.handle_command(SlcanCommand::OpenChannel);
+        assert_eq!(
+            session.handle_command(SlcanCommand::PollOne),
+            SessionOutput::forward(SlcanCommand::PollOne, None)
+        );
+        assert_eq!(
+            session.handle_command(SlcanCommand::PollAll),
+            SessionOutput::forward(SlcanCommand::PollAll, None)
+        );
+        assert_eq!(
+            session.handle_can(SlcanCommand::PollEnd),
+            Some(SlcanCommand::PollEnd)
+        );
+    }
+
+    #[test]
+    fn test_poll_auto_poll_on() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_command(SlcanCommand::PollAll),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_auto_startup() {
+        let mut session = SlcanSession::new();
+        let cmd = SlcanCommand::AutoStartup(SlcanStartupMode::Normal);
+        assert_eq!(
+            session.handle_command(cmd),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+
+        let mut session = open_session();
+        let cmd = SlcanCommand::AutoStartup(SlcanStartupMode::Normal);
+        assert_eq!(
+            session.handle_command(cmd),
+            SessionOutput::forward(SlcanCommand::AutoStartup(SlcanStartupMode::Normal), None)
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let mut session = SlcanSession::new();
+        let cmd = SlcanCommand::WriteConfig(SlcanConfigValue::UartBaud(Some(115_200)));
+        assert_eq!(
+            session.handle_command(cmd),
+            SessionOutput::forward(
+                SlcanCommand::WriteConfig(SlcanConfigValue::UartBaud(Some(115_200))),
+                None
+            )
+        );
+        assert_eq!(
+            session.handle_can(SlcanCommand::ConfigValue(SlcanConfigValue::UartBaud(None))),
+            Some(SlcanCommand::ConfigValue(SlcanConfigValue::UartBaud(None)))
+        );
+
+        // Read while open, written only while closed
+        let mut session = open_session();
+        let cmd = SlcanCommand::ReadConfig(SlcanConfigKey::UartBaud);
+        assert_eq!(
+            session.handle_command(cmd),
+            SessionOutput::forward(SlcanCommand::ReadConfig(SlcanConfigKey::UartBaud), None)
+        );
+        assert_eq!(
+            session.handle_command(SlcanCommand::FactoryReset),
+            SessionOutput::reply(SLCAN_BELL)
+        );
+    }
+
+    #[test]
+    fn test_apply_startup() {
+        let mut session = SlcanSession::new();
+        session.apply_startup(&StartupConfig {
+            mode: SlcanStartupMode::ListenOnly,
+            auto_poll: false,
+            ..StartupConfig::default()
+        });
+        assert!(session.channel() == ChannelState::ListenOnly);
+        assert_eq!(
+            session.handle_command(SlcanCommand::PollOne),
+            SessionOutput::forward(SlcanCommand::PollOne, None)
+        );
+
+        // Off leaves the channel closed
+        let mut session = SlcanSession::new();
+        session.apply_startup(&StartupConfig::default());
+        assert!(session.channel() == ChannelState::Closed);
+    }
+
+    #[test]
+    fn test_can_tx_echo() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_can(SlcanCommand::TxEcho(test_frame())),
+            Some(SlcanCommand::TxEcho(test_frame()))
+        );
+
+        session.handle_command(SlcanCommand::CloseChannel);
+        assert_eq!(session.handle_can(SlcanCommand::TxEcho(test_frame())), None);
+
+        let id = test_frame().id;
+        assert_eq!(
+            session.handle_can(SlcanCommand::TxAck(id)),
+            Some(SlcanCommand::TxAck(id))
+        );
+    }
+
+    #[test]
+    fn test_can_detected_bitrate() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_can(SlcanCommand::DetectedBitrate(250_000)),
+            Some(SlcanCommand::DetectedBitrate(250_000))
+        );
+    }
+
+    #[test]
+    fn test_can_replies_closed() {
+        let mut session = SlcanSession::new();
+        assert_eq!(
+            session.handle_can(SlcanCommand::Ack),
+            Some(SlcanCommand::Ack)
+        );
+        assert_eq!(
+            session.handle_can(SlcanCommand::Bell),
+            Some(SlcanCommand::Bell)
+        );
+    }
+
+    #[test]
+    fn test_can_status_flags() {
+        let mut session = open_session();
+        assert_eq!(
+            session.handle_can(SlcanCommand::StatusFlags(SlcanStatusFlags(0x08))),
+            Some(SlcanCommand::StatusFlags(SlcanStatusFlags(0x08)))
+        );
+    }
+}
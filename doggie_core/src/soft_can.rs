@@ -0,0 +1,673 @@
+use embedded_can::{ExtendedId, Id, StandardId};
+use slcan::CanFrame;
+
+// PIO cycles per CAN bit, the bit is sampled 10 cycles after its start
+pub const SOFT_CAN_CYCLES_PER_BIT: u32 = 16;
+
+// Longest frame up to the CRC delimiter: 118 bits of an extended frame with 8
+// data bytes, 29 stuff bits and the delimiter
+const MAX_FRAME_BITS: usize = 148;
+const MAX_FRAME_WORDS: usize = MAX_FRAME_BITS.div_ceil(32);
+
+const CRC15_POLY: u16 = 0x4599;
+
+// Recessive bits closing a frame (EOF and intermission) and marking the bus
+// idle after an error
+const FRAME_END_BITS: u8 = 10;
+const BUS_IDLE_BITS: u8 = 11;
+
+// Same bits in a row before a stuff bit
+const STUFF_RUN: u8 = 5;
+
+fn crc15_bit(crc: u16, bit: bool) -> u16 {
+    let next = bit ^ (crc & 0x4000 != 0);
+    let crc = (crc << 1) & 0x7FFF;
+
+    if next {
+        crc ^ CRC15_POLY
+    } else {
+        crc
+    }
+}
+
+// PIO clock divider for `bitrate`, in 16.8 fixed point. Returns None if the
+// system clock is too slow or too fast for it.
+pub fn soft_can_clock_divider(sys_clock_hz: u32, bitrate: u32) -> Option<u32> {
+    let cycles_hz = bitrate as u64 * SOFT_CAN_CYCLES_PER_BIT as u64;
+    let divider = (sys_clock_hz as u64 * 256 + cycles_hz / 2) / cycles_hz;
+
+    if !(0x100..=0x00FF_FFFF).contains(&divider) {
+        return None;
+    }
+
+    Some(divider as u32)
+}
+
+// Bits as driven on the bus, MSB first. `true` is recessive.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SoftCanBits {
+    words: [u32; MAX_FRAME_WORDS],
+    len: usize,
+}
+
+impl SoftCanBits {
+    fn new() -> Self {
+        SoftCanBits {
+            words: [0; MAX_FRAME_WORDS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bit: bool) {
+        if bit {
+            self.words[self.len / 32] |= 1 << (31 - self.len % 32);
+        }
+        self.len += 1;
+    }
+
+    // Stuffed bits of the frame, from the start of frame up to the CRC
+    // delimiter. The ACK slot and the end of frame are left recessive.
+    pub fn encode(frame: &CanFrame) -> Self {
+        let mut stuffer = Stuffer::new(SoftCanBits::new());
+        let mut crc = 0;
+        let mut push = |stuffer: &mut Stuffer, value: u32, len: u32| {
+            for i in (0..len).rev() {
+                let bit = value >> i & 1 != 0;
+                crc = crc15_bit(crc, bit);
+                stuffer.push(bit);
+            }
+        };
+
+        let remote = frame.is_remote() as u32;
+
+        // Start of frame
+        push(&mut stuffer, 0, 1);
+        match frame.id {
+            Id::Standard(id) => {
+                // ID, RTR, IDE and r0
+                push(&mut stuffer, id.as_raw() as u32, 11);
+                push(&mut stuffer, remote << 2, 3);
+            }
+            Id::Extended(id) => {
+                // ID.28-18, SRR, IDE, ID.17-0, RTR, r1 and r0
+                push(&mut stuffer, id.as_raw() >> 18, 11);
+                push(&mut stuffer, 0b11, 2);
+                push(&mut stuffer, id.as_raw() & 0x3FFFF, 18);
+                push(&mut stuffer, remote << 2, 3);
+            }
+        }
+        push(&mut stuffer, frame.dlc as u32, 4);
+
+        if !frame.is_remote() {
+            for byte in &frame.data[..frame.dlc] {
+                push(&mut stuffer, *byte as u32, 8);
+            }
+        }
+
+        let crc = crc;
+        for i in (0..15).rev() {
+            stuffer.push(crc >> i & 1 != 0);
+        }
+        stuffer.flush();
+
+        // CRC delimiter
+        stuffer.bits.push(true);
+
+        stuffer.bits
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bit(&self, index: usize) -> bool {
+        self.words[index / 32] & 1 << (31 - index % 32) != 0
+    }
+
+    // Last 32 bits, as given by SoftCanEvent::AckPattern for this frame
+    pub fn ack_pattern(&self) -> u32 {
+        (self.len.saturating_sub(32)..self.len)
+            .fold(0, |pattern, i| pattern << 1 | self.bit(i) as u32)
+    }
+
+    // Words holding the bits, the last one padded with dominant bits
+    pub fn words(&self) -> &[u32] {
+        &self.words[..self.len.div_ceil(32)]
+    }
+}
+
+// Inserts a stuff bit after every 5 equal bits. The stuff bit is added when
+// the next bit comes, or by flush at the end of the stuffed fields.
+struct Stuffer {
+    bits: SoftCanBits,
+    same: u8,
+    last: bool,
+}
+
+impl Stuffer {
+    fn new(bits: SoftCanBits) -> Self {
+        // The bus is recessive before the start of frame
+        Stuffer {
+            bits,
+            same: 0,
+            last: true,
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.same == STUFF_RUN {
+            self.bits.push(!self.last);
+            self.last = !self.last;
+            self.same = 1;
+        }
+    }
+
+    fn push(&mut self, bit: bool) {
+        self.flush();
+        self.bits.push(bit);
+
+        if bit == self.last {
+            self.same += 1;
+        } else {
+            self.last = bit;
+            self.same = 1;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SoftCanError {
+    // Six equal bits in a row
+    Stuff,
+    Crc,
+    // A delimiter wasn't recessive
+    Form,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SoftCanEvent {
+    // The data field is over, so the rest of the frame is known. The ACK slot
+    // must be driven after the bus carried the last 32 bits in the pattern,
+    // which end with the CRC delimiter
+    AckPattern(u32),
+    // Received after the ACK delimiter, `acked` if some node drove the ACK slot
+    Frame { frame: CanFrame, acked: bool },
+    Error(SoftCanError),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Field {
+    // Counting recessive bits until a start of frame is allowed
+    Idle,
+    IdA,
+    // RTR of standard frames, SRR of extended ones
+    Srr,
+    Ide,
+    IdB,
+    Rtr,
+    Reserved,
+    Dlc,
+    Data,
+    Crc,
+    CrcDelimiter,
+    AckSlot,
+    AckDelimiter,
+}
+
+// Decodes the bits sampled from the bus, one at a time
+pub struct SoftCanDecoder {
+    field: Field,
+    remaining: u8,
+    value: u32,
+    // Last raw bits, including stuff bits
+    history: u32,
+    idle_bits: u8,
+    idle_needed: u8,
+    same: u8,
+    last: bool,
+    crc: u16,
+    id: u32,
+    extended: bool,
+    remote: bool,
+    dlc: usize,
+    data: [u8; 8],
+    data_len: usize,
+    data_index: usize,
+    acked: bool,
+}
+
+impl Default for SoftCanDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoftCanDecoder {
+    pub fn new() -> Self {
+        SoftCanDecoder {
+            field: Field::Idle,
+            remaining: 0,
+            value: 0,
+            history: 0,
+            idle_bits: 0,
+            idle_needed: BUS_IDLE_BITS,
+            same: 0,
+            last: true,
+            crc: 0,
+            id: 0,
+            extended: false,
+            remote: false,
+            dlc: 0,
+            data: [0; 8],
+            data_len: 0,
+            data_index: 0,
+            acked: false,
+        }
+    }
+
+    // Wait for the bus to be idle again, as after losing bits
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    // Whether a frame can start, the bus has been idle long enough
+    pub fn is_idle(&self) -> bool {
+        self.field == Field::Idle && self.idle_bits >= self.idle_needed
+    }
+
+    fn next(&mut self, field: Field, len: u8) {
+        self.field = field;
+        self.remaining = len;
+        self.value = 0;
+    }
+
+    fn idle(&mut self, needed: u8) {
+        self.field = Field::Idle;
+        self.idle_bits = 0;
+        self.idle_needed = needed;
+    }
+
+    fn error(&mut self, error: SoftCanError) -> Option<SoftCanEvent> {
+        self.idle(BUS_IDLE_BITS);
+        Some(SoftCanEvent::Error(error))
+    }
+
+    pub fn push(&mut self, bit: bool) -> Option<SoftCanEvent> {
+        self.history = self.history << 1 | bit as u32;
+
+        match self.field {
+            Field::Idle => {
+                if bit {
+                    self.idle_bits = self.idle_bits.saturating_add(1);
+                    return None;
+                }
+
+                // Dominant bits before the bus is idle are error or overload
+                // frames, wait again
+                if self.idle_bits < self.idle_needed {
+                    self.idle_bits = 0;
+                    return None;
+                }
+
+                // Start of frame
+                self.same = 1;
+                self.last = false;
+                self.crc = crc15_bit(0, false);
+                self.data = [0; 8];
+                self.data_index = 0;
+                self.extended = false;
+                self.next(Field::IdA, 11);
+                None
+            }
+            Field::CrcDelimiter if self.same == STUFF_RUN => {
+                // Stuff bit after the last CRC bits
+                if bit == self.last {
+                    return self.error(SoftCanError::Stuff);
+                }
+                self.same = 0;
+                None
+            }
+            Field::CrcDelimiter => {
+                if !bit {
+                    return self.error(SoftCanError::Form);
+                }
+                self.next(Field::AckSlot, 1);
+                None
+            }
+            Field::AckSlot => {
+                self.acked = !bit;
+                self.next(Field::AckDelimiter, 1);
+                None
+            }
+            Field::AckDelimiter => {
+                if !bit {
+                    return self.error(SoftCanError::Form);
+                }
+                self.idle(FRAME_END_BITS);
+                Some(SoftCanEvent::Frame {
+                    frame: self.frame(),
+                    acked: self.acked,
+                })
+            }
+            _ => {
+                if self.same == STUFF_RUN {
+                    if bit == self.last {
+                        return self.error(SoftCanError::Stuff);
+                    }
+                    self.last = bit;
+                    self.same = 1;
+                    return None;
+                }
+
+                if bit == self.last {
+                    self.same += 1;
+                } else {
+                    self.last = bit;
+                    self.same = 1;
+                }
+
+                if self.field != Field::Crc {
+                    self.crc = crc15_bit(self.crc, bit);
+                }
+
+                self.value = self.value << 1 | bit as u32;
+                self.remaining -= 1;
+                if self.remaining > 0 {
+                    return None;
+                }
+
+                self.end_field()
+            }
+        }
+    }
+
+    fn end_field(&mut self) -> Option<SoftCanEvent> {
+        match self.field {
+            Field::IdA => {
+                self.id = self.value;
+                self.next(Field::Srr, 1);
+            }
+            Field::Srr => {
+                self.remote = self.value != 0;
+                self.next(Field::Ide, 1);
+            }
+            Field::Ide if self.value != 0 => {
+                self.extended = true;
+                self.next(Field::IdB, 18);
+            }
+            Field::Ide => self.next(Field::Reserved, 1),
+            Field::IdB => {
+                self.id = self.id << 18 | self.value;
+                self.next(Field::Rtr, 1);
+            }
+            Field::Rtr => {
+                self.remote = self.value != 0;
+                self.next(Field::Reserved, 2);
+            }
+            Field::Reserved => self.next(Field::Dlc, 4),
+            Field::Dlc => {
+                // DLC 9 to 15 mean 8 bytes
+                self.dlc = (self.value as usize).min(8);
+                self.data_len = if self.remote { 0 } else { self.dlc };
+
+                if self.data_len == 0 {
+                    return self.data_end();
+                }
+                self.next(Field::Data, 8);
+            }
+            Field::Data => {
+                self.data[self.data_index] = self.value as u8;
+                self.data_index += 1;
+
+                if self.data_index == self.data_len {
+                    return self.data_end();
+                }
+                self.next(Field::Data, 8);
+            }
+            Field::Crc => {
+                if self.value as u16 != self.crc {
+                    return self.error(SoftCanError::Crc);
+                }
+                self.next(Field::CrcDelimiter, 1);
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    // Predict the stuffed CRC and delimiter that should follow
+    fn data_end(&mut self) -> Option<SoftCanEvent> {
+        let mut stuffer = Stuffer {
+            bits: SoftCanBits::new(),
+            same: self.same,
+            last: self.last,
+        };
+        for i in (0..15).rev() {
+            stuffer.push(self.crc >> i & 1 != 0);
+        }
+        stuffer.flush();
+        stuffer.bits.push(true);
+
+        let mut pattern = self.history;
+        for i in 0..stuffer.bits.len() {
+            pattern = pattern << 1 | stuffer.bits.bit(i) as u32;
+        }
+
+        self.next(Field::Crc, 15);
+        Some(SoftCanEvent::AckPattern(pattern))
+    }
+
+    fn frame(&self) -> CanFrame {
+        let id = if self.extended {
+            Id::Extended(ExtendedId::new(self.id).unwrap())
+        } else {
+            Id::Standard(StandardId::new(self.id as u16).unwrap())
+        };
+
+        CanFrame::new(id, self.remote, &self.data[..self.dlc]).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn standard_frame(id: u16, data: &[u8]) -> CanFrame {
+        CanFrame::new(StandardId::new(id).unwrap(), false, data).unwrap()
+    }
+
+    fn extended_frame(id: u32, data: &[u8]) -> CanFrame {
+        CanFrame::new(ExtendedId::new(id).unwrap(), false, data).unwrap()
+    }
+
+    // Decoder ready for a start of frame
+    fn idle_decoder() -> SoftCanDecoder {
+        let mut decoder = SoftCanDecoder::new();
+        for _ in 0..BUS_IDLE_BITS {
+            assert_eq!(decoder.push(true), None);
+        }
+        decoder
+    }
+
+    // Feed the frame bits followed by the ACK slot and delimiter, returning the
+    // events
+    fn decode(
+        decoder: &mut SoftCanDecoder,
+        bits: &SoftCanBits,
+        ack: bool,
+    ) -> [Option<SoftCanEvent>; 2] {
+        let mut events = [None, None];
+        let mut count = 0;
+
+        let trailer = [!ack, true];
+        let bits = (0..bits.len()).map(|i| bits.bit(i)).chain(trailer);
+        for bit in bits {
+            if let Some(event) = decoder.push(bit) {
+                events[count] = Some(event);
+                count += 1;
+            }
+        }
+
+        events
+    }
+
+    // CRC15 of the bytes, MSB first
+    fn crc15(bytes: &[u8]) -> u16 {
+        bytes.iter().fold(0, |crc, byte| {
+            (0..8)
+                .rev()
+                .fold(crc, |crc, i| crc15_bit(crc, byte >> i & 1 != 0))
+        })
+    }
+
+    #[test]
+    fn test_crc15_check() {
+        // Check value of CRC-15/CAN
+        assert_eq!(crc15(b"123456789"), 0x059E);
+    }
+
+    #[test]
+    fn test_encode_standard() {
+        let bits = SoftCanBits::encode(&standard_frame(0x123, &[0x11, 0x22]));
+        assert_eq!(bits.len(), 53);
+        assert_eq!(bits.words(), &[0x1230_6112, 0x20CB_7800]);
+    }
+
+    #[test]
+    fn test_encode_extended() {
+        let bits = SoftCanBits::encode(&extended_frame(0x18DA_F110, &[2, 1, 0x0C, 0, 0, 0, 0, 0]));
+        assert_eq!(bits.len(), 130);
+        assert_eq!(
+            bits.words(),
+            &[
+                0x636E_F110,
+                0x4821_0443,
+                0x0410_4104,
+                0x1041_1A86,
+                0x4000_0000
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_remote() {
+        let frame = CanFrame::new(StandardId::new(0x7FF).unwrap(), true, &[0; 2]).unwrap();
+        let bits = SoftCanBits::encode(&frame);
+        assert_eq!(bits.len(), 38);
+        assert_eq!(bits.words(), &[0x7DF6_11A4, 0x1C00_0000]);
+    }
+
+    #[test]
+    fn test_encode_stuffing() {
+        // All dominant fields, a stuff bit every 5 bits
+        let bits = SoftCanBits::encode(&standard_frame(0, &[]));
+        assert_eq!(bits.len(), 41);
+        assert_eq!(bits.words(), &[0x0410_4104, 0x1080_0000]);
+
+        let mut same = 0;
+        for i in 1..bits.len() {
+            same = if bits.bit(i) == bits.bit(i - 1) {
+                same + 1
+            } else {
+                0
+            };
+            assert!(same < 5);
+        }
+    }
+
+    #[test]
+    fn test_decode_roundtrip() {
+        let frames = [
+            standard_frame(0x123, &[0x11, 0x22]),
+            standard_frame(0, &[]),
+            extended_frame(0x18DA_F110, &[2, 1, 0x0C, 0, 0, 0, 0, 0]),
+            CanFrame::new(ExtendedId::new(0x1FFF_FFFF).unwrap(), true, &[0; 8]).unwrap(),
+        ];
+
+        let mut decoder = idle_decoder();
+        for frame in frames {
+            let bits = SoftCanBits::encode(&frame);
+            let [_, event] = decode(&mut decoder, &bits, true);
+            assert_eq!(event, Some(SoftCanEvent::Frame { frame, acked: true }));
+
+            // End of frame and intermission
+            for _ in 0..FRAME_END_BITS {
+                decoder.push(true);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_not_acked() {
+        let frame = standard_frame(0x7E8, &[0x04, 0x41]);
+        let bits = SoftCanBits::encode(&frame);
+        let [_, event] = decode(&mut idle_decoder(), &bits, false);
+        assert_eq!(
+            event,
+            Some(SoftCanEvent::Frame {
+                frame,
+                acked: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_ack_pattern() {
+        for frame in [standard_frame(0x123, &[0x11, 0x22]), standard_frame(0, &[])] {
+            let bits = SoftCanBits::encode(&frame);
+            let [pattern, _] = decode(&mut idle_decoder(), &bits, true);
+
+            // Last 32 bits up to the CRC delimiter
+            let expected = (bits.len() - 32..bits.len())
+                .fold(0, |pattern, i| pattern << 1 | bits.bit(i) as u32);
+            assert_eq!(pattern, Some(SoftCanEvent::AckPattern(expected)));
+            assert_eq!(bits.ack_pattern(), expected);
+        }
+    }
+
+    #[test]
+    fn test_decode_crc_error() {
+        let mut bits = SoftCanBits::encode(&standard_frame(0x123, &[0x11, 0x22]));
+        // Flip the last CRC bit, not followed by a stuff bit
+        bits.words[1] ^= 1 << (31 - 19);
+        let [_, event] = decode(&mut idle_decoder(), &bits, true);
+        assert_eq!(event, Some(SoftCanEvent::Error(SoftCanError::Crc)));
+    }
+
+    #[test]
+    fn test_decode_stuff_error() {
+        let mut decoder = idle_decoder();
+        // Start of frame and 5 more dominant bits
+        for _ in 0..5 {
+            assert_eq!(decoder.push(false), None);
+        }
+        assert_eq!(
+            decoder.push(false),
+            Some(SoftCanEvent::Error(SoftCanError::Stuff))
+        );
+        assert!(!decoder.is_idle());
+    }
+
+    #[test]
+    fn test_decode_waits_idle() {
+        let bits = SoftCanBits::encode(&standard_frame(0x123, &[]));
+        let mut decoder = SoftCanDecoder::new();
+        // Joining in the middle of a frame
+        for _ in 0..3 {
+            decoder.push(true);
+        }
+        assert_eq!(decode(&mut decoder, &bits, true), [None, None]);
+    }
+
+    #[test]
+    fn test_clock_divider() {
+        // 125 MHz to 16 cycles per bit at 500 kbit/s
+        assert_eq!(soft_can_clock_divider(125_000_000, 500_000), Some(0x0F_A0));
+        assert_eq!(soft_can_clock_divider(125_000_000, 10_000_000), None);
+    }
+}
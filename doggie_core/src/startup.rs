@@ -0,0 +1,775 @@
+use crate::device_id::crc16;
+use crate::filter::SoftwareFilter;
+use crate::mcp2515::Mcp2515Config;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use slcan::{
+    SlcanAcceptanceFilter, SlcanBitTiming, SlcanConfigKey, SlcanConfigValue, SlcanFilterMode,
+    SlcanFilterRule, SlcanFrameType, SlcanIdFormat, SlcanNickname, SlcanPayloadMatch,
+    SlcanStartupMode, SlcanTimestampMode, SLCAN_NICKNAME_MAX_LEN,
+};
+
+// Bytes written to flash for a record, each in a slot of its own. A multiple
+// of the write size of every supported flash (RP2040 pages are 256 bytes)
+pub const STARTUP_RECORD_SIZE: usize = 512;
+
+const STARTUP_MAGIC: [u8; 4] = *b"DGQS";
+// Version 2 added the sequence number and the device entries set with cW
+const STARTUP_VERSION: u8 = 2;
+// Magic, version, sequence number and payload length
+const HEADER_SIZE: usize = 11;
+const CRC_SIZE: usize = 2;
+
+// Bitrate set by the host, as it was given
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StartupBitrate {
+    // S, SA or cWb, in bits per second
+    Bps(u32),
+    // s or cWs
+    BitTiming(SlcanBitTiming),
+}
+
+// Settings saved with Q and applied at power on, along with the device
+// entries set one at a time with cW
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StartupConfig {
+    // Channel state at power on
+    pub mode: SlcanStartupMode,
+    // None keeps the bitrate the controller starts with
+    pub bitrate: Option<StartupBitrate>,
+    pub acceptance: SlcanAcceptanceFilter,
+    pub software_filter: SoftwareFilter,
+    pub timestamp: SlcanTimestampMode,
+    pub auto_poll: bool,
+    // Read by the board before the peripherals are set up, None keeps the
+    // board default
+    pub mcp2515_crystal_hz: Option<u32>,
+    pub uart_baud: Option<u32>,
+    pub nickname: SlcanNickname,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        StartupConfig {
+            mode: SlcanStartupMode::Off,
+            bitrate: None,
+            acceptance: SlcanAcceptanceFilter::default(),
+            software_filter: SoftwareFilter::new(),
+            timestamp: SlcanTimestampMode::Off,
+            auto_poll: true,
+            mcp2515_crystal_hz: None,
+            uart_baud: None,
+            nickname: SlcanNickname::default(),
+        }
+    }
+}
+
+struct RecordWriter<'a> {
+    buffer: &'a mut [u8],
+    index: usize,
+}
+
+impl RecordWriter<'_> {
+    fn u8(&mut self, value: u8) {
+        self.buffer[self.index] = value;
+        self.index += 1;
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buffer[self.index..self.index + 4].copy_from_slice(&value.to_le_bytes());
+        self.index += 4;
+    }
+}
+
+struct RecordReader<'a> {
+    buffer: &'a [u8],
+    index: usize,
+}
+
+impl RecordReader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let value = *self.buffer.get(self.index)?;
+        self.index += 1;
+        Some(value)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.buffer.get(self.index..self.index + 4)?;
+        self.index += 4;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+impl StartupConfig {
+    // Magic, version, sequence number, payload length, payload and the CRC of
+    // all of them. The rest of the slot is left erased
+    pub fn to_record(&self, sequence: u32, record: &mut [u8; STARTUP_RECORD_SIZE]) {
+        record.fill(0xFF);
+
+        let mut writer = RecordWriter {
+            buffer: &mut record[HEADER_SIZE..],
+            index: 0,
+        };
+        self.write_payload(&mut writer);
+        let len = writer.index;
+
+        record[0..4].copy_from_slice(&STARTUP_MAGIC);
+        record[4] = STARTUP_VERSION;
+        record[5..9].copy_from_slice(&sequence.to_le_bytes());
+        record[9..11].copy_from_slice(&(len as u16).to_le_bytes());
+
+        let crc = crc16(&record[..HEADER_SIZE + len]);
+        record[HEADER_SIZE + len..HEADER_SIZE + len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // The sequence number and the settings, None for an erased, corrupt,
+    // partially written or unknown record
+    pub fn from_record(record: &[u8]) -> Option<(u32, Self)> {
+        if record.len() < HEADER_SIZE || record[0..4] != STARTUP_MAGIC {
+            return None;
+        }
+        if record[4] != STARTUP_VERSION {
+            return None;
+        }
+
+        let sequence = u32::from_le_bytes([record[5], record[6], record[7], record[8]]);
+        let len = u16::from_le_bytes([record[9], record[10]]) as usize;
+        let crc = record.get(HEADER_SIZE + len..HEADER_SIZE + len + CRC_SIZE)?;
+        if crc16(&record[..HEADER_SIZE + len]) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return None;
+        }
+
+        let mut reader = RecordReader {
+            buffer: &record[HEADER_SIZE..HEADER_SIZE + len],
+            index: 0,
+        };
+        Some((sequence, Self::read_payload(&mut reader)?))
+    }
+
+    // Entry as read with cR
+    pub fn value(&self, key: SlcanConfigKey) -> SlcanConfigValue {
+        match key {
+            SlcanConfigKey::Bitrate => SlcanConfigValue::Bitrate(match self.bitrate {
+                Some(StartupBitrate::Bps(bps)) => Some(bps),
+                _ => None,
+            }),
+            SlcanConfigKey::BitTiming => SlcanConfigValue::BitTiming(match self.bitrate {
+                Some(StartupBitrate::BitTiming(timing)) => Some(timing),
+                _ => None,
+            }),
+            SlcanConfigKey::Crystal => SlcanConfigValue::Crystal(self.mcp2515_crystal_hz),
+            SlcanConfigKey::UartBaud => SlcanConfigValue::UartBaud(self.uart_baud),
+            SlcanConfigKey::Timestamp => SlcanConfigValue::Timestamp(self.timestamp),
+            SlcanConfigKey::Nickname => SlcanConfigValue::Nickname(self.nickname),
+        }
+    }
+
+    // Entry as written with cW, a bitrate replaces a bit timing and back
+    pub fn set(&mut self, value: SlcanConfigValue) {
+        match value {
+            SlcanConfigValue::Bitrate(bps) => self.bitrate = bps.map(StartupBitrate::Bps),
+            SlcanConfigValue::BitTiming(timing) => {
+                self.bitrate = timing.map(StartupBitrate::BitTiming)
+            }
+            SlcanConfigValue::Crystal(hz) => self.mcp2515_crystal_hz = hz,
+            SlcanConfigValue::UartBaud(baud) => self.uart_baud = baud,
+            SlcanConfigValue::Timestamp(mode) => self.timestamp = mode,
+            SlcanConfigValue::Nickname(nickname) => self.nickname = nickname,
+        }
+    }
+
+    // Board MCP2515 settings, with the saved crystal
+    pub fn mcp2515_config(&self) -> Mcp2515Config {
+        let mut config = Mcp2515Config::default();
+        if let Some(crystal_hz) = self.mcp2515_crystal_hz {
+            config.crystal_hz = crystal_hz;
+        }
+        config
+    }
+
+    fn write_payload(&self, writer: &mut RecordWriter) {
+        writer.u8(match self.mode {
+            SlcanStartupMode::Off => 0,
+            SlcanStartupMode::Normal => 1,
+            SlcanStartupMode::ListenOnly => 2,
+        });
+
+        match self.bitrate {
+            None => writer.u8(0),
+            Some(StartupBitrate::Bps(bps)) => {
+                writer.u8(1);
+                writer.u32(bps);
+            }
+            Some(StartupBitrate::BitTiming(timing)) => {
+                writer.u8(2);
+                writer.u8(timing.btr0);
+                writer.u8(timing.btr1);
+            }
+        }
+
+        writer.u32(self.acceptance.code);
+        writer.u32(self.acceptance.mask);
+        writer.u8(match self.acceptance.mode {
+            SlcanFilterMode::Dual => 0,
+            SlcanFilterMode::Single => 1,
+        });
+
+        writer.u8(match self.timestamp {
+            SlcanTimestampMode::Off => 0,
+            SlcanTimestampMode::Milliseconds => 1,
+            SlcanTimestampMode::Microseconds => 2,
+        });
+        writer.u8(self.auto_poll as u8);
+
+        // Zero is not a valid crystal or baud rate, it stands for None
+        writer.u32(self.mcp2515_crystal_hz.unwrap_or(0));
+        writer.u32(self.uart_baud.unwrap_or(0));
+
+        let nickname = self.nickname.as_bytes();
+        writer.u8(nickname.len() as u8);
+        for byte in nickname {
+            writer.u8(*byte);
+        }
+
+        let rules = self.software_filter.rules();
+        writer.u8(rules.len() as u8);
+        for rule in rules {
+            writer.u8(match rule.format {
+                SlcanIdFormat::Any => 0,
+                SlcanIdFormat::Standard => 1,
+                SlcanIdFormat::Extended => 2,
+            });
+            writer.u8(match rule.frame_type {
+                SlcanFrameType::Any => 0,
+                SlcanFrameType::Data => 1,
+                SlcanFrameType::Remote => 2,
+            });
+            writer.u32(rule.id_low);
+            writer.u32(rule.id_high);
+
+            let payload = rule.payload_matches();
+            writer.u8(payload.len() as u8);
+            for payload_match in payload {
+                writer.u8(payload_match.index);
+                writer.u8(payload_match.value);
+                writer.u8(payload_match.mask);
+            }
+        }
+    }
+
+    fn read_payload(reader: &mut RecordReader) -> Option<Self> {
+        let mode = match reader.u8()? {
+            0 => SlcanStartupMode::Off,
+            1 => SlcanStartupMode::Normal,
+            2 => SlcanStartupMode::ListenOnly,
+            _ => return None,
+        };
+
+        let bitrate = match reader.u8()? {
+            0 => None,
+            1 => Some(StartupBitrate::Bps(reader.u32()?)),
+            2 => Some(StartupBitrate::BitTiming(SlcanBitTiming {
+                btr0: reader.u8()?,
+                btr1: reader.u8()?,
+            })),
+            _ => return None,
+        };
+
+        let acceptance = SlcanAcceptanceFilter {
+            code: reader.u32()?,
+            mask: reader.u32()?,
+            mode: match reader.u8()? {
+                0 => SlcanFilterMode::Dual,
+                1 => SlcanFilterMode::Single,
+                _ => return None,
+            },
+        };
+
+        let timestamp = match reader.u8()? {
+            0 => SlcanTimestampMode::Off,
+            1 => SlcanTimestampMode::Milliseconds,
+            2 => SlcanTimestampMode::Microseconds,
+            _ => return None,
+        };
+        let auto_poll = reader.u8()? != 0;
+
+        let mcp2515_crystal_hz = Some(reader.u32()?).filter(|hz| *hz != 0);
+        let uart_baud = Some(reader.u32()?).filter(|baud| *baud != 0);
+
+        let mut name = [0; SLCAN_NICKNAME_MAX_LEN];
+        let name_len = reader.u8()? as usize;
+        for byte in name.get_mut(..name_len)? {
+            *byte = reader.u8()?;
+        }
+        let nickname = SlcanNickname::new(&name[..name_len])?;
+
+        let mut software_filter = SoftwareFilter::new();
+        for _ in 0..reader.u8()? {
+            let format = match reader.u8()? {
+                0 => SlcanIdFormat::Any,
+                1 => SlcanIdFormat::Standard,
+                2 => SlcanIdFormat::Extended,
+                _ => return None,
+            };
+            let frame_type = match reader.u8()? {
+                0 => SlcanFrameType::Any,
+                1 => SlcanFrameType::Data,
+                2 => SlcanFrameType::Remote,
+                _ => return None,
+            };
+
+            let mut rule = SlcanFilterRule::new(format, frame_type, reader.u32()?, reader.u32()?)?;
+            for _ in 0..reader.u8()? {
+                rule = rule.with_payload_match(SlcanPayloadMatch {
+                    index: reader.u8()?,
+                    value: reader.u8()?,
+                    mask: reader.u8()?,
+                })?;
+            }
+
+            if !software_filter.add(rule) {
+                return None;
+            }
+        }
+
+        Some(StartupConfig {
+            mode,
+            bitrate,
+            acceptance,
+            software_filter,
+            timestamp,
+            auto_poll,
+            mcp2515_crystal_hz,
+            uart_baud,
+            nickname,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StartupStoreError<E> {
+    Flash(E),
+    // The region can't hold two erase blocks of slots
+    NoSpace,
+}
+
+impl<E> From<E> for StartupStoreError<E> {
+    fn from(error: E) -> Self {
+        StartupStoreError::Flash(error)
+    }
+}
+
+// Startup settings in a flash region of their own, wear leveled over
+// slots. Every save goes to the slot after the newest record, with a higher
+// sequence number, and a block is only erased when the slots reach it. As
+// the newest record is never in the block being erased, a save cut short by
+// a power loss leaves the previous record in place.
+// `offset` must be at the start of an erase block, and the region must hold
+// at least two of them
+pub struct StartupStore<F> {
+    flash: F,
+    offset: u32,
+    size: usize,
+}
+
+impl<F: NorFlash> StartupStore<F> {
+    pub fn new(flash: F, offset: u32, size: usize) -> Self {
+        StartupStore {
+            flash,
+            offset,
+            size,
+        }
+    }
+
+    // Erase blocks may be smaller than a slot, they are then erased together
+    fn block_size() -> usize {
+        STARTUP_RECORD_SIZE.div_ceil(F::ERASE_SIZE) * F::ERASE_SIZE
+    }
+
+    fn slots_per_block() -> usize {
+        Self::block_size() / STARTUP_RECORD_SIZE
+    }
+
+    fn blocks(&self) -> usize {
+        self.size / Self::block_size()
+    }
+
+    fn slots(&self) -> usize {
+        self.blocks() * Self::slots_per_block()
+    }
+
+    fn slot_offset(&self, slot: usize) -> u32 {
+        let block = slot / Self::slots_per_block();
+        let index = slot % Self::slots_per_block();
+        self.offset + (block * Self::block_size() + index * STARTUP_RECORD_SIZE) as u32
+    }
+
+    fn read_slot(
+        &mut self,
+        slot: usize,
+        record: &mut [u8; STARTUP_RECORD_SIZE],
+    ) -> Result<(), F::Error> {
+        self.flash.read(self.slot_offset(slot), record)
+    }
+
+    // Slot, sequence number and settings of the newest valid record
+    fn newest(&mut self) -> Result<Option<(usize, u32, StartupConfig)>, F::Error> {
+        let mut newest: Option<(usize, u32, StartupConfig)> = None;
+        let mut record = [0; STARTUP_RECORD_SIZE];
+
+        for slot in 0..self.slots() {
+            self.read_slot(slot, &mut record)?;
+            let Some((sequence, config)) = StartupConfig::from_record(&record) else {
+                continue;
+            };
+
+            // Sequence numbers wrap around
+            let newer = match &newest {
+                Some((_, newest_sequence, _)) => sequence.wrapping_sub(*newest_sequence) as i32 > 0,
+                None => true,
+            };
+            if newer {
+                newest = Some((slot, sequence, config));
+            }
+        }
+
+        Ok(newest)
+    }
+
+    // None when nothing valid was saved
+    pub fn load(&mut self) -> Option<StartupConfig> {
+        self.newest().ok().flatten().map(|(_, _, config)| config)
+    }
+
+    // Settings to boot with, the defaults when nothing valid was saved
+    pub fn load_or_default(&mut self) -> StartupConfig {
+        self.load().unwrap_or_default()
+    }
+
+    pub fn save(&mut self, config: &StartupConfig) -> Result<(), StartupStoreError<F::Error>> {
+        if self.blocks() < 2 {
+            return Err(StartupStoreError::NoSpace);
+        }
+
+        let (mut slot, sequence) = match self.newest()? {
+            Some((slot, sequence, _)) => ((slot + 1) % self.slots(), sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+
+        // A slot written by an interrupted save is skipped, up to the next
+        // block, which is erased
+        let mut record = [0; STARTUP_RECORD_SIZE];
+        loop {
+            if slot % Self::slots_per_block() == 0 {
+                let from = self.slot_offset(slot);
+                self.flash.erase(from, from + Self::block_size() as u32)?;
+                break;
+            }
+
+            self.read_slot(slot, &mut record)?;
+            if record.iter().all(|byte| *byte == 0xFF) {
+                break;
+            }
+            slot = (slot + 1) % self.slots();
+        }
+
+        config.to_record(sequence, &mut record);
+        self.flash.write(self.slot_offset(slot), &record)?;
+        Ok(())
+    }
+
+    // Erases every slot, the defaults are used from the next boot on
+    pub fn factory_reset(&mut self) -> Result<(), StartupStoreError<F::Error>> {
+        if self.blocks() < 2 {
+            return Err(StartupStoreError::NoSpace);
+        }
+
+        let size = (self.blocks() * Self::block_size()) as u32;
+        self.flash.erase(self.offset, self.offset + size)?;
+        Ok(())
+    }
+}
+
+// For boards without flash to spare, saving always fails
+pub struct NoFlash;
+
+impl ErrorType for NoFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for NoFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, _offset: u32, _bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Err(NorFlashErrorKind::OutOfBounds)
+    }
+
+    fn capacity(&self) -> usize {
+        0
+    }
+}
+
+impl NorFlash for NoFlash {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = 1;
+
+    fn erase(&mut self, _from: u32, _to: u32) -> Result<(), Self::Error> {
+        Err(NorFlashErrorKind::OutOfBounds)
+    }
+
+    fn write(&mut self, _offset: u32, _bytes: &[u8]) -> Result<(), Self::Error> {
+        Err(NorFlashErrorKind::OutOfBounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockFlash, MOCK_FLASH_SIZE};
+
+    // Four blocks of two slots
+    fn test_store() -> StartupStore<MockFlash> {
+        StartupStore::new(MockFlash::new(), 0, MOCK_FLASH_SIZE)
+    }
+
+    fn test_config() -> StartupConfig {
+        let mut software_filter = SoftwareFilter::new();
+        let rule =
+            SlcanFilterRule::new(SlcanIdFormat::Standard, SlcanFrameType::Data, 0x100, 0x1FF)
+                .unwrap()
+                .with_payload_match(SlcanPayloadMatch {
+                    index: 2,
+                    value: 0x10,
+                    mask: 0xF0,
+                })
+                .unwrap();
+        software_filter.add(rule);
+
+        StartupConfig {
+            mode: SlcanStartupMode::ListenOnly,
+            bitrate: Some(StartupBitrate::Bps(500_000)),
+            acceptance: SlcanAcceptanceFilter {
+                code: 0x1234_5678,
+                mask: 0x0000_FFFF,
+                mode: SlcanFilterMode::Single,
+            },
+            software_filter,
+            timestamp: SlcanTimestampMode::Milliseconds,
+            auto_poll: false,
+            mcp2515_crystal_hz: Some(16_000_000),
+            uart_baud: Some(115_200),
+            nickname: SlcanNickname::new(b"Bench car").unwrap(),
+        }
+    }
+
+    fn nicknamed(name: &[u8]) -> StartupConfig {
+        StartupConfig {
+            nickname: SlcanNickname::new(name).unwrap(),
+            ..StartupConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let mut record = [0; STARTUP_RECORD_SIZE];
+        test_config().to_record(7, &mut record);
+        assert_eq!(
+            StartupConfig::from_record(&record),
+            Some((7, test_config()))
+        );
+
+        let config = StartupConfig {
+            bitrate: Some(StartupBitrate::BitTiming(SlcanBitTiming {
+                btr0: 0x03,
+                btr1: 0x1C,
+            })),
+            ..StartupConfig::default()
+        };
+        config.to_record(0, &mut record);
+        assert_eq!(StartupConfig::from_record(&record), Some((0, config)));
+    }
+
+    #[test]
+    fn test_record_erased() {
+        assert_eq!(
+            StartupConfig::from_record(&[0xFF; STARTUP_RECORD_SIZE]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_record_corrupt() {
+        let mut record = [0; STARTUP_RECORD_SIZE];
+        test_config().to_record(0, &mut record);
+        record[HEADER_SIZE + 1] ^= 0x01;
+        assert_eq!(StartupConfig::from_record(&record), None);
+    }
+
+    #[test]
+    fn test_record_other_version() {
+        let mut record = [0; STARTUP_RECORD_SIZE];
+        test_config().to_record(0, &mut record);
+        record[4] = STARTUP_VERSION + 1;
+        assert_eq!(StartupConfig::from_record(&record), None);
+    }
+
+    #[test]
+    fn test_config_values() {
+        let mut config = StartupConfig::default();
+        config.set(SlcanConfigValue::BitTiming(Some(SlcanBitTiming {
+            btr0: 0x03,
+            btr1: 0x1C,
+        })));
+        assert_eq!(
+            config.value(SlcanConfigKey::Bitrate),
+            SlcanConfigValue::Bitrate(None)
+        );
+
+        // The bitrate replaces the bit timing
+        config.set(SlcanConfigValue::Bitrate(Some(250_000)));
+        assert_eq!(config.bitrate, Some(StartupBitrate::Bps(250_000)));
+        assert_eq!(
+            config.value(SlcanConfigKey::BitTiming),
+            SlcanConfigValue::BitTiming(None)
+        );
+
+        config.set(SlcanConfigValue::Crystal(Some(20_000_000)));
+        assert_eq!(config.mcp2515_config().crystal_hz, 20_000_000);
+        assert_eq!(
+            config.value(SlcanConfigKey::Crystal),
+            SlcanConfigValue::Crystal(Some(20_000_000))
+        );
+    }
+
+    #[test]
+    fn test_store_save_load() {
+        let mut store = test_store();
+        assert_eq!(store.load(), None);
+        assert_eq!(store.load_or_default(), StartupConfig::default());
+
+        store.save(&test_config()).unwrap();
+        assert_eq!(store.load(), Some(test_config()));
+
+        store.save(&StartupConfig::default()).unwrap();
+        assert_eq!(store.load(), Some(StartupConfig::default()));
+    }
+
+    #[test]
+    fn test_store_startup_off() {
+        let mut store = test_store();
+        store.save(&test_config()).unwrap();
+        assert_eq!(store.load(), Some(test_config()));
+
+        // Q0 keeps the settings, but the channel stays closed
+        let config = StartupConfig {
+            mode: SlcanStartupMode::Off,
+            ..test_config()
+        };
+        store.save(&config).unwrap();
+        assert_eq!(store.load(), Some(config));
+    }
+
+    #[test]
+    fn test_store_wear_leveling() {
+        let mut store = test_store();
+
+        // Every slot is used before one is written again, with a single
+        // erase per block
+        for round in 0..3u8 {
+            for slot in 0..8u8 {
+                store
+                    .save(&nicknamed(&[b'a' + round, b'0' + slot]))
+                    .unwrap();
+                assert_eq!(store.load(), Some(nicknamed(&[b'a' + round, b'0' + slot])));
+            }
+        }
+        assert_eq!(store.flash.erases, 3 * 4);
+    }
+
+    #[test]
+    fn test_store_corrupt_record() {
+        let mut store = test_store();
+        store.save(&test_config()).unwrap();
+        store.save(&nicknamed(b"newer")).unwrap();
+
+        // The newest record is in the second slot
+        store.flash.data[STARTUP_RECORD_SIZE + HEADER_SIZE] ^= 0x01;
+        assert_eq!(store.load(), Some(test_config()));
+
+        store.flash.data[HEADER_SIZE] ^= 0x01;
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn test_store_partial_write() {
+        let mut store = test_store();
+        store.save(&test_config()).unwrap();
+
+        // Power lost in the middle of the record
+        store.flash.write_budget = Some(HEADER_SIZE + 8);
+        assert!(store.save(&nicknamed(b"lost")).is_err());
+        assert_eq!(store.load(), Some(test_config()));
+
+        // The half written slot is skipped
+        store.flash.write_budget = None;
+        store.save(&nicknamed(b"saved")).unwrap();
+        assert_eq!(store.load(), Some(nicknamed(b"saved")));
+        assert_eq!(
+            StartupConfig::from_record(&store.flash.data[2 * STARTUP_RECORD_SIZE..])
+                .map(|(_, config)| config),
+            Some(nicknamed(b"saved"))
+        );
+    }
+
+    #[test]
+    fn test_store_partial_write_at_wrap() {
+        let mut store = test_store();
+        for slot in 0..8u8 {
+            store.save(&nicknamed(&[b'0' + slot])).unwrap();
+        }
+
+        // The save to the first block erased it, the newest record is
+        // still in the last one
+        store.flash.write_budget = Some(0);
+        assert!(store.save(&nicknamed(b"lost")).is_err());
+        assert_eq!(store.load(), Some(nicknamed(b"7")));
+    }
+
+    #[test]
+    fn test_store_sequence_wrap() {
+        let mut store = test_store();
+        let mut record = [0; STARTUP_RECORD_SIZE];
+        nicknamed(b"older").to_record(u32::MAX, &mut record);
+        store.flash.data[..STARTUP_RECORD_SIZE].copy_from_slice(&record);
+
+        store.save(&nicknamed(b"newer")).unwrap();
+        assert_eq!(store.load(), Some(nicknamed(b"newer")));
+    }
+
+    #[test]
+    fn test_store_factory_reset() {
+        let mut store = test_store();
+        store.save(&test_config()).unwrap();
+        store.factory_reset().unwrap();
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn test_store_no_space() {
+        let mut store = StartupStore::new(MockFlash::new(), 0, 1024);
+        assert_eq!(store.save(&test_config()), Err(StartupStoreError::NoSpace));
+    }
+
+    #[test]
+    fn test_store_out_of_flash() {
+        let mut store = StartupStore::new(MockFlash::new(), 4096, 2048);
+        assert!(store.save(&test_config()).is_err());
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn test_no_flash() {
+        let mut store = StartupStore::new(NoFlash, 0, 0);
+        assert_eq!(store.save(&test_config()), Err(StartupStoreError::NoSpace));
+        assert_eq!(store.load(), None);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::can::{BitTiming, CanStatus};
+use crate::filter::{FilterEntry, FilterFit};
+use embedded_can::Id;
+use slcan::{SlcanAcceptanceFilter, SlcanFilterMode};
+
+// The ESP32 TWAI runs from the 80 MHz APB clock
+pub const TWAI_CLOCK_HZ: u32 = 80_000_000;
+
+// Only even prescalers are supported
+const BRP_MIN: u16 = 2;
+const BRP_MAX: u16 = 128;
+// Time quanta per bit, longest first so the sample point is more precise
+const QUANTA_MIN: u32 = 8;
+const QUANTA_MAX: u32 = 25;
+const TSEG1_MAX: u8 = 16;
+const TSEG2_MAX: u8 = 8;
+const SJW_MAX: u8 = 4;
+// Largest bitrate error accepted, in parts per thousand
+const BITRATE_TOLERANCE: u32 = 5;
+
+// STATUS register
+const STATUS_OVERRUN: u8 = 1 << 1;
+const STATUS_TX_BUFFER: u8 = 1 << 2;
+const STATUS_ERROR: u8 = 1 << 6;
+const STATUS_BUS_OFF: u8 = 1 << 7;
+
+// Bit timing closest to `bps`, with the sample point around 80%. Returns
+// None if the TWAI can't get close enough, as for rates under 25 kbit/s
+pub fn twai_bit_timing(bps: u32) -> Option<BitTiming> {
+    let mut best: Option<(u32, u16, u32)> = None;
+
+    for prescaler in (BRP_MIN..=BRP_MAX).step_by(2) {
+        for quanta in (QUANTA_MIN..=QUANTA_MAX).rev() {
+            let rate = TWAI_CLOCK_HZ / (prescaler as u32 * quanta);
+            let error = rate.abs_diff(bps);
+
+            if best.is_none_or(|(best_error, _, _)| error < best_error) {
+                best = Some((error, prescaler, quanta));
+            }
+        }
+    }
+
+    let (error, prescaler, quanta) = best?;
+    if error * 1000 > bps * BITRATE_TOLERANCE {
+        return None;
+    }
+
+    // Keep tseg1 in range for the longest bits
+    let tseg2 = (quanta / 5)
+        .max(quanta - 1 - TSEG1_MAX as u32)
+        .clamp(1, TSEG2_MAX as u32) as u8;
+    let tseg1 = (quanta - 1) as u8 - tseg2;
+
+    Some(BitTiming {
+        clock_hz: TWAI_CLOCK_HZ,
+        prescaler,
+        sjw: tseg2.min(3),
+        tseg1,
+        tseg2,
+        triple_sample: false,
+    })
+}
+
+// BUS_TIMING_0 and BUS_TIMING_1 values for `timing`, None if the TWAI can't
+// do it
+pub fn twai_btr(timing: &BitTiming) -> Option<[u8; 2]> {
+    let timing = timing.with_clock(TWAI_CLOCK_HZ)?;
+
+    if !(BRP_MIN..=BRP_MAX).contains(&timing.prescaler)
+        || timing.prescaler & 1 != 0
+        || !(1..=SJW_MAX).contains(&timing.sjw)
+        || !(1..=TSEG1_MAX).contains(&timing.tseg1)
+        || !(1..=TSEG2_MAX).contains(&timing.tseg2)
+    {
+        return None;
+    }
+
+    let btr0 = (timing.prescaler / 2 - 1) as u8 | (timing.sjw - 1) << 6;
+    let btr1 = (timing.tseg1 - 1) | (timing.tseg2 - 1) << 4 | (timing.triple_sample as u8) << 7;
+
+    Some([btr0, btr1])
+}
+
+// There is no error passive flag, CanStatus::bus_state tells it from the
+// counters
+pub fn twai_status_from_registers(status: u8, tec: u8, rec: u8) -> CanStatus {
+    CanStatus {
+        tx_fifo_full: status & STATUS_TX_BUFFER == 0,
+        error_warning: status & STATUS_ERROR != 0,
+        data_overrun: status & STATUS_OVERRUN != 0,
+        bus_off: status & STATUS_BUS_OFF != 0,
+        tx_error_count: tec,
+        rx_error_count: rec,
+        ..CanStatus::default()
+    }
+}
+
+// One of the two filters of the dual mode, on ID.10-0 and RTR of standard
+// frames or ID.28-13 of extended ones. `care` has the bits that must match.
+#[derive(Clone, Copy)]
+struct DualFilter {
+    code: u16,
+    care: u16,
+    standard: bool,
+}
+
+impl DualFilter {
+    fn new(entry: &FilterEntry) -> Self {
+        match entry.id {
+            Id::Standard(id) => DualFilter {
+                code: id.as_raw() << 5,
+                care: ((entry.mask & 0x7FF) << 5) as u16,
+                standard: true,
+            },
+            Id::Extended(id) => DualFilter {
+                code: (id.as_raw() >> 13) as u16,
+                care: ((entry.mask >> 13) & 0xFFFF) as u16,
+                standard: false,
+            },
+        }
+    }
+
+    // Filter matching both, keeping the bits they agree on
+    fn merge(&self, other: &DualFilter) -> DualFilter {
+        DualFilter {
+            code: self.code,
+            care: self.care & other.care & !(self.code ^ other.code),
+            standard: self.standard || other.standard,
+        }
+    }
+}
+
+// Acceptance code and mask letting through at least the frames matching any
+// of the filters. A single entry uses the single filter mode, more entries are
+// merged into the two filters of the dual mode. The other id format always
+// gets through, so only an empty list is exact.
+pub fn twai_acceptance_from_filters(filters: &[FilterEntry]) -> (SlcanAcceptanceFilter, FilterFit) {
+    match filters {
+        [] => (SlcanAcceptanceFilter::default(), FilterFit::Exact),
+        [entry] => {
+            let (code, care) = match entry.id {
+                Id::Standard(id) => ((id.as_raw() as u32) << 21, (entry.mask & 0x7FF) << 21),
+                Id::Extended(id) => (id.as_raw() << 3, (entry.mask & 0x1FFF_FFFF) << 3),
+            };
+            let filter = SlcanAcceptanceFilter {
+                code,
+                mask: !care,
+                mode: SlcanFilterMode::Single,
+            };
+
+            (filter, FilterFit::Superset)
+        }
+        [first, second, rest @ ..] => {
+            let mut filter1 = DualFilter::new(first);
+            let mut filter2 = DualFilter::new(second);
+
+            // Merge each remaining entry where it loses the fewest bits
+            for entry in rest {
+                let entry = DualFilter::new(entry);
+                let merged1 = filter1.merge(&entry);
+                let merged2 = filter2.merge(&entry);
+
+                if merged1.care.count_ones() + filter2.care.count_ones()
+                    >= filter1.care.count_ones() + merged2.care.count_ones()
+                {
+                    filter1 = merged1;
+                } else {
+                    filter2 = merged2;
+                }
+            }
+
+            // Filter 1 compares the first data byte of standard frames on its
+            // low bits and on the low nibble of filter 2
+            if filter1.standard {
+                filter1.care &= !0x001F;
+                filter2.care &= !0x000F;
+            }
+            // Don't care about RTR
+            if filter2.standard {
+                filter2.care &= !0x001F;
+            }
+
+            let filter = SlcanAcceptanceFilter {
+                code: (filter1.code as u32) << 16 | filter2.code as u32,
+                mask: !((filter1.care as u32) << 16 | filter2.care as u32),
+                mode: SlcanFilterMode::Dual,
+            };
+
+            (filter, FilterFit::Superset)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::can::BusState;
+    use embedded_can::{ExtendedId, StandardId};
+    use slcan::CanFrame;
+
+    fn standard(id: u16, mask: u32) -> FilterEntry {
+        FilterEntry {
+            id: Id::Standard(StandardId::new(id).unwrap()),
+            mask,
+        }
+    }
+
+    fn extended(id: u32, mask: u32) -> FilterEntry {
+        FilterEntry {
+            id: Id::Extended(ExtendedId::new(id).unwrap()),
+            mask,
+        }
+    }
+
+    fn frame(id: Id, remote: bool, data: &[u8]) -> CanFrame {
+        CanFrame::new(id, remote, data).unwrap()
+    }
+
+    // Every frame on the entry ids must get through, whatever its payload
+    fn assert_accepts(filter: &SlcanAcceptanceFilter, entries: &[FilterEntry]) {
+        for entry in entries {
+            assert!(filter.accepts(&frame(entry.id, false, &[])));
+            assert!(filter.accepts(&frame(entry.id, false, &[0xA5, 0x5A])));
+            assert!(filter.accepts(&frame(entry.id, true, &[])));
+        }
+    }
+
+    #[test]
+    fn test_bit_timing_1000k() {
+        let timing = twai_bit_timing(1_000_000).unwrap();
+        assert_eq!(timing.prescaler, 4);
+        assert_eq!((timing.tseg1, timing.tseg2, timing.sjw), (15, 4, 3));
+        assert_eq!(timing.bitrate(), 1_000_000);
+        assert_eq!(twai_btr(&timing), Some([0x81, 0x3E]));
+    }
+
+    #[test]
+    fn test_bit_timing_all_rates() {
+        for bps in [
+            40_000, 50_000, 80_000, 100_000, 125_000, 200_000, 250_000, 500_000, 800_000, 1_000_000,
+        ] {
+            let timing = twai_bit_timing(bps).unwrap();
+            assert_eq!(timing.bitrate(), bps);
+            assert!(twai_btr(&timing).is_some());
+        }
+
+        let timing = twai_bit_timing(31_250).unwrap();
+        assert_eq!(timing.bitrate(), 31_250);
+        let timing = twai_bit_timing(33_333).unwrap();
+        assert_eq!(timing.bitrate(), 33_333);
+        assert_eq!((timing.tseg1, timing.tseg2), (16, 8));
+    }
+
+    #[test]
+    fn test_bit_timing_too_slow() {
+        assert_eq!(twai_bit_timing(20_000), None);
+        assert_eq!(twai_bit_timing(5_000), None);
+    }
+
+    #[test]
+    fn test_btr_from_slcan() {
+        // 500 kbit/s as BTR0=0x00 BTR1=0x1C on the 8 MHz SJA1000 clock
+        let timing = BitTiming::from(slcan::SlcanBitTiming {
+            btr0: 0x00,
+            btr1: 0x1C,
+        });
+        assert_eq!(twai_btr(&timing), Some([0x04, 0x1C]));
+    }
+
+    #[test]
+    fn test_btr_out_of_range() {
+        let timing = BitTiming {
+            clock_hz: TWAI_CLOCK_HZ,
+            prescaler: 3,
+            sjw: 1,
+            tseg1: 12,
+            tseg2: 3,
+            triple_sample: false,
+        };
+        assert_eq!(twai_btr(&timing), None);
+        assert_eq!(
+            twai_btr(&BitTiming {
+                prescaler: 4,
+                tseg1: 17,
+                ..timing
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_status() {
+        let status = twai_status_from_registers(STATUS_OVERRUN | STATUS_ERROR, 130, 5);
+        assert!(status.data_overrun);
+        assert!(status.error_warning);
+        assert_eq!(status.bus_state(), BusState::ErrorPassive);
+        assert!(status.tx_fifo_full);
+        assert!(!status.bus_off);
+
+        let status = twai_status_from_registers(STATUS_TX_BUFFER, 0, 0);
+        assert!(!status.tx_fifo_full);
+        assert_eq!(status.bus_state(), BusState::ErrorActive);
+    }
+
+    #[test]
+    fn test_acceptance_empty() {
+        let (filter, fit) = twai_acceptance_from_filters(&[]);
+        assert!(filter.accepts_all());
+        assert_eq!(fit, FilterFit::Exact);
+    }
+
+    #[test]
+    fn test_acceptance_single_standard() {
+        let entries = [standard(0x123, 0x7FF)];
+        let (filter, _) = twai_acceptance_from_filters(&entries);
+        assert_eq!(filter.mode, SlcanFilterMode::Single);
+        assert_eq!(filter.code, 0x2460_0000);
+        assert_eq!(filter.mask, 0x001F_FFFF);
+        assert_accepts(&filter, &entries);
+        assert!(!filter.accepts(&frame(standard(0x124, 0).id, false, &[])));
+    }
+
+    #[test]
+    fn test_acceptance_single_extended() {
+        let entries = [extended(0x18DA_F110, 0x1FFF_FF00)];
+        let (filter, _) = twai_acceptance_from_filters(&entries);
+        assert_accepts(&filter, &entries);
+        assert!(filter.accepts(&frame(extended(0x18DA_F1FF, 0).id, false, &[])));
+        assert!(!filter.accepts(&frame(extended(0x18DA_F210, 0).id, false, &[])));
+    }
+
+    #[test]
+    fn test_acceptance_dual() {
+        let entries = [standard(0x123, 0x7FF), standard(0x456, 0x7FF)];
+        let (filter, _) = twai_acceptance_from_filters(&entries);
+        assert_eq!(filter.mode, SlcanFilterMode::Dual);
+        assert_accepts(&filter, &entries);
+        assert!(!filter.accepts(&frame(standard(0x789, 0).id, false, &[0x11])));
+    }
+
+    #[test]
+    fn test_acceptance_merged() {
+        let entries = [
+            standard(0x7E8, 0x7FF),
+            extended(0x18DA_F110, 0x1FFF_FFFF),
+            standard(0x7E9, 0x7FF),
+            extended(0x18DA_F111, 0x1FFF_FFFF),
+            standard(0x100, 0x700),
+        ];
+        let (filter, fit) = twai_acceptance_from_filters(&entries);
+        assert_eq!(fit, FilterFit::Superset);
+        assert_accepts(&filter, &entries);
+    }
+}
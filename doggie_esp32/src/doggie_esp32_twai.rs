@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+
+mod nvs;
+mod twai_device;
+
+use embassy_executor::Spawner;
+use esp_backtrace as _;
+use esp_println as _;
+use esp_hal::{
+    efuse::Efuse,
+    timer::timg::TimerGroup,
+    twai::{BaudRate, TwaiConfiguration, TwaiMode},
+    uart::Uart,
+    Async,
+};
+use defmt::info;
+use doggie_core::*;
+use nvs::FlashType;
+use twai_device::TwaiCan;
+
+const READ_BUF_SIZE: usize = 64;
+// Unless another one was saved with cWu
+const DEFAULT_BAUDRATE: u32 = 115_200;
+
+#[esp_hal_embassy::main]
+async fn main(spawner: Spawner) {
+    info!("Init!");
+    let p = esp_hal::init(esp_hal::Config::default());
+
+    // Factory MAC address, unique to each chip
+    let device_id = DeviceId::new(&Efuse::read_base_mac_address());
+
+    // Startup settings saved in flash
+    let mut startup_store = nvs::startup_store();
+    let startup = startup_store.load_or_default();
+
+    let timg0 = TimerGroup::new(p.TIMG0);
+    esp_hal_embassy::init(timg0.timer0);
+
+    // Setup UART (using these pins, also passes through USB)
+    let (tx_pin, rx_pin) = (p.GPIO1, p.GPIO3);
+
+    let config = esp_hal::uart::Config::default()
+        .baudrate(startup.uart_baud.unwrap_or(DEFAULT_BAUDRATE))
+        .rx_fifo_full_threshold(READ_BUF_SIZE as u16);
+
+    let serial = Uart::new_with_config(p.UART0, config, rx_pin, tx_pin)
+        .unwrap()
+        .into_async();
+
+    info!("Serial init ok");
+
+    // Setup TWAI, only a transceiver is needed on these pins
+    let (can_tx_pin, can_rx_pin) = (p.GPIO21, p.GPIO22);
+
+    let twai = TwaiConfiguration::new(
+        p.TWAI0,
+        can_rx_pin,
+        can_tx_pin,
+        BaudRate::B250K,
+        TwaiMode::Normal,
+    )
+    .into_async()
+    .start();
+
+    info!("TWAI init ok");
+
+    // Create the Bsp
+    let bsp = Bsp::new(TwaiCan::new(twai), serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
+
+    // Create and run the Doggie core
+    let core = Core::new(spawner, bsp);
+
+    core_run!(core);
+}
+
+core_create_tasks!(Uart<'static, Async>, TwaiCan, FlashType);
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 
+mod nvs;
 mod spi_device;
 mod soft_timer;
 
@@ -9,6 +10,8 @@ use embassy_executor::Spawner;
 use esp_backtrace as _;
 use esp_println as _;
 use esp_hal::{
+    efuse::Efuse,
+    gpio::{Input, Pull},
     prelude::*,
     spi::{
         master::Spi,
@@ -19,25 +22,36 @@ use esp_hal::{
     Async,
     Blocking
 };
-use mcp2515::MCP2515;
 use defmt::info;
 use doggie_core::*;
+use nvs::FlashType;
 use spi_device::CustomSpiDevice;
 
 const READ_BUF_SIZE: usize = 64;
+// Unless another one was saved with cWu
+const DEFAULT_BAUDRATE: u32 = 115_200;
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
     info!("Init!");
     let p = esp_hal::init(esp_hal::Config::default());
 
+    // Factory MAC address, unique to each chip
+    let device_id = DeviceId::new(&Efuse::read_base_mac_address());
+
+    // Startup settings saved in flash
+    let mut startup_store = nvs::startup_store();
+    let startup = startup_store.load_or_default();
+
     let timg0 = TimerGroup::new(p.TIMG0);
     esp_hal_embassy::init(timg0.timer0);
 
     // Setup UART (using these pins, also passes through USB)
     let (tx_pin, rx_pin) = (p.GPIO1, p.GPIO3);
     
-    let config = esp_hal::uart::Config::default().rx_fifo_full_threshold(READ_BUF_SIZE as u16);
+    let config = esp_hal::uart::Config::default()
+        .baudrate(startup.uart_baud.unwrap_or(DEFAULT_BAUDRATE))
+        .rx_fifo_full_threshold(READ_BUF_SIZE as u16);
 
     let serial = Uart::new_with_config(p.UART0, config, rx_pin, tx_pin)
         .unwrap()
@@ -68,8 +82,16 @@ async fn main(spawner: Spawner) {
     // Create SoftTimer
     let delay = SoftTimer {};
 
+    // MCP2515 INT pin
+    let int = Input::new(p.GPIO4, Pull::Up);
+
     // Create the Bsp
-    let bsp = Bsp::new_with_mcp2515(spi, delay, serial);
+    // Crystal, CLKOUT and initial bitrate, set through the mcp2515-* features.
+    // A crystal saved with cWx takes over
+    let config = startup.mcp2515_config();
+    let bsp = Bsp::new_with_mcp2515_irq(spi, delay, int, config, serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
 
     info!("MCP2515 init ok");    
 
@@ -81,5 +103,6 @@ async fn main(spawner: Spawner) {
 
 core_create_tasks!(
     Uart<'static, Async>,
-    MCP2515<CustomSpiDevice<'static, Blocking>>
+    Mcp2515Irq<CustomSpiDevice<'static, Blocking>, Input<'static>>,
+    FlashType
 );
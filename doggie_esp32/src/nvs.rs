@@ -0,0 +1,13 @@
+use doggie_core::StartupStore;
+use esp_storage::FlashStorage;
+
+// NVS partition of the default partition table. Nothing else uses it
+// without ESP-IDF, the startup settings take its first two 4K sectors
+const STARTUP_OFFSET: u32 = 0x9000;
+const STARTUP_SIZE: usize = 0x2000;
+
+pub type FlashType = FlashStorage;
+
+pub fn startup_store() -> StartupStore<FlashType> {
+    StartupStore::new(FlashStorage::new(), STARTUP_OFFSET, STARTUP_SIZE)
+}
@@ -0,0 +1,242 @@
+use defmt::{error, info};
+use doggie_core::{
+    twai_acceptance_from_filters, twai_bit_timing, twai_btr, twai_status_from_registers,
+    AsyncCanDevice, BitTiming, BitrateError, CanBitrate, CanDevice, CanMode, CanStatus,
+    FilterEntry, FilterFit,
+};
+use embassy_time::Instant;
+use embedded_can::{blocking::Can, ErrorKind};
+use esp_hal::peripherals::TWAI0;
+use esp_hal::twai::{EspTwaiFrame, Twai};
+use esp_hal::Async;
+use slcan::{SlcanAcceptanceFilter, SlcanFilterMode};
+
+type RegisterBlock = <TWAI0 as core::ops::Deref>::Target;
+
+// MODE bits
+const MODE_RESET: u32 = 1 << 0;
+const MODE_LISTEN_ONLY: u32 = 1 << 1;
+const MODE_SELF_TEST: u32 = 1 << 2;
+const MODE_SINGLE_FILTER: u32 = 1 << 3;
+
+// CMD bits
+const CMD_CLEAR_OVERRUN: u32 = 1 << 3;
+
+// STATUS bits
+const STATUS_TX_COMPLETE: u32 = 1 << 3;
+
+#[derive(Debug)]
+pub struct TwaiError(ErrorKind);
+
+impl embedded_can::Error for TwaiError {
+    fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
+// The esp-hal driver handles frames and interrupts, the configuration it
+// doesn't expose is written straight to the registers in reset mode
+pub struct TwaiCan {
+    twai: Twai<'static, Async>,
+    // Frame in the TX buffer, until it is sent
+    tx_pending: Option<EspTwaiFrame>,
+}
+
+impl TwaiCan {
+    pub fn new(twai: Twai<'static, Async>) -> Self {
+        TwaiCan {
+            twai,
+            tx_pending: None,
+        }
+    }
+
+    fn registers() -> &'static RegisterBlock {
+        unsafe { &*TWAI0::PTR }
+    }
+
+    // Registers can only be written in reset mode. `f` may change the mode
+    // bits, they are written back once done, leaving reset mode if the
+    // controller was running
+    fn configure(&mut self, f: impl FnOnce(&RegisterBlock, &mut u32)) {
+        let regs = Self::registers();
+        let mut mode = regs.mode().read().bits();
+
+        regs.mode().write(|w| unsafe { w.bits(mode | MODE_RESET) });
+        f(regs, &mut mode);
+        regs.mode().write(|w| unsafe { w.bits(mode) });
+    }
+
+    fn write_bus_timing(&mut self, btr: [u8; 2]) {
+        self.configure(|regs, _| {
+            regs.bus_timing_0()
+                .write(|w| unsafe { w.bits(btr[0] as u32) });
+            regs.bus_timing_1()
+                .write(|w| unsafe { w.bits(btr[1] as u32) });
+        });
+    }
+
+    // ACR0-3 and AMR0-3 share the DATA registers while in reset mode
+    fn write_acceptance(&mut self, filter: &SlcanAcceptanceFilter) {
+        let code = filter.code.to_be_bytes();
+        let mask = filter.mask.to_be_bytes();
+
+        self.configure(|regs, mode| {
+            regs.data_0().write(|w| unsafe { w.bits(code[0] as u32) });
+            regs.data_1().write(|w| unsafe { w.bits(code[1] as u32) });
+            regs.data_2().write(|w| unsafe { w.bits(code[2] as u32) });
+            regs.data_3().write(|w| unsafe { w.bits(code[3] as u32) });
+            regs.data_4().write(|w| unsafe { w.bits(mask[0] as u32) });
+            regs.data_5().write(|w| unsafe { w.bits(mask[1] as u32) });
+            regs.data_6().write(|w| unsafe { w.bits(mask[2] as u32) });
+            regs.data_7().write(|w| unsafe { w.bits(mask[3] as u32) });
+
+            match filter.mode {
+                SlcanFilterMode::Single => *mode |= MODE_SINGLE_FILTER,
+                SlcanFilterMode::Dual => *mode &= !MODE_SINGLE_FILTER,
+            }
+        });
+    }
+}
+
+impl Can for TwaiCan {
+    type Frame = EspTwaiFrame;
+    type Error = TwaiError;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        match self.twai.transmit(frame) {
+            Ok(()) => {
+                self.tx_pending = Some(frame.clone());
+                Ok(())
+            }
+            // TX buffer still in use
+            Err(nb::Error::WouldBlock) => Err(TwaiError(ErrorKind::Overrun)),
+            Err(nb::Error::Other(err)) => {
+                error!("CAN controller Error: {:?}", defmt::Debug2Format(&err));
+                Err(TwaiError(ErrorKind::Other))
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        match self.twai.receive() {
+            Ok(frame) => Ok(frame),
+            Err(_) => Err(TwaiError(ErrorKind::Other)),
+        }
+    }
+}
+
+impl CanDevice for TwaiCan {
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        let Some(btr) = twai_bit_timing(bitrate.bps()).and_then(|timing| twai_btr(&timing)) else {
+            error!("Bitrate not supported by the TWAI");
+            return Err(BitrateError::Unsupported);
+        };
+
+        info!("Setting bitrate to: {}", bitrate.bps());
+        self.write_bus_timing(btr);
+
+        Ok(())
+    }
+
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        let Some(btr) = twai_btr(&timing) else {
+            error!("Bit timing can't be represented on the TWAI");
+            return Err(BitrateError::Unsupported);
+        };
+
+        info!("Setting bit timing to {} bps", timing.bitrate());
+        self.write_bus_timing(btr);
+
+        Ok(())
+    }
+
+    fn status(&mut self) -> CanStatus {
+        let regs = Self::registers();
+
+        let status = regs.status().read().bits() as u8;
+        let tec = regs.tx_err_cnt().read().bits() as u8;
+        let rec = regs.rx_err_cnt().read().bits() as u8;
+
+        // Overrun is latched, clear it once read
+        regs.cmd().write(|w| unsafe { w.bits(CMD_CLEAR_OVERRUN) });
+
+        twai_status_from_registers(status, tec, rec)
+    }
+
+    fn set_mode(&mut self, mode: CanMode) {
+        // Reset mode aborts the transmission
+        if mode == CanMode::Configuration {
+            self.tx_pending = None;
+        }
+
+        self.configure(|_, bits| {
+            *bits &= !(MODE_RESET | MODE_LISTEN_ONLY | MODE_SELF_TEST);
+            *bits |= match mode {
+                CanMode::Normal => 0,
+                CanMode::ListenOnly => MODE_LISTEN_ONLY,
+                // Self test transmits without waiting for an ACK
+                CanMode::Loopback => MODE_SELF_TEST,
+                CanMode::Configuration => MODE_RESET,
+            };
+        });
+    }
+
+    fn set_filters(&mut self, filters: &[FilterEntry]) -> FilterFit {
+        let (filter, fit) = twai_acceptance_from_filters(filters);
+        self.write_acceptance(&filter);
+
+        info!("Filters changed");
+        fit
+    }
+
+    // The TWAI acceptance filter is the SJA1000 one
+    fn set_acceptance_filter(&mut self, filter: &SlcanAcceptanceFilter) -> FilterFit {
+        self.write_acceptance(filter);
+
+        info!("Filters changed");
+        FilterFit::Exact
+    }
+
+    // Transmission complete is cleared by the next transmission request
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        self.tx_pending.as_ref()?;
+
+        if Self::registers().status().read().bits() & STATUS_TX_COMPLETE == 0 {
+            return None;
+        }
+
+        self.tx_pending.take().map(|frame| (frame, Instant::now()))
+    }
+}
+
+// Uses the TWAI interrupt bound by the esp-hal async driver
+impl AsyncCanDevice for TwaiCan {
+    // The driver only exposes RX through receive_async and waits for the TX
+    // buffer itself, so there is nothing else to wait for
+    async fn wait_for_event(&mut self) {
+        embassy_futures::yield_now().await;
+    }
+
+    async fn receive_async(&mut self) -> Result<(Self::Frame, Instant), Self::Error> {
+        match self.twai.receive_async().await {
+            Ok(frame) => Ok((frame, Instant::now())),
+            Err(err) => {
+                error!("CAN bus error: {:?}", defmt::Debug2Format(&err));
+                Err(TwaiError(ErrorKind::Other))
+            }
+        }
+    }
+
+    async fn transmit_async(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        match self.twai.transmit_async(frame).await {
+            Ok(()) => {
+                self.tx_pending = Some(frame.clone());
+                Ok(())
+            }
+            Err(err) => {
+                error!("CAN controller Error: {:?}", defmt::Debug2Format(&err));
+                Err(TwaiError(ErrorKind::Other))
+            }
+        }
+    }
+}
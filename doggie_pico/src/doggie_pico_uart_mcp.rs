@@ -4,22 +4,25 @@
 mod soft_timer;
 mod spi;
 mod spi_device;
+mod unique_id;
 
 use defmt::info;
 use doggie_core::{
     core_create_tasks, core_run, Bsp, CanChannel, CanChannelReceiver, CanChannelSender, Core,
+    Mcp2515Irq,
 };
 use embassy_executor::Spawner;
 use embassy_rp::{
     bind_interrupts,
+    gpio::{Input, Pull},
     peripherals::{SPI0, UART0},
     spi::Blocking,
     uart::{BufferedInterruptHandler, BufferedUart, Config},
 };
-use mcp2515::MCP2515;
 use soft_timer::SoftTimer;
 use spi_device::CustomSpiDevice;
 use static_cell::StaticCell;
+use unique_id::{device_id, startup_store, FlashType};
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
@@ -30,12 +33,18 @@ bind_interrupts!(struct Irqs {
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
+    // Also holds the startup settings
+    let mut flash = unique_id::flash(p.FLASH, p.DMA_CH0);
+    let device_id = device_id(&mut flash);
+    let mut startup_store = startup_store(flash);
+    let startup = startup_store.load_or_default();
+
     let serial = {
         // Setup UART
         let (tx_pin, rx_pin, uart_no) = (p.PIN_0, p.PIN_1, p.UART0);
 
         let mut uart_config = Config::default();
-        uart_config.baudrate = 921_600;
+        uart_config.baudrate = startup.uart_baud.unwrap_or(921_600);
 
         static TX_BUF: StaticCell<[u8; 16]> = StaticCell::new();
         let tx_buf = &mut TX_BUF.init([0; 16])[..];
@@ -56,9 +65,17 @@ async fn main(spawner: Spawner) {
     // Create SoftTimer
     let delay = SoftTimer {};
 
+    // MCP2515 INT pin
+    let int = Input::new(p.PIN_20, Pull::Up);
+
     // Create the Bsp
     // let bsp = Bsp::new(can, uart);
-    let bsp = Bsp::new_with_mcp2515(spi, delay, serial);
+    // Crystal, CLKOUT and initial bitrate, set through the mcp2515-* features.
+    // A crystal saved with cWx takes over
+    let config = startup.mcp2515_config();
+    let bsp = Bsp::new_with_mcp2515_irq(spi, delay, int, config, serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
 
     info!("MCP2515 init ok");
 
@@ -69,6 +86,6 @@ async fn main(spawner: Spawner) {
 }
 
 type SerialType = BufferedUart<'static, UART0>;
-type CanType = MCP2515<CustomSpiDevice<'static, SPI0, Blocking>>;
+type CanType = Mcp2515Irq<CustomSpiDevice<'static, SPI0, Blocking>, Input<'static>>;
 
-core_create_tasks!(SerialType, CanType);
+core_create_tasks!(SerialType, CanType, FlashType);
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+
+mod pio_can;
+mod unique_id;
+
+use defmt::info;
+use doggie_core::{
+    core_create_tasks, core_run, Bsp, CanBitrate, CanChannel, CanChannelReceiver, CanChannelSender,
+    Core,
+};
+use embassy_executor::Spawner;
+use embassy_rp::{
+    bind_interrupts,
+    peripherals::{PIO0, UART0},
+    pio::{InterruptHandler, Pio},
+    uart::{BufferedInterruptHandler, BufferedUart, Config},
+};
+use pio_can::PioCan;
+use static_cell::StaticCell;
+use unique_id::{device_id, startup_store, FlashType};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
+    PIO0_IRQ_0 => InterruptHandler<PIO0>;
+});
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+
+    // Also holds the startup settings
+    let mut flash = unique_id::flash(p.FLASH, p.DMA_CH0);
+    let device_id = device_id(&mut flash);
+    let mut startup_store = startup_store(flash);
+    let startup = startup_store.load_or_default();
+
+    let serial = {
+        // Setup UART
+        let (tx_pin, rx_pin, uart_no) = (p.PIN_0, p.PIN_1, p.UART0);
+
+        let mut uart_config = Config::default();
+        uart_config.baudrate = startup.uart_baud.unwrap_or(921_600);
+
+        static TX_BUF: StaticCell<[u8; 16]> = StaticCell::new();
+        let tx_buf = &mut TX_BUF.init([0; 16])[..];
+
+        static RX_BUF: StaticCell<[u8; 16]> = StaticCell::new();
+        let rx_buf = &mut RX_BUF.init([0; 16])[..];
+        let serial = BufferedUart::new(uart_no, Irqs, tx_pin, rx_pin, tx_buf, rx_buf, uart_config);
+
+        info!("UART init ok");
+
+        serial
+    };
+
+    // CAN transceiver on PIO0
+    let pio = Pio::new(p.PIO0, Irqs);
+    let can = PioCan::new(pio, p.PIN_4, p.PIN_5, CanBitrate::from_kbps(250));
+
+    // Create the Bsp
+    let bsp = Bsp::new(can, serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
+
+    // Create and run the Doggie core
+    let core = Core::new(spawner, bsp);
+
+    core_run!(core);
+}
+
+type SerialType = BufferedUart<'static, UART0>;
+type CanType = PioCan<'static, PIO0>;
+
+core_create_tasks!(SerialType, CanType, FlashType);
@@ -7,16 +7,17 @@ mod spi_device;
 mod unique_id;
 mod usb_device;
 
-use unique_id::serial_number;
+use unique_id::{device_id, startup_store, FlashType};
 
 use defmt::info;
 use doggie_core::{
     core_create_tasks, core_run, Bsp, CanChannel, CanChannelReceiver, CanChannelSender, Core,
+    Mcp2515Irq, UsbSerialBuffer,
 };
 use embassy_executor::Spawner;
 use embassy_rp::{
     bind_interrupts,
-    gpio::{Level, Output},
+    gpio::{Input, Level, Output, Pull},
     peripherals::{SPI0, USB},
     spi::Blocking,
     usb::{Driver, InterruptHandler},
@@ -26,7 +27,6 @@ use embassy_usb::{
     class::cdc_acm::{CdcAcmClass, State},
     UsbDevice,
 };
-use mcp2515::MCP2515;
 use soft_timer::SoftTimer;
 use spi_device::CustomSpiDevice;
 use static_cell::StaticCell;
@@ -61,9 +61,16 @@ async fn main(spawner: Spawner) {
     let led = Output::new(p.PIN_25, Level::Low);
     spawner.spawn(blink_task(led)).unwrap();
 
-    let device_id: &str = serial_number(p.FLASH, p.DMA_CH0);
+    // Also holds the startup settings
+    let mut flash = unique_id::flash(p.FLASH, p.DMA_CH0);
+    let device_id = device_id(&mut flash);
+    let mut startup_store = startup_store(flash);
+    let startup = startup_store.load_or_default();
 
-    info!("Serial number: {}", device_id);
+    static USB_SERIAL: StaticCell<UsbSerialBuffer> = StaticCell::new();
+    let usb_serial = device_id.usb_serial(USB_SERIAL.init([0; 32]));
+
+    info!("Serial number: {}", usb_serial);
 
     let serial = {
         info!("USB init");
@@ -76,7 +83,7 @@ async fn main(spawner: Spawner) {
             let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
             config.manufacturer = Some("Aznarez/Gianatiempo");
             config.product = Some("DoggiePico");
-            config.serial_number = Some(device_id);
+            config.serial_number = Some(usb_serial);
             config.max_power = 100;
             config.max_packet_size_0 = 64;
 
@@ -137,9 +144,17 @@ async fn main(spawner: Spawner) {
     // Create SoftTimer
     let delay = SoftTimer {};
 
+    // MCP2515 INT pin
+    let int = Input::new(p.PIN_20, Pull::Up);
+
     // Create the Bsp
     // let bsp = Bsp::new(can, uart);
-    let bsp = Bsp::new_with_mcp2515(spi, delay, serial);
+    // Crystal, CLKOUT and initial bitrate, set through the mcp2515-* features.
+    // A crystal saved with cWx takes over
+    let config = startup.mcp2515_config();
+    let bsp = Bsp::new_with_mcp2515_irq(spi, delay, int, config, serial)
+        .with_device_id(device_id)
+        .with_startup(startup_store, startup);
 
     info!("MCP2515 init ok");
 
@@ -150,6 +165,6 @@ async fn main(spawner: Spawner) {
 }
 
 type SerialType = UsbWrapper<'static>;
-type CanType = MCP2515<CustomSpiDevice<'static, SPI0, Blocking>>;
+type CanType = Mcp2515Irq<CustomSpiDevice<'static, SPI0, Blocking>, Input<'static>>;
 
-core_create_tasks!(SerialType, CanType);
+core_create_tasks!(SerialType, CanType, FlashType);
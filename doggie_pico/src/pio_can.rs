@@ -0,0 +1,628 @@
+use defmt::{debug, error, info};
+use doggie_core::{
+    soft_can_clock_divider, AsyncCanDevice, BitTiming, BitrateError, CanBitrate, CanDevice,
+    CanMode, CanStatus, FilterEntry, FilterFit, SoftCanBits, SoftCanDecoder, SoftCanEvent,
+};
+use embassy_futures::select::{select, Either};
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::gpio::Level;
+use embassy_rp::pio::{
+    Config, Direction, FifoJoin, Instance, Irq, Pio, PioPin, ShiftConfig, ShiftDirection,
+    StateMachine,
+};
+use embassy_time::Instant;
+use embedded_can::{blocking::Can, ErrorKind, Frame, Id};
+use fixed::FixedU32;
+use heapless::{Deque, Vec};
+use slcan::CanFrame;
+
+// Frames waiting to be read, the PIO only buffers a few bits
+const RX_QUEUE_SIZE: usize = 8;
+
+// Frames sent, until the core reads them
+const TX_SENT_SIZE: usize = 4;
+
+// The software filter and the acceptance filter never give more
+const MAX_FILTERS: usize = 32;
+
+// TX error count over this takes the node off the bus
+const BUS_OFF_LIMIT: u16 = 255;
+
+// Bits sampled per RX FIFO word
+const RX_WORD_BITS: u32 = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PioFrame {
+    id: Id,
+    is_remote: bool,
+    dlc: usize,
+    data: [u8; 8],
+}
+
+impl Frame for PioFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+
+        let mut frame = PioFrame {
+            id: id.into(),
+            is_remote: false,
+            dlc: data.len(),
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+
+        Some(frame)
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+
+        Some(PioFrame {
+            id: id.into(),
+            is_remote: true,
+            dlc,
+            data: [0; 8],
+        })
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.is_remote
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn dlc(&self) -> usize {
+        self.dlc
+    }
+
+    // Remote frames carry no data
+    fn data(&self) -> &[u8] {
+        if self.is_remote {
+            &[]
+        } else {
+            &self.data[..self.dlc]
+        }
+    }
+}
+
+impl From<&PioFrame> for CanFrame {
+    fn from(frame: &PioFrame) -> Self {
+        CanFrame::new(frame.id, frame.is_remote, &frame.data[..frame.dlc]).unwrap()
+    }
+}
+
+impl From<&CanFrame> for PioFrame {
+    fn from(frame: &CanFrame) -> Self {
+        let mut data = [0; 8];
+        data[..frame.dlc].copy_from_slice(&frame.data[..frame.dlc]);
+
+        PioFrame {
+            id: frame.id,
+            is_remote: frame.is_remote(),
+            dlc: frame.dlc,
+            data,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PioCanError(ErrorKind);
+
+// How a frame put on the bus ended
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TxOutcome {
+    Acked,
+    // Another node sent a frame with a higher priority, not an error
+    ArbitrationLost,
+    // Not acked, or a bit error
+    Error,
+}
+
+impl embedded_can::Error for PioCanError {
+    fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
+// Software CAN controller on a bare transceiver, in the spirit of can2040.
+// State machine 0 samples the bus and state machine 1 drives it, both at
+// SOFT_CAN_CYCLES_PER_BIT cycles per bit. The CPU decodes the sampled bits,
+// tells state machine 1 which bits to expect before the ACK slot and hands it
+// the stuffed frames to send. Errors are only counted, no error frames are
+// sent, as an error passive node.
+pub struct PioCan<'d, PIO: Instance> {
+    rx: StateMachine<'d, PIO, 0>,
+    tx: StateMachine<'d, PIO, 1>,
+    tx_done: Irq<'d, PIO, 0>,
+    ack_address: u8,
+    send_address: u8,
+    decoder: SoftCanDecoder,
+    rx_queue: Deque<(PioFrame, Instant), RX_QUEUE_SIZE>,
+    tx_sent: Deque<(PioFrame, Instant), TX_SENT_SIZE>,
+    overrun: bool,
+    // Frame on the bus, its ACK pattern and how it ended
+    tx_frame: Option<PioFrame>,
+    tx_pattern: u32,
+    tx_result: Option<TxOutcome>,
+    // Latched until the status is read
+    arbitration_lost: bool,
+    filters: Vec<FilterEntry, MAX_FILTERS>,
+    mode: CanMode,
+    // Goes past 255 when bus off
+    tec: u16,
+    rec: u8,
+}
+
+impl<'d, PIO: Instance> PioCan<'d, PIO> {
+    pub fn new(
+        pio: Pio<'d, PIO>,
+        rx_pin: impl PioPin,
+        tx_pin: impl PioPin,
+        bitrate: CanBitrate,
+    ) -> Self {
+        let Pio {
+            mut common,
+            irq0,
+            mut sm0,
+            mut sm1,
+            ..
+        } = pio;
+
+        // Sample each bit 10 cycles after it starts and set IRQ 4. A falling
+        // edge after a recessive bit resynchronizes the sampling, as CAN nodes
+        // do on recessive to dominant edges.
+        let rx_program = pio_proc::pio_asm!(
+            ".wrap_target",
+            "sample:",
+            "    in pins, 1",
+            "    irq nowait 4",
+            "    jmp pin recessive",
+            "    jmp sample [12]", // Dominant, next sample 16 cycles later
+            "recessive:",
+            "    set x, 2",
+            "poll:",
+            "    jmp pin high",
+            "    jmp sample [8]", // Falling edge, the bit started now
+            "high:",
+            "    jmp x-- poll",
+            "    nop [5]", // No edge, next sample 16 cycles later
+            ".wrap",
+        );
+
+        // Drive the bus from the IRQ 4 sample points. By default it waits for
+        // the last 32 bits to match the pattern pushed by the CPU, up to the
+        // CRC delimiter, and drives the ACK slot. From `send` it pulls the
+        // number of bits minus one and the bits MSB first, drives each one at
+        // the start of the bit and stops when a recessive bit reads back
+        // dominant, which is losing the arbitration or a bit error. IRQ 0 is
+        // set once it stops sending.
+        let tx_program = pio_proc::pio_asm!(
+            "public send:",
+            "    pull block",
+            "    out y, 32",
+            "    wait 1 irq 4 [2]",
+            "next:",
+            "    pull ifempty block",
+            "    out x, 1",
+            "    mov pins, x",
+            "    jmp y-- bit",
+            "done:",
+            "    irq nowait 0",
+            "public ack:",
+            "    set y, 0", // A zero pattern never matches
+            ".wrap_target",
+            "check:",
+            "    mov x, y",
+            "    pull noblock", // New pattern, or x when none
+            "    mov y, osr",
+            "    wait 1 irq 4",
+            "    in pins, 1",
+            "    mov x, isr",
+            "    jmp x!=y check",
+            "    set pins, 0", // ACK slot
+            "    wait 1 irq 4 [3]",
+            "    set pins, 1",
+            ".wrap",
+            "bit:",
+            "    wait 1 irq 4",
+            "    jmp !x next [1]", // Dominant bits aren't checked
+            "    jmp pin next",
+            "    jmp done",
+        );
+
+        let rx_pin = common.make_pio_pin(rx_pin);
+        let tx_pin = common.make_pio_pin(tx_pin);
+
+        let rx_loaded = common.load_program(&rx_program.program);
+        let tx_loaded = common.load_program(&tx_program.program);
+
+        let divider = Self::clock_divider(bitrate.bps());
+
+        let mut config = Config::default();
+        config.use_program(&rx_loaded, &[]);
+        config.set_in_pins(&[&rx_pin]);
+        config.set_jmp_pin(&rx_pin);
+        config.shift_in = ShiftConfig {
+            auto_fill: true,
+            threshold: RX_WORD_BITS as u8,
+            direction: ShiftDirection::Left,
+        };
+        config.fifo_join = FifoJoin::RxOnly;
+        config.clock_divider = divider;
+        sm0.set_config(&config);
+
+        let mut config = Config::default();
+        config.use_program(&tx_loaded, &[]);
+        config.set_in_pins(&[&rx_pin]);
+        config.set_jmp_pin(&rx_pin);
+        config.set_out_pins(&[&tx_pin]);
+        config.set_set_pins(&[&tx_pin]);
+        config.shift_in = ShiftConfig {
+            auto_fill: false,
+            threshold: 32,
+            direction: ShiftDirection::Left,
+        };
+        config.shift_out = ShiftConfig {
+            auto_fill: false,
+            threshold: 32,
+            direction: ShiftDirection::Left,
+        };
+        config.fifo_join = FifoJoin::TxOnly;
+        config.clock_divider = divider;
+        sm1.set_config(&config);
+
+        // Recessive until the channel is open
+        sm1.set_pins(Level::High, &[&tx_pin]);
+        sm1.set_pin_dirs(Direction::Out, &[&tx_pin]);
+
+        info!("PIO CAN init ok");
+
+        PioCan {
+            rx: sm0,
+            tx: sm1,
+            tx_done: irq0,
+            ack_address: tx_loaded.origin + tx_program.public_defines.ack as u8,
+            send_address: tx_loaded.origin + tx_program.public_defines.send as u8,
+            decoder: SoftCanDecoder::new(),
+            rx_queue: Deque::new(),
+            tx_sent: Deque::new(),
+            overrun: false,
+            tx_frame: None,
+            tx_pattern: 0,
+            tx_result: None,
+            arbitration_lost: false,
+            filters: Vec::new(),
+            mode: CanMode::Configuration,
+            tec: 0,
+            rec: 0,
+        }
+    }
+
+    fn clock_divider(bitrate: u32) -> FixedU32<fixed::types::extra::U8> {
+        let divider = soft_can_clock_divider(clk_sys_freq(), bitrate).unwrap_or_else(|| {
+            error!("Bitrate out of the PIO range");
+            u32::from(u16::MAX) << 8
+        });
+
+        FixedU32::from_bits(divider)
+    }
+
+    fn set_clock(&mut self, bitrate: u32) {
+        let divider = Self::clock_divider(bitrate);
+
+        self.rx.set_enable(false);
+        self.tx.set_enable(false);
+        self.rx.set_clock_divider(divider);
+        self.tx.set_clock_divider(divider);
+        self.rx.clkdiv_restart();
+        self.tx.clkdiv_restart();
+
+        self.start();
+    }
+
+    // Restart both state machines for the current mode, dropping anything
+    // half received
+    fn start(&mut self) {
+        self.rx.set_enable(false);
+        self.tx.set_enable(false);
+        self.rx.clear_fifos();
+        self.tx.clear_fifos();
+        self.decoder.reset();
+        self.tx_frame = None;
+
+        if self.mode == CanMode::Configuration {
+            return;
+        }
+
+        self.rx.restart();
+        self.tx.restart();
+        unsafe { self.tx.exec_jmp(self.ack_address) };
+
+        self.tx.set_enable(true);
+        self.rx.set_enable(true);
+    }
+
+    fn accepts(&self, id: Id) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|filter| filter.matches(id))
+    }
+
+    fn process_word(&mut self, word: u32) {
+        // Bits lost while the FIFO was full, wait for the bus to be idle
+        if self.rx.rx().stalled() {
+            self.decoder.reset();
+            self.overrun = true;
+        }
+
+        for i in (0..RX_WORD_BITS).rev() {
+            match self.decoder.push(word >> i & 1 != 0) {
+                Some(SoftCanEvent::AckPattern(pattern)) => {
+                    // Frames sent by this node are acked by the others, the
+                    // ones that won the arbitration over it by this node
+                    let own = self.tx_frame.is_some() && pattern == self.tx_pattern;
+                    if self.mode == CanMode::Normal && !own {
+                        self.tx.tx().try_push(pattern);
+                    }
+                }
+                Some(SoftCanEvent::Frame { frame, acked }) => {
+                    let frame = PioFrame::from(&frame);
+
+                    if self.tx_frame.as_ref() == Some(&frame) {
+                        self.tx_result = Some(if acked {
+                            TxOutcome::Acked
+                        } else {
+                            TxOutcome::Error
+                        });
+                        continue;
+                    }
+                    // Another node won the arbitration
+                    if self.tx_frame.is_some() {
+                        self.tx_result = Some(TxOutcome::ArbitrationLost);
+                    }
+
+                    self.rec = self.rec.saturating_sub(1);
+
+                    if !self.accepts(frame.id) {
+                        continue;
+                    }
+                    if self.rx_queue.push_back((frame, Instant::now())).is_err() {
+                        self.overrun = true;
+                    }
+                }
+                Some(SoftCanEvent::Error(e)) => {
+                    debug!("CAN bus error: {:?}", defmt::Debug2Format(&e));
+                    self.rec = self.rec.saturating_add(1);
+
+                    if self.tx_frame.is_some() {
+                        self.tx_result = Some(TxOutcome::Error);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Wait for the bus to be idle, so the frame starts after the intermission
+    async fn wait_idle(&mut self) {
+        while !self.decoder.is_idle() {
+            let word = self.rx.rx().wait_pull().await;
+            self.process_word(word);
+        }
+    }
+
+    // Send the frame once and wait for the outcome
+    async fn send(&mut self, frame: &PioFrame) -> TxOutcome {
+        self.wait_idle().await;
+
+        let bits = SoftCanBits::encode(&CanFrame::from(frame));
+        self.tx_frame = Some(frame.clone());
+        self.tx_pattern = bits.ack_pattern();
+        self.tx_result = None;
+
+        // Drop any ACK pattern left
+        self.tx.clear_fifos();
+        unsafe { self.tx.exec_jmp(self.send_address) };
+        self.tx.tx().push(bits.len() as u32 - 1);
+        for word in bits.words() {
+            self.tx.tx().push(*word);
+        }
+
+        // Keep decoding while sending, the frame comes back through RX
+        while self.tx_result.is_none() {
+            let word = match select(self.tx_done.wait(), self.rx.rx().wait_pull()).await {
+                Either::First(()) => continue,
+                Either::Second(word) => word,
+            };
+            self.process_word(word);
+        }
+
+        self.tx_frame = None;
+        self.tx_result.unwrap_or(TxOutcome::Error)
+    }
+
+    fn is_bus_off(&self) -> bool {
+        self.tec > BUS_OFF_LIMIT
+    }
+
+    fn tx_error_count(&self) -> u8 {
+        self.tec.min(BUS_OFF_LIMIT) as u8
+    }
+
+    // Only the latest frames are kept
+    fn push_sent(&mut self, frame: &PioFrame) {
+        if self.tx_sent.is_full() {
+            self.tx_sent.pop_front();
+        }
+        let _ = self.tx_sent.push_back((frame.clone(), Instant::now()));
+    }
+}
+
+impl<'d, PIO: Instance> Can for PioCan<'d, PIO> {
+    type Frame = PioFrame;
+    type Error = PioCanError;
+
+    // Frames go out through transmit_async, the state machine needs the CPU
+    // to follow the bus while sending
+    fn transmit(&mut self, _frame: &Self::Frame) -> Result<(), Self::Error> {
+        Err(PioCanError(ErrorKind::Other))
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        while let Some(word) = self.rx.rx().try_pull() {
+            self.process_word(word);
+        }
+
+        match self.rx_queue.pop_front() {
+            Some((frame, _)) => Ok(frame),
+            None => Err(PioCanError(ErrorKind::Other)),
+        }
+    }
+}
+
+impl<'d, PIO: Instance> CanDevice for PioCan<'d, PIO> {
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        if soft_can_clock_divider(clk_sys_freq(), bitrate.bps()).is_none() {
+            error!("Bitrate out of the PIO range");
+            return Err(BitrateError::Unsupported);
+        }
+
+        info!("Setting bitrate to: {}", bitrate.bps());
+        self.set_clock(bitrate.bps());
+
+        Ok(())
+    }
+
+    // Only the bitrate is used, the sample point is fixed
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        if soft_can_clock_divider(clk_sys_freq(), timing.bitrate()).is_none() {
+            error!("Bit timing out of the PIO range");
+            return Err(BitrateError::Unsupported);
+        }
+
+        info!("Setting bit timing to {} bps", timing.bitrate());
+        self.set_clock(timing.bitrate());
+
+        Ok(())
+    }
+
+    // Warning and passive come from the counters, see CanStatus::bus_state
+    fn status(&mut self) -> CanStatus {
+        let status = CanStatus {
+            rx_fifo_full: self.rx_queue.is_full(),
+            tx_fifo_full: self.tx_frame.is_some(),
+            data_overrun: self.overrun,
+            bus_off: self.is_bus_off(),
+            arbitration_lost: self.arbitration_lost,
+            tx_error_count: self.tx_error_count(),
+            rx_error_count: self.rec,
+            ..CanStatus::default()
+        };
+
+        // Overrun and arbitration lost are latched, clear them once read
+        self.overrun = false;
+        self.arbitration_lost = false;
+
+        status
+    }
+
+    // A restart is also how the node recovers from bus off
+    fn set_mode(&mut self, mode: CanMode) {
+        self.mode = mode;
+        self.tec = 0;
+        self.rec = 0;
+        self.start();
+    }
+
+    // Filtering is done by the CPU while decoding, so it is always exact
+    fn set_filters(&mut self, filters: &[FilterEntry]) -> FilterFit {
+        self.filters.clear();
+        for filter in filters.iter().take(MAX_FILTERS) {
+            let _ = self.filters.push(*filter);
+        }
+
+        info!("Filters changed");
+        FilterFit::Exact
+    }
+
+    // Frames are acked while transmit_async waits, so they are known sent
+    // once it returns
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        self.tx_sent.pop_front()
+    }
+}
+
+impl<'d, PIO: Instance> AsyncCanDevice for PioCan<'d, PIO> {
+    async fn wait_for_event(&mut self) {
+        let word = self.rx.rx().wait_pull().await;
+        self.process_word(word);
+    }
+
+    async fn receive_async(&mut self) -> Result<(Self::Frame, Instant), Self::Error> {
+        loop {
+            if let Some(entry) = self.rx_queue.pop_front() {
+                return Ok(entry);
+            }
+            if core::mem::take(&mut self.overrun) {
+                return Err(PioCanError(ErrorKind::Overrun));
+            }
+
+            self.wait_for_event().await;
+        }
+    }
+
+    // transmit_async returns once the frame was acked, there is never a
+    // confirmation left to wait for
+    async fn receive_or_transmitted(
+        &mut self,
+    ) -> Option<Result<(Self::Frame, Instant), Self::Error>> {
+        Some(self.receive_async().await)
+    }
+
+    async fn transmit_async(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        match self.mode {
+            // Off the bus until restarted
+            CanMode::Normal if self.is_bus_off() => return Err(PioCanError(ErrorKind::Other)),
+            CanMode::Normal => {}
+            // Straight to RX, without touching the bus
+            CanMode::Loopback => {
+                if self.accepts(frame.id) {
+                    let _ = self.rx_queue.push_back((frame.clone(), Instant::now()));
+                }
+                self.push_sent(frame);
+                return Ok(());
+            }
+            CanMode::ListenOnly | CanMode::Configuration => {
+                return Err(PioCanError(ErrorKind::Other));
+            }
+        }
+
+        // Retransmit until acked, as CAN controllers do, until bus off.
+        // Losing the arbitration isn't an error, the frame waits its turn
+        loop {
+            match self.send(frame).await {
+                TxOutcome::Acked => break,
+                TxOutcome::ArbitrationLost => self.arbitration_lost = true,
+                TxOutcome::Error => {
+                    self.tec = self.tec.saturating_add(8);
+                    if self.is_bus_off() {
+                        error!("Bus off, transmission stopped");
+                        return Err(PioCanError(ErrorKind::Other));
+                    }
+                }
+            }
+        }
+        self.tec = self.tec.saturating_sub(1);
+        self.push_sent(frame);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+use async_io::Async;
+use doggie_core::{
+    AsyncCanDevice, BitTiming, BitrateError, CanBitrate, CanDevice, CanMode, CanStatus,
+    FilterEntry, FilterFit,
+};
+use embassy_time::Instant;
+use embedded_can::{blocking::Can, ErrorKind, Frame, Id};
+use log::{error, info};
+use socketcan::{CanSocket, Socket};
+use std::collections::VecDeque;
+use std::future::pending;
+use std::io;
+
+// Frames waiting to be read, like the RX FIFO of a real controller
+const RX_QUEUE_SIZE: usize = 64;
+
+// Frames sent, until the core reads them
+const TX_SENT_SIZE: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimFrame {
+    id: Id,
+    is_remote: bool,
+    dlc: usize,
+    data: [u8; 8],
+}
+
+impl Frame for SimFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+
+        let mut frame = SimFrame {
+            id: id.into(),
+            is_remote: false,
+            dlc: data.len(),
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+
+        Some(frame)
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+
+        Some(SimFrame {
+            id: id.into(),
+            is_remote: true,
+            dlc,
+            data: [0; 8],
+        })
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.is_remote
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn dlc(&self) -> usize {
+        self.dlc
+    }
+
+    // Remote frames carry no data
+    fn data(&self) -> &[u8] {
+        if self.is_remote {
+            &[]
+        } else {
+            &self.data[..self.dlc]
+        }
+    }
+}
+
+fn convert_frame<F: Frame, T: Frame>(frame: &F) -> Option<T> {
+    if frame.is_remote_frame() {
+        T::new_remote(frame.id(), frame.dlc())
+    } else {
+        T::new(frame.id(), frame.data())
+    }
+}
+
+#[derive(Debug)]
+pub struct SimError(ErrorKind);
+
+impl embedded_can::Error for SimError {
+    fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
+enum Backend {
+    // Linux SocketCAN interface, usually a vcan
+    SocketCan(Async<CanSocket>),
+    // In memory bus, transmitted frames are received back
+    Memory,
+}
+
+pub struct SimCan {
+    backend: Backend,
+    rx_queue: VecDeque<SimFrame>,
+    tx_sent: VecDeque<(SimFrame, Instant)>,
+    mode: CanMode,
+    filters: Vec<FilterEntry>,
+    status: CanStatus,
+}
+
+impl SimCan {
+    fn new(backend: Backend) -> Self {
+        SimCan {
+            backend,
+            rx_queue: VecDeque::with_capacity(RX_QUEUE_SIZE),
+            tx_sent: VecDeque::with_capacity(TX_SENT_SIZE),
+            mode: CanMode::Normal,
+            filters: Vec::new(),
+            status: CanStatus::default(),
+        }
+    }
+
+    pub fn new_memory() -> Self {
+        Self::new(Backend::Memory)
+    }
+
+    pub fn new_socketcan(iface: &str) -> io::Result<Self> {
+        // Async puts the socket in non blocking mode
+        let socket = Async::new(CanSocket::open(iface)?)?;
+
+        Ok(Self::new(Backend::SocketCan(socket)))
+    }
+
+    fn push_rx(&mut self, frame: SimFrame) {
+        if self.rx_queue.len() >= RX_QUEUE_SIZE {
+            self.status.data_overrun = true;
+            return;
+        }
+
+        self.rx_queue.push_back(frame);
+    }
+
+    fn next_frame(&mut self) -> Option<SimFrame> {
+        if let Some(frame) = self.rx_queue.pop_front() {
+            return Some(frame);
+        }
+
+        match &self.backend {
+            Backend::SocketCan(socket) => match socket.get_ref().read_frame() {
+                Ok(frame) => convert_frame(&frame),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+                Err(e) => {
+                    error!("SocketCAN read failed: {}", e);
+                    None
+                }
+            },
+            Backend::Memory => None,
+        }
+    }
+
+    fn send(&mut self, frame: &SimFrame) -> Result<(), SimError> {
+        match (self.mode, &self.backend) {
+            (CanMode::Configuration | CanMode::ListenOnly, _) => {
+                error!("Transmit while the controller can't transmit");
+                Err(SimError(ErrorKind::Other))
+            }
+            (CanMode::Loopback, _) | (CanMode::Normal, Backend::Memory) => {
+                self.push_rx(frame.clone());
+                Ok(())
+            }
+            (CanMode::Normal, Backend::SocketCan(socket)) => {
+                let Some(frame) = convert_frame::<_, socketcan::CanFrame>(frame) else {
+                    return Err(SimError(ErrorKind::Other));
+                };
+
+                match socket.get_ref().write_frame(&frame) {
+                    Ok(_) => Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        Err(SimError(ErrorKind::Overrun))
+                    }
+                    Err(e) => {
+                        error!("SocketCAN write failed: {}", e);
+                        Err(SimError(ErrorKind::Other))
+                    }
+                }
+            }
+        }
+    }
+
+    // Only the latest frames are kept
+    fn push_sent(&mut self, frame: &SimFrame) {
+        if self.tx_sent.len() >= TX_SENT_SIZE {
+            self.tx_sent.pop_front();
+        }
+
+        self.tx_sent.push_back((frame.clone(), Instant::now()));
+    }
+
+    fn accepts(&self, frame: &SimFrame) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|f| f.matches(frame.id()))
+    }
+}
+
+impl Can for SimCan {
+    type Frame = SimFrame;
+    type Error = SimError;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        self.send(frame)?;
+        self.push_sent(frame);
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        while let Some(frame) = self.next_frame() {
+            // Off the bus, pending frames are dropped
+            if self.mode == CanMode::Configuration {
+                continue;
+            }
+
+            if self.accepts(&frame) {
+                return Ok(frame);
+            }
+        }
+
+        Err(SimError(ErrorKind::Other))
+    }
+}
+
+impl CanDevice for SimCan {
+    // There is no physical bus, the bitrate is only logged
+    fn set_bitrate(&mut self, bitrate: CanBitrate) -> Result<(), BitrateError> {
+        info!("Setting bitrate to {} bps", bitrate.bps());
+        Ok(())
+    }
+
+    fn set_bit_timing(&mut self, timing: BitTiming) -> Result<(), BitrateError> {
+        info!("Setting bit timing to {} bps", timing.bitrate());
+        Ok(())
+    }
+
+    fn status(&mut self) -> CanStatus {
+        let mut status = self.status;
+        status.rx_fifo_full = self.rx_queue.len() >= RX_QUEUE_SIZE;
+
+        // Overrun is latched, clear it once read
+        self.status.data_overrun = false;
+
+        status
+    }
+
+    fn set_mode(&mut self, mode: CanMode) {
+        self.mode = mode;
+    }
+
+    // Filters are checked in software, so they are always exact
+    fn set_filters(&mut self, filters: &[FilterEntry]) -> FilterFit {
+        self.filters = filters.to_vec();
+        FilterFit::Exact
+    }
+
+    // Frames are on the bus as soon as they are written
+    fn transmitted(&mut self) -> Option<(Self::Frame, Instant)> {
+        self.tx_sent.pop_front()
+    }
+}
+
+impl AsyncCanDevice for SimCan {
+    async fn wait_for_event(&mut self) {
+        if !self.rx_queue.is_empty() {
+            return;
+        }
+
+        match &self.backend {
+            Backend::SocketCan(socket) => {
+                if let Err(e) = socket.readable().await {
+                    error!("SocketCAN wait failed: {}", e);
+                }
+            }
+            // Frames only show up when we transmit them
+            Backend::Memory => pending().await,
+        }
+    }
+
+    // Frames are sent as they are written, there is never a confirmation
+    // left to wait for
+    async fn receive_or_transmitted(
+        &mut self,
+    ) -> Option<Result<(Self::Frame, Instant), Self::Error>> {
+        Some(self.receive_async().await)
+    }
+
+    async fn transmit_async(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        loop {
+            match self.transmit(frame) {
+                Err(SimError(ErrorKind::Overrun)) => {
+                    if let Backend::SocketCan(socket) = &self.backend {
+                        let _ = socket.writable().await;
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::StandardId;
+
+    fn test_frame(id: u16) -> SimFrame {
+        SimFrame::new(StandardId::new(id).unwrap(), &[0x11, 0x22]).unwrap()
+    }
+
+    #[test]
+    fn test_memory_loopback() {
+        let mut can = SimCan::new_memory();
+        can.transmit(&test_frame(0x123)).unwrap();
+        assert_eq!(can.receive().unwrap(), test_frame(0x123));
+        assert!(can.receive().is_err());
+    }
+
+    #[test]
+    fn test_listen_only_transmit() {
+        let mut can = SimCan::new_memory();
+        can.set_mode(CanMode::ListenOnly);
+        assert!(can.transmit(&test_frame(0x123)).is_err());
+    }
+
+    #[test]
+    fn test_configuration_drops_frames() {
+        let mut can = SimCan::new_memory();
+        can.transmit(&test_frame(0x123)).unwrap();
+        can.set_mode(CanMode::Configuration);
+        assert!(can.receive().is_err());
+
+        can.set_mode(CanMode::Normal);
+        assert!(can.receive().is_err());
+    }
+
+    #[test]
+    fn test_transmitted() {
+        let mut can = SimCan::new_memory();
+        can.transmit(&test_frame(0x123)).unwrap();
+        can.set_mode(CanMode::ListenOnly);
+        assert!(can.transmit(&test_frame(0x456)).is_err());
+
+        assert_eq!(can.transmitted().unwrap().0, test_frame(0x123));
+        assert!(can.transmitted().is_none());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut can = SimCan::new_memory();
+        can.set_filters(&[FilterEntry {
+            id: StandardId::new(0x120).unwrap().into(),
+            mask: 0x7F0,
+        }]);
+
+        can.transmit(&test_frame(0x223)).unwrap();
+        can.transmit(&test_frame(0x123)).unwrap();
+        assert_eq!(can.receive().unwrap(), test_frame(0x123));
+        assert!(can.receive().is_err());
+    }
+
+    #[test]
+    fn test_rx_overrun() {
+        let mut can = SimCan::new_memory();
+        for _ in 0..=RX_QUEUE_SIZE {
+            can.transmit(&test_frame(0x123)).unwrap();
+        }
+
+        let status = can.status();
+        assert!(status.data_overrun);
+        assert!(status.rx_fifo_full);
+        assert!(!can.status().data_overrun);
+    }
+}
@@ -0,0 +1,14 @@
+// doggie_core logs through defmt, which needs a probe to decode the frames.
+// On the host there is nothing listening, so the output is discarded.
+#[defmt::global_logger]
+struct NoopLogger;
+
+unsafe impl defmt::Logger for NoopLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
@@ -0,0 +1,50 @@
+mod can_device;
+mod logger;
+mod pty;
+
+use can_device::SimCan;
+use doggie_core::{
+    core_create_tasks, core_run, Bsp, CanChannel, CanChannelReceiver, CanChannelSender, Core,
+    DeviceId,
+};
+use embassy_executor::Spawner;
+use log::info;
+use pty::PtySerial;
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    // Setup CAN, either a SocketCAN interface or the in memory bus
+    let can = match std::env::args().nth(1) {
+        Some(iface) if iface != "memory" => {
+            let can = SimCan::new_socketcan(&iface).expect("Failed to open the CAN interface");
+            info!("SocketCAN init ok on {}", iface);
+            can
+        }
+        _ => {
+            info!("Memory bus init ok");
+            SimCan::new_memory()
+        }
+    };
+
+    // Setup the PTY, the host tools attach to its slave side
+    let serial = PtySerial::new().expect("Failed to open a PTY");
+    println!("Serial port: {}", serial.path().display());
+
+    // Create the Bsp, the host has no chip id so a fixed one is used
+    let bsp = Bsp::new(can, serial).with_device_id(DeviceId::new(b"doggie_sim"));
+
+    // Create and run the Doggie core
+    let core = Core::new(spawner, bsp);
+
+    core_run!(core);
+}
+
+type SerialType = PtySerial;
+type CanType = SimCan;
+
+core_create_tasks!(SerialType, CanType);
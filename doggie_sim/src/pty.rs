@@ -0,0 +1,62 @@
+use async_io::Async;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::ttyname;
+use std::fs::File;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+
+// Serial port backed by a pseudo terminal, the host tools open the slave side
+pub struct PtySerial {
+    master: Async<File>,
+    // Reading the master fails with EIO while no one has the slave open, so
+    // we keep a handle to it ourselves
+    _slave: OwnedFd,
+    path: PathBuf,
+}
+
+impl PtySerial {
+    pub fn new() -> io::Result<Self> {
+        let pty = openpty(None, None)?;
+
+        // Raw mode, slcan is a binary protocol for the tty layer
+        let mut termios = tcgetattr(&pty.slave)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)?;
+
+        let path = ttyname(&pty.slave)?;
+        let master = Async::new(File::from(pty.master))?;
+
+        Ok(PtySerial {
+            master,
+            _slave: pty.slave,
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl embedded_io_async::ErrorType for PtySerial {
+    type Error = io::Error;
+}
+
+impl embedded_io_async::Read for PtySerial {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.master.read(buf).await
+    }
+}
+
+impl embedded_io_async::Write for PtySerial {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.master.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.master.flush().await
+    }
+}
@@ -0,0 +1,219 @@
+use crate::CanFrame;
+use embedded_can::Id;
+
+// SJA1000 acceptance filter mode, selected with W
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanFilterMode {
+    // Two short filters, the Lawicel default
+    Dual,
+    // One long filter
+    Single,
+}
+
+// SJA1000 acceptance code and mask registers (ACR0-3 and AMR0-3), as set by
+// M and m. ACR0 is the most significant byte. A mask bit set to 1 means
+// "don't care".
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SlcanAcceptanceFilter {
+    pub code: u32,
+    pub mask: u32,
+    pub mode: SlcanFilterMode,
+}
+
+impl Default for SlcanAcceptanceFilter {
+    // Accept everything, as the SJA1000 after reset
+    fn default() -> Self {
+        SlcanAcceptanceFilter {
+            code: 0,
+            mask: 0xFFFF_FFFF,
+            mode: SlcanFilterMode::Dual,
+        }
+    }
+}
+
+// Check `value` against `code` on the bits that are set in `care` and clear in
+// `mask`
+fn matches(value: u32, code: u32, mask: u32, care: u32) -> bool {
+    (value ^ code) & !mask & care == 0
+}
+
+impl SlcanAcceptanceFilter {
+    // Every bit don't care, in both modes
+    pub fn accepts_all(&self) -> bool {
+        self.mask == 0xFFFF_FFFF
+    }
+
+    pub fn accepts(&self, frame: &CanFrame) -> bool {
+        let rtr = frame.is_remote() as u32;
+        // Data bytes are only compared when the frame carries them
+        let data_len = if frame.is_remote() { 0 } else { frame.dlc };
+        let data0 = frame.data[0] as u32;
+        let data1 = frame.data[1] as u32;
+
+        match (self.mode, frame.id) {
+            (SlcanFilterMode::Single, Id::Standard(id)) => {
+                // ACR0-1: ID.10-0, RTR and 4 unused bits, ACR2-3: data bytes 1 and 2
+                let value = (id.as_raw() as u32) << 21 | rtr << 20 | data0 << 8 | data1;
+                let care = match data_len {
+                    0 => 0xFFF0_0000,
+                    1 => 0xFFF0_FF00,
+                    _ => 0xFFF0_FFFF,
+                };
+
+                matches(value, self.code, self.mask, care)
+            }
+            (SlcanFilterMode::Single, Id::Extended(id)) => {
+                // ACR0-3: ID.28-0, RTR and 2 unused bits
+                let value = id.as_raw() << 3 | rtr << 2;
+
+                matches(value, self.code, self.mask, 0xFFFF_FFFC)
+            }
+            (SlcanFilterMode::Dual, Id::Standard(id)) => {
+                let id = id.as_raw() as u32;
+
+                // Filter 1, ACR0-1 and the low nibble of ACR3: ID.10-0, RTR
+                // and data byte 1
+                let value = id << 21 | rtr << 20 | (data0 >> 4) << 16 | (data0 & 0x0F);
+                let care = if data_len == 0 {
+                    0xFFF0_0000
+                } else {
+                    0xFFFF_000F
+                };
+                let filter1 = matches(value, self.code, self.mask, care);
+
+                // Filter 2, ACR2 and the high nibble of ACR3: ID.10-0 and RTR
+                let value = id << 5 | rtr << 4;
+                let filter2 = matches(value, self.code, self.mask, 0x0000_FFF0);
+
+                filter1 || filter2
+            }
+            (SlcanFilterMode::Dual, Id::Extended(id)) => {
+                // Filter 1 on ACR0-1, filter 2 on ACR2-3: ID.28-13
+                let id = id.as_raw() >> 13;
+
+                let filter1 = matches(id << 16, self.code, self.mask, 0xFFFF_0000);
+                let filter2 = matches(id, self.code, self.mask, 0x0000_FFFF);
+
+                filter1 || filter2
+            }
+        }
+    }
+
+    // Both modes compare the standard id, or the 11 upper bits of an extended
+    // id, at the same position of each filter. Returns the (id, mask) of both
+    // filters on those 11 bits, with the mask bits set to 1 for the bits that
+    // must match. Frames passing this check are a superset of the accepted
+    // ones, so it can be done by hardware filters.
+    pub fn id_prefilters(&self) -> [(u16, u16); 2] {
+        let filter = |shift: u32| {
+            let id = (self.code >> shift) as u16 & 0x7FF;
+            let mask = !(self.mask >> shift) as u16 & 0x7FF;
+            (id, mask)
+        };
+
+        match self.mode {
+            SlcanFilterMode::Single => [filter(21); 2],
+            SlcanFilterMode::Dual => [filter(21), filter(5)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::{ExtendedId, StandardId};
+
+    fn standard_frame(id: u16, data: &[u8]) -> CanFrame {
+        CanFrame::new(StandardId::new(id).unwrap(), false, data).unwrap()
+    }
+
+    fn extended_frame(id: u32) -> CanFrame {
+        CanFrame::new(ExtendedId::new(id).unwrap(), false, &[]).unwrap()
+    }
+
+    fn filter(code: u32, mask: u32, mode: SlcanFilterMode) -> SlcanAcceptanceFilter {
+        SlcanAcceptanceFilter { code, mask, mode }
+    }
+
+    #[test]
+    fn test_default_accepts_all() {
+        let filter = SlcanAcceptanceFilter::default();
+        assert!(filter.accepts_all());
+        assert!(filter.accepts(&standard_frame(0x123, &[0x11])));
+        assert!(filter.accepts(&extended_frame(0x1ABCDEF)));
+    }
+
+    #[test]
+    fn test_single_standard_id() {
+        // ID 0x123, everything else don't care
+        let filter = filter(0x2460_0000, 0x001F_FFFF, SlcanFilterMode::Single);
+        assert!(filter.accepts(&standard_frame(0x123, &[])));
+        assert!(!filter.accepts(&standard_frame(0x124, &[])));
+    }
+
+    #[test]
+    fn test_single_standard_rtr() {
+        // ID 0x123 data frames only
+        let filter = filter(0x2460_0000, 0x000F_FFFF, SlcanFilterMode::Single);
+        assert!(filter.accepts(&standard_frame(0x123, &[])));
+        assert!(
+            !filter.accepts(&CanFrame::new(StandardId::new(0x123).unwrap(), true, &[]).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_single_standard_data() {
+        // ID 0x123 with 0xAA as first data byte
+        let filter = filter(0x2460_AA00, 0x000F_00FF, SlcanFilterMode::Single);
+        assert!(filter.accepts(&standard_frame(0x123, &[0xAA, 0x01])));
+        assert!(!filter.accepts(&standard_frame(0x123, &[0xAB, 0x01])));
+        // Without data the data bytes are not compared
+        assert!(filter.accepts(&standard_frame(0x123, &[])));
+    }
+
+    #[test]
+    fn test_single_extended_id() {
+        let filter = filter(0x1ABCDEF << 3, 0x0000_0007, SlcanFilterMode::Single);
+        assert!(filter.accepts(&extended_frame(0x1ABCDEF)));
+        assert!(!filter.accepts(&extended_frame(0x1ABCDEE)));
+    }
+
+    #[test]
+    fn test_dual_standard_ids() {
+        // Filter 1 on ID 0x123, filter 2 on ID 0x456
+        let filter = filter(0x246F_8ACF, 0x001F_001F, SlcanFilterMode::Dual);
+        assert!(filter.accepts(&standard_frame(0x123, &[0x11])));
+        assert!(filter.accepts(&standard_frame(0x456, &[0x11])));
+        assert!(!filter.accepts(&standard_frame(0x789, &[0x11])));
+    }
+
+    #[test]
+    fn test_dual_standard_data() {
+        // Filter 1 on ID 0x123 with 0xA5 as first data byte, filter 2 rejects all
+        let filter = filter(0x246A_0005, 0x0000_0000, SlcanFilterMode::Dual);
+        assert!(filter.accepts(&standard_frame(0x123, &[0xA5])));
+        assert!(!filter.accepts(&standard_frame(0x123, &[0xA4])));
+    }
+
+    #[test]
+    fn test_dual_extended_ids() {
+        // Filter 1 on ID.28-13 of 0x1ABCDEF, filter 2 on ID.28-13 of 0x0012000
+        let filter = filter(0x0D5E_0009, 0x0000_0000, SlcanFilterMode::Dual);
+        assert!(filter.accepts(&extended_frame(0x1ABCDEF)));
+        assert!(filter.accepts(&extended_frame(0x1ABD000)));
+        assert!(filter.accepts(&extended_frame(0x0012000)));
+        assert!(!filter.accepts(&extended_frame(0x0014000)));
+    }
+
+    #[test]
+    fn test_id_prefilters_single() {
+        let filter = filter(0x2460_0000, 0x001F_FFFF, SlcanFilterMode::Single);
+        assert_eq!(filter.id_prefilters(), [(0x123, 0x7FF); 2]);
+    }
+
+    #[test]
+    fn test_id_prefilters_dual() {
+        let filter = filter(0x246F_8ACF, 0x003F_00FF, SlcanFilterMode::Dual);
+        assert_eq!(filter.id_prefilters(), [(0x123, 0x7FE), (0x456, 0x7F8)]);
+    }
+}
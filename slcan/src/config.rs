@@ -0,0 +1,116 @@
+use crate::{SlcanBitTiming, SlcanTimestampMode};
+
+// Longest nickname, in characters
+pub const SLCAN_NICKNAME_MAX_LEN: usize = 16;
+
+// Name given to the device by the user, printable ASCII
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct SlcanNickname {
+    bytes: [u8; SLCAN_NICKNAME_MAX_LEN],
+    len: usize,
+}
+
+impl SlcanNickname {
+    // Returns None when too long or not printable
+    pub fn new(name: &[u8]) -> Option<Self> {
+        if name.len() > SLCAN_NICKNAME_MAX_LEN || !name.iter().all(|c| (0x20..0x7F).contains(c)) {
+            return None;
+        }
+
+        let mut nickname = SlcanNickname::default();
+        nickname.bytes[..name.len()].copy_from_slice(name);
+        nickname.len = name.len();
+        Some(nickname)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+// Device configuration entry, read with cR and written with cW
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanConfigKey {
+    Bitrate,   // b
+    BitTiming, // s
+    Crystal,   // x
+    UartBaud,  // u
+    Timestamp, // t
+    Nickname,  // n
+}
+
+impl SlcanConfigKey {
+    pub fn from_char(key: u8) -> Option<Self> {
+        match key {
+            b'b' => Some(SlcanConfigKey::Bitrate),
+            b's' => Some(SlcanConfigKey::BitTiming),
+            b'x' => Some(SlcanConfigKey::Crystal),
+            b'u' => Some(SlcanConfigKey::UartBaud),
+            b't' => Some(SlcanConfigKey::Timestamp),
+            b'n' => Some(SlcanConfigKey::Nickname),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> u8 {
+        match self {
+            SlcanConfigKey::Bitrate => b'b',
+            SlcanConfigKey::BitTiming => b's',
+            SlcanConfigKey::Crystal => b'x',
+            SlcanConfigKey::UartBaud => b'u',
+            SlcanConfigKey::Timestamp => b't',
+            SlcanConfigKey::Nickname => b'n',
+        }
+    }
+}
+
+// Value of a configuration entry, None keeps the board default
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanConfigValue {
+    // Bitrate at power on, in bits per second
+    Bitrate(Option<u32>),
+    // Bitrate at power on, as BTR0 and BTR1
+    BitTiming(Option<SlcanBitTiming>),
+    // MCP2515 crystal, in Hz
+    Crystal(Option<u32>),
+    // Baud rate of UART boards, in bits per second
+    UartBaud(Option<u32>),
+    Timestamp(SlcanTimestampMode),
+    Nickname(SlcanNickname),
+}
+
+impl SlcanConfigValue {
+    pub fn key(&self) -> SlcanConfigKey {
+        match self {
+            SlcanConfigValue::Bitrate(_) => SlcanConfigKey::Bitrate,
+            SlcanConfigValue::BitTiming(_) => SlcanConfigKey::BitTiming,
+            SlcanConfigValue::Crystal(_) => SlcanConfigKey::Crystal,
+            SlcanConfigValue::UartBaud(_) => SlcanConfigKey::UartBaud,
+            SlcanConfigValue::Timestamp(_) => SlcanConfigKey::Timestamp,
+            SlcanConfigValue::Nickname(_) => SlcanConfigKey::Nickname,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nickname() {
+        let nickname = SlcanNickname::new(b"Doggie 1").unwrap();
+        assert_eq!(nickname.as_bytes(), b"Doggie 1");
+        assert_eq!(SlcanNickname::new(b"").unwrap().as_bytes(), b"");
+
+        assert_eq!(SlcanNickname::new(b"A nickname too long"), None);
+        assert_eq!(SlcanNickname::new(b"Doggie\r"), None);
+    }
+
+    #[test]
+    fn test_config_key_chars() {
+        for key in [b'b', b's', b'x', b'u', b't', b'n'] {
+            assert_eq!(SlcanConfigKey::from_char(key).unwrap().to_char(), key);
+        }
+        assert_eq!(SlcanConfigKey::from_char(b'z'), None);
+    }
+}
@@ -1,6 +1,13 @@
 #![no_std]
 
+mod acceptance;
+mod config;
+mod rules;
+
+pub use acceptance::*;
+pub use config::*;
 use embedded_can::{ExtendedId, Id, StandardId};
+pub use rules::*;
 
 fn nibble_to_hex_char(value: u8) -> u8 {
     match value {
@@ -20,6 +27,16 @@ fn write_hex(value: u32, size: usize, buffer: &mut [u8]) -> usize {
     size
 }
 
+fn write_dec(value: u32, size: usize, buffer: &mut [u8]) -> usize {
+    let mut value = value;
+    for index in 0..size {
+        buffer[size - 1 - index] = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+
+    size
+}
+
 fn hex_char_to_u8(hex_char: u8) -> Option<u8> {
     match hex_char {
         b'0'..=b'9' => Some(hex_char - b'0'), // Convert '0'-'9' to 0-9
@@ -44,87 +61,226 @@ fn hex_char_slice_to_u32(slice: &[u8]) -> Option<u32> {
     Some(res)
 }
 
+// Max payload of a classic CAN frame
+pub const CAN_MAX_DLEN: usize = 8;
+// Max payload of a CAN FD frame
+pub const CANFD_MAX_DLEN: usize = 64;
+
+// Longest slcan message, a TX echo: Z + D + 8 id + 1 dlc + 128 data + ESI +
+// 8 timestamp + \r
+pub const SLCAN_MTU: usize = 149;
+
+// Follows the data of an FD frame sent by an error passive node
+const SLCAN_ESI_FLAG: u8 = b'i';
+
+// CAN FD data length for each DLC value (0-15)
+const CANFD_DLC_TO_LEN: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+pub fn canfd_dlc_to_len(dlc: u8) -> Option<usize> {
+    CANFD_DLC_TO_LEN.get(dlc as usize).copied()
+}
+
+pub fn canfd_len_to_dlc(len: usize) -> Option<u8> {
+    CANFD_DLC_TO_LEN
+        .iter()
+        .position(|l| *l == len)
+        .map(|dlc| dlc as u8)
+}
+
+// Lawicel timestamps are in milliseconds and wrap around at 60000
+pub const SLCAN_TIMESTAMP_WRAP_MS: u64 = 60_000;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanTimestampMode {
+    Off,
+    // Lawicel, 16 bits of milliseconds
+    Milliseconds,
+    // Extension, 32 bits of microseconds
+    Microseconds,
+}
+
+impl SlcanTimestampMode {
+    // Timestamp of a frame received `elapsed_us` after the counter started
+    pub fn timestamp(&self, elapsed_us: u64) -> Option<SlcanTimestamp> {
+        match self {
+            SlcanTimestampMode::Off => None,
+            SlcanTimestampMode::Milliseconds => Some(SlcanTimestamp::Milliseconds(
+                (elapsed_us / 1000 % SLCAN_TIMESTAMP_WRAP_MS) as u16,
+            )),
+            SlcanTimestampMode::Microseconds => {
+                Some(SlcanTimestamp::Microseconds(elapsed_us as u32))
+            }
+        }
+    }
+}
+
+// How the channel comes up at power on, set with Q
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanStartupMode {
+    // Closed, waiting for the host
+    Off,
+    // Open, as with O
+    Normal,
+    // Open in listen only, as with L
+    ListenOnly,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanTimestamp {
+    Milliseconds(u16),
+    Microseconds(u32),
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct CanFrame {
     pub id: Id,
-    pub data: [u8; 8],
+    pub data: [u8; CANFD_MAX_DLEN],
     pub dlc: usize,
-    pub timestamp: Option<u16>,
+    pub timestamp: Option<SlcanTimestamp>,
     is_remote: bool,
+    is_fd: bool,
+    brs: bool,
+    esi: bool,
 }
 
 impl CanFrame {
     pub fn new(id: impl Into<Id>, is_remote: bool, data: &[u8]) -> Option<Self> {
         let len = data.len();
-        if len > 16 {
+        if len > CAN_MAX_DLEN {
             return None;
         }
 
         let mut frame = CanFrame {
             id: id.into(),
             is_remote,
+            is_fd: false,
+            brs: false,
+            esi: false,
             dlc: len,
             timestamp: None,
-            data: [0; 8],
+            data: [0; CANFD_MAX_DLEN],
         };
 
-        for index in 0..len {
-            frame.data[index] = data[index];
-        }
+        frame.data[0..len].copy_from_slice(data);
 
         Some(frame)
     }
 
-    fn new_from_hex_data(id: impl Into<Id>, is_remote: bool, data: &[u8]) -> Option<Self> {
+    pub fn new_fd(id: impl Into<Id>, brs: bool, esi: bool, data: &[u8]) -> Option<Self> {
         let len = data.len();
-        if len > 16 {
-            return None;
-        }
-
-        if len % 2 != 0 {
-            return None;
-        }
 
-        let len = len / 2;
+        // Only the lengths representable by a DLC are valid on FD
+        canfd_len_to_dlc(len)?;
 
         let mut frame = CanFrame {
             id: id.into(),
-            is_remote: is_remote,
+            is_remote: false,
+            is_fd: true,
+            brs,
+            esi,
             dlc: len,
             timestamp: None,
-            data: [0; 8],
+            data: [0; CANFD_MAX_DLEN],
         };
 
-        for i in 0..len {
-            let Some(high) = hex_char_to_u8(data[2 * i]) else {
-                return None;
-            };
+        frame.data[0..len].copy_from_slice(data);
 
-            let Some(low) = hex_char_to_u8(data[2 * i + 1]) else {
-                return None;
-            };
+        Some(frame)
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.is_remote
+    }
+
+    pub fn is_fd(&self) -> bool {
+        self.is_fd
+    }
+
+    pub fn brs(&self) -> bool {
+        self.brs
+    }
+
+    pub fn esi(&self) -> bool {
+        self.esi
+    }
 
-            frame.data[i] = high << 4 | low;
+    pub fn data(&self) -> &[u8] {
+        &self.data[0..self.dlc]
+    }
+
+    fn decode_hex_data(data: &[u8], out: &mut [u8; CANFD_MAX_DLEN]) -> Option<usize> {
+        let len = data.len();
+        if len > CANFD_MAX_DLEN * 2 {
+            return None;
         }
 
-        Some(frame)
+        if len % 2 == 1 {
+            return None;
+        }
+
+        let len = len / 2;
+
+        for i in 0..len {
+            let high = hex_char_to_u8(data[2 * i])?;
+            let low = hex_char_to_u8(data[2 * i + 1])?;
+
+            out[i] = high << 4 | low;
+        }
+
+        Some(len)
+    }
+
+    fn new_from_hex_data(id: impl Into<Id>, is_remote: bool, data: &[u8]) -> Option<Self> {
+        let mut buffer = [0; CANFD_MAX_DLEN];
+        let len = Self::decode_hex_data(data, &mut buffer)?;
+
+        CanFrame::new(id, is_remote, &buffer[0..len])
+    }
+
+    fn new_fd_from_hex_data(id: impl Into<Id>, brs: bool, esi: bool, data: &[u8]) -> Option<Self> {
+        let mut buffer = [0; CANFD_MAX_DLEN];
+        let len = Self::decode_hex_data(data, &mut buffer)?;
+
+        CanFrame::new_fd(id, brs, esi, &buffer[0..len])
     }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum SlcanCommand {
-    OpenChannel,               // O
-    CloseChannel,              // C
-    ReadStatusFlags,           // F
-    Listen,                    // L
-    SetBitrate(SlcanBitrates), // S
-    SetBitTimeRegister(u32),   // s
-    Frame(CanFrame),           // t/r/T/R
-    FilterId(Id),              // m
-    FilterMask(Id),            // M
-    Timestamp(bool),           // Z
-    Version,                   // V/v
-    SerialNo,                  // N
+    OpenChannel,                        // O
+    CloseChannel,                       // C
+    ReadStatusFlags,                    // F
+    Listen,                             // L
+    SetBitrate(SlcanBitrates),          // S
+    SetBitTimeRegister(SlcanBitTiming), // s
+    Autobaud,                           // SA
+    Frame(CanFrame),                    // t/r/T/R/d/D/b/B
+    AcceptanceCode(u32),                // M
+    AcceptanceMask(u32),                // m
+    FilterMode(SlcanFilterMode),        // W
+    AddFilterRule(SlcanFilterRule),     // fA
+    ClearFilterRules,                   // fC
+    Timestamp(SlcanTimestampMode),      // Z
+    EchoMode(bool),                     // E, echo sent frames instead of z/Z
+    AutoPoll(bool),                     // X, frames are held until polled when off
+    PollOne,                            // P
+    PollAll,                            // A
+    PollEnd,                            // A response, after the polled frames
+    AutoStartup(SlcanStartupMode),      // Q, saves the current settings
+    ReadConfig(SlcanConfigKey),         // cR
+    WriteConfig(SlcanConfigValue),      // cW
+    FactoryReset,                       // cF, erases the device configuration
+    Version,                            // V/v
+    SerialNo,                           // N
+    StatusFlags(SlcanStatusFlags),      // F response
+    DetectedBitrate(u32),               // SA response, in bits per second
+    ConfigValue(SlcanConfigValue),      // cR response
+    State(SlcanState),                  // s, sent when the error state changes
+    TxDropped,                          // e1O, a frame that couldn't be sent
+    TxAck(Id),                          // z/Z response, the frame was accepted
+    TxEcho(CanFrame),                   // z/Z + frame, the frame was sent
+    Ack,                                // \r response
+    Bell,                               // BELL response
     IncompleteMessage,
 }
 
@@ -136,7 +292,7 @@ pub enum SlcanError {
 }
 
 #[repr(u16)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum SlcanBitrates {
     CAN10KB = 10,
     CAN20KB = 20,
@@ -149,39 +305,267 @@ pub enum SlcanBitrates {
     CAN1000KB = 1000,
 }
 
+impl SlcanBitrates {
+    pub fn bps(&self) -> u32 {
+        *self as u32 * 1000
+    }
+}
+
+// BTR0/BTR1 values are given for a SJA1000 running at 16 MHz, so the
+// time quantum clock (before the prescaler) is 8 MHz
+pub const SLCAN_BTR_CLOCK_HZ: u32 = 8_000_000;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SlcanBitTiming {
+    pub btr0: u8,
+    pub btr1: u8,
+}
+
+impl SlcanBitTiming {
+    pub fn prescaler(&self) -> u16 {
+        (self.btr0 & 0x3f) as u16 + 1
+    }
+
+    pub fn sjw(&self) -> u8 {
+        (self.btr0 >> 6) + 1
+    }
+
+    pub fn tseg1(&self) -> u8 {
+        (self.btr1 & 0x0f) + 1
+    }
+
+    pub fn tseg2(&self) -> u8 {
+        ((self.btr1 >> 4) & 0x07) + 1
+    }
+
+    pub fn triple_sample(&self) -> bool {
+        self.btr1 & 0x80 != 0
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        let quanta = 1 + self.tseg1() as u32 + self.tseg2() as u32;
+        SLCAN_BTR_CLOCK_HZ / (self.prescaler() as u32 * quanta)
+    }
+}
+
+// Lawicel status flags, as answered to the F command
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct SlcanStatusFlags(pub u8);
+
+impl SlcanStatusFlags {
+    pub const RX_FIFO_FULL: u8 = 1 << 0;
+    pub const TX_FIFO_FULL: u8 = 1 << 1;
+    pub const ERROR_WARNING: u8 = 1 << 2;
+    pub const DATA_OVERRUN: u8 = 1 << 3;
+    pub const ERROR_PASSIVE: u8 = 1 << 5;
+    pub const ARBITRATION_LOST: u8 = 1 << 6;
+    pub const BUS_ERROR: u8 = 1 << 7;
+
+    pub fn set(&mut self, flag: u8, value: bool) {
+        if value {
+            self.0 |= flag;
+        } else {
+            self.0 &= !flag;
+        }
+    }
+
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+}
+
+// Controller error state, as in the Linux slcan state frames
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanBusState {
+    Active,
+    Warning,
+    Passive,
+    BusOff,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SlcanState {
+    pub state: SlcanBusState,
+    pub tx_error_count: u8,
+    pub rx_error_count: u8,
+}
+
 pub struct SlcanSerializer {
-    msg_buffer: [u8; 31],
+    msg_buffer: [u8; SLCAN_MTU],
     msg_len: usize,
 }
 
+impl Default for SlcanSerializer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SlcanSerializer {
     pub fn new() -> Self {
         SlcanSerializer {
-            msg_buffer: [0; 31],
+            msg_buffer: [0; SLCAN_MTU],
             msg_len: 0,
         }
     }
 
-    pub fn to_bytes(&mut self, cmd: SlcanCommand) -> Option<([u8; 31], usize)> {
-        if let SlcanCommand::Frame(frame) = cmd {
-            Some(self.serialize_frame(frame))
-        } else {
-            None
+    pub fn to_bytes(&mut self, cmd: SlcanCommand) -> Option<([u8; SLCAN_MTU], usize)> {
+        match cmd {
+            SlcanCommand::Frame(frame) => Some(self.serialize_frame(frame)),
+            SlcanCommand::StatusFlags(flags) => Some(self.serialize_status_flags(flags)),
+            SlcanCommand::DetectedBitrate(bps) => Some(self.serialize_detected_bitrate(bps)),
+            SlcanCommand::State(state) => Some(self.serialize_state(state)),
+            SlcanCommand::TxDropped => Some(self.serialize_tx_dropped()),
+            SlcanCommand::TxAck(id) => Some(Self::serialize_tx_ack(id)),
+            SlcanCommand::TxEcho(frame) => Some(self.serialize_tx_echo(frame)),
+            SlcanCommand::PollEnd => Some(Self::serialize_poll_end()),
+            SlcanCommand::ConfigValue(value) => Some(Self::serialize_config_value(value)),
+            SlcanCommand::Ack => Some(Self::serialize_reply(b'\r')),
+            SlcanCommand::Bell => Some(Self::serialize_reply(0x07)),
+            _ => None,
+        }
+    }
+
+    fn serialize_detected_bitrate(&mut self, bps: u32) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+
+        res[0] = b'S';
+        res[1] = b'A';
+        let index = 2 + write_hex(bps, 8, &mut res[2..]);
+        res[index] = b'\r';
+
+        (res, index + 1)
+    }
+
+    // sXRRRTTT, with the error counters in decimal
+    fn serialize_state(&mut self, state: SlcanState) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+
+        res[0] = b's';
+        res[1] = match state.state {
+            SlcanBusState::Active => b'a',
+            SlcanBusState::Warning => b'w',
+            SlcanBusState::Passive => b'p',
+            SlcanBusState::BusOff => b'b',
+        };
+        let mut index = 2;
+        index += write_dec(state.rx_error_count as u32, 3, &mut res[index..]);
+        index += write_dec(state.tx_error_count as u32, 3, &mut res[index..]);
+        res[index] = b'\r';
+
+        (res, index + 1)
+    }
+
+    // Error frame with a single TX overflow error
+    fn serialize_tx_dropped(&mut self) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+        res[..4].copy_from_slice(b"e1O\r");
+
+        (res, 4)
+    }
+
+    // Lawicel answers z to t and r, Z to T and R
+    fn tx_ack(id: Id) -> u8 {
+        match id {
+            Id::Standard(_) => b'z',
+            Id::Extended(_) => b'Z',
+        }
+    }
+
+    fn serialize_tx_ack(id: Id) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+        res[0] = Self::tx_ack(id);
+        res[1] = b'\r';
+
+        (res, 2)
+    }
+
+    // The frame as received, after the ack character
+    fn serialize_tx_echo(&mut self, frame: CanFrame) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+
+        res[0] = Self::tx_ack(frame.id);
+        let (msg, size) = self.serialize_frame(frame);
+        res[1..1 + size].copy_from_slice(&msg[..size]);
+
+        (res, size + 1)
+    }
+
+    fn serialize_poll_end() -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+        res[0] = b'A';
+        res[1] = b'\r';
+
+        (res, 2)
+    }
+
+    // c, the key and its value, nothing for a board default
+    fn serialize_config_value(value: SlcanConfigValue) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+
+        res[0] = b'c';
+        res[1] = value.key().to_char();
+        let mut index = 2;
+        match value {
+            SlcanConfigValue::Bitrate(Some(value))
+            | SlcanConfigValue::Crystal(Some(value))
+            | SlcanConfigValue::UartBaud(Some(value)) => {
+                index += write_hex(value, 8, &mut res[index..]);
+            }
+            SlcanConfigValue::BitTiming(Some(timing)) => {
+                let btr = (timing.btr0 as u32) << 8 | timing.btr1 as u32;
+                index += write_hex(btr, 4, &mut res[index..]);
+            }
+            SlcanConfigValue::Timestamp(mode) => {
+                res[index] = match mode {
+                    SlcanTimestampMode::Off => b'0',
+                    SlcanTimestampMode::Milliseconds => b'1',
+                    SlcanTimestampMode::Microseconds => b'2',
+                };
+                index += 1;
+            }
+            SlcanConfigValue::Nickname(nickname) => {
+                let name = nickname.as_bytes();
+                res[index..index + name.len()].copy_from_slice(name);
+                index += name.len();
+            }
+            _ => {}
         }
+        res[index] = b'\r';
+
+        (res, index + 1)
+    }
+
+    fn serialize_reply(reply: u8) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+        res[0] = reply;
+
+        (res, 1)
+    }
+
+    fn serialize_status_flags(&mut self, flags: SlcanStatusFlags) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
+
+        res[0] = b'F';
+        let index = 1 + write_hex(flags.0 as u32, 2, &mut res[1..]);
+        res[index] = b'\r';
+
+        (res, index + 1)
     }
 
-    fn serialize_frame(&mut self, frame: CanFrame) -> ([u8; 31], usize) {
-        let mut res = [0; 31];
+    fn serialize_frame(&mut self, frame: CanFrame) -> ([u8; SLCAN_MTU], usize) {
+        let mut res = [0; SLCAN_MTU];
 
         let mut index: usize = 0;
 
         match frame.id {
             Id::Standard(id) => {
-                if frame.is_remote {
-                    res[0] = b'r';
-                } else {
-                    res[0] = b't';
-                }
+                res[0] = match (frame.is_fd, frame.brs, frame.is_remote) {
+                    (true, true, _) => b'b',
+                    (true, false, _) => b'd',
+                    (false, _, true) => b'r',
+                    (false, _, false) => b't',
+                };
 
                 index += 1;
 
@@ -189,11 +573,12 @@ impl SlcanSerializer {
             }
 
             Id::Extended(id) => {
-                if frame.is_remote {
-                    res[0] = b'R';
-                } else {
-                    res[0] = b'T';
-                }
+                res[0] = match (frame.is_fd, frame.brs, frame.is_remote) {
+                    (true, true, _) => b'B',
+                    (true, false, _) => b'D',
+                    (false, _, true) => b'R',
+                    (false, _, false) => b'T',
+                };
 
                 index += 1;
 
@@ -201,16 +586,28 @@ impl SlcanSerializer {
             }
         }
 
-        index += write_hex(frame.dlc as u32, 1, &mut res[index..]);
+        let dlc = if frame.is_fd {
+            // Frames are only built with valid FD lengths
+            canfd_len_to_dlc(frame.dlc).unwrap_or(0)
+        } else {
+            frame.dlc as u8
+        };
 
+        index += write_hex(dlc as u32, 1, &mut res[index..]);
         for i in 0..frame.dlc {
             index += write_hex(frame.data[i] as u32, 2, &mut res[index..]);
         }
 
+        if frame.is_fd && frame.esi {
+            res[index] = SLCAN_ESI_FLAG;
+            index += 1;
+        }
+
         match frame.timestamp {
-            Some(t) => {
-                index += write_hex(t as u32, 4, &mut res[index..]);
+            Some(SlcanTimestamp::Milliseconds(t)) => {
+                index += write_hex(t as u32, 4, &mut res[index..])
             }
+            Some(SlcanTimestamp::Microseconds(t)) => index += write_hex(t, 8, &mut res[index..]),
             None => {}
         }
 
@@ -232,7 +629,7 @@ impl SlcanSerializer {
     }
 
     pub fn from_byte(&mut self, byte: u8) -> Result<SlcanCommand, SlcanError> {
-        if self.msg_len < 31 {
+        if self.msg_len < SLCAN_MTU {
             self.msg_buffer[self.msg_len] = byte;
             self.msg_len += 1;
 
@@ -243,11 +640,11 @@ impl SlcanSerializer {
                 return cmd;
             }
 
-            return Ok(SlcanCommand::IncompleteMessage);
+            Ok(SlcanCommand::IncompleteMessage)
         } else {
             // Message too log
             self.msg_len = 0;
-            return Err(SlcanError::MessageTooLong);
+            Err(SlcanError::MessageTooLong)
         }
     }
 
@@ -258,14 +655,26 @@ impl SlcanSerializer {
             b'F' => self.deserialize_status_flag(),
             b'L' => self.deserialize_listen(),
             b'S' => self.deserialize_set_bitrate(),
-            b's' => Err(SlcanError::CommandNotImplemented),
+            b's' => self.deserialize_set_bit_time_register(),
             b't' => self.deserialize_standard_frame(false),
             b'T' => self.deserialize_extended_frame(false),
             b'r' => self.deserialize_standard_frame(true),
             b'R' => self.deserialize_extended_frame(true),
-            b'm' => self.deserialize_filter_id(),
-            b'M' => self.deserialize_filter_mask(),
+            b'd' => self.deserialize_standard_fd_frame(false),
+            b'D' => self.deserialize_extended_fd_frame(false),
+            b'b' => self.deserialize_standard_fd_frame(true),
+            b'B' => self.deserialize_extended_fd_frame(true),
+            b'M' => self.deserialize_acceptance(SlcanCommand::AcceptanceCode),
+            b'm' => self.deserialize_acceptance(SlcanCommand::AcceptanceMask),
+            b'W' => self.deserialize_filter_mode(),
+            b'f' => self.deserialize_filter_rule(),
             b'Z' => self.deserialize_timestamp(),
+            b'E' => self.deserialize_echo_mode(),
+            b'X' => self.deserialize_auto_poll(),
+            b'P' => self.deserialize_poll(SlcanCommand::PollOne),
+            b'A' => self.deserialize_poll(SlcanCommand::PollAll),
+            b'Q' => self.deserialize_auto_startup(),
+            b'c' => self.deserialize_config(),
             b'V' => self.deserialize_version(),
             b'v' => self.deserialize_version(),
             b'N' => self.deserialize_serial_no(),
@@ -320,64 +729,90 @@ impl SlcanSerializer {
         }
     }
 
-    fn deserialize_filter_id(&self) -> Result<SlcanCommand, SlcanError> {
-        match self.msg_len {
-            5 => {
-                // Standard Id
-                let Some(id) = hex_char_slice_to_u32(&self.msg_buffer[1..4]) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+    // Acceptance code and mask are the 4 SJA1000 registers, ACR0/AMR0 first
+    fn deserialize_acceptance(
+        &self,
+        command: fn(u32) -> SlcanCommand,
+    ) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len != 10 {
+            return Err(SlcanError::InvalidCommand);
+        }
 
-                let Some(standard_id) = StandardId::new(id as u16) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+        let Some(value) = hex_char_slice_to_u32(&self.msg_buffer[1..9]) else {
+            return Err(SlcanError::InvalidCommand);
+        };
 
-                Ok(SlcanCommand::FilterId(Id::Standard(standard_id)))
-            }
-            10 => {
-                // Extended Id
-                let Some(id) = hex_char_slice_to_u32(&self.msg_buffer[1..9]) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+        Ok(command(value))
+    }
 
-                let Some(extended_id) = ExtendedId::new(id) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+    fn deserialize_filter_mode(&self) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len != 3 {
+            return Err(SlcanError::InvalidCommand);
+        }
 
-                Ok(SlcanCommand::FilterId(Id::Extended(extended_id)))
-            }
+        match self.msg_buffer[1] {
+            b'0' => Ok(SlcanCommand::FilterMode(SlcanFilterMode::Dual)),
+            b'1' => Ok(SlcanCommand::FilterMode(SlcanFilterMode::Single)),
             _ => Err(SlcanError::InvalidCommand),
         }
     }
 
-    fn deserialize_filter_mask(&self) -> Result<SlcanCommand, SlcanError> {
-        match self.msg_len {
-            5 => {
-                // Standard Id
-                let Some(id) = hex_char_slice_to_u32(&self.msg_buffer[1..4]) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+    // fC clears the rules, fA adds one:
+    // fA<format><type><id low: 8><id high: 8>[<byte index: 2><value: 2><mask: 2>]...
+    // format is 0 any, 1 standard or 2 extended, type is 0 any, 1 data or 2 remote
+    fn deserialize_filter_rule(&self) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len < 3 {
+            return Err(SlcanError::InvalidCommand);
+        }
 
-                let Some(standard_id) = StandardId::new(id as u16) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+        let msg = &self.msg_buffer[2..self.msg_len - 1];
 
-                Ok(SlcanCommand::FilterMask(Id::Standard(standard_id)))
-            }
-            10 => {
-                // Extended Id
-                let Some(id) = hex_char_slice_to_u32(&self.msg_buffer[1..9]) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+        match self.msg_buffer[1] {
+            b'C' if msg.is_empty() => Ok(SlcanCommand::ClearFilterRules),
+            b'A' => Self::parse_filter_rule(msg)
+                .map(SlcanCommand::AddFilterRule)
+                .ok_or(SlcanError::InvalidCommand),
+            _ => Err(SlcanError::InvalidCommand),
+        }
+    }
 
-                let Some(extended_id) = ExtendedId::new(id) else {
-                    return Err(SlcanError::InvalidCommand);
-                };
+    fn parse_filter_rule(msg: &[u8]) -> Option<SlcanFilterRule> {
+        if msg.len() < 18 {
+            return None;
+        }
 
-                Ok(SlcanCommand::FilterMask(Id::Extended(extended_id)))
-            }
-            _ => Err(SlcanError::InvalidCommand),
+        let payload_matches = msg[18..].chunks_exact(6);
+        if !payload_matches.remainder().is_empty() {
+            return None;
+        }
+
+        let format = match msg[0] {
+            b'0' => SlcanIdFormat::Any,
+            b'1' => SlcanIdFormat::Standard,
+            b'2' => SlcanIdFormat::Extended,
+            _ => return None,
+        };
+
+        let frame_type = match msg[1] {
+            b'0' => SlcanFrameType::Any,
+            b'1' => SlcanFrameType::Data,
+            b'2' => SlcanFrameType::Remote,
+            _ => return None,
+        };
+
+        let id_low = hex_char_slice_to_u32(&msg[2..10])?;
+        let id_high = hex_char_slice_to_u32(&msg[10..18])?;
+        let mut rule = SlcanFilterRule::new(format, frame_type, id_low, id_high)?;
+
+        for payload_match in payload_matches {
+            rule = rule.with_payload_match(SlcanPayloadMatch {
+                index: hex_char_slice_to_u32(&payload_match[0..2])? as u8,
+                value: hex_char_slice_to_u32(&payload_match[2..4])? as u8,
+                mask: hex_char_slice_to_u32(&payload_match[4..6])? as u8,
+            })?;
         }
+
+        Some(rule)
     }
 
     fn deserialize_standard_frame(&self, is_remote: bool) -> Result<SlcanCommand, SlcanError> {
@@ -438,6 +873,79 @@ impl SlcanSerializer {
         Ok(SlcanCommand::Frame(new_frame))
     }
 
+    // ESI flag of an FD frame whose data ends at `data_end`, None when
+    // something else follows the data
+    fn fd_esi_flag(&self, data_end: usize) -> Option<bool> {
+        match self.msg_len.checked_sub(data_end)? {
+            1 => Some(false),
+            2 if self.msg_buffer[data_end] == SLCAN_ESI_FLAG => Some(true),
+            _ => None,
+        }
+    }
+
+    fn deserialize_standard_fd_frame(&self, brs: bool) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len < 6 {
+            return Err(SlcanError::InvalidCommand);
+        }
+        let Some(id) = hex_char_slice_to_u32(&self.msg_buffer[1..4]) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+        let Some(dlc) = hex_char_to_u8(self.msg_buffer[4]) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+        let Some(len) = canfd_dlc_to_len(dlc) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+
+        let Some(esi) = self.fd_esi_flag(5 + len * 2) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+
+        let Some(standard_id) = StandardId::new(id as u16) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+
+        let Some(new_frame) =
+            CanFrame::new_fd_from_hex_data(standard_id, brs, esi, &self.msg_buffer[5..5 + len * 2])
+        else {
+            return Err(SlcanError::InvalidCommand);
+        };
+        Ok(SlcanCommand::Frame(new_frame))
+    }
+
+    fn deserialize_extended_fd_frame(&self, brs: bool) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len < 11 {
+            return Err(SlcanError::InvalidCommand);
+        }
+        let Some(id) = hex_char_slice_to_u32(&self.msg_buffer[1..9]) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+        let Some(dlc) = hex_char_to_u8(self.msg_buffer[9]) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+        let Some(len) = canfd_dlc_to_len(dlc) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+
+        let Some(esi) = self.fd_esi_flag(10 + len * 2) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+
+        let Some(extended_id) = ExtendedId::new(id) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+
+        let Some(new_frame) = CanFrame::new_fd_from_hex_data(
+            extended_id,
+            brs,
+            esi,
+            &self.msg_buffer[10..10 + len * 2],
+        ) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+        Ok(SlcanCommand::Frame(new_frame))
+    }
+
     fn deserialize_set_bitrate(&self) -> Result<SlcanCommand, SlcanError> {
         if self.msg_len == 3 {
             match self.msg_buffer[1] {
@@ -450,6 +958,8 @@ impl SlcanSerializer {
                 b'6' => Ok(SlcanCommand::SetBitrate(SlcanBitrates::CAN500KB)),
                 b'7' => Ok(SlcanCommand::SetBitrate(SlcanBitrates::CAN800KB)),
                 b'8' => Ok(SlcanCommand::SetBitrate(SlcanBitrates::CAN1000KB)),
+                // Extension, detect the bitrate
+                b'A' => Ok(SlcanCommand::Autobaud),
                 _ => Err(SlcanError::InvalidCommand),
             }
         } else {
@@ -457,11 +967,131 @@ impl SlcanSerializer {
         }
     }
 
-    fn deserialize_timestamp(&self) -> Result<SlcanCommand, SlcanError> {
+    fn deserialize_set_bit_time_register(&self) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len != 6 {
+            return Err(SlcanError::InvalidCommand);
+        }
+
+        let Some(btr) = hex_char_slice_to_u32(&self.msg_buffer[1..5]) else {
+            return Err(SlcanError::InvalidCommand);
+        };
+
+        Ok(SlcanCommand::SetBitTimeRegister(SlcanBitTiming {
+            btr0: (btr >> 8) as u8,
+            btr1: btr as u8,
+        }))
+    }
+
+    fn deserialize_echo_mode(&self) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len == 3 {
+            match self.msg_buffer[1] {
+                b'0' => Ok(SlcanCommand::EchoMode(false)),
+                b'1' => Ok(SlcanCommand::EchoMode(true)),
+                _ => Err(SlcanError::InvalidCommand),
+            }
+        } else {
+            Err(SlcanError::InvalidCommand)
+        }
+    }
+
+    fn deserialize_auto_poll(&self) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len == 3 {
+            match self.msg_buffer[1] {
+                b'0' => Ok(SlcanCommand::AutoPoll(false)),
+                b'1' => Ok(SlcanCommand::AutoPoll(true)),
+                _ => Err(SlcanError::InvalidCommand),
+            }
+        } else {
+            Err(SlcanError::InvalidCommand)
+        }
+    }
+
+    fn deserialize_auto_startup(&self) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len == 3 {
+            match self.msg_buffer[1] {
+                b'0' => Ok(SlcanCommand::AutoStartup(SlcanStartupMode::Off)),
+                b'1' => Ok(SlcanCommand::AutoStartup(SlcanStartupMode::Normal)),
+                b'2' => Ok(SlcanCommand::AutoStartup(SlcanStartupMode::ListenOnly)),
+                _ => Err(SlcanError::InvalidCommand),
+            }
+        } else {
+            Err(SlcanError::InvalidCommand)
+        }
+    }
+
+    fn deserialize_config(&self) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len < 3 {
+            return Err(SlcanError::InvalidCommand);
+        }
+
+        let msg = &self.msg_buffer[2..self.msg_len - 1];
+
+        match self.msg_buffer[1] {
+            b'F' if msg.is_empty() => Ok(SlcanCommand::FactoryReset),
+            b'R' if msg.len() == 1 => SlcanConfigKey::from_char(msg[0])
+                .map(SlcanCommand::ReadConfig)
+                .ok_or(SlcanError::InvalidCommand),
+            b'W' if !msg.is_empty() => Self::parse_config_value(msg)
+                .map(SlcanCommand::WriteConfig)
+                .ok_or(SlcanError::InvalidCommand),
+            _ => Err(SlcanError::InvalidCommand),
+        }
+    }
+
+    // Key and value, an empty value sets the board default back
+    fn parse_config_value(msg: &[u8]) -> Option<SlcanConfigValue> {
+        let value = &msg[1..];
+
+        // Zero is not a valid bitrate, crystal or baud rate
+        let hex_u32 = || match value.len() {
+            0 => Some(None),
+            8 => hex_char_slice_to_u32(value).filter(|v| *v != 0).map(Some),
+            _ => None,
+        };
+
+        match SlcanConfigKey::from_char(msg[0])? {
+            SlcanConfigKey::Bitrate => hex_u32().map(SlcanConfigValue::Bitrate),
+            SlcanConfigKey::Crystal => hex_u32().map(SlcanConfigValue::Crystal),
+            SlcanConfigKey::UartBaud => hex_u32().map(SlcanConfigValue::UartBaud),
+            SlcanConfigKey::BitTiming => match value.len() {
+                0 => Some(SlcanConfigValue::BitTiming(None)),
+                4 => {
+                    let btr = hex_char_slice_to_u32(value)?;
+                    Some(SlcanConfigValue::BitTiming(Some(SlcanBitTiming {
+                        btr0: (btr >> 8) as u8,
+                        btr1: btr as u8,
+                    })))
+                }
+                _ => None,
+            },
+            SlcanConfigKey::Timestamp => match value {
+                b"0" => Some(SlcanConfigValue::Timestamp(SlcanTimestampMode::Off)),
+                b"1" => Some(SlcanConfigValue::Timestamp(
+                    SlcanTimestampMode::Milliseconds,
+                )),
+                b"2" => Some(SlcanConfigValue::Timestamp(
+                    SlcanTimestampMode::Microseconds,
+                )),
+                _ => None,
+            },
+            SlcanConfigKey::Nickname => SlcanNickname::new(value).map(SlcanConfigValue::Nickname),
+        }
+    }
+
+    fn deserialize_poll(&self, cmd: SlcanCommand) -> Result<SlcanCommand, SlcanError> {
+        if self.msg_len == 2 {
+            Ok(cmd)
+        } else {
+            Err(SlcanError::InvalidCommand)
+        }
+    }
+
+    fn deserialize_timestamp(&self) -> Result<SlcanCommand, SlcanError> {
         if self.msg_len == 3 {
             match self.msg_buffer[1] {
-                b'0' => Ok(SlcanCommand::Timestamp(false)),
-                b'1' => Ok(SlcanCommand::Timestamp(true)),
+                b'0' => Ok(SlcanCommand::Timestamp(SlcanTimestampMode::Off)),
+                b'1' => Ok(SlcanCommand::Timestamp(SlcanTimestampMode::Milliseconds)),
+                b'2' => Ok(SlcanCommand::Timestamp(SlcanTimestampMode::Microseconds)),
                 _ => Err(SlcanError::InvalidCommand),
             }
         } else {
@@ -474,6 +1104,12 @@ impl SlcanSerializer {
 mod tests {
     use super::*;
 
+    fn classic_data(data: [u8; 8]) -> [u8; CANFD_MAX_DLEN] {
+        let mut res = [0; CANFD_MAX_DLEN];
+        res[0..8].copy_from_slice(&data);
+        res
+    }
+
     #[test]
     fn test_deserialize_from_bytes() {
         let mut serializer = SlcanSerializer::new();
@@ -526,14 +1162,65 @@ mod tests {
     }
 
     #[test]
-    fn test_deserialize_command_not_implemented() {
+    fn test_deserialize_set_bit_time_register_valid() {
+        let mut serializer = SlcanSerializer::new();
+        // s031C : BTR0 0x03, BTR1 0x1C (125 Kbps)
+        assert_eq!(
+            serializer.from_bytes(b"s031C\r"),
+            Ok(SlcanCommand::SetBitTimeRegister(SlcanBitTiming {
+                btr0: 0x03,
+                btr1: 0x1C
+            }))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_set_bit_time_register_invalid_len() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
             serializer.from_bytes(b"s\r"),
-            Err(SlcanError::CommandNotImplemented)
+            Err(SlcanError::InvalidCommand)
         );
     }
 
+    #[test]
+    fn test_deserialize_set_bit_time_register_invalid_hex() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"s03XC\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_bit_timing_125kbps() {
+        let timing = SlcanBitTiming {
+            btr0: 0x03,
+            btr1: 0x1C,
+        };
+        assert_eq!(timing.prescaler(), 4);
+        assert_eq!(timing.sjw(), 1);
+        assert_eq!(timing.tseg1(), 13);
+        assert_eq!(timing.tseg2(), 2);
+        assert!(!timing.triple_sample());
+        assert_eq!(timing.bitrate(), 125_000);
+    }
+
+    #[test]
+    fn test_bit_timing_83_3kbps() {
+        // BRP 6, SJW 2, TSEG1 11, TSEG2 4, triple sampling
+        let timing = SlcanBitTiming {
+            btr0: 0x45,
+            btr1: 0xBA,
+        };
+        assert_eq!(timing.prescaler(), 6);
+        assert_eq!(timing.sjw(), 2);
+        assert_eq!(timing.tseg1(), 11);
+        assert_eq!(timing.tseg2(), 4);
+        assert!(timing.triple_sample());
+        assert_eq!(timing.bitrate(), 83_333);
+    }
+
     #[test]
     fn test_deserialize_undefined_command() {
         let mut serializer = SlcanSerializer::new();
@@ -547,7 +1234,7 @@ mod tests {
     fn test_deserialize_too_long_command() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX"),
+            serializer.from_bytes(&[b'X'; SLCAN_MTU + 1]),
             Err(SlcanError::MessageTooLong)
         );
     }
@@ -666,6 +1353,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bitrate_bps() {
+        assert_eq!(SlcanBitrates::CAN10KB.bps(), 10_000);
+        assert_eq!(SlcanBitrates::CAN800KB.bps(), 800_000);
+    }
+
+    #[test]
+    fn test_deserialize_autobaud() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(serializer.from_bytes(b"SA\r"), Ok(SlcanCommand::Autobaud));
+    }
+
     #[test]
     fn test_deserialize_set_bitrate_invalid_bitrate() {
         let mut serializer = SlcanSerializer::new();
@@ -710,10 +1409,13 @@ mod tests {
             serializer.from_bytes(b"t1230\r"),
             Ok(SlcanCommand::Frame(CanFrame {
                 id: Id::Standard(StandardId::new(0x123).unwrap()),
-                data: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                data: classic_data([0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
                 dlc: 0,
                 timestamp: None,
                 is_remote: false,
+                is_fd: false,
+                brs: false,
+                esi: false,
             }))
         );
     }
@@ -735,10 +1437,13 @@ mod tests {
             serializer.from_bytes(b"t4563112233\r"),
             Ok(SlcanCommand::Frame(CanFrame {
                 id: Id::Standard(StandardId::new(0x456).unwrap()),
-                data: [0x11, 0x22, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00],
+                data: classic_data([0x11, 0x22, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00]),
                 dlc: 3,
                 timestamp: None,
                 is_remote: false,
+                is_fd: false,
+                brs: false,
+                esi: false,
             }))
         );
     }
@@ -803,10 +1508,132 @@ mod tests {
         assert_eq!(serializer.to_bytes(SlcanCommand::OpenChannel), None)
     }
 
+    #[test]
+    fn test_serialize_detected_bitrate() {
+        let mut serializer = SlcanSerializer::new();
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[..11].copy_from_slice(b"SA0007A120\r");
+
+        assert_eq!(
+            serializer
+                .to_bytes(SlcanCommand::DetectedBitrate(500_000))
+                .unwrap(),
+            (res, 11)
+        )
+    }
+
+    #[test]
+    fn test_serialize_state() {
+        let mut serializer = SlcanSerializer::new();
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[..9].copy_from_slice(b"sp057133\r");
+
+        assert_eq!(
+            serializer
+                .to_bytes(SlcanCommand::State(SlcanState {
+                    state: SlcanBusState::Passive,
+                    tx_error_count: 133,
+                    rx_error_count: 57,
+                }))
+                .unwrap(),
+            (res, 9)
+        )
+    }
+
+    #[test]
+    fn test_serialize_state_bus_off() {
+        let mut serializer = SlcanSerializer::new();
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[..9].copy_from_slice(b"sb000255\r");
+
+        assert_eq!(
+            serializer
+                .to_bytes(SlcanCommand::State(SlcanState {
+                    state: SlcanBusState::BusOff,
+                    tx_error_count: 255,
+                    rx_error_count: 0,
+                }))
+                .unwrap(),
+            (res, 9)
+        )
+    }
+
+    #[test]
+    fn test_serialize_tx_dropped() {
+        let mut serializer = SlcanSerializer::new();
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[..4].copy_from_slice(b"e1O\r");
+
+        assert_eq!(
+            serializer.to_bytes(SlcanCommand::TxDropped).unwrap(),
+            (res, 4)
+        )
+    }
+
+    #[test]
+    fn test_serialize_ack() {
+        let mut serializer = SlcanSerializer::new();
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[0] = b'\r';
+
+        assert_eq!(serializer.to_bytes(SlcanCommand::Ack).unwrap(), (res, 1))
+    }
+
+    #[test]
+    fn test_serialize_bell() {
+        let mut serializer = SlcanSerializer::new();
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[0] = 0x07;
+
+        assert_eq!(serializer.to_bytes(SlcanCommand::Bell).unwrap(), (res, 1))
+    }
+
+    #[test]
+    fn test_serialize_status_flags_empty() {
+        let mut serializer = SlcanSerializer::new();
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[0] = b'F';
+        res[1] = b'0';
+        res[2] = b'0';
+        res[3] = b'\r';
+
+        assert_eq!(
+            serializer
+                .to_bytes(SlcanCommand::StatusFlags(SlcanStatusFlags::default()))
+                .unwrap(),
+            (res, 4)
+        );
+    }
+
+    #[test]
+    fn test_serialize_status_flags() {
+        let mut serializer = SlcanSerializer::new();
+        let mut flags = SlcanStatusFlags::default();
+        flags.set(SlcanStatusFlags::DATA_OVERRUN, true);
+        flags.set(SlcanStatusFlags::BUS_ERROR, true);
+        flags.set(SlcanStatusFlags::ERROR_WARNING, true);
+        flags.set(SlcanStatusFlags::ERROR_WARNING, false);
+
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[0] = b'F';
+        res[1] = b'8';
+        res[2] = b'8';
+        res[3] = b'\r';
+
+        assert!(flags.contains(SlcanStatusFlags::BUS_ERROR));
+        assert!(!flags.contains(SlcanStatusFlags::ERROR_WARNING));
+        assert_eq!(
+            serializer
+                .to_bytes(SlcanCommand::StatusFlags(flags))
+                .unwrap(),
+            (res, 4)
+        );
+    }
+
     #[test]
     fn test_serialize_standard_frame_t_len_0() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b't';
         res[1] = b'1';
         res[2] = b'2';
@@ -818,20 +1645,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Standard(StandardId::new(0x123).unwrap()),
-                    data: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 0,
                     timestamp: None,
-                    is_remote: false
+                    is_remote: false,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 6)
         );
     }
 
     #[test]
     fn test_serialize_standard_frame_t_len_3() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b't';
         res[1] = b'1';
         res[2] = b'2';
@@ -849,20 +1679,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Standard(StandardId::new(0x123).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
                     timestamp: None,
-                    is_remote: false
+                    is_remote: false,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 12)
         );
     }
 
     #[test]
     fn test_serialize_extended_frame_t_len_0() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'T';
         res[1] = b'1';
         res[2] = b'2';
@@ -879,20 +1712,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Extended(ExtendedId::new(0x12345678).unwrap()),
-                    data: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 0,
                     timestamp: None,
-                    is_remote: false
+                    is_remote: false,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 11)
         );
     }
 
     #[test]
     fn test_serialize_extended_frame_t_len_3() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'T';
         res[1] = b'1';
         res[2] = b'2';
@@ -915,20 +1751,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Extended(ExtendedId::new(0x12345678).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
                     timestamp: None,
-                    is_remote: false
+                    is_remote: false,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 17)
         );
     }
 
     #[test]
     fn test_serialize_standard_frame_r_len_0() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'r';
         res[1] = b'1';
         res[2] = b'2';
@@ -940,20 +1779,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Standard(StandardId::new(0x123).unwrap()),
-                    data: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 0,
                     timestamp: None,
-                    is_remote: true
+                    is_remote: true,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 6)
         );
     }
 
     #[test]
     fn test_serialize_standard_frame_r_len_3() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'r';
         res[1] = b'1';
         res[2] = b'2';
@@ -971,20 +1813,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Standard(StandardId::new(0x123).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
                     timestamp: None,
-                    is_remote: true
+                    is_remote: true,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 12)
         );
     }
 
     #[test]
     fn test_serialize_extended_frame_r_len_0() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'R';
         res[1] = b'1';
         res[2] = b'2';
@@ -1001,20 +1846,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Extended(ExtendedId::new(0x12345678).unwrap()),
-                    data: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 0,
                     timestamp: None,
-                    is_remote: true
+                    is_remote: true,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 11)
         );
     }
 
     #[test]
     fn test_serialize_extended_frame_r_len_3() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'R';
         res[1] = b'1';
         res[2] = b'2';
@@ -1037,13 +1885,16 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Extended(ExtendedId::new(0x12345678).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
                     timestamp: None,
-                    is_remote: true
+                    is_remote: true,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 17)
         );
     }
 
@@ -1055,10 +1906,13 @@ mod tests {
             serializer.from_bytes(b"r4563112233\r"),
             Ok(SlcanCommand::Frame(CanFrame {
                 id: Id::Standard(StandardId::new(0x456).unwrap()),
-                data: [0x11, 0x22, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00],
+                data: classic_data([0x11, 0x22, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00]),
                 dlc: 3,
                 timestamp: None,
                 is_remote: true,
+                is_fd: false,
+                brs: false,
+                esi: false,
             }))
         );
     }
@@ -1089,10 +1943,13 @@ mod tests {
             serializer.from_bytes(b"T12ABCDEF2AA55\r"),
             Ok(SlcanCommand::Frame(CanFrame {
                 id: Id::Extended(ExtendedId::new(0x12ABCDEF).unwrap()),
-                data: [0xAA, 0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                data: classic_data([0xAA, 0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
                 dlc: 2,
                 timestamp: None,
                 is_remote: false,
+                is_fd: false,
+                brs: false,
+                esi: false,
             }))
         );
     }
@@ -1141,175 +1998,235 @@ mod tests {
             serializer.from_bytes(b"R12ABCDEF2AA55\r"),
             Ok(SlcanCommand::Frame(CanFrame {
                 id: Id::Extended(ExtendedId::new(0x12ABCDEF).unwrap()),
-                data: [0xAA, 0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                data: classic_data([0xAA, 0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
                 dlc: 2,
                 timestamp: None,
                 is_remote: true,
+                is_fd: false,
+                brs: false,
+                esi: false,
             }))
         );
     }
 
     #[test]
-    fn test_deserialize_filter_standard_id_valid() {
+    fn test_deserialize_acceptance_code_valid() {
         let mut serializer = SlcanSerializer::new();
-        // m123 : Filter standard id 0x123
+        // M00000000 : Acceptance code 0x00000000
         assert_eq!(
-            serializer.from_bytes(b"m123\r"),
-            Ok(SlcanCommand::FilterId(Id::Standard(
-                StandardId::new(0x123).unwrap()
-            )))
+            serializer.from_bytes(b"M00000000\r"),
+            Ok(SlcanCommand::AcceptanceCode(0x00000000))
         )
     }
 
     #[test]
-    fn test_deserialize_filter_extended_id_valid() {
+    fn test_deserialize_acceptance_mask_valid() {
         let mut serializer = SlcanSerializer::new();
-        // m12ABCDEF : Filter extended id 0x12ABCDEF
+        // mFFFFFFFF : Acceptance mask 0xFFFFFFFF
         assert_eq!(
-            serializer.from_bytes(b"m12ABCDEF\r"),
-            Ok(SlcanCommand::FilterId(Id::Extended(
-                ExtendedId::new(0x12ABCDEF).unwrap()
-            )))
+            serializer.from_bytes(b"mFFFFFFFF\r"),
+            Ok(SlcanCommand::AcceptanceMask(0xFFFFFFFF))
         )
     }
 
     #[test]
-    fn test_deserialize_filter_id_invalid_len() {
+    fn test_deserialize_acceptance_lowercase_hex() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"m1\r"),
-            Err(SlcanError::InvalidCommand)
+            serializer.from_bytes(b"M2460abcd\r"),
+            Ok(SlcanCommand::AcceptanceCode(0x2460ABCD))
         )
     }
 
     #[test]
-    fn test_deserialize_filter_standard_id_invalid_hex() {
+    fn test_deserialize_acceptance_code_invalid_len() {
         let mut serializer = SlcanSerializer::new();
+        // The old id format is not accepted
         assert_eq!(
-            serializer.from_bytes(b"m1X3\r"),
+            serializer.from_bytes(b"M123\r"),
             Err(SlcanError::InvalidCommand)
         )
     }
 
     #[test]
-    fn test_deserialize_filter_standard_id_invalid_range() {
+    fn test_deserialize_acceptance_mask_invalid_len() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"mFFF\r"),
+            serializer.from_bytes(b"m123456789\r"),
             Err(SlcanError::InvalidCommand)
         )
     }
 
     #[test]
-    fn test_deserialize_filter_extended_id_invalid_hex() {
+    fn test_deserialize_acceptance_invalid_hex() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"m1X345678\r"),
+            serializer.from_bytes(b"M1X345678\r"),
             Err(SlcanError::InvalidCommand)
         )
     }
 
     #[test]
-    fn test_deserialize_filter_extended_id_invalid_range() {
+    fn test_deserialize_filter_mode() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"mFFFFFFFF\r"),
-            Err(SlcanError::InvalidCommand)
+            serializer.from_bytes(b"W0\r"),
+            Ok(SlcanCommand::FilterMode(SlcanFilterMode::Dual))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"W1\r"),
+            Ok(SlcanCommand::FilterMode(SlcanFilterMode::Single))
         )
     }
 
     #[test]
-    fn test_deserialize_filter_standard_mask_valid() {
+    fn test_deserialize_filter_mode_invalid() {
         let mut serializer = SlcanSerializer::new();
-        // M123 : Filter standard mask 0x123
         assert_eq!(
-            serializer.from_bytes(b"M123\r"),
-            Ok(SlcanCommand::FilterMask(Id::Standard(
-                StandardId::new(0x123).unwrap()
-            )))
+            serializer.from_bytes(b"W2\r"),
+            Err(SlcanError::InvalidCommand)
         )
     }
 
     #[test]
-    fn test_deserialize_filter_extended_mask_valid() {
+    fn test_deserialize_clear_filter_rules() {
         let mut serializer = SlcanSerializer::new();
-        // M12ABCDEF : Filter extended mask 0x12ABCDEF
         assert_eq!(
-            serializer.from_bytes(b"M12ABCDEF\r"),
-            Ok(SlcanCommand::FilterMask(Id::Extended(
-                ExtendedId::new(0x12ABCDEF).unwrap()
-            )))
+            serializer.from_bytes(b"fC\r"),
+            Ok(SlcanCommand::ClearFilterRules)
         )
     }
 
     #[test]
-    fn test_deserialize_filter_mask_invalid_len() {
+    fn test_deserialize_filter_rule_range() {
         let mut serializer = SlcanSerializer::new();
+        // fA10000001000000001FF : Standard frames with ids 0x100 to 0x1FF
         assert_eq!(
-            serializer.from_bytes(b"M1\r"),
-            Err(SlcanError::InvalidCommand)
+            serializer.from_bytes(b"fA1000000100000001FF\r"),
+            Ok(SlcanCommand::AddFilterRule(
+                SlcanFilterRule::new(SlcanIdFormat::Standard, SlcanFrameType::Any, 0x100, 0x1FF)
+                    .unwrap()
+            ))
         )
     }
 
     #[test]
-    fn test_deserialize_filter_standard_mask_invalid_hex() {
+    fn test_deserialize_filter_rule_payload() {
         let mut serializer = SlcanSerializer::new();
+        // Data frames with id 0x7E8 and 0x41 as second byte
         assert_eq!(
-            serializer.from_bytes(b"M1X3\r"),
-            Err(SlcanError::InvalidCommand)
+            serializer.from_bytes(b"fA01000007E8000007E80141FF\r"),
+            Ok(SlcanCommand::AddFilterRule(
+                SlcanFilterRule::new(SlcanIdFormat::Any, SlcanFrameType::Data, 0x7E8, 0x7E8)
+                    .unwrap()
+                    .with_payload_match(SlcanPayloadMatch {
+                        index: 1,
+                        value: 0x41,
+                        mask: 0xFF
+                    })
+                    .unwrap()
+            ))
         )
     }
 
     #[test]
-    fn test_deserialize_filter_standard_mask_invalid_range() {
+    fn test_deserialize_filter_rule_invalid() {
         let mut serializer = SlcanSerializer::new();
+        // Empty range
         assert_eq!(
-            serializer.from_bytes(b"MFFF\r"),
+            serializer.from_bytes(b"fA0000000200000000100\r"),
             Err(SlcanError::InvalidCommand)
-        )
+        );
+        // Unknown format
+        assert_eq!(
+            serializer.from_bytes(b"fA30000000000000007FF\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+        // Incomplete payload match
+        assert_eq!(
+            serializer.from_bytes(b"fA000000000000007FF0141\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+        // Too many payload matches
+        assert_eq!(
+            serializer.from_bytes(b"fA000000000000007FF0000000100000200000300000400\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+        assert_eq!(
+            serializer.from_bytes(b"fX\r"),
+            Err(SlcanError::InvalidCommand)
+        );
     }
 
     #[test]
-    fn test_deserialize_filter_extended_mask_invalid_hex() {
+    fn test_deserialize_timestamp_enabled() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"M1X345678\r"),
-            Err(SlcanError::InvalidCommand)
+            serializer.from_bytes(b"Z1\r"),
+            Ok(SlcanCommand::Timestamp(SlcanTimestampMode::Milliseconds))
         )
     }
 
     #[test]
-    fn test_deserialize_filter_extended_mask_invalid_range() {
+    fn test_deserialize_timestamp_disabled() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"MFFFFFFFF\r"),
-            Err(SlcanError::InvalidCommand)
+            serializer.from_bytes(b"Z0\r"),
+            Ok(SlcanCommand::Timestamp(SlcanTimestampMode::Off))
         )
     }
 
     #[test]
-    fn test_deserialize_timestamp_enabled() {
+    fn test_deserialize_timestamp_micros() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"Z1\r"),
-            Ok(SlcanCommand::Timestamp(true))
+            serializer.from_bytes(b"Z2\r"),
+            Ok(SlcanCommand::Timestamp(SlcanTimestampMode::Microseconds))
         )
     }
 
     #[test]
-    fn test_deserialize_timestamp_disabled() {
-        let mut serializer = SlcanSerializer::new();
+    fn test_timestamp_off() {
+        assert_eq!(SlcanTimestampMode::Off.timestamp(1_500), None);
+    }
+
+    #[test]
+    fn test_timestamp_millis() {
         assert_eq!(
-            serializer.from_bytes(b"Z0\r"),
-            Ok(SlcanCommand::Timestamp(false))
-        )
+            SlcanTimestampMode::Milliseconds.timestamp(1_500),
+            Some(SlcanTimestamp::Milliseconds(1))
+        );
+    }
+
+    #[test]
+    fn test_timestamp_millis_wrap() {
+        assert_eq!(
+            SlcanTimestampMode::Milliseconds.timestamp(59_999_999),
+            Some(SlcanTimestamp::Milliseconds(59_999))
+        );
+        assert_eq!(
+            SlcanTimestampMode::Milliseconds.timestamp(60_000_000),
+            Some(SlcanTimestamp::Milliseconds(0))
+        );
+        assert_eq!(
+            SlcanTimestampMode::Milliseconds.timestamp(125_000_000),
+            Some(SlcanTimestamp::Milliseconds(5_000))
+        );
+    }
+
+    #[test]
+    fn test_timestamp_micros() {
+        assert_eq!(
+            SlcanTimestampMode::Microseconds.timestamp(0x1_0000_0010),
+            Some(SlcanTimestamp::Microseconds(0x10))
+        );
     }
 
     #[test]
     fn test_deserialize_timestamp_wrong_val() {
         let mut serializer = SlcanSerializer::new();
         assert_eq!(
-            serializer.from_bytes(b"Z8\r"),
+            serializer.from_bytes(b"Z3\r"),
             Err(SlcanError::InvalidCommand)
         )
     }
@@ -1389,7 +2306,7 @@ mod tests {
     #[test]
     fn test_serialize_standard_frame_r_len_3_w_timestamp() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'r';
         res[1] = b'1';
         res[2] = b'2';
@@ -1411,20 +2328,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Standard(StandardId::new(0x123).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
-                    timestamp: Some(1),
-                    is_remote: true
+                    timestamp: Some(SlcanTimestamp::Milliseconds(1)),
+                    is_remote: true,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 16)
         );
     }
 
     #[test]
     fn test_serialize_standard_frame_t_len_3_w_timestamp() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b't';
         res[1] = b'1';
         res[2] = b'2';
@@ -1446,20 +2366,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Standard(StandardId::new(0x123).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
-                    timestamp: Some(1),
-                    is_remote: false
+                    timestamp: Some(SlcanTimestamp::Milliseconds(1)),
+                    is_remote: false,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 16)
         );
     }
 
     #[test]
     fn test_serialize_extended_frame_r_len_3_w_timestamp() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'R';
         res[1] = b'1';
         res[2] = b'2';
@@ -1486,20 +2409,23 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Extended(ExtendedId::new(0x12345678).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
-                    timestamp: Some(1),
-                    is_remote: true
+                    timestamp: Some(SlcanTimestamp::Milliseconds(1)),
+                    is_remote: true,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 21)
         );
     }
 
     #[test]
     fn test_serialize_extended_frame_t_len_3_w_timestamp() {
         let mut serializer = SlcanSerializer::new();
-        let mut res: [u8; 31] = [0; 31];
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
         res[0] = b'T';
         res[1] = b'1';
         res[2] = b'2';
@@ -1526,13 +2452,517 @@ mod tests {
             serializer
                 .to_bytes(SlcanCommand::Frame(CanFrame {
                     id: Id::Extended(ExtendedId::new(0x12345678).unwrap()),
-                    data: [0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00],
+                    data: classic_data([0xf1, 0xf2, 0xf3, 0x00, 0x00, 0x00, 0x00, 0x00]),
                     dlc: 3,
-                    timestamp: Some(1),
-                    is_remote: false
+                    timestamp: Some(SlcanTimestamp::Milliseconds(1)),
+                    is_remote: false,
+                    is_fd: false,
+                    brs: false,
+                    esi: false
                 }))
                 .unwrap(),
-            res
+            (res, 21)
+        );
+    }
+
+    #[test]
+    fn test_canfd_dlc_to_len() {
+        assert_eq!(canfd_dlc_to_len(8), Some(8));
+        assert_eq!(canfd_dlc_to_len(9), Some(12));
+        assert_eq!(canfd_dlc_to_len(13), Some(32));
+        assert_eq!(canfd_dlc_to_len(15), Some(64));
+        assert_eq!(canfd_dlc_to_len(16), None);
+    }
+
+    #[test]
+    fn test_canfd_len_to_dlc() {
+        assert_eq!(canfd_len_to_dlc(0), Some(0));
+        assert_eq!(canfd_len_to_dlc(20), Some(0xB));
+        assert_eq!(canfd_len_to_dlc(48), Some(0xE));
+        assert_eq!(canfd_len_to_dlc(9), None);
+        assert_eq!(canfd_len_to_dlc(65), None);
+    }
+
+    #[test]
+    fn test_new_classic_frame_too_long() {
+        assert_eq!(
+            CanFrame::new(StandardId::new(0x123).unwrap(), false, &[0; 9]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_new_fd_frame_invalid_len() {
+        assert_eq!(
+            CanFrame::new_fd(StandardId::new(0x123).unwrap(), false, false, &[0; 10]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_deserialize_standard_fd_frame_d_len_12_valid_data() {
+        let mut serializer = SlcanSerializer::new();
+        // d1239 : can_id 0x123, dlc 9 (12 bytes)
+        assert_eq!(
+            serializer.from_bytes(b"d1239000102030405060708090A0B\r"),
+            Ok(SlcanCommand::Frame(
+                CanFrame::new_fd(
+                    StandardId::new(0x123).unwrap(),
+                    false,
+                    false,
+                    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]
+                )
+                .unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_standard_fd_frame_b_len_3_valid_data() {
+        let mut serializer = SlcanSerializer::new();
+        let cmd = serializer.from_bytes(b"b4563112233\r");
+        assert_eq!(
+            cmd,
+            Ok(SlcanCommand::Frame(
+                CanFrame::new_fd(
+                    StandardId::new(0x456).unwrap(),
+                    true,
+                    false,
+                    &[0x11, 0x22, 0x33]
+                )
+                .unwrap()
+            ))
+        );
+
+        let Ok(SlcanCommand::Frame(frame)) = cmd else {
+            panic!("Expected a frame");
+        };
+        assert!(frame.is_fd());
+        assert!(frame.brs());
+        assert!(!frame.is_remote());
+    }
+
+    #[test]
+    fn test_deserialize_extended_fd_frame_d_len_64_valid_data() {
+        let mut serializer = SlcanSerializer::new();
+        let mut msg = [b'0'; 1 + 8 + 1 + 128 + 1];
+        msg[0] = b'D';
+        msg[1..9].copy_from_slice(b"12ABCDEF");
+        msg[9] = b'F';
+        msg[138] = b'\r';
+
+        assert_eq!(
+            serializer.from_bytes(&msg),
+            Ok(SlcanCommand::Frame(
+                CanFrame::new_fd(ExtendedId::new(0x12ABCDEF).unwrap(), false, false, &[0; 64])
+                    .unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_extended_fd_frame_b_len_0_valid_data() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"B12ABCDEF0\r"),
+            Ok(SlcanCommand::Frame(
+                CanFrame::new_fd(ExtendedId::new(0x12ABCDEF).unwrap(), true, false, &[]).unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_standard_fd_frame_invalid_data_len() {
+        let mut serializer = SlcanSerializer::new();
+        // DLC 9 requires 12 bytes of data
+        assert_eq!(
+            serializer.from_bytes(b"d12391122334455667788\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_extended_fd_frame_invalid_hex_in_dlc() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"D12ABCDEFX11\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_standard_frame_t_classic_dlc_too_high() {
+        let mut serializer = SlcanSerializer::new();
+        // DLC 9 is only valid on FD frames
+        assert_eq!(
+            serializer.from_bytes(b"t1239000102030405060708090A0B\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_serialize_standard_fd_frame_d_len_12() {
+        let mut serializer = SlcanSerializer::new();
+        let expected = b"d123900112233445566778899AABB\r";
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[0..expected.len()].copy_from_slice(expected);
+
+        assert_eq!(
+            serializer
+                .to_bytes(SlcanCommand::Frame(
+                    CanFrame::new_fd(
+                        StandardId::new(0x123).unwrap(),
+                        false,
+                        false,
+                        &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB]
+                    )
+                    .unwrap()
+                ))
+                .unwrap(),
+            (res, expected.len())
+        );
+    }
+
+    #[test]
+    fn test_serialize_extended_fd_frame_b_len_3_w_timestamp() {
+        let mut serializer = SlcanSerializer::new();
+        let expected = b"B123456783F1F2F30001\r";
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[0..expected.len()].copy_from_slice(expected);
+
+        let mut frame = CanFrame::new_fd(
+            ExtendedId::new(0x12345678).unwrap(),
+            true,
+            false,
+            &[0xf1, 0xf2, 0xf3],
+        )
+        .unwrap();
+        frame.timestamp = Some(SlcanTimestamp::Milliseconds(1));
+
+        assert_eq!(
+            serializer.to_bytes(SlcanCommand::Frame(frame)).unwrap(),
+            (res, expected.len())
+        );
+    }
+
+    #[test]
+    fn test_serialize_standard_frame_t_w_timestamp_micros() {
+        let mut serializer = SlcanSerializer::new();
+        let expected = b"t1232112200ABCDEF\r";
+        let mut res: [u8; SLCAN_MTU] = [0; SLCAN_MTU];
+        res[0..expected.len()].copy_from_slice(expected);
+
+        let mut frame =
+            CanFrame::new(StandardId::new(0x123).unwrap(), false, &[0x11, 0x22]).unwrap();
+        frame.timestamp = Some(SlcanTimestamp::Microseconds(0xABCDEF));
+
+        assert_eq!(
+            serializer.to_bytes(SlcanCommand::Frame(frame)).unwrap(),
+            (res, expected.len())
+        );
+    }
+
+    #[test]
+    fn test_serialize_longest_message() {
+        let mut serializer = SlcanSerializer::new();
+        let mut frame =
+            CanFrame::new_fd(ExtendedId::new(0x1ABCDEF).unwrap(), false, true, &[0; 64]).unwrap();
+        frame.timestamp = Some(SlcanTimestamp::Microseconds(u32::MAX));
+
+        let (_, size) = serializer.to_bytes(SlcanCommand::TxEcho(frame)).unwrap();
+        assert_eq!(size, SLCAN_MTU);
+    }
+
+    #[test]
+    fn test_deserialize_echo_mode() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"E1\r"),
+            Ok(SlcanCommand::EchoMode(true))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"E0\r"),
+            Ok(SlcanCommand::EchoMode(false))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"E2\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_auto_poll() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"X0\r"),
+            Ok(SlcanCommand::AutoPoll(false))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"X1\r"),
+            Ok(SlcanCommand::AutoPoll(true))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"X\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_poll() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(serializer.from_bytes(b"P\r"), Ok(SlcanCommand::PollOne));
+        assert_eq!(serializer.from_bytes(b"A\r"), Ok(SlcanCommand::PollAll));
+        assert_eq!(
+            serializer.from_bytes(b"P1\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_auto_startup() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"Q0\r"),
+            Ok(SlcanCommand::AutoStartup(SlcanStartupMode::Off))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"Q1\r"),
+            Ok(SlcanCommand::AutoStartup(SlcanStartupMode::Normal))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"Q2\r"),
+            Ok(SlcanCommand::AutoStartup(SlcanStartupMode::ListenOnly))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"Q3\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_config() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"cRb\r"),
+            Ok(SlcanCommand::ReadConfig(SlcanConfigKey::Bitrate))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cF\r"),
+            Ok(SlcanCommand::FactoryReset)
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cRz\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cF1\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_write_config() {
+        let mut serializer = SlcanSerializer::new();
+        assert_eq!(
+            serializer.from_bytes(b"cWb0007A120\r"),
+            Ok(SlcanCommand::WriteConfig(SlcanConfigValue::Bitrate(Some(
+                500_000
+            ))))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cWs031C\r"),
+            Ok(SlcanCommand::WriteConfig(SlcanConfigValue::BitTiming(
+                Some(SlcanBitTiming {
+                    btr0: 0x03,
+                    btr1: 0x1C
+                })
+            )))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cWx\r"),
+            Ok(SlcanCommand::WriteConfig(SlcanConfigValue::Crystal(None)))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cWt2\r"),
+            Ok(SlcanCommand::WriteConfig(SlcanConfigValue::Timestamp(
+                SlcanTimestampMode::Microseconds
+            )))
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cWnBench car\r"),
+            Ok(SlcanCommand::WriteConfig(SlcanConfigValue::Nickname(
+                SlcanNickname::new(b"Bench car").unwrap()
+            )))
+        );
+
+        assert_eq!(
+            serializer.from_bytes(b"cWu00000000\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cWu1C200\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+        assert_eq!(
+            serializer.from_bytes(b"cWt3\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_serialize_config_value() {
+        let mut serializer = SlcanSerializer::new();
+
+        let (res, size) = serializer
+            .to_bytes(SlcanCommand::ConfigValue(SlcanConfigValue::UartBaud(Some(
+                921_600,
+            ))))
+            .unwrap();
+        assert_eq!(&res[0..size], b"cu000E1000\r");
+
+        let (res, size) = serializer
+            .to_bytes(SlcanCommand::ConfigValue(SlcanConfigValue::BitTiming(None)))
+            .unwrap();
+        assert_eq!(&res[0..size], b"cs\r");
+
+        let nickname = SlcanNickname::new(b"Bench car").unwrap();
+        let (res, size) = serializer
+            .to_bytes(SlcanCommand::ConfigValue(SlcanConfigValue::Nickname(
+                nickname,
+            )))
+            .unwrap();
+        assert_eq!(&res[0..size], b"cnBench car\r");
+        assert_eq!(
+            serializer.from_bytes(b"cWnBench car\r"),
+            Ok(SlcanCommand::WriteConfig(SlcanConfigValue::Nickname(
+                nickname
+            )))
+        );
+    }
+
+    #[test]
+    fn test_serialize_poll_end() {
+        let mut serializer = SlcanSerializer::new();
+        let (res, size) = serializer.to_bytes(SlcanCommand::PollEnd).unwrap();
+        assert_eq!(&res[0..size], b"A\r");
+    }
+
+    #[test]
+    fn test_serialize_tx_ack() {
+        let mut serializer = SlcanSerializer::new();
+
+        let (res, size) = serializer
+            .to_bytes(SlcanCommand::TxAck(StandardId::new(0x123).unwrap().into()))
+            .unwrap();
+        assert_eq!(&res[0..size], b"z\r");
+
+        let (res, size) = serializer
+            .to_bytes(SlcanCommand::TxAck(ExtendedId::new(0x123).unwrap().into()))
+            .unwrap();
+        assert_eq!(&res[0..size], b"Z\r");
+    }
+
+    #[test]
+    fn test_serialize_tx_echo_w_timestamp() {
+        let mut serializer = SlcanSerializer::new();
+        let mut frame =
+            CanFrame::new(StandardId::new(0x123).unwrap(), false, &[0x11, 0x22]).unwrap();
+        frame.timestamp = Some(SlcanTimestamp::Milliseconds(0x1234));
+
+        let (res, size) = serializer.to_bytes(SlcanCommand::TxEcho(frame)).unwrap();
+        assert_eq!(&res[0..size], b"zt123211221234\r");
+    }
+
+    #[test]
+    fn test_deserialize_fd_frame_esi() {
+        let mut serializer = SlcanSerializer::new();
+        let cmd = serializer.from_bytes(b"b4563112233i\r");
+        assert_eq!(
+            cmd,
+            Ok(SlcanCommand::Frame(
+                CanFrame::new_fd(
+                    StandardId::new(0x456).unwrap(),
+                    true,
+                    true,
+                    &[0x11, 0x22, 0x33]
+                )
+                .unwrap()
+            ))
+        );
+
+        assert_eq!(
+            serializer.from_bytes(b"b4563112233x\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+        assert_eq!(
+            serializer.from_bytes(b"D123456780ii\r"),
+            Err(SlcanError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_serialize_fd_frame_esi_w_timestamp() {
+        let mut serializer = SlcanSerializer::new();
+        let mut frame = CanFrame::new_fd(
+            ExtendedId::new(0x12345678).unwrap(),
+            false,
+            true,
+            &[0xf1, 0xf2, 0xf3],
+        )
+        .unwrap();
+        frame.timestamp = Some(SlcanTimestamp::Milliseconds(1));
+
+        let (res, size) = serializer.to_bytes(SlcanCommand::Frame(frame)).unwrap();
+        assert_eq!(&res[0..size], b"D123456783F1F2F3i0001\r");
+    }
+
+    #[test]
+    fn test_fd_frame_round_trip_esi() {
+        let mut serializer = SlcanSerializer::new();
+        for (id, brs) in [(0x7FF, false), (0x1, true)] {
+            let frame =
+                CanFrame::new_fd(StandardId::new(id).unwrap(), brs, true, &[0xAA; 12]).unwrap();
+            let (buffer, size) = serializer.to_bytes(SlcanCommand::Frame(frame)).unwrap();
+
+            let Ok(SlcanCommand::Frame(frame)) = serializer.from_bytes(&buffer[0..size]) else {
+                panic!("Expected a frame");
+            };
+            assert!(frame.esi());
+            assert_eq!(frame.brs(), brs);
+            assert_eq!(frame.data(), &[0xAA; 12]);
+        }
+
+        let frame =
+            CanFrame::new_fd(ExtendedId::new(0x1ABCDEF).unwrap(), true, true, &[0; 64]).unwrap();
+        let (buffer, size) = serializer.to_bytes(SlcanCommand::TxEcho(frame)).unwrap();
+        assert_eq!(size, SLCAN_MTU - 8);
+        assert_eq!(
+            serializer.from_bytes(&buffer[1..size]),
+            Ok(SlcanCommand::Frame(
+                CanFrame::new_fd(ExtendedId::new(0x1ABCDEF).unwrap(), true, true, &[0; 64])
+                    .unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_fd_frame_round_trip_len_64() {
+        let mut serializer = SlcanSerializer::new();
+        let mut data = [0; 64];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let frame =
+            CanFrame::new_fd(ExtendedId::new(0x1ABCDEF).unwrap(), true, false, &data).unwrap();
+        let (buffer, size) = serializer.to_bytes(SlcanCommand::Frame(frame)).unwrap();
+
+        assert_eq!(size, 1 + 8 + 1 + 128 + 1);
+        assert_eq!(
+            serializer.from_bytes(&buffer[0..size]),
+            Ok(SlcanCommand::Frame(
+                CanFrame::new_fd(ExtendedId::new(0x1ABCDEF).unwrap(), true, false, &data).unwrap()
+            ))
         );
     }
 }
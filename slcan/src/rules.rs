@@ -0,0 +1,202 @@
+use crate::CanFrame;
+use embedded_can::Id;
+
+// Payload bytes a single rule can check
+pub const SLCAN_RULE_MAX_PAYLOAD_MATCHES: usize = 4;
+
+// Largest extended id
+const EXTENDED_ID_MAX: u32 = 0x1FFF_FFFF;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanIdFormat {
+    Any,
+    Standard,
+    Extended,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SlcanFrameType {
+    Any,
+    Data,
+    Remote,
+}
+
+// Payload byte at `index` must be equal to `value` on the bits set in `mask`
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct SlcanPayloadMatch {
+    pub index: u8,
+    pub value: u8,
+    pub mask: u8,
+}
+
+// Software filter rule, set with fA. A frame matches when its id is in the
+// range, with the format and type of the rule, and every payload match holds
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SlcanFilterRule {
+    pub format: SlcanIdFormat,
+    pub frame_type: SlcanFrameType,
+    pub id_low: u32,
+    pub id_high: u32,
+    payload: [SlcanPayloadMatch; SLCAN_RULE_MAX_PAYLOAD_MATCHES],
+    payload_len: usize,
+}
+
+impl Default for SlcanFilterRule {
+    // Match every frame
+    fn default() -> Self {
+        SlcanFilterRule {
+            format: SlcanIdFormat::Any,
+            frame_type: SlcanFrameType::Any,
+            id_low: 0,
+            id_high: EXTENDED_ID_MAX,
+            payload: [SlcanPayloadMatch::default(); SLCAN_RULE_MAX_PAYLOAD_MATCHES],
+            payload_len: 0,
+        }
+    }
+}
+
+impl SlcanFilterRule {
+    // Returns None for an empty or out of range id range
+    pub fn new(
+        format: SlcanIdFormat,
+        frame_type: SlcanFrameType,
+        id_low: u32,
+        id_high: u32,
+    ) -> Option<Self> {
+        let rule = SlcanFilterRule {
+            format,
+            frame_type,
+            id_low,
+            id_high,
+            ..Default::default()
+        };
+
+        rule.is_valid().then_some(rule)
+    }
+
+    // The fields are public, a rule built by hand may have a wrong id range
+    pub fn is_valid(&self) -> bool {
+        self.id_low <= self.id_high && self.id_high <= EXTENDED_ID_MAX
+    }
+
+    // Returns None when the rule already has all its payload matches
+    pub fn with_payload_match(mut self, payload_match: SlcanPayloadMatch) -> Option<Self> {
+        if self.payload_len == SLCAN_RULE_MAX_PAYLOAD_MATCHES {
+            return None;
+        }
+
+        self.payload[self.payload_len] = payload_match;
+        self.payload_len += 1;
+
+        Some(self)
+    }
+
+    pub fn payload_matches(&self) -> &[SlcanPayloadMatch] {
+        &self.payload[0..self.payload_len]
+    }
+
+    pub fn matches(&self, frame: &CanFrame) -> bool {
+        let id = match (self.format, frame.id) {
+            (SlcanIdFormat::Any | SlcanIdFormat::Standard, Id::Standard(id)) => id.as_raw() as u32,
+            (SlcanIdFormat::Any | SlcanIdFormat::Extended, Id::Extended(id)) => id.as_raw(),
+            _ => return false,
+        };
+
+        if id < self.id_low || id > self.id_high {
+            return false;
+        }
+
+        match (self.frame_type, frame.is_remote()) {
+            (SlcanFrameType::Data, true) | (SlcanFrameType::Remote, false) => return false,
+            _ => {}
+        }
+
+        // Remote frames carry no payload, so they fail any payload match
+        let data = if frame.is_remote() {
+            &[][..]
+        } else {
+            frame.data()
+        };
+
+        self.payload_matches().iter().all(|m| {
+            data.get(m.index as usize)
+                .is_some_and(|byte| (byte ^ m.value) & m.mask == 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::{ExtendedId, StandardId};
+
+    fn standard_frame(id: u16, data: &[u8]) -> CanFrame {
+        CanFrame::new(StandardId::new(id).unwrap(), false, data).unwrap()
+    }
+
+    fn payload_match(index: u8, value: u8, mask: u8) -> SlcanPayloadMatch {
+        SlcanPayloadMatch { index, value, mask }
+    }
+
+    #[test]
+    fn test_rule_invalid_range() {
+        assert_eq!(
+            SlcanFilterRule::new(SlcanIdFormat::Any, SlcanFrameType::Any, 0x200, 0x100),
+            None
+        );
+        assert_eq!(
+            SlcanFilterRule::new(SlcanIdFormat::Any, SlcanFrameType::Any, 0, 0x2000_0000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rule_id_range() {
+        let rule =
+            SlcanFilterRule::new(SlcanIdFormat::Any, SlcanFrameType::Any, 0x100, 0x1FF).unwrap();
+        assert!(rule.matches(&standard_frame(0x100, &[])));
+        assert!(rule.matches(&standard_frame(0x1FF, &[])));
+        assert!(!rule.matches(&standard_frame(0x200, &[])));
+    }
+
+    #[test]
+    fn test_rule_format() {
+        let rule =
+            SlcanFilterRule::new(SlcanIdFormat::Extended, SlcanFrameType::Any, 0, 0x7FF).unwrap();
+        let extended = CanFrame::new(ExtendedId::new(0x123).unwrap(), false, &[]).unwrap();
+        assert!(rule.matches(&extended));
+        assert!(!rule.matches(&standard_frame(0x123, &[])));
+    }
+
+    #[test]
+    fn test_rule_remote_only() {
+        let rule =
+            SlcanFilterRule::new(SlcanIdFormat::Any, SlcanFrameType::Remote, 0, 0x7FF).unwrap();
+        let remote = CanFrame::new(StandardId::new(0x123).unwrap(), true, &[0; 2]).unwrap();
+        assert!(rule.matches(&remote));
+        assert!(!rule.matches(&standard_frame(0x123, &[])));
+    }
+
+    #[test]
+    fn test_rule_payload() {
+        let rule = SlcanFilterRule::new(SlcanIdFormat::Any, SlcanFrameType::Any, 0x7E8, 0x7E8)
+            .unwrap()
+            .with_payload_match(payload_match(1, 0x41, 0xFF))
+            .unwrap()
+            .with_payload_match(payload_match(2, 0x0C, 0x0F))
+            .unwrap();
+        assert!(rule.matches(&standard_frame(0x7E8, &[0x04, 0x41, 0xFC])));
+        assert!(!rule.matches(&standard_frame(0x7E8, &[0x04, 0x41, 0xFD])));
+        // Too short to hold the byte
+        assert!(!rule.matches(&standard_frame(0x7E8, &[0x04, 0x41])));
+    }
+
+    #[test]
+    fn test_rule_payload_full() {
+        let mut rule = SlcanFilterRule::default();
+        for index in 0..SLCAN_RULE_MAX_PAYLOAD_MATCHES as u8 {
+            rule = rule.with_payload_match(payload_match(index, 0, 0)).unwrap();
+        }
+        assert_eq!(rule.with_payload_match(payload_match(0, 0, 0)), None);
+    }
+}